@@ -0,0 +1,53 @@
+//! Measures per-call overhead of `PySandbox::exec` on a warm sandbox,
+//! with on-demand and pooling instance allocation.
+//!
+//! Run with `cargo bench --bench exec_latency` after building
+//! sandbox.wasm.
+
+use pybox::sandbox::{EngineOptions, PoolingOptions, PySandbox};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 50;
+
+fn main() {
+    if !Path::new("sandbox.wasm").exists() {
+        eprintln!("sandbox.wasm not found, run `uv run build_component.py` first");
+        return;
+    }
+
+    // Compiling and linking happen here, once, and not in the exec loop
+    let start = Instant::now();
+    let on_demand = PySandbox::new(None).expect("Failed to create sandbox");
+    println!("construction: {:?}", start.elapsed());
+    measure("on-demand allocation", on_demand);
+
+    let pooling = PySandbox::builder()
+        .engine_options(EngineOptions {
+            pooling: Some(PoolingOptions::default()),
+            ..EngineOptions::default()
+        })
+        .build()
+        .expect("Failed to create pooling sandbox");
+    measure("pooling allocation", pooling);
+}
+
+fn measure(name: &str, mut sandbox: PySandbox) {
+    // Warm up so one-time costs aren't measured
+    sandbox.exec("1").unwrap();
+
+    let mut samples: Vec<Duration> = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            sandbox.exec("1 + 1").unwrap();
+            start.elapsed()
+        })
+        .collect();
+    samples.sort();
+
+    let total: Duration = samples.iter().sum();
+    println!("exec latency with {} over {} runs", name, ITERATIONS);
+    println!("  mean: {:?}", total / ITERATIONS);
+    println!("  p50:  {:?}", samples[samples.len() / 2]);
+    println!("  p99:  {:?}", samples[samples.len() * 99 / 100]);
+}
@@ -0,0 +1,55 @@
+//! Compares moving a large input through `exec_with_inputs` as JSON and
+//! as MessagePack, and reading it back with `get_globals`.
+//!
+//! Run with `cargo bench --bench transport --features msgpack` after
+//! building sandbox.wasm.
+
+use pybox::sandbox::{PySandbox, Transport};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+const ROWS: usize = 50_000;
+
+fn main() {
+    if !Path::new("sandbox.wasm").exists() {
+        eprintln!("sandbox.wasm not found, run `cargo xtask build-component` first");
+        return;
+    }
+
+    let rows: Vec<Value> = (0..ROWS)
+        .map(|i| json!({"id": i, "score": i as f64 / 7.0, "name": format!("row-{}", i)}))
+        .collect();
+    let mut inputs = Map::new();
+    inputs.insert("rows".to_string(), Value::Array(rows));
+
+    for transport in [Transport::Json, Transport::MessagePack] {
+        let sandbox = PySandbox::builder()
+            .transport(transport)
+            .keep_globals(true)
+            .build()
+            .expect("Failed to create sandbox");
+        measure(transport, sandbox, &inputs);
+    }
+}
+
+fn measure(transport: Transport, mut sandbox: PySandbox, inputs: &Map<String, Value>) {
+    // Warm up so one-time costs aren't measured
+    sandbox.exec_with_inputs("len(rows)", inputs).unwrap();
+
+    let mut samples: Vec<Duration> = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            sandbox.exec_with_inputs("out = rows[::-1]\nlen(out)", inputs).unwrap();
+            sandbox.get_globals(&["out"]).unwrap();
+            start.elapsed()
+        })
+        .collect();
+    samples.sort();
+
+    let total: Duration = samples.iter().sum();
+    println!("{} rows in and out as {:?} over {} runs", ROWS, transport, ITERATIONS);
+    println!("  mean: {:?}", total / ITERATIONS);
+    println!("  p50:  {:?}", samples[samples.len() / 2]);
+}
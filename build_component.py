@@ -1,23 +1,86 @@
 #!/usr/bin/env python3
 """Build script to generate sandbox.wasm from guest.py using componentize-py."""
 
+import argparse
+import os
 import subprocess
 import sys
 
+# App module generated when preloading modules, componentize-py imports it
+# while snapshotting so the preloaded modules end up in the initial memory
+PREINIT_MODULE = "guest_preinit"
+
+
+def is_module_name(name):
+    return all(part.isidentifier() for part in name.split("."))
+
+
 def main():
-    print("Building sandbox.wasm from guest.py...")
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("-o", "--output", default="sandbox.wasm")
+    parser.add_argument(
+        "--preload",
+        nargs="*",
+        default=[],
+        metavar="MODULE",
+        help="modules to import before the interpreter is snapshotted",
+    )
+    parser.add_argument(
+        "-p",
+        "--python-path",
+        action="append",
+        default=[],
+        metavar="DIR",
+        help="extra directory to bundle, e.g. unpacked wasi wheels of numpy",
+    )
+    parser.add_argument(
+        "--scientific",
+        metavar="WHEELS_DIR",
+        help="bundle numpy and pandas from WHEELS_DIR into sandbox-scientific.wasm",
+    )
+    args = parser.parse_args()
+
+    if args.scientific:
+        args.python_path.append(args.scientific)
+        args.preload.extend(["numpy", "pandas"])
+        if args.output == "sandbox.wasm":
+            args.output = "sandbox-scientific.wasm"
+
+    for module in args.preload:
+        if not is_module_name(module):
+            parser.error(f"invalid module name {module!r}")
+
+    app = "guest"
+    if args.preload:
+        app = PREINIT_MODULE
+        with open(f"{PREINIT_MODULE}.py", "w") as f:
+            f.write("from guest import WitWorld  # noqa: F401\n")
+            for module in args.preload:
+                f.write(f"import {module}  # noqa: F401\n")
+
+    print(f"Building {args.output} from guest.py...")
+    # Built against real WASI rather than `--stub-wasi`: the guest reads
+    # scripts and packages from the directories the host mounts, which the
+    # stubbed filesystem interface can't do. The host decides what the
+    # guest can reach through the mounts and stdio it configures.
     cmd = [
         "componentize-py",
         "-d", "sandbox.wit",
         "componentize",
-        "--stub-wasi",
-        "guest",
-        "-o", "sandbox.wasm"
+        app,
+        "-o", args.output
     ]
+    # Passing any path replaces the default, so keep the guest's directory
+    for path in ["."] + args.python_path if args.python_path else []:
+        cmd.extend(["-p", path])
 
-    result = subprocess.run(cmd, check=True)
-    print("Successfully built sandbox.wasm")
+    try:
+        subprocess.run(cmd, check=True)
+    finally:
+        if args.preload:
+            os.remove(f"{PREINIT_MODULE}.py")
+    print(f"Successfully built {args.output}")
     return 0
 
 if __name__ == "__main__":
-    sys.exit(main())
\ No newline at end of file
+    sys.exit(main())
@@ -0,0 +1,188 @@
+#ifndef PYBOX_H
+#define PYBOX_H
+
+/* Generated by cbindgen from ffi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The outcome of a call, `PYBOX_STATUS_OK` on success.
+ */
+typedef enum PyboxStatus {
+  PYBOX_STATUS_OK = 0,
+  /**
+   * The code raised an exception it didn't handle.
+   */
+  PYBOX_STATUS_PYTHON_ERROR = 1,
+  /**
+   * The code ran past its timeout.
+   */
+  PYBOX_STATUS_TIMEOUT = 2,
+  /**
+   * The code used up its fuel limit.
+   */
+  PYBOX_STATUS_FUEL_EXHAUSTED = 3,
+  /**
+   * The execution was cancelled.
+   */
+  PYBOX_STATUS_CANCELLED = 4,
+  /**
+   * The code ran into a table, instance or stack limit.
+   */
+  PYBOX_STATUS_LIMIT_EXCEEDED = 5,
+  /**
+   * A required pointer was NULL or a string was not UTF-8.
+   */
+  PYBOX_STATUS_INVALID_ARGUMENT = 6,
+  /**
+   * Any other failure, such as the component failing to load.
+   */
+  PYBOX_STATUS_ERROR = 7,
+  /**
+   * The code ran out of memory under the sandbox's memory limit.
+   */
+  PYBOX_STATUS_MEMORY_LIMIT_EXCEEDED = 8,
+  /**
+   * The guest crashed or was stopped for requesting random bytes under
+   * a deny policy, `pybox_result_error` says how.
+   */
+  PYBOX_STATUS_TRAPPED = 9,
+  /**
+   * The code was over the sandbox's size or line limit and didn't run.
+   */
+  PYBOX_STATUS_CODE_TOO_LARGE = 10,
+  /**
+   * The sandbox's execution quota is used up for now.
+   */
+  PYBOX_STATUS_QUOTA_EXCEEDED = 11,
+  /**
+   * The code ran more lines of Python than the sandbox's line budget.
+   */
+  PYBOX_STATUS_LINE_BUDGET_EXCEEDED = 12,
+} PyboxStatus;
+
+/**
+ * The outcome of `pybox_exec`, read with the `pybox_result_*` functions.
+ */
+typedef struct PyboxResult PyboxResult;
+
+/**
+ * A sandbox, created with `pybox_sandbox_new`.
+ */
+typedef struct PyboxSandbox PyboxSandbox;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a sandbox from the component at `component_path`, or
+ * `sandbox.wasm` when it is NULL. A `timeout_seconds` of 0 uses the
+ * default timeout.
+ *
+ * Returns NULL on failure, `pybox_last_error` describes why. Free the
+ * sandbox with `pybox_sandbox_free`.
+ *
+ * # Safety
+ *
+ * `component_path` must be NULL or a NUL terminated string.
+ */
+struct PyboxSandbox *pybox_sandbox_new(const char *component_path, uint64_t timeout_seconds);
+
+/**
+ * Free a sandbox. Does nothing when `sandbox` is NULL.
+ *
+ * # Safety
+ *
+ * `sandbox` must be NULL or come from `pybox_sandbox_new`, and must not
+ * be used afterwards.
+ */
+void pybox_sandbox_free(struct PyboxSandbox *sandbox);
+
+/**
+ * Run `code` on a fresh interpreter and capture its output. A
+ * `timeout_ms` of 0 uses the sandbox's timeout.
+ *
+ * Always returns a result, free it with `pybox_result_free`.
+ *
+ * # Safety
+ *
+ * `sandbox` must come from `pybox_sandbox_new` and not be used by
+ * another thread during the call. `code` must be a NUL terminated
+ * string.
+ */
+struct PyboxResult *pybox_exec(struct PyboxSandbox *sandbox, const char *code, uint64_t timeout_ms);
+
+/**
+ * The status of a result.
+ *
+ * # Safety
+ *
+ * `result` must come from `pybox_exec`.
+ */
+enum PyboxStatus pybox_result_status(const struct PyboxResult *result);
+
+/**
+ * The JSON encoded value of the last expression, NULL unless the status
+ * is `PYBOX_STATUS_OK`.
+ *
+ * # Safety
+ *
+ * `result` must come from `pybox_exec`.
+ */
+const char *pybox_result_value(const struct PyboxResult *result);
+
+/**
+ * Text the code printed to stdout.
+ *
+ * # Safety
+ *
+ * `result` must come from `pybox_exec`.
+ */
+const char *pybox_result_stdout(const struct PyboxResult *result);
+
+/**
+ * Text the code printed to stderr.
+ *
+ * # Safety
+ *
+ * `result` must come from `pybox_exec`.
+ */
+const char *pybox_result_stderr(const struct PyboxResult *result);
+
+/**
+ * A description of the failure, NULL when the status is
+ * `PYBOX_STATUS_OK`.
+ *
+ * # Safety
+ *
+ * `result` must come from `pybox_exec`.
+ */
+const char *pybox_result_error(const struct PyboxResult *result);
+
+/**
+ * Free a result and the strings read from it. Does nothing when
+ * `result` is NULL.
+ *
+ * # Safety
+ *
+ * `result` must be NULL or come from `pybox_exec`, and must not be used
+ * afterwards.
+ */
+void pybox_result_free(struct PyboxResult *result);
+
+/**
+ * Why the last `pybox_sandbox_new` on this thread failed, or NULL. Valid
+ * until the next failure on the same thread.
+ */
+const char *pybox_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PYBOX_H */
@@ -0,0 +1,394 @@
+//! C ABI for embedding pybox in applications not written in Rust, built
+//! as `libpybox_ffi`. `include/pybox.h` is generated from this file with
+//! `cargo xtask ffi-header` and checked into the repository, building the
+//! crate doesn't touch it. `cargo xtask ffi-header --check` fails when
+//! it is out of date.
+//!
+//! ```c
+//! PyboxSandbox *sandbox = pybox_sandbox_new(NULL, 5);
+//! if (sandbox == NULL) {
+//!     fprintf(stderr, "%s\n", pybox_last_error());
+//!     return 1;
+//! }
+//! PyboxResult *result = pybox_exec(sandbox, "print('hi')\n1 + 1", 0);
+//! if (pybox_result_status(result) == PYBOX_STATUS_OK) {
+//!     printf("%s%s\n", pybox_result_stdout(result), pybox_result_value(result));
+//! } else {
+//!     fprintf(stderr, "%s\n", pybox_result_error(result));
+//! }
+//! pybox_result_free(result);
+//! pybox_sandbox_free(sandbox);
+//! ```
+//!
+//! Strings passed in must be NUL terminated UTF-8. Strings returned are
+//! owned by the object they came from and stay valid until it is freed.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use pybox::error::{PyboxError, PythonError};
+use pybox::sandbox::{ExecOptions, ExecOutput, PySandbox};
+
+/// The outcome of a call, `PYBOX_STATUS_OK` on success.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PyboxStatus {
+    Ok = 0,
+    /// The code raised an exception it didn't handle.
+    PythonError = 1,
+    /// The code ran past its timeout.
+    Timeout = 2,
+    /// The code used up its fuel limit.
+    FuelExhausted = 3,
+    /// The execution was cancelled.
+    Cancelled = 4,
+    /// The code ran into a table, instance or stack limit.
+    LimitExceeded = 5,
+    /// A required pointer was NULL or a string was not UTF-8.
+    InvalidArgument = 6,
+    /// Any other failure, such as the component failing to load.
+    Error = 7,
+    /// The code ran out of memory under the sandbox's memory limit.
+    MemoryLimitExceeded = 8,
+    /// The guest crashed or was stopped for requesting random bytes under
+    /// a deny policy, `pybox_result_error` says how.
+    Trapped = 9,
+    /// The code was over the sandbox's size or line limit and didn't run.
+    CodeTooLarge = 10,
+    /// The sandbox's execution quota is used up for now.
+    QuotaExceeded = 11,
+    /// The code ran more lines of Python than the sandbox's line budget.
+    LineBudgetExceeded = 12,
+}
+
+impl PyboxStatus {
+    fn of(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<PythonError>().is_some() {
+            return PyboxStatus::PythonError;
+        }
+        match error.downcast_ref::<PyboxError>() {
+            Some(PyboxError::Timeout) => PyboxStatus::Timeout,
+            Some(PyboxError::FuelExhausted { .. }) => PyboxStatus::FuelExhausted,
+            Some(PyboxError::ExecutionBudgetExceeded { .. }) => PyboxStatus::LineBudgetExceeded,
+            Some(PyboxError::Cancelled) => PyboxStatus::Cancelled,
+            Some(PyboxError::LimitExceeded(_)) => PyboxStatus::LimitExceeded,
+            Some(PyboxError::MemoryLimitExceeded { .. }) => PyboxStatus::MemoryLimitExceeded,
+            Some(PyboxError::Trap(_) | PyboxError::RandomDenied) => PyboxStatus::Trapped,
+            Some(PyboxError::CodeTooLarge { .. }) => PyboxStatus::CodeTooLarge,
+            Some(PyboxError::QuotaExceeded { .. }) => PyboxStatus::QuotaExceeded,
+            None => PyboxStatus::Error,
+        }
+    }
+}
+
+/// A sandbox, created with `pybox_sandbox_new`.
+pub struct PyboxSandbox {
+    sandbox: PySandbox,
+}
+
+/// The outcome of `pybox_exec`, read with the `pybox_result_*` functions.
+pub struct PyboxResult {
+    status: PyboxStatus,
+    value: Option<CString>,
+    stdout: CString,
+    stderr: CString,
+    error: Option<CString>,
+}
+
+impl PyboxResult {
+    fn new(result: Result<ExecOutput>) -> Self {
+        match result {
+            Ok(output) => PyboxResult {
+                status: PyboxStatus::Ok,
+                value: Some(c_string(output.value)),
+                stdout: c_string(output.stdout),
+                stderr: c_string(output.stderr),
+                error: None,
+            },
+            Err(e) => PyboxResult {
+                status: PyboxStatus::of(&e),
+                value: None,
+                stdout: CString::default(),
+                stderr: CString::default(),
+                error: Some(c_string(format!("{:#}", e))),
+            },
+        }
+    }
+
+    fn failure(status: PyboxStatus, message: &str) -> Self {
+        PyboxResult {
+            status,
+            value: None,
+            stdout: CString::default(),
+            stderr: CString::default(),
+            error: Some(c_string(message.to_string())),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(c_string(message)));
+}
+
+/// Create a sandbox from the component at `component_path`, or
+/// `sandbox.wasm` when it is NULL. A `timeout_seconds` of 0 uses the
+/// default timeout.
+///
+/// Returns NULL on failure, `pybox_last_error` describes why. Free the
+/// sandbox with `pybox_sandbox_free`.
+///
+/// # Safety
+///
+/// `component_path` must be NULL or a NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_sandbox_new(
+    component_path: *const c_char,
+    timeout_seconds: u64,
+) -> *mut PyboxSandbox {
+    let result = catch(|| {
+        let mut builder = PySandbox::builder();
+        if !component_path.is_null() {
+            builder = builder.component_file(unsafe { str_arg(component_path) }?);
+        }
+        if timeout_seconds > 0 {
+            builder = builder.timeout_seconds(timeout_seconds);
+        }
+        builder.build()
+    });
+    match result {
+        Ok(sandbox) => Box::into_raw(Box::new(PyboxSandbox { sandbox })),
+        Err(e) => {
+            set_last_error(format!("{:#}", e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Free a sandbox. Does nothing when `sandbox` is NULL.
+///
+/// # Safety
+///
+/// `sandbox` must be NULL or come from `pybox_sandbox_new`, and must not
+/// be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_sandbox_free(sandbox: *mut PyboxSandbox) {
+    if !sandbox.is_null() {
+        drop(unsafe { Box::from_raw(sandbox) });
+    }
+}
+
+/// Run `code` on a fresh interpreter and capture its output. A
+/// `timeout_ms` of 0 uses the sandbox's timeout.
+///
+/// Always returns a result, free it with `pybox_result_free`.
+///
+/// # Safety
+///
+/// `sandbox` must come from `pybox_sandbox_new` and not be used by
+/// another thread during the call. `code` must be a NUL terminated
+/// string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec(
+    sandbox: *mut PyboxSandbox,
+    code: *const c_char,
+    timeout_ms: u64,
+) -> *mut PyboxResult {
+    let result = if sandbox.is_null() || code.is_null() {
+        PyboxResult::failure(PyboxStatus::InvalidArgument, "sandbox and code must not be NULL")
+    } else {
+        match unsafe { str_arg(code) } {
+            Ok(code) => {
+                let sandbox = unsafe { &mut (*sandbox).sandbox };
+                let options = ExecOptions {
+                    timeout: (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms)),
+                    capture_output: true,
+                    ..Default::default()
+                };
+                PyboxResult::new(catch(|| sandbox.exec_with_options(code, &options)))
+            }
+            Err(e) => PyboxResult::failure(PyboxStatus::InvalidArgument, &e.to_string()),
+        }
+    };
+    Box::into_raw(Box::new(result))
+}
+
+/// The status of a result.
+///
+/// # Safety
+///
+/// `result` must come from `pybox_exec`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_status(result: *const PyboxResult) -> PyboxStatus {
+    unsafe { &*result }.status
+}
+
+/// The JSON encoded value of the last expression, NULL unless the status
+/// is `PYBOX_STATUS_OK`.
+///
+/// # Safety
+///
+/// `result` must come from `pybox_exec`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_value(result: *const PyboxResult) -> *const c_char {
+    opt_ptr(&unsafe { &*result }.value)
+}
+
+/// Text the code printed to stdout.
+///
+/// # Safety
+///
+/// `result` must come from `pybox_exec`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_stdout(result: *const PyboxResult) -> *const c_char {
+    unsafe { &*result }.stdout.as_ptr()
+}
+
+/// Text the code printed to stderr.
+///
+/// # Safety
+///
+/// `result` must come from `pybox_exec`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_stderr(result: *const PyboxResult) -> *const c_char {
+    unsafe { &*result }.stderr.as_ptr()
+}
+
+/// A description of the failure, NULL when the status is
+/// `PYBOX_STATUS_OK`.
+///
+/// # Safety
+///
+/// `result` must come from `pybox_exec`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_error(result: *const PyboxResult) -> *const c_char {
+    opt_ptr(&unsafe { &*result }.error)
+}
+
+/// Free a result and the strings read from it. Does nothing when
+/// `result` is NULL.
+///
+/// # Safety
+///
+/// `result` must be NULL or come from `pybox_exec`, and must not be used
+/// afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_result_free(result: *mut PyboxResult) {
+    if !result.is_null() {
+        drop(unsafe { Box::from_raw(result) });
+    }
+}
+
+/// Why the last `pybox_sandbox_new` on this thread failed, or NULL. Valid
+/// until the next failure on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| opt_ptr(&last.borrow()))
+}
+
+/// Run `f`, turning a panic into an error so it doesn't unwind into C.
+fn catch<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow!("pybox panicked: {}", message))
+    })
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str> {
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| anyhow!("Argument is not valid UTF-8"))
+}
+
+// Output may contain NUL bytes, which C strings can't hold
+fn c_string(s: String) -> CString {
+    CString::new(s).unwrap_or_else(|e| {
+        let s = String::from_utf8_lossy(&e.into_vec()).replace('\0', "\u{FFFD}");
+        CString::new(s).unwrap()
+    })
+}
+
+fn opt_ptr(s: &Option<CString>) -> *const c_char {
+    s.as_deref().map_or(ptr::null(), CStr::as_ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pybox::error::PyException;
+
+    fn string(s: *const c_char) -> Option<String> {
+        (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_status_by_error_kind() {
+        let exception = anyhow::Error::from(PythonError {
+            exception: PyException::Value,
+            message: "bad".to_string(),
+            lineno: None,
+        });
+        assert_eq!(PyboxStatus::of(&exception), PyboxStatus::PythonError);
+        assert_eq!(PyboxStatus::of(&PyboxError::Timeout.into()), PyboxStatus::Timeout);
+        let over_budget = PyboxError::ExecutionBudgetExceeded { budget: 50, executed: 51 };
+        assert_eq!(PyboxStatus::of(&over_budget.into()), PyboxStatus::LineBudgetExceeded);
+        assert_eq!(PyboxStatus::of(&anyhow!("no sandbox.wasm")), PyboxStatus::Error);
+    }
+
+    #[test]
+    fn test_exec_rejects_null_arguments() {
+        unsafe {
+            let result = pybox_exec(ptr::null_mut(), c"1".as_ptr(), 0);
+            assert_eq!(pybox_result_status(result), PyboxStatus::InvalidArgument);
+            assert_eq!(string(pybox_result_value(result)), None);
+            assert!(string(pybox_result_error(result)).is_some());
+            assert_eq!(string(pybox_result_stdout(result)).as_deref(), Some(""));
+            pybox_result_free(result);
+        }
+    }
+
+    #[test]
+    fn test_sandbox_new_sets_last_error() {
+        let sandbox = unsafe { pybox_sandbox_new(c"missing.wasm".as_ptr(), 0) };
+        assert!(sandbox.is_null());
+        assert!(string(pybox_last_error()).is_some());
+    }
+
+    #[test]
+    fn test_c_string_replaces_nul() {
+        assert_eq!(c_string("a\0b".to_string()).to_str().unwrap(), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_exec() {
+        let component = c"../sandbox.wasm";
+        if !std::path::Path::new("../sandbox.wasm").exists() {
+            return;
+        }
+        unsafe {
+            let sandbox = pybox_sandbox_new(component.as_ptr(), 0);
+            assert!(!sandbox.is_null());
+            let result = pybox_exec(sandbox, c"print('hi')\n1 + 1".as_ptr(), 0);
+            assert_eq!(pybox_result_status(result), PyboxStatus::Ok);
+            assert_eq!(string(pybox_result_value(result)).as_deref(), Some("2"));
+            assert_eq!(string(pybox_result_stdout(result)).as_deref(), Some("hi\n"));
+            pybox_result_free(result);
+
+            let result = pybox_exec(sandbox, c"{}['a']".as_ptr(), 0);
+            assert_eq!(pybox_result_status(result), PyboxStatus::PythonError);
+            pybox_result_free(result);
+            pybox_sandbox_free(sandbox);
+        }
+    }
+}
@@ -1,81 +1,1354 @@
 import wit_world
 from componentize_py_types import Err
+import builtins
+import contextlib
+import inspect
+import io
 import json
+import os
+import pickle
+import struct
+import sys
+import types
+import typing
 
+# Variables left behind by the most recent exec, read back by get_global
+last_namespace: dict = {}
 
-def handle(e: Exception) -> Err[str]:
+# Import policy set by the host, None means any module may be imported
+allowed_imports: set | None = None
+blocked_imports: set = set()
+
+# Directory matplotlib figures are saved to when the host captures them
+figure_dir: str | None = None
+figures_captured = 0
+# Figure.savefig before capture_figures wrapped it
+original_savefig = None
+
+# Whether user code polls the host for an interrupt at its timeout, and
+# how many trace events pass between polls
+interrupt_checks = False
+INTERRUPT_CHECK_INTERVAL = 1000
+interrupt_countdown = INTERRUPT_CHECK_INTERVAL
+interrupt_raised = False
+
+# Most lines of Python the host lets an execution run, if limited, and how
+# many the current one has run
+line_budget: int | None = None
+lines_run = 0
+budget_exceeded = False
+
+
+def handle(e: Exception) -> Err[wit_world.PythonError]:
+    return Err(
+        wit_world.PythonError(
+            exception_type=type(e).__name__, message=str(e), lineno=error_lineno(e)
+        )
+    )
+
+
+def describe(e: Exception) -> str:
+    """Format e as the exception's class name followed by its message."""
     message = str(e)
     if message == "":
-        return Err(f"{type(e).__name__}")
+        return f"{type(e).__name__}"
     else:
-        return Err(f"{type(e).__name__}: {message}")
+        return f"{type(e).__name__}: {message}"
+
+
+def error_lineno(e: Exception) -> int | None:
+    """Line of the user's code that raised e, if it came from there."""
+    if isinstance(e, SyntaxError) and e.filename == "<string>":
+        return e.lineno
+    lineno = None
+    tb = e.__traceback__
+    while tb is not None:
+        if tb.tb_frame.f_code.co_filename == "<string>":
+            lineno = tb.tb_lineno
+        tb = tb.tb_next
+    return lineno
+
+
+class ExecutionBudgetExceeded(Exception):
+    """Raised in user code once it runs more lines than the host's
+    line_budget. Code that catches it can clean up, but the execution
+    fails anyway. Lines are counted by a trace function the code itself
+    can remove, so the budget is not a security boundary."""
+
+
+class HostCallError(Exception):
+    """Raised in user code when a host function fails."""
+
+
+def call_host(name: str, args=None):
+    """Call the host function registered as name with JSON serializable
+    args and return its decoded result."""
+    try:
+        result = wit_world.host_call(name, json.dumps(args))
+    except Err as e:
+        raise HostCallError(e.value) from None
+    return json.loads(result)
+
+
+def host_input(prompt="") -> str:
+    """Replacement for input() asking the host's on_input callback for
+    the line instead of reading stdin."""
+    # Output printed before the prompt should reach the host first
+    sys.stdout.flush()
+    line = wit_world.read_input(str(prompt))
+    if line is None:
+        raise EOFError("EOF when reading a line")
+    return line
+
+
+class ExtensionError(Exception):
+    """Raised in user code when a host extension fails."""
+
+
+def call_extension(extension: str, method: str, payload: str = "") -> str:
+    """Call method on the host extension registered as extension."""
+    try:
+        return wit_world.extension_call(extension, method, payload)
+    except Err as e:
+        raise ExtensionError(e.value) from None
+
+
+# Methods of the IPython display protocol, by the MIME type they render
+REPR_METHODS = {
+    "text/html": "_repr_html_",
+    "image/png": "_repr_png_",
+    "application/json": "_repr_json_",
+}
+
+
+def display(obj, mime: str | None = None) -> None:
+    """Send obj to the host as a rich output of type mime. Without mime the
+    type is picked from the object's _repr_*_ methods, falling back to JSON
+    for dicts and lists and plain text for anything else."""
+    if mime is None:
+        mime = infer_mime(obj)
+    method = REPR_METHODS.get(mime)
+    if method is not None and hasattr(obj, method):
+        obj = getattr(obj, method)()
+    wit_world.display(mime, encode_display(obj, mime))
+
+
+def progress(percent, message: str = "") -> None:
+    """Tell the host how far along the code is, percent from 0 to 100,
+    with an optional message describing the current step."""
+    percent = float(percent)
+    if percent != percent:
+        raise ValueError("percent must be a number, not NaN")
+    wit_world.report_progress(max(0.0, min(100.0, percent)), str(message))
+
+
+def infer_mime(obj) -> str:
+    for mime, method in REPR_METHODS.items():
+        if hasattr(obj, method):
+            return mime
+    if isinstance(obj, (dict, list)):
+        return "application/json"
+    return "text/plain"
+
+
+def encode_display(obj, mime: str) -> bytes:
+    """Bytes and strings are sent as is, other objects are serialized
+    according to mime."""
+    if isinstance(obj, (bytes, bytearray, memoryview)):
+        return bytes(obj)
+    if isinstance(obj, str):
+        return obj.encode()
+    if mime == "application/json" or mime.endswith("+json"):
+        return json.dumps(obj).encode()
+    if mime == "text/plain":
+        return repr(obj).encode()
+    raise TypeError(f"can't display {type(obj).__name__} as {mime}")
+
+
+# Expose host functions and extensions to user code as `pybox.host` and
+# `pybox.extensions`, rich output as `pybox.display` and progress reports
+# as `pybox.progress`
+pybox_module = types.ModuleType("pybox")
+pybox_module.display = display
+pybox_module.progress = progress
+pybox_module.host = types.ModuleType("pybox.host")
+pybox_module.host.call = call_host
+pybox_module.host.HostCallError = HostCallError
+pybox_module.extensions = types.ModuleType("pybox.extensions")
+pybox_module.extensions.call = call_extension
+pybox_module.extensions.ExtensionError = ExtensionError
+pybox_module.ExecutionBudgetExceeded = ExecutionBudgetExceeded
+sys.modules["pybox"] = pybox_module
+sys.modules["pybox.host"] = pybox_module.host
+sys.modules["pybox.extensions"] = pybox_module.extensions
 
 
 class WitWorld(wit_world.WitWorld):
+    def configure(self, settings: str) -> None:
+        try:
+            settings = json.loads(settings)
+            if "argv" in settings:
+                sys.argv = [str(arg) for arg in settings["argv"]]
+            for entry in reversed(settings.get("path", [])):
+                if entry not in sys.path:
+                    sys.path.insert(0, entry)
+            global allowed_imports, blocked_imports
+            allowed = settings.get("allowed_imports")
+            allowed_imports = None if allowed is None else set(allowed)
+            blocked_imports = set(settings.get("blocked_imports", []))
+            if settings.get("figure_dir"):
+                capture_figures(settings["figure_dir"])
+            if settings.get("input"):
+                builtins.input = host_input
+            global interrupt_checks, line_budget
+            interrupt_checks = bool(settings.get("interrupt"))
+            line_budget = settings.get("line_budget")
+        except Exception as e:
+            raise handle(e)
+
     def eval(self, code: str) -> str:
         try:
             program = compile(code, "<string>", "eval")
-            return json.dumps(eval(program))
+            return json.dumps(eval(program, last_namespace))
         except Exception as e:
             raise handle(e)
 
-    def exec(self, code: str) -> None:
+    def exec(self, code: str) -> str:
         try:
-            local_vars = {}
+            return run_statements(code, {})
+        except Exception as e:
+            raise handle(e)
 
-            # Split into lines and filter empty ones, but keep track of indentation
-            all_lines = code.split('\n')
+    def exec_captured(self, code: str) -> wit_world.CapturedOutput:
+        try:
+            stdout, stderr = io.StringIO(), io.StringIO()
+            with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+                value = run_statements(code, {})
+            return wit_world.CapturedOutput(
+                value=value, stdout=stdout.getvalue(), stderr=stderr.getvalue()
+            )
+        except Exception as e:
+            raise handle(e)
 
-            # Group lines into complete statements (handling multi-line blocks)
-            statements = []
-            i = 0
-            while i < len(all_lines):
-                line = all_lines[i]
-
-                # Skip empty lines
-                if not line.strip():
-                    i += 1
-                    continue
-
-                # Start of a new statement
-                current_stmt = [line]
-
-                # Check if this line ends with ':' (start of indented block)
-                if line.rstrip().endswith(':'):
-                    i += 1
-                    # Collect all indented lines that follow
-                    while i < len(all_lines):
-                        next_line = all_lines[i]
-                        if not next_line.strip():
-                            # Keep empty lines in the block
-                            current_stmt.append(next_line)
-                        elif next_line[0] == ' ' or next_line[0] == '\t':
-                            # Indented line - part of the block
-                            current_stmt.append(next_line)
-                        else:
-                            # Not indented - end of block
-                            break
-                        i += 1
-                else:
-                    i += 1
+    def exec_bytes(self, code: str) -> bytes:
+        try:
+            result = evaluate_statements(code, {})
+            if not isinstance(result, (bytes, bytearray, memoryview)):
+                raise TypeError(
+                    f"last expression must be bytes, got {type(result).__name__}"
+                )
+            return bytes(result)
+        except Exception as e:
+            raise handle(e)
+
+    def exec_with_inputs(self, code: str, inputs: str) -> str:
+        try:
+            local_vars = json.loads(inputs)
+            for name in local_vars:
+                if not name.isidentifier():
+                    raise ValueError(f"invalid input name {name!r}")
+            return run_statements(code, local_vars)
+        except Exception as e:
+            raise handle(e)
 
-                statements.append('\n'.join(current_stmt))
+    def exec_with_inputs_msgpack(self, code: str, inputs: bytes) -> bytes:
+        try:
+            local_vars = msgpack_unpack(inputs)
+            if not isinstance(local_vars, dict):
+                raise TypeError("inputs must be a map")
+            for name in local_vars:
+                if not isinstance(name, str) or not name.isidentifier():
+                    raise ValueError(f"invalid input name {name!r}")
+            return msgpack_pack(evaluate_statements(code, local_vars))
+        except Exception as e:
+            raise handle(e)
 
-            if not statements:
-                return json.dumps(None)
+    def exec_with_table(self, code: str, table_ipc: bytes) -> bytes:
+        try:
+            local_vars = {"table": arrow_read_stream(table_ipc)}
+            return arrow_write_stream(as_table(evaluate_statements(code, local_vars)))
+        except Exception as e:
+            raise handle(e)
 
-            # Execute all but the last statement
-            for stmt in statements[:-1]:
-                exec(stmt, {}, local_vars)
+    def describe_tools(self, source: str) -> str:
+        try:
+            tools = define_tools(source)
+            return json.dumps([tool_schema(function) for function in tools.values()])
+        except Exception as e:
+            raise handle(e)
 
-            # Try to evaluate last statement as expression
-            last_stmt = statements[-1]
+    def invoke_tool(self, source: str, name: str, arguments: str) -> str:
+        try:
+            tools = define_tools(source)
+            if name not in tools:
+                raise NameError(f"no tool named '{name}'")
+            arguments = json.loads(arguments)
+            if not isinstance(arguments, dict):
+                raise TypeError("tool arguments must be a JSON object")
+            function = tools[name]
+            # Report missing or unexpected arguments as the caller's
+            # mistake rather than a TypeError from inside the tool
             try:
-                result = eval(last_stmt, {}, local_vars)
-            except SyntaxError:
-                exec(last_stmt, {}, local_vars)
-                result = None
+                inspect.signature(function).bind(**arguments)
+            except TypeError as e:
+                raise ValueError(f"invalid arguments for {name}: {e}") from None
+            return json.dumps(function(**arguments))
+        except Exception as e:
+            raise handle(e)
 
+    def call_function(self, name: str, args: str, kwargs: str) -> str:
+        try:
+            if name not in last_namespace:
+                raise NameError(f"name '{name}' is not defined")
+            function = last_namespace[name]
+            if not callable(function):
+                raise TypeError(f"'{name}' is not callable")
+            result = function(*json.loads(args), **json.loads(kwargs))
             return json.dumps(result)
         except Exception as e:
             raise handle(e)
+
+    def exec_cell(self, code: str) -> str:
+        try:
+            return run_cell(lambda: run_statements(code, last_namespace))
+        except Exception as e:
+            raise handle(e)
+
+    def eval_cell(self, expression: str) -> str:
+        try:
+            return run_cell(
+                lambda: json.dumps(
+                    eval(compile(expression, "<string>", "eval"), last_namespace)
+                )
+            )
+        except Exception as e:
+            raise handle(e)
+
+    def snapshot_globals(self) -> bytes:
+        try:
+            return snapshot_namespace(last_namespace)
+        except Exception as e:
+            raise handle(e)
+
+    def restore_globals(self, snapshot: bytes) -> None:
+        try:
+            global last_namespace
+            last_namespace = restore_namespace(snapshot)
+        except Exception as e:
+            raise handle(e)
+
+    def version(self) -> str:
+        try:
+            return json.dumps(interpreter_version())
+        except Exception as e:
+            raise handle(e)
+
+    def get_global(self, name: str) -> str:
+        try:
+            if name not in last_namespace:
+                raise NameError(f"name '{name}' is not defined")
+            return json.dumps(last_namespace[name])
+        except Exception as e:
+            raise handle(e)
+
+    def get_global_msgpack(self, name: str) -> bytes:
+        try:
+            if name not in last_namespace:
+                raise NameError(f"name '{name}' is not defined")
+            return msgpack_pack(last_namespace[name])
+        except Exception as e:
+            raise handle(e)
+
+
+def msgpack_pack(obj) -> bytes:
+    """Encode obj as MessagePack. Supports the types JSON does plus
+    bytes, tuples are encoded as arrays."""
+    out = bytearray()
+    pack_into(out, obj)
+    return bytes(out)
+
+
+def pack_into(out: bytearray, obj) -> None:
+    if obj is None:
+        out.append(0xC0)
+    elif obj is True or obj is False:
+        out.append(0xC3 if obj else 0xC2)
+    elif isinstance(obj, int):
+        if 0 <= obj < 0x80:
+            out.append(obj)
+        elif -0x20 <= obj < 0:
+            out += struct.pack(">b", obj)
+        elif 0 <= obj < 2**64:
+            out += struct.pack(">BQ", 0xCF, obj)
+        elif -(2**63) <= obj < 0:
+            out += struct.pack(">Bq", 0xD3, obj)
+        else:
+            raise OverflowError("int too large to encode as MessagePack")
+    elif isinstance(obj, float):
+        out += struct.pack(">Bd", 0xCB, obj)
+    elif isinstance(obj, str):
+        data = obj.encode("utf-8")
+        pack_header(out, len(data), 0xA0, 32, (0xD9, 0xDA, 0xDB))
+        out += data
+    elif isinstance(obj, (bytes, bytearray, memoryview)):
+        data = bytes(obj)
+        pack_header(out, len(data), None, 0, (0xC4, 0xC5, 0xC6))
+        out += data
+    elif isinstance(obj, (list, tuple)):
+        pack_header(out, len(obj), 0x90, 16, (None, 0xDC, 0xDD))
+        for item in obj:
+            pack_into(out, item)
+    elif isinstance(obj, dict):
+        pack_header(out, len(obj), 0x80, 16, (None, 0xDE, 0xDF))
+        for key, value in obj.items():
+            pack_into(out, key)
+            pack_into(out, value)
+    else:
+        raise TypeError(
+            f"Object of type {type(obj).__name__} is not MessagePack serializable"
+        )
+
+
+def pack_header(out: bytearray, length: int, fix, fix_limit: int, sized) -> None:
+    """Write the type and length of a str, bin, array or map, using the
+    fix form below fix_limit and otherwise the smallest of the 8, 16 and
+    32 bit forms in sized that fits."""
+    if fix is not None and length < fix_limit:
+        out.append(fix | length)
+        return
+    for code, fmt, limit in zip(sized, (">BB", ">BH", ">BI"), (2**8, 2**16, 2**32)):
+        if code is not None and length < limit:
+            out += struct.pack(fmt, code, length)
+            return
+    raise OverflowError("value too large to encode as MessagePack")
+
+
+def msgpack_unpack(data: bytes):
+    """Decode a single MessagePack value, the inverse of msgpack_pack.
+    Extension types are rejected."""
+    value, offset = unpack_from(memoryview(data), 0)
+    if offset != len(data):
+        raise ValueError("trailing data after MessagePack value")
+    return value
+
+
+# Fixed size types: format and payload size by type byte
+MSGPACK_SCALARS = {
+    0xCA: ">f", 0xCB: ">d",
+    0xCC: ">B", 0xCD: ">H", 0xCE: ">I", 0xCF: ">Q",
+    0xD0: ">b", 0xD1: ">h", 0xD2: ">i", 0xD3: ">q",
+}
+# Sized types: kind and the format of their length by type byte
+MSGPACK_SIZED = {
+    0xC4: ("bin", ">B"), 0xC5: ("bin", ">H"), 0xC6: ("bin", ">I"),
+    0xD9: ("str", ">B"), 0xDA: ("str", ">H"), 0xDB: ("str", ">I"),
+    0xDC: ("array", ">H"), 0xDD: ("array", ">I"),
+    0xDE: ("map", ">H"), 0xDF: ("map", ">I"),
+}
+
+
+def unpack_from(data: memoryview, offset: int):
+    if offset >= len(data):
+        raise ValueError("truncated MessagePack value")
+    code = data[offset]
+    offset += 1
+    if code <= 0x7F:
+        return code, offset
+    if code >= 0xE0:
+        return code - 0x100, offset
+    if code == 0xC0:
+        return None, offset
+    if code in (0xC2, 0xC3):
+        return code == 0xC3, offset
+    if code in MSGPACK_SCALARS:
+        fmt = MSGPACK_SCALARS[code]
+        (value,) = struct.unpack_from(fmt, data, offset)
+        return value, offset + struct.calcsize(fmt)
+    if 0x80 <= code <= 0x8F:
+        kind, length = "map", code & 0x0F
+    elif 0x90 <= code <= 0x9F:
+        kind, length = "array", code & 0x0F
+    elif 0xA0 <= code <= 0xBF:
+        kind, length = "str", code & 0x1F
+    elif code in MSGPACK_SIZED:
+        kind, fmt = MSGPACK_SIZED[code]
+        (length,) = struct.unpack_from(fmt, data, offset)
+        offset += struct.calcsize(fmt)
+    else:
+        raise ValueError(f"unsupported MessagePack type 0x{code:02x}")
+
+    if kind in ("str", "bin"):
+        end = offset + length
+        if end > len(data):
+            raise ValueError("truncated MessagePack value")
+        chunk = bytes(data[offset:end])
+        return (chunk.decode("utf-8") if kind == "str" else chunk), end
+    if kind == "array":
+        items = []
+        for _ in range(length):
+            item, offset = unpack_from(data, offset)
+            items.append(item)
+        return items, offset
+    result = {}
+    for _ in range(length):
+        key, offset = unpack_from(data, offset)
+        result[key], offset = unpack_from(data, offset)
+    return result, offset
+
+
+class Table:
+    """A table exchanged with the host as Arrow, held as one list of
+    values per column with None for nulls."""
+
+    def __init__(self, columns=None):
+        self.columns = {str(name): list(values) for name, values in (columns or {}).items()}
+        lengths = {len(values) for values in self.columns.values()}
+        if len(lengths) > 1:
+            raise ValueError("all columns of a table must have the same length")
+
+    @classmethod
+    def from_rows(cls, rows) -> "Table":
+        """Build a table from a list of dicts, missing keys become None."""
+        names = []
+        for row in rows:
+            names += [name for name in row if name not in names]
+        return cls({name: [row.get(name) for row in rows] for name in names})
+
+    @property
+    def column_names(self) -> list:
+        return list(self.columns)
+
+    @property
+    def num_rows(self) -> int:
+        return len(next(iter(self.columns.values()), []))
+
+    def __len__(self) -> int:
+        return self.num_rows
+
+    def __getitem__(self, name: str) -> list:
+        return self.columns[name]
+
+    def __setitem__(self, name: str, values) -> None:
+        values = list(values)
+        if self.columns and len(values) != self.num_rows:
+            raise ValueError(f"column {name!r} has {len(values)} values, expected {self.num_rows}")
+        self.columns[name] = values
+
+    def __eq__(self, other) -> bool:
+        return isinstance(other, Table) and self.columns == other.columns
+
+    def __repr__(self) -> str:
+        return f"Table({self.num_rows} rows, columns={self.column_names})"
+
+    def rows(self) -> list:
+        return [dict(zip(self.columns, values)) for values in zip(*self.columns.values())]
+
+    def to_pandas(self):
+        import pandas
+        return pandas.DataFrame(self.columns)
+
+
+pybox_module.Table = Table
+
+
+def as_table(obj) -> Table:
+    """Convert the value code returned from exec_with_table to a Table:
+    a Table, a dict of columns, a list of row dicts or a pandas
+    DataFrame."""
+    if isinstance(obj, Table):
+        return obj
+    if isinstance(obj, dict):
+        return Table(obj)
+    if isinstance(obj, list) and all(isinstance(row, dict) for row in obj):
+        return Table.from_rows(obj)
+    if hasattr(obj, "to_dict") and hasattr(obj, "columns"):
+        return Table(obj.to_dict(orient="list"))
+    raise TypeError(
+        f"last expression must be a table, got {type(obj).__name__}"
+    )
+
+
+# A minimal Arrow IPC stream codec: just enough of the flatbuffers format
+# to read and write schemas and record batches of int, float, bool and
+# utf8 columns, as pyarrow isn't available in the guest.
+
+ARROW_CONTINUATION = 0xFFFFFFFF
+ARROW_VERSION_V5 = 4
+# MessageHeader union members
+ARROW_SCHEMA, ARROW_DICTIONARY_BATCH, ARROW_RECORD_BATCH = 1, 2, 3
+# Type union members
+ARROW_NULL, ARROW_INT, ARROW_FLOAT, ARROW_UTF8, ARROW_BOOL, ARROW_LARGE_UTF8 = 1, 2, 3, 5, 6, 20
+ARROW_INT_FORMATS = {
+    (8, True): "b", (16, True): "h", (32, True): "i", (64, True): "q",
+    (8, False): "B", (16, False): "H", (32, False): "I", (64, False): "Q",
+}
+# FloatingPoint precision: HALF, SINGLE, DOUBLE
+ARROW_FLOAT_FORMATS = {0: "e", 1: "f", 2: "d"}
+
+
+class FlatTable:
+    """Read access to a flatbuffers table at pos in buf."""
+
+    def __init__(self, buf, pos: int):
+        self.buf = buf
+        self.pos = pos
+        (soffset,) = struct.unpack_from("<i", buf, pos)
+        self.vtable = pos - soffset
+        (self.vtable_size,) = struct.unpack_from("<H", buf, self.vtable)
+
+    def offset(self, slot: int) -> int:
+        entry = 4 + 2 * slot
+        if entry >= self.vtable_size:
+            return 0
+        return struct.unpack_from("<H", self.buf, self.vtable + entry)[0]
+
+    def scalar(self, slot: int, fmt: str, default=0):
+        offset = self.offset(slot)
+        if not offset:
+            return default
+        return struct.unpack_from(fmt, self.buf, self.pos + offset)[0]
+
+    def indirect(self, slot: int):
+        offset = self.offset(slot)
+        if not offset:
+            return None
+        at = self.pos + offset
+        return at + struct.unpack_from("<I", self.buf, at)[0]
+
+    def table(self, slot: int):
+        at = self.indirect(slot)
+        return None if at is None else FlatTable(self.buf, at)
+
+    def string(self, slot: int):
+        at = self.indirect(slot)
+        if at is None:
+            return None
+        (length,) = struct.unpack_from("<I", self.buf, at)
+        return bytes(self.buf[at + 4:at + 4 + length]).decode("utf-8")
+
+    def structs(self, slot: int, fmt: str) -> list:
+        at = self.indirect(slot)
+        if at is None:
+            return []
+        (length,) = struct.unpack_from("<I", self.buf, at)
+        size = struct.calcsize(fmt)
+        return [struct.unpack_from(fmt, self.buf, at + 4 + i * size) for i in range(length)]
+
+    def tables(self, slot: int) -> list:
+        at = self.indirect(slot)
+        if at is None:
+            return []
+        (length,) = struct.unpack_from("<I", self.buf, at)
+        elements = [at + 4 + 4 * i for i in range(length)]
+        return [FlatTable(self.buf, e + struct.unpack_from("<I", self.buf, e)[0]) for e in elements]
+
+
+class FlatBuilder:
+    """Writes flatbuffers front to back: every table is preceded by its
+    vtable and followed by the objects it refers to, so all offsets point
+    forward as the format requires.
+
+    Objects are tables, ("table", [(slot, fmt, value)]) where fmt is a
+    struct format or "offset" for a child object, strings, ("string", str),
+    vectors of structs, ("structs", fmt, [tuple]), and vectors of tables,
+    ("tables", [table])."""
+
+    def __init__(self):
+        self.buf = bytearray(4)
+
+    def finish(self, root) -> bytes:
+        struct.pack_into("<I", self.buf, 0, self.write(root))
+        return bytes(self.buf)
+
+    def pad(self, align: int, remainder: int = 0) -> None:
+        while len(self.buf) % align != remainder:
+            self.buf.append(0)
+
+    def write(self, obj) -> int:
+        kind = obj[0]
+        if kind == "string":
+            data = obj[1].encode("utf-8")
+            self.pad(4)
+            at = len(self.buf)
+            self.buf += struct.pack("<I", len(data)) + data + b"\0"
+            return at
+        if kind == "structs":
+            _, fmt, items = obj
+            # Elements are 8 byte aligned, after the 4 byte length
+            self.pad(8, 4)
+            at = len(self.buf)
+            self.buf += struct.pack("<I", len(items))
+            for item in items:
+                self.buf += struct.pack(fmt, *item)
+            return at
+        if kind == "tables":
+            children = obj[1]
+            self.pad(4)
+            at = len(self.buf)
+            self.buf += struct.pack("<I", len(children)) + bytes(4 * len(children))
+            for i, child in enumerate(children):
+                element = at + 4 + 4 * i
+                struct.pack_into("<I", self.buf, element, self.write(child) - element)
+            return at
+        return self.write_table(obj[1])
+
+    def write_table(self, fields: list) -> int:
+        # Lay out fields largest first after the vtable offset, with the
+        # table starting 4 bytes past an 8 byte boundary so 8 byte fields
+        # right after the vtable offset are aligned
+        def size(field):
+            return 4 if field[1] == "offset" else struct.calcsize(field[1])
+
+        layout = []
+        end = 4
+        for field in sorted(fields, key=size, reverse=True):
+            while (4 + end) % size(field):
+                end += 1
+            layout.append((end, field))
+            end += size(field)
+        slots = 1 + max((field[0] for field in fields), default=-1)
+        vtable = [0] * slots
+        for offset, (slot, _, _) in layout:
+            vtable[slot] = offset
+
+        self.pad(2)
+        vtable_at = len(self.buf)
+        self.buf += struct.pack(f"<HH{slots}H", 4 + 2 * slots, end, *vtable)
+        self.pad(8, 4)
+        at = len(self.buf)
+        self.buf += struct.pack("<i", at - vtable_at) + bytes(end - 4)
+        for offset, (_, fmt, value) in layout:
+            if fmt != "offset":
+                struct.pack_into(fmt, self.buf, at + offset, value)
+        for offset, (_, fmt, value) in layout:
+            if fmt == "offset":
+                field_at = at + offset
+                struct.pack_into("<I", self.buf, field_at, self.write(value) - field_at)
+        return at
+
+
+def arrow_read_stream(data: bytes) -> Table:
+    """Decode an Arrow IPC stream into a Table, concatenating its record
+    batches."""
+    data = memoryview(data)
+    offset = 0
+    fields = None
+    columns = None
+    while offset + 4 <= len(data):
+        (length,) = struct.unpack_from("<I", data, offset)
+        offset += 4
+        # Streams before Arrow 0.15 have no continuation marker
+        if length == ARROW_CONTINUATION:
+            (length,) = struct.unpack_from("<I", data, offset)
+            offset += 4
+        if length == 0:
+            break
+        metadata = data[offset:offset + length]
+        message = FlatTable(metadata, struct.unpack_from("<I", metadata, 0)[0])
+        offset += length
+        header_type = message.scalar(1, "<B")
+        header = message.table(2)
+        body_length = message.scalar(3, "<q")
+        body = data[offset:offset + body_length]
+        offset += body_length
+
+        if header_type == ARROW_SCHEMA:
+            fields = [arrow_read_field(field) for field in header.tables(1)]
+            columns = {name: [] for name, _ in fields}
+        elif header_type == ARROW_RECORD_BATCH:
+            if fields is None:
+                raise ValueError("Arrow record batch before the schema")
+            if header.table(3) is not None:
+                raise ValueError("compressed Arrow record batches are not supported")
+            nodes = iter(header.structs(1, "<qq"))
+            buffers = iter(header.structs(2, "<qq"))
+            for name, arrow_type in fields:
+                length, null_count = next(nodes)
+                columns[name] += arrow_read_column(arrow_type, length, null_count, buffers, body)
+        elif header_type == ARROW_DICTIONARY_BATCH:
+            raise ValueError("dictionary encoded Arrow columns are not supported")
+        else:
+            raise ValueError(f"unsupported Arrow message type {header_type}")
+    if columns is None:
+        raise ValueError("Arrow stream has no schema")
+    return Table(columns)
+
+
+def arrow_read_field(field: FlatTable):
+    name = field.string(0) or ""
+    type_id = field.scalar(2, "<B")
+    detail = field.table(3)
+    if field.table(4) is not None:
+        raise ValueError(f"column {name!r} is dictionary encoded, which is not supported")
+    if type_id == ARROW_INT:
+        arrow_type = ("int", ARROW_INT_FORMATS[(detail.scalar(0, "<i"), bool(detail.scalar(1, "<B")))])
+    elif type_id == ARROW_FLOAT:
+        arrow_type = ("float", ARROW_FLOAT_FORMATS[detail.scalar(0, "<h")])
+    elif type_id == ARROW_UTF8:
+        arrow_type = ("utf8", "i")
+    elif type_id == ARROW_LARGE_UTF8:
+        arrow_type = ("utf8", "q")
+    elif type_id == ARROW_BOOL:
+        arrow_type = ("bool", None)
+    elif type_id == ARROW_NULL:
+        arrow_type = ("null", None)
+    else:
+        raise ValueError(f"column {name!r} has an unsupported Arrow type ({type_id})")
+    return name, arrow_type
+
+
+def arrow_read_column(arrow_type, length: int, null_count: int, buffers, body) -> list:
+    kind, fmt = arrow_type
+    if kind == "null":
+        return [None] * length
+
+    def buffer():
+        offset, size = next(buffers)
+        return body[offset:offset + size]
+
+    validity = buffer()
+    if kind == "int" or kind == "float":
+        values = list(struct.unpack_from(f"<{length}{fmt}", buffer()))
+    elif kind == "bool":
+        values = arrow_read_bits(buffer(), length)
+    else:
+        offsets = struct.unpack_from(f"<{length + 1}{fmt}", buffer())
+        data = bytes(buffer())
+        values = [data[offsets[i]:offsets[i + 1]].decode("utf-8") for i in range(length)]
+    if null_count and len(validity):
+        valid = arrow_read_bits(validity, length)
+        values = [value if ok else None for value, ok in zip(values, valid)]
+    return values
+
+
+def arrow_read_bits(data, length: int) -> list:
+    return [bool(data[i >> 3] & (1 << (i & 7))) for i in range(length)]
+
+
+def arrow_write_bits(bits) -> bytes:
+    out = bytearray((len(bits) + 7) // 8)
+    for i, bit in enumerate(bits):
+        if bit:
+            out[i >> 3] |= 1 << (i & 7)
+    return bytes(out)
+
+
+def arrow_column_type(name: str, values: list) -> str:
+    present = [value for value in values if value is not None]
+    if all(isinstance(value, bool) for value in present):
+        return "bool" if present else "utf8"
+    if any(isinstance(value, bool) for value in present):
+        raise TypeError(f"column {name!r} mixes bools with other values")
+    if all(isinstance(value, int) for value in present):
+        return "int64"
+    if all(isinstance(value, (int, float)) for value in present):
+        return "float64"
+    if all(isinstance(value, str) for value in present):
+        return "utf8"
+    kinds = sorted({type(value).__name__ for value in present})
+    raise TypeError(f"column {name!r} can't be sent as Arrow, it holds {', '.join(kinds)}")
+
+
+def arrow_message(header_type: int, header, body: bytes) -> bytes:
+    message = ("table", [
+        (0, "<h", ARROW_VERSION_V5),
+        (1, "<B", header_type),
+        (2, "offset", header),
+        (3, "<q", len(body)),
+    ])
+    metadata = FlatBuilder().finish(message)
+    # Keep the body that follows 8 byte aligned
+    metadata += bytes(-len(metadata) % 8)
+    return struct.pack("<Ii", ARROW_CONTINUATION, len(metadata)) + metadata + body
+
+
+def arrow_write_stream(table: Table) -> bytes:
+    """Encode a Table as an Arrow IPC stream with a single record batch.
+    Columns become int64, float64, bool or utf8 depending on their values,
+    columns of only None become utf8."""
+    types = {name: arrow_column_type(name, values) for name, values in table.columns.items()}
+    fields = []
+    for name, arrow_type in types.items():
+        if arrow_type == "int64":
+            type_id, detail = ARROW_INT, [(0, "<i", 64), (1, "<B", 1)]
+        elif arrow_type == "float64":
+            type_id, detail = ARROW_FLOAT, [(0, "<h", 2)]
+        else:
+            type_id, detail = (ARROW_BOOL if arrow_type == "bool" else ARROW_UTF8), []
+        fields.append(("table", [
+            (0, "offset", ("string", name)),
+            (1, "<B", 1),
+            (2, "<B", type_id),
+            (3, "offset", ("table", detail)),
+            (5, "offset", ("tables", [])),
+        ]))
+    schema = ("table", [(1, "offset", ("tables", fields))])
+
+    body = bytearray()
+    nodes = []
+    buffers = []
+
+    def add_buffer(data: bytes) -> None:
+        buffers.append((len(body), len(data)))
+        body.extend(data)
+        body.extend(bytes(-len(body) % 8))
+
+    length = table.num_rows
+    for name, values in table.columns.items():
+        null_count = sum(value is None for value in values)
+        nodes.append((length, null_count))
+        add_buffer(arrow_write_bits([value is not None for value in values]) if null_count else b"")
+        arrow_type = types[name]
+        if arrow_type == "int64":
+            add_buffer(struct.pack(f"<{length}q", *(0 if v is None else v for v in values)))
+        elif arrow_type == "float64":
+            add_buffer(struct.pack(f"<{length}d", *(0.0 if v is None else v for v in values)))
+        elif arrow_type == "bool":
+            add_buffer(arrow_write_bits([bool(value) for value in values]))
+        else:
+            encoded = [b"" if value is None else value.encode("utf-8") for value in values]
+            offsets = [0]
+            for data in encoded:
+                offsets.append(offsets[-1] + len(data))
+            add_buffer(struct.pack(f"<{length + 1}i", *offsets))
+            add_buffer(b"".join(encoded))
+    batch = ("table", [
+        (0, "<q", length),
+        (1, "offset", ("structs", "<qq", nodes)),
+        (2, "offset", ("structs", "<qq", buffers)),
+    ])
+
+    return (
+        arrow_message(ARROW_SCHEMA, schema, b"")
+        + arrow_message(ARROW_RECORD_BATCH, batch, bytes(body))
+        + struct.pack("<Ii", ARROW_CONTINUATION, 0)
+    )
+
+
+# Module name functions defined by tool source get, to tell them apart
+# from functions it imports
+TOOLS_MODULE = "__tools__"
+JSON_TYPES = {
+    str: "string", int: "integer", float: "number", bool: "boolean",
+    list: "array", tuple: "array", dict: "object", type(None): "null",
+}
+DOCSTRING_ARG_SECTIONS = ("Args", "Arguments", "Parameters")
+
+
+def define_tools(source: str) -> dict:
+    """Run source and return the public functions it defines by name."""
+    namespace = {"__name__": TOOLS_MODULE}
+    evaluate_statements(source, namespace)
+    return {
+        name: value
+        for name, value in namespace.items()
+        if inspect.isfunction(value)
+        and value.__module__ == TOOLS_MODULE
+        and not name.startswith("_")
+    }
+
+
+def tool_schema(function) -> dict:
+    """Describe function as a tool: its name, the description from its
+    docstring and a JSON Schema of its keyword arguments."""
+    description, documented = parse_docstring(inspect.getdoc(function) or "")
+    try:
+        hints = typing.get_type_hints(function)
+    except Exception:
+        hints = {}
+    properties = {}
+    required = []
+    for parameter in inspect.signature(function).parameters.values():
+        if parameter.kind in (parameter.VAR_POSITIONAL, parameter.VAR_KEYWORD):
+            continue
+        if parameter.kind == parameter.POSITIONAL_ONLY:
+            raise TypeError(
+                f"tool {function.__name__} has positional-only parameter {parameter.name}"
+            )
+        schema = type_schema(hints.get(parameter.name, parameter.annotation))
+        if parameter.name in documented:
+            schema["description"] = documented[parameter.name]
+        if parameter.default is parameter.empty:
+            required.append(parameter.name)
+        elif is_json(parameter.default):
+            schema["default"] = parameter.default
+        properties[parameter.name] = schema
+    return {
+        "name": function.__name__,
+        "description": description,
+        "parameters": {
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": False,
+        },
+    }
+
+
+def type_schema(annotation) -> dict:
+    """JSON Schema for a parameter annotation, empty (anything) for
+    annotations it can't express."""
+    if annotation in JSON_TYPES:
+        return {"type": JSON_TYPES[annotation]}
+    origin = typing.get_origin(annotation)
+    args = typing.get_args(annotation)
+    if origin is typing.Literal:
+        return {"enum": list(args)}
+    if origin in (typing.Union, types.UnionType):
+        options = [type_schema(arg) for arg in args]
+        if all(list(option) == ["type"] for option in options):
+            return {"type": [option["type"] for option in options]}
+        return {"anyOf": options}
+    if origin in (list, tuple, set, frozenset):
+        schema = {"type": "array"}
+        if args and args[-1] is not Ellipsis:
+            schema["items"] = type_schema(args[0])
+        return schema
+    if origin is dict:
+        schema = {"type": "object"}
+        if len(args) == 2:
+            schema["additionalProperties"] = type_schema(args[1])
+        return schema
+    return {}
+
+
+def parse_docstring(doc: str):
+    """Split a docstring into its description and the descriptions of
+    parameters documented Google style (an `Args:` section) or Sphinx
+    style (`:param name:` fields). The description ends at the first
+    section or field."""
+    description = []
+    documented = {}
+    section = None
+    current = None
+    arg_indent = None
+    for line in doc.splitlines():
+        stripped = line.strip()
+        indent = len(line) - len(line.lstrip())
+        if stripped.startswith(":"):
+            section = "fields"
+            field, _, text = stripped[1:].partition(":")
+            words = field.split()
+            current = words[-1] if len(words) > 1 and words[0] == "param" else None
+            if current is not None:
+                documented[current] = text.strip()
+        elif indent == 0 and stripped.endswith(":") and len(stripped.split()) <= 2:
+            section = stripped[:-1]
+            current = None
+            arg_indent = None
+        elif section is None:
+            description.append(line)
+        elif section in DOCSTRING_ARG_SECTIONS and stripped:
+            if arg_indent is None:
+                arg_indent = indent
+            if indent == arg_indent:
+                name, _, text = stripped.partition(":")
+                current = name.split()[0]
+                documented[current] = text.strip()
+            elif current is not None:
+                documented[current] += " " + stripped
+        elif section == "fields" and current is not None and stripped and indent > 0:
+            documented[current] += " " + stripped
+    return "\n".join(description).strip(), documented
+
+
+def is_json(value) -> bool:
+    try:
+        json.dumps(value)
+        return True
+    except (TypeError, ValueError):
+        return False
+
+
+# Format of session snapshots, bumped when it changes
+SNAPSHOT_VERSION = 1
+
+
+def snapshot_namespace(namespace: dict) -> bytes:
+    """Pickle the user globals of namespace. Modules are recorded by name
+    to be imported again on restore. Values that can't be pickled, such as
+    functions and classes defined by the code itself, open files and
+    generators, are left out."""
+    values = {}
+    modules = {}
+    for name, value in namespace.items():
+        if name.startswith("__") and name.endswith("__"):
+            continue
+        if isinstance(value, types.ModuleType):
+            modules[name] = value.__name__
+            continue
+        try:
+            pickle.dumps(value)
+        except Exception:
+            continue
+        values[name] = value
+    # Pickled together so values sharing objects still share them
+    return pickle.dumps(
+        {"version": SNAPSHOT_VERSION, "modules": modules, "values": pickle.dumps(values)}
+    )
+
+
+def restore_namespace(snapshot: bytes) -> dict:
+    """The namespace pickled by snapshot_namespace. Modules are imported,
+    and classes of values loaded, subject to the host's import policy."""
+    state = SnapshotUnpickler(io.BytesIO(snapshot)).load()
+    if not isinstance(state, dict) or state.get("version") != SNAPSHOT_VERSION:
+        raise ValueError("not a session snapshot of this version of pybox")
+    namespace = {}
+    for name, module in state["modules"].items():
+        guarded_import(module)
+        namespace[name] = sys.modules[module]
+    namespace.update(SnapshotUnpickler(io.BytesIO(state["values"])).load())
+    return namespace
+
+
+class SnapshotUnpickler(pickle.Unpickler):
+    def find_class(self, module, name):
+        if module.partition(".")[0] not in ("builtins", "copyreg"):
+            guarded_import(module)
+        return super().find_class(module, name)
+
+
+def interpreter_version() -> dict:
+    """The Python version and installed distributions, for the host to
+    tell component variants apart."""
+    packages = {}
+    try:
+        from importlib import metadata
+    except ImportError:
+        metadata = None
+    if metadata is not None:
+        for dist in metadata.distributions():
+            name = dist.metadata["Name"]
+            if name:
+                packages[name] = dist.version
+    return {
+        "python": "%d.%d.%d" % sys.version_info[:3],
+        "implementation": sys.implementation.name,
+        "packages": packages,
+    }
+
+
+def guarded_import(name, globals=None, locals=None, fromlist=(), level=0):
+    """Replacement for __import__ that enforces the host's import policy on
+    absolute imports made by user code. Best-effort: code can still reach
+    modules already in sys.modules, or the original __import__."""
+    root = name.partition(".")[0]
+    # The host API is always available, it is the sanctioned way out
+    if level == 0 and root != "pybox":
+        if root in blocked_imports or (
+            allowed_imports is not None and root not in allowed_imports
+        ):
+            raise ImportError(f"import of '{name}' is not allowed")
+    return builtins.__import__(name, globals, locals, fromlist, level)
+
+
+def capture_figures(directory: str) -> None:
+    """Render matplotlib headlessly and save figures to directory when they
+    are shown, saved or still open at the end of a run. Without matplotlib
+    there is nothing to capture."""
+    global figure_dir, original_savefig
+    figure_dir = directory
+    os.environ["MPLBACKEND"] = "Agg"
+    try:
+        import matplotlib
+    except ImportError:
+        return
+    matplotlib.use("Agg")
+    import matplotlib.pyplot as plt
+    from matplotlib.figure import Figure
+
+    if original_savefig is None:
+        original_savefig = Figure.savefig
+
+        def savefig(self, *args, **kwargs):
+            original_savefig(self, *args, **kwargs)
+            capture_figure(self)
+
+        Figure.savefig = savefig
+    plt.show = lambda *args, **kwargs: flush_figures()
+
+
+def capture_figure(figure) -> None:
+    """Save figure as the next PNG in figure_dir, once."""
+    global figures_captured
+    if getattr(figure, "_pybox_captured", False):
+        return
+    figures_captured += 1
+    path = os.path.join(figure_dir, f"figure-{figures_captured:04d}.png")
+    original_savefig(figure, path, format="png")
+    figure._pybox_captured = True
+
+
+def flush_figures() -> None:
+    """Capture and close every open figure."""
+    plt = sys.modules.get("matplotlib.pyplot")
+    if figure_dir is None or original_savefig is None or plt is None:
+        return
+    for number in plt.get_fignums():
+        capture_figure(plt.figure(number))
+    plt.close("all")
+
+
+def run_cell(run) -> str:
+    """Call run with stdout and stderr captured and report its JSON
+    value, the output and the exception it raised, if any, as a JSON
+    object."""
+    stdout, stderr = io.StringIO(), io.StringIO()
+    value = error = None
+    try:
+        with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+            value = run()
+    except Exception as e:
+        error = describe(e)
+    return json.dumps(
+        {
+            "value": value,
+            "stdout": stdout.getvalue(),
+            "stderr": stderr.getvalue(),
+            "error": error,
+        }
+    )
+
+
+def run_statements(code: str, local_vars: dict) -> str:
+    """Execute code in local_vars and return the JSON serialized value of
+    the last statement if it is an expression."""
+    return json.dumps(evaluate_statements(code, local_vars))
+
+
+def evaluate_statements(code: str, local_vars: dict):
+    """Execute code in local_vars and return the value of the last
+    statement if it is an expression, otherwise None."""
+    global interrupt_countdown, interrupt_raised, lines_run, budget_exceeded
+    tracing = interrupt_checks or line_budget is not None
+    try:
+        if tracing:
+            interrupt_countdown = INTERRUPT_CHECK_INTERVAL
+            interrupt_raised = False
+            lines_run = 0
+            budget_exceeded = False
+            sys.settrace(trace_user_code)
+        value = evaluate_statements_in(code, local_vars)
+    except KeyboardInterrupt:
+        # Not an Exception, report it like one so the host sees a timeout
+        if interrupt_raised:
+            raise TimeoutError("interrupted at the timeout") from None
+        raise
+    finally:
+        if tracing:
+            sys.settrace(None)
+        flush_figures()
+    # Tracing stopped when the budget ran out, code that caught the
+    # exception must not get to return a value
+    if budget_exceeded:
+        raise budget_error()
+    return value
+
+
+def trace_user_code(frame, event, arg):
+    """Trace function counting lines of user code against the line budget
+    and polling the host for an interrupt. Python stops tracing when it
+    raises, so cleanup code runs uninterrupted."""
+    global lines_run, budget_exceeded
+    # The guest's own frames don't count against the budget
+    if event == "call" and frame.f_globals is globals():
+        return None
+    if interrupt_checks:
+        check_interrupt()
+    if event == "line" and line_budget is not None:
+        lines_run += 1
+        if lines_run > line_budget:
+            budget_exceeded = True
+            wit_world.line_budget_exceeded(lines_run)
+            raise budget_error()
+    return trace_user_code
+
+
+def budget_error() -> ExecutionBudgetExceeded:
+    return ExecutionBudgetExceeded(
+        f"executed {lines_run} lines, over the budget of {line_budget}"
+    )
+
+
+def check_interrupt():
+    """Raise KeyboardInterrupt in user code once the host asks for it,
+    polling every INTERRUPT_CHECK_INTERVAL trace events."""
+    global interrupt_countdown, interrupt_raised
+    interrupt_countdown -= 1
+    if interrupt_countdown <= 0:
+        interrupt_countdown = INTERRUPT_CHECK_INTERVAL
+        if wit_world.interrupt_requested():
+            interrupt_raised = True
+            raise KeyboardInterrupt
+
+
+def evaluate_statements_in(code: str, local_vars: dict):
+    global last_namespace
+    last_namespace = local_vars
+
+    # Only user code sees the guarded import, modules it imports keep the
+    # real builtins so their own imports are unaffected
+    if allowed_imports is not None or blocked_imports:
+        restricted = dict(vars(builtins))
+        restricted["__import__"] = guarded_import
+        local_vars["__builtins__"] = restricted
+
+    # Split into lines and filter empty ones, but keep track of indentation
+    all_lines = code.split('\n')
+
+    # Group lines into complete statements (handling multi-line blocks),
+    # each with the index of the line it starts on
+    statements = []
+    i = 0
+    while i < len(all_lines):
+        line = all_lines[i]
+
+        # Skip empty lines
+        if not line.strip():
+            i += 1
+            continue
+
+        # Start of a new statement
+        start = i
+        current_stmt = [line]
+
+        # Check if this line ends with ':' (start of indented block)
+        if line.rstrip().endswith(':'):
+            i += 1
+            # Collect all indented lines that follow
+            while i < len(all_lines):
+                next_line = all_lines[i]
+                if not next_line.strip():
+                    # Keep empty lines in the block
+                    current_stmt.append(next_line)
+                elif next_line[0] == ' ' or next_line[0] == '\t':
+                    # Indented line - part of the block
+                    current_stmt.append(next_line)
+                else:
+                    # Not indented - end of block
+                    break
+                i += 1
+        else:
+            i += 1
+
+        # Pad with the lines before it so line numbers in errors match
+        # the original code
+        statements.append('\n' * start + '\n'.join(current_stmt))
+
+    if not statements:
+        return None
+
+    # Execute all but the last statement. The namespace is used as the
+    # globals so functions can see top level imports and definitions.
+    for stmt in statements[:-1]:
+        exec(compile(stmt, "<string>", "exec"), local_vars)
+
+    # Try to evaluate last statement as expression
+    last_stmt = statements[-1]
+    try:
+        program = compile(last_stmt, "<string>", "eval")
+    except SyntaxError:
+        exec(compile(last_stmt, "<string>", "exec"), local_vars)
+        return None
+    return eval(program, local_vars)
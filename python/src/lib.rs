@@ -0,0 +1,164 @@
+//! The `pybox` extension module for running untrusted code from a host
+//! Python process, built with maturin from python/pyproject.toml.
+//!
+//! ```python
+//! import pybox
+//!
+//! sandbox = pybox.Sandbox(timeout=5)
+//! result = sandbox.exec("print('hi')\n1 + 1")
+//! assert result.value == 2 and result.stdout == "hi\n"
+//! ```
+
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+use pybox::error::{self, PyboxError};
+use pybox::sandbox::{ExecOptions, PySandbox};
+
+create_exception!(pybox, SandboxError, PyException, "Base class of pybox errors.");
+create_exception!(
+    pybox,
+    PythonError,
+    SandboxError,
+    "The sandboxed code raised an exception it didn't handle."
+);
+create_exception!(pybox, ExecTimeout, SandboxError, "The sandboxed code ran past its timeout.");
+create_exception!(
+    pybox,
+    LimitExceeded,
+    SandboxError,
+    "The sandboxed code used up its fuel, ran into a memory limit or quota, or was too large to run."
+);
+
+/// A sandbox whose `exec` runs code on a fresh interpreter each call.
+#[pyclass(module = "pybox")]
+pub struct Sandbox {
+    // Sandboxes are `Send` but not `Sync`
+    sandbox: Mutex<PySandbox>,
+    timeout: Option<Duration>,
+}
+
+#[pymethods]
+impl Sandbox {
+    /// `timeout` is in seconds, `max_memory` in bytes. `component` is the
+    /// path of the wasm component, `sandbox.wasm` by default.
+    #[new]
+    #[pyo3(signature = (timeout=None, component=None, fuel=None, max_memory=None))]
+    fn new(
+        timeout: Option<f64>,
+        component: Option<PathBuf>,
+        fuel: Option<u64>,
+        max_memory: Option<usize>,
+    ) -> PyResult<Self> {
+        let timeout = timeout.map(seconds).transpose()?;
+        let mut builder = PySandbox::builder();
+        if let Some(path) = component {
+            builder = builder.component_file(path);
+        }
+        if let Some(fuel) = fuel {
+            builder = builder.fuel_limit(fuel);
+        }
+        if let Some(bytes) = max_memory {
+            builder = builder.max_memory_bytes(bytes);
+        }
+        let sandbox = builder.build().map_err(to_py_err)?;
+        Ok(Self {
+            sandbox: Mutex::new(sandbox),
+            timeout,
+        })
+    }
+
+    /// Run `code` and return the value of its last expression along with
+    /// what it printed. The GIL is released while the code runs.
+    #[pyo3(signature = (code, timeout=None))]
+    fn exec(&self, py: Python<'_>, code: &str, timeout: Option<f64>) -> PyResult<ExecResult> {
+        let options = ExecOptions {
+            timeout: timeout.map(seconds).transpose()?.or(self.timeout),
+            capture_output: true,
+            ..Default::default()
+        };
+        let output = py
+            .detach(|| lock(&self.sandbox).exec_with_options(code, &options))
+            .map_err(to_py_err)?;
+        let value = py.import("json")?.call_method1("loads", (output.value,))?;
+        Ok(ExecResult {
+            value: value.unbind(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+            wall_time: output.stats.wall_time.as_secs_f64(),
+        })
+    }
+}
+
+/// The outcome of `Sandbox.exec`.
+#[pyclass(module = "pybox", frozen, get_all)]
+pub struct ExecResult {
+    /// The value of the last expression, decoded from JSON.
+    value: Py<PyAny>,
+    stdout: String,
+    stderr: String,
+    /// Seconds the call took.
+    wall_time: f64,
+}
+
+#[pymethods]
+impl ExecResult {
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        Ok(format!(
+            "ExecResult(value={}, stdout={:?}, stderr={:?})",
+            self.value.bind(py).repr()?,
+            self.stdout,
+            self.stderr
+        ))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "pybox")]
+fn pybox_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Sandbox>()?;
+    m.add_class::<ExecResult>()?;
+    m.add("SandboxError", py.get_type::<SandboxError>())?;
+    m.add("PythonError", py.get_type::<PythonError>())?;
+    m.add("ExecTimeout", py.get_type::<ExecTimeout>())?;
+    m.add("LimitExceeded", py.get_type::<LimitExceeded>())?;
+    Ok(())
+}
+
+fn seconds(timeout: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(timeout)
+        .map_err(|_| PyValueError::new_err("timeout must be a positive number of seconds"))
+}
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    let message = format!("{:#}", e);
+    if e.downcast_ref::<error::PythonError>().is_some() {
+        return PythonError::new_err(message);
+    }
+    match e.downcast_ref::<PyboxError>() {
+        Some(PyboxError::Timeout | PyboxError::Cancelled) => ExecTimeout::new_err(message),
+        Some(
+            PyboxError::FuelExhausted { .. }
+            | PyboxError::ExecutionBudgetExceeded { .. }
+            | PyboxError::LimitExceeded(_)
+            | PyboxError::MemoryLimitExceeded { .. }
+            | PyboxError::CodeTooLarge { .. }
+            | PyboxError::QuotaExceeded { .. },
+        ) => LimitExceeded::new_err(message),
+        Some(PyboxError::Trap(_) | PyboxError::RandomDenied) | None => {
+            SandboxError::new_err(message)
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic mid-execution leaves the sandbox usable, the next call
+    // instantiates a fresh store
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
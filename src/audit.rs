@@ -0,0 +1,263 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::metrics::ExecOutcome;
+
+// Hash the first record of a log chains from
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What was executed and how it ended, passed to an `AuditSink` after
+/// every execution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// When the execution started.
+    pub timestamp: SystemTime,
+    /// Hex encoded SHA-256 of the code.
+    pub code_sha256: String,
+    /// Wall-clock limit of the execution.
+    pub timeout: Duration,
+    pub fuel_limit: Option<u64>,
+    pub max_memory_bytes: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    pub outcome: ExecOutcome,
+    pub duration: Duration,
+}
+
+impl AuditRecord {
+    /// The record as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "timestamp": iso_timestamp(self.timestamp),
+            "code_sha256": self.code_sha256,
+            "limits": {
+                "timeout_ms": self.timeout.as_millis() as u64,
+                "fuel": self.fuel_limit,
+                "memory_bytes": self.max_memory_bytes,
+                "output_bytes": self.max_output_bytes,
+            },
+            "outcome": self.outcome.as_str(),
+            "duration_ms": self.duration.as_millis() as u64,
+        })
+    }
+}
+
+/// Receives an `AuditRecord` for every execution. Register one with
+/// `PySandboxBuilder::audit`.
+///
+/// An error from `record` fails the execution it describes, so nothing
+/// runs without being recorded.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> Result<()>;
+}
+
+/// The `AuditSink` a sandbox reports to, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Auditor(Option<Arc<dyn AuditSink>>);
+
+impl Auditor {
+    pub(crate) fn new(sink: Arc<dyn AuditSink>) -> Self {
+        Self(Some(sink))
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn record(&self, record: &AuditRecord) -> Result<()> {
+        match &self.0 {
+            Some(sink) => sink.record(record).context("Failed to write audit record"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
+}
+
+/// Hex encoded SHA-256 of `code`.
+pub fn code_sha256(code: &str) -> String {
+    to_hex(&Sha256::digest(code.as_bytes()))
+}
+
+/// Appends records to a JSON lines file. Each line carries the hash of
+/// the line before it and its own hash, so editing, removing or
+/// reordering lines is detected by `JsonlAuditLog::verify`.
+#[derive(Debug)]
+pub struct JsonlAuditLog {
+    path: PathBuf,
+    // The open file and the hash of its last line
+    state: Mutex<(File, String)>,
+}
+
+impl JsonlAuditLog {
+    /// Open the log at `path`, continuing its chain if it already exists.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let last_hash = if path.exists() {
+            Self::verify(path)?
+        } else {
+            GENESIS_HASH.to_string()
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new((file, last_hash)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check the chain of the log at `path`, returning the hash of its
+    /// last line.
+    pub fn verify(path: impl AsRef<Path>) -> Result<String> {
+        let path = path.as_ref();
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let mut entry: Value = serde_json::from_str(&line)
+                .with_context(|| format!("Line {} of the audit log is not JSON", i + 1))?;
+            let hash = entry
+                .as_object_mut()
+                .and_then(|entry| entry.remove("hash"))
+                .and_then(|hash| hash.as_str().map(str::to_string))
+                .with_context(|| format!("Line {} of the audit log has no hash", i + 1))?;
+            if entry["prev_hash"] != prev_hash.as_str() || chain_hash(&entry) != hash {
+                return Err(anyhow!("Audit log {} was modified at line {}", path.display(), i + 1));
+            }
+            prev_hash = hash;
+        }
+        Ok(prev_hash)
+    }
+}
+
+impl AuditSink for JsonlAuditLog {
+    fn record(&self, record: &AuditRecord) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (file, last_hash) = &mut *state;
+
+        let mut entry = record.to_json();
+        entry["prev_hash"] = json!(last_hash);
+        let hash = chain_hash(&entry);
+        entry["hash"] = json!(hash);
+
+        writeln!(file, "{}", entry)?;
+        file.sync_data()?;
+        *last_hash = hash;
+        Ok(())
+    }
+}
+
+/// Hash of an entry without its `hash` field. Parsing a line back keeps
+/// its key order, so this matches the hash computed when writing it.
+fn chain_hash(entry: &Value) -> String {
+    to_hex(&Sha256::digest(entry.to_string().as_bytes()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Format `time` as an ISO 8601 UTC timestamp with microseconds.
+pub fn iso_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Days to civil date, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(outcome: ExecOutcome) -> AuditRecord {
+        AuditRecord {
+            timestamp: UNIX_EPOCH + Duration::from_secs(951_782_400),
+            code_sha256: code_sha256("1 + 1"),
+            timeout: Duration::from_secs(5),
+            fuel_limit: None,
+            max_memory_bytes: Some(1 << 20),
+            max_output_bytes: None,
+            outcome,
+            duration: Duration::from_millis(12),
+        }
+    }
+
+    #[test]
+    fn test_iso_timestamp() {
+        let time = UNIX_EPOCH + Duration::new(951_782_400 + 3_723, 5_000);
+        assert_eq!(iso_timestamp(time), "2000-02-29T01:02:03.000005Z");
+    }
+
+    #[test]
+    fn test_record_json() {
+        let json = record(ExecOutcome::Succeeded).to_json();
+        assert_eq!(json["timestamp"], "2000-02-29T00:00:00.000000Z");
+        assert_eq!(
+            json["code_sha256"],
+            "72fce59447a01f488b1169d2d742679cfe306a89772d67fef018bcfe95431f68"
+        );
+        assert_eq!(json["limits"]["timeout_ms"], 5000);
+        assert_eq!(json["limits"]["memory_bytes"], 1 << 20);
+        assert_eq!(json["outcome"], "succeeded");
+    }
+
+    #[test]
+    fn test_jsonl_log_chains_and_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let log = JsonlAuditLog::open(&path).unwrap();
+        log.record(&record(ExecOutcome::Succeeded)).unwrap();
+        drop(log);
+        // Reopening continues the chain
+        let log = JsonlAuditLog::open(&path).unwrap();
+        log.record(&record(ExecOutcome::TimedOut)).unwrap();
+        assert!(JsonlAuditLog::verify(&path).is_ok());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        fs::write(&path, contents.replace("timed_out", "succeeded")).unwrap();
+        let err = JsonlAuditLog::verify(&path).unwrap_err();
+        assert!(err.to_string().contains("modified at line 2"), "{}", err);
+    }
+}
@@ -0,0 +1,223 @@
+//! How many core instances, memories and tables instantiating a component
+//! creates. Wasmtime fails an instantiation that goes over a count limit
+//! with a plain message, so these are compared with the limits to tell
+//! which one it ran into.
+
+use wasmparser::{
+    ComponentAlias, ComponentExternalKind, ComponentInstance, ComponentOuterAliasKind,
+    ComponentTypeRef, Encoding, Instance, Parser, Payload,
+};
+use wasmtime::component::Component;
+
+/// What one instantiation of a component creates, `None` where it can't
+/// be worked out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ComponentCounts {
+    pub(crate) instances: Option<usize>,
+    pub(crate) memories: Option<usize>,
+    pub(crate) tables: Option<usize>,
+}
+
+impl ComponentCounts {
+    /// The counts of `component`, compiled from `bytes`.
+    pub(crate) fn of(component: &Component, bytes: &[u8]) -> Self {
+        Self {
+            instances: core_instances(bytes),
+            ..Self::compiled(component)
+        }
+    }
+
+    /// The counts Wasmtime can tell from a compiled component, for one
+    /// loaded without its original bytes. Instances aren't among them.
+    pub(crate) fn compiled(component: &Component) -> Self {
+        let required = component.resources_required();
+        Self {
+            instances: None,
+            memories: required.as_ref().map(|r| r.num_memories as usize),
+            tables: required.as_ref().map(|r| r.num_tables as usize),
+        }
+    }
+}
+
+// A component being parsed, with the core instances it creates and what
+// each component in its index space would create when instantiated
+#[derive(Default)]
+struct Scope {
+    instances: Option<usize>,
+    components: Vec<Option<usize>>,
+}
+
+impl Scope {
+    fn add(&mut self, instances: Option<usize>) {
+        self.instances = self.instances.zip(instances).map(|(a, b)| a + b);
+    }
+}
+
+/// Core instances created by instantiating the component in `bytes`,
+/// counting those of the nested components it instantiates.
+fn core_instances(bytes: &[u8]) -> Option<usize> {
+    // `None` entries are core modules, whose payloads are skipped
+    let mut stack: Vec<Option<Scope>> = Vec::new();
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.ok()? {
+            Payload::Version { encoding, .. } => stack.push(match encoding {
+                Encoding::Component => Some(Scope {
+                    instances: Some(0),
+                    ..Scope::default()
+                }),
+                Encoding::Module => None,
+            }),
+            Payload::End(_) => {
+                let Some(Some(done)) = stack.pop() else {
+                    continue;
+                };
+                match stack.last_mut() {
+                    Some(Some(parent)) => parent.components.push(done.instances),
+                    Some(None) => return None,
+                    None => return done.instances,
+                }
+            }
+            Payload::InstanceSection(reader) => {
+                let scope = stack.last_mut()?.as_mut()?;
+                for instance in reader {
+                    if let Instance::Instantiate { .. } = instance.ok()? {
+                        scope.add(Some(1));
+                    }
+                }
+            }
+            Payload::ComponentInstanceSection(reader) => {
+                let scope = stack.last_mut()?.as_mut()?;
+                for instance in reader {
+                    if let ComponentInstance::Instantiate { component_index, .. } = instance.ok()? {
+                        let nested = scope.components.get(component_index as usize).copied().flatten();
+                        scope.add(nested);
+                    }
+                }
+            }
+            // Imported components are instantiated outside of this one
+            Payload::ComponentImportSection(reader) => {
+                let scope = stack.last_mut()?.as_mut()?;
+                for import in reader {
+                    if let ComponentTypeRef::Component(_) = import.ok()?.ty {
+                        scope.components.push(None);
+                    }
+                }
+            }
+            Payload::ComponentExportSection(reader) => {
+                let scope = stack.last_mut()?.as_mut()?;
+                for export in reader {
+                    let export = export.ok()?;
+                    if export.kind == ComponentExternalKind::Component {
+                        let exported = scope.components.get(export.index as usize).copied().flatten();
+                        scope.components.push(exported);
+                    }
+                }
+            }
+            Payload::ComponentAliasSection(reader) => {
+                for alias in reader {
+                    let aliased = match alias.ok()? {
+                        ComponentAlias::InstanceExport {
+                            kind: ComponentExternalKind::Component,
+                            ..
+                        } => None,
+                        ComponentAlias::Outer {
+                            kind: ComponentOuterAliasKind::Component,
+                            count,
+                            index,
+                        } => {
+                            let outer = stack.len().checked_sub(1 + count as usize)?;
+                            let outer = stack[outer].as_ref()?;
+                            outer.components.get(index as usize).copied().flatten()
+                        }
+                        _ => continue,
+                    };
+                    stack.last_mut()?.as_mut()?.components.push(aliased);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Engine;
+
+    fn counts(text: &str) -> ComponentCounts {
+        let bytes = wat::parse_str(text).unwrap();
+        let component = Component::new(&Engine::default(), &bytes).unwrap();
+        ComponentCounts::of(&component, &bytes)
+    }
+
+    #[test]
+    fn test_counts_core_instances_memories_and_tables() {
+        let counts = counts(
+            r#"(component
+                (core module $m (memory 1) (table 1 funcref))
+                (core module $empty)
+                (core instance (instantiate $m))
+                (core instance (instantiate $m))
+                (core instance (instantiate $empty))
+            )"#,
+        );
+        assert_eq!(
+            counts,
+            ComponentCounts {
+                instances: Some(3),
+                memories: Some(2),
+                tables: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_counts_instances_of_nested_components() {
+        let counts = counts(
+            r#"(component
+                (component $inner
+                    (core module $m)
+                    (core instance (instantiate $m))
+                    (core instance (instantiate $m))
+                )
+                (instance (instantiate $inner))
+                (instance (instantiate $inner))
+            )"#,
+        );
+        assert_eq!(counts.instances, Some(4));
+    }
+
+    #[test]
+    fn test_counts_agree_with_wasmtimes_limit() {
+        use wasmtime::component::Linker;
+        use wasmtime::{Store, StoreLimits, StoreLimitsBuilder};
+
+        let engine = Engine::default();
+        let bytes = wat::parse_str(
+            r#"(component
+                (component $inner
+                    (core module $m (memory 1))
+                    (core instance (instantiate $m))
+                )
+                (core module $m)
+                (core instance (instantiate $m))
+                (instance (instantiate $inner))
+                (instance (instantiate $inner))
+            )"#,
+        )
+        .unwrap();
+        let component = Component::new(&engine, &bytes).unwrap();
+        let needed = ComponentCounts::of(&component, &bytes).instances.unwrap();
+        assert_eq!(needed, 3);
+
+        let instantiate = |instances| {
+            let limits = StoreLimitsBuilder::new().instances(instances).build();
+            let mut store = Store::new(&engine, limits);
+            store.limiter(|limits: &mut StoreLimits| limits);
+            Linker::new(&engine).instantiate(&mut store, &component).map(|_| ())
+        };
+        assert!(instantiate(needed).is_ok());
+        assert!(instantiate(needed - 1).is_err());
+    }
+}
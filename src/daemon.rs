@@ -0,0 +1,163 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::pool::SandboxPool;
+use crate::report::{exec_report, ExecRequest};
+use crate::sandbox::PySandbox;
+
+/// Largest message either side may send.
+pub const MAX_FRAME_BYTES: u32 = 16 << 20;
+
+/// Serve executions on the Unix socket at `socket` from `workers` warm
+/// sandboxes until the process is stopped, so clients skip compiling the
+/// component on every run.
+///
+/// Messages are JSON objects prefixed with their length as a big-endian
+/// `u32`. A request is `{"code": "...", "timeout_ms": 500}` and runs on a
+/// fresh interpreter, the response is the object of
+/// `report::exec_report`. A connection may send any number of requests,
+/// one at a time. Requested timeouts are lowered to `max_timeout`, so a
+/// client can't keep a worker busy for longer.
+pub fn run(
+    sandbox: PySandbox,
+    socket: impl AsRef<Path>,
+    workers: usize,
+    max_timeout: Duration,
+) -> Result<()> {
+    let socket = socket.as_ref();
+    let listener = bind(socket)?;
+    let pool = Arc::new(SandboxPool::new(sandbox, workers));
+    eprintln!("pybox daemon: listening on {}", socket.display());
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let pool = pool.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &pool, max_timeout) {
+                eprintln!("pybox daemon: connection closed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Bind `socket`, replacing a socket file left behind by a daemon that
+/// is no longer running.
+fn bind(socket: &Path) -> Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("A daemon is already listening on {}", socket.display());
+        }
+        fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale socket {}", socket.display()))?;
+    }
+    UnixListener::bind(socket).with_context(|| format!("Failed to bind {}", socket.display()))
+}
+
+fn serve(mut stream: UnixStream, pool: &SandboxPool, max_timeout: Duration) -> Result<()> {
+    while let Some(request) = read_frame(&mut stream)? {
+        let started = Instant::now();
+        let response = match ExecRequest::parse(&request, max_timeout) {
+            Ok(request) => {
+                let result = pool.exec_with_options(&request.code, &request.options());
+                exec_report(&result, started.elapsed())
+            }
+            Err(message) => json!({ "ok": false, "error": message }),
+        };
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+/// Read the next message, `None` once the peer closed the connection
+/// between messages.
+pub fn read_frame(stream: &mut impl Read) -> Result<Option<Value>> {
+    let mut header = [0; 4];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(header);
+    if len > MAX_FRAME_BYTES {
+        return Err(anyhow!("Message of {} bytes is over the limit of {}", len, MAX_FRAME_BYTES));
+    }
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).context("Connection closed mid-message")?;
+    serde_json::from_slice(&body).context("Invalid message").map(Some)
+}
+
+/// Write `message` with its length prefix.
+pub fn write_frame(stream: &mut impl Write, message: &Value) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_BYTES)
+        .ok_or_else(|| anyhow!("Message of {} bytes is too large", body.len()))?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// A connection to a running daemon.
+///
+/// ```no_run
+/// # use pybox::daemon::Client;
+/// let mut client = Client::connect("/run/pybox.sock")?;
+/// let report = client.exec("1 + 1", None)?;
+/// assert_eq!(report["result"], 2);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    pub fn connect(socket: impl AsRef<Path>) -> Result<Self> {
+        let socket = socket.as_ref();
+        let stream = UnixStream::connect(socket)
+            .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+        Ok(Self { stream })
+    }
+
+    /// Run `code` on the daemon and return its report.
+    pub fn exec(&mut self, code: &str, timeout: Option<Duration>) -> Result<Value> {
+        let mut request = json!({ "code": code });
+        if let Some(timeout) = timeout {
+            request["timeout_ms"] = json!(timeout.as_millis() as u64);
+        }
+        write_frame(&mut self.stream, &request)?;
+        read_frame(&mut self.stream)?.context("Daemon closed the connection")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        write_frame(&mut a, &json!({"code": "1"})).unwrap();
+        write_frame(&mut a, &json!([1, 2])).unwrap();
+        drop(a);
+        assert_eq!(read_frame(&mut b).unwrap(), Some(json!({"code": "1"})));
+        assert_eq!(read_frame(&mut b).unwrap(), Some(json!([1, 2])));
+        assert_eq!(read_frame(&mut b).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_frame_rejects_oversized_message() {
+        let mut input = &(MAX_FRAME_BYTES + 1).to_be_bytes()[..];
+        assert!(read_frame(&mut input).is_err());
+    }
+}
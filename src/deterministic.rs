@@ -0,0 +1,273 @@
+use std::cell::Cell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::SeedableRng;
+use wasmtime::Config;
+use wasmtime::component::HasData;
+use wasmtime_wasi::p2::bindings::random::{insecure, insecure_seed, random};
+use wasmtime_wasi::random::WasiRandomCtx;
+use wasmtime_wasi::{HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+use crate::error::PyboxError;
+
+/// Wall clock time every deterministic execution starts at,
+/// 2000-01-01T00:00:00Z.
+pub const START_TIME: Duration = Duration::from_secs(946_684_800);
+
+/// How far the virtual clocks move forward each time they are read. Time
+/// must advance or code that waits for the clock would never finish.
+pub const CLOCK_STEP: Duration = Duration::from_millis(1);
+
+/// A wall clock that starts at `START_TIME` and advances by `CLOCK_STEP`
+/// on every read, independent of real time.
+pub struct VirtualWallClock {
+    now: Cell<Duration>,
+}
+
+impl Default for VirtualWallClock {
+    fn default() -> Self {
+        Self {
+            now: Cell::new(START_TIME),
+        }
+    }
+}
+
+impl HostWallClock for VirtualWallClock {
+    fn resolution(&self) -> Duration {
+        CLOCK_STEP
+    }
+
+    fn now(&self) -> Duration {
+        let now = self.now.get();
+        self.now.set(now + CLOCK_STEP);
+        now
+    }
+}
+
+/// A monotonic clock that starts at zero and advances by `CLOCK_STEP` on
+/// every read.
+#[derive(Default)]
+pub struct VirtualMonotonicClock {
+    now: Cell<u64>,
+}
+
+impl HostMonotonicClock for VirtualMonotonicClock {
+    fn resolution(&self) -> u64 {
+        CLOCK_STEP.as_nanos() as u64
+    }
+
+    fn now(&self) -> u64 {
+        let now = self.now.get();
+        self.now.set(now + CLOCK_STEP.as_nanos() as u64);
+        now
+    }
+}
+
+/// A wall clock that always reads the same time.
+pub struct FixedWallClock {
+    now: Duration,
+}
+
+impl FixedWallClock {
+    pub fn new(at: SystemTime) -> Self {
+        Self {
+            // Times before the epoch read as the epoch
+            now: at.duration_since(UNIX_EPOCH).unwrap_or_default(),
+        }
+    }
+}
+
+impl HostWallClock for FixedWallClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self) -> Duration {
+        self.now
+    }
+}
+
+/// What guest code can learn from the clocks, see
+/// `PySandboxBuilder::clock_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockPolicy {
+    /// The host's clocks, or the virtual ones of `deterministic`.
+    #[default]
+    Real,
+    /// `time.time()` and the other wall clock reads always return this
+    /// time, so replays see the same dates. The monotonic clock keeps
+    /// running so timeouts and sleeps still work.
+    FrozenAt(SystemTime),
+    /// The wall clock always reads the Unix epoch, so guest code can't
+    /// tell the date or time events with it. Only the monotonic clock,
+    /// which has no meaning outside the execution, keeps running.
+    MonotonicOnly,
+}
+
+impl ClockPolicy {
+    /// Apply the policy to `builder`, after any other clock settings.
+    pub fn configure_wasi(&self, builder: &mut WasiCtxBuilder) {
+        match *self {
+            Self::Real => {}
+            Self::FrozenAt(at) => {
+                builder.wall_clock(FixedWallClock::new(at));
+            }
+            Self::MonotonicOnly => {
+                builder.wall_clock(FixedWallClock::new(UNIX_EPOCH));
+            }
+        }
+    }
+}
+
+/// Where guest code gets random bytes from, see `PySandboxBuilder::random`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RandomPolicy {
+    /// The host's generators, or the seeded ones of `deterministic`.
+    #[default]
+    Host,
+    /// Generators seeded from this value, so `random`, `os.urandom` and
+    /// everything built on them return the same values on every run.
+    /// Unlike `deterministic`, the clocks are left alone.
+    Seeded(u64),
+    /// Every request for random bytes stops the execution with
+    /// `PyboxError::RandomDenied`, so guest code can't draw on entropy at
+    /// all.
+    Deny,
+}
+
+impl RandomPolicy {
+    /// Apply the policy to `builder`, after any other random settings.
+    /// `Deny` is enforced when the guest asks, see `GuardedRandom`.
+    pub fn configure_wasi(&self, builder: &mut WasiCtxBuilder) {
+        if let Self::Seeded(seed) = *self {
+            seed_random(builder, seed);
+        }
+    }
+}
+
+/// The `wasi:random` interfaces of a store, failing every call when the
+/// store's `RandomPolicy` is `Deny` instead of handing out bytes.
+pub(crate) struct GuardedRandom<'a> {
+    pub(crate) random: &'a mut WasiRandomCtx,
+    pub(crate) denied: bool,
+}
+
+impl GuardedRandom<'_> {
+    fn allowed(&mut self) -> anyhow::Result<&mut WasiRandomCtx> {
+        if self.denied {
+            return Err(PyboxError::RandomDenied.into());
+        }
+        Ok(&mut *self.random)
+    }
+}
+
+impl HasData for GuardedRandom<'static> {
+    type Data<'a> = GuardedRandom<'a>;
+}
+
+impl random::Host for GuardedRandom<'_> {
+    fn get_random_bytes(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
+        self.allowed()?.get_random_bytes(len)
+    }
+
+    fn get_random_u64(&mut self) -> anyhow::Result<u64> {
+        self.allowed()?.get_random_u64()
+    }
+}
+
+impl insecure::Host for GuardedRandom<'_> {
+    fn get_insecure_random_bytes(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
+        self.allowed()?.get_insecure_random_bytes(len)
+    }
+
+    fn get_insecure_random_u64(&mut self) -> anyhow::Result<u64> {
+        self.allowed()?.get_insecure_random_u64()
+    }
+}
+
+impl insecure_seed::Host for GuardedRandom<'_> {
+    fn insecure_seed(&mut self) -> anyhow::Result<(u64, u64)> {
+        self.allowed()?.insecure_seed()
+    }
+}
+
+/// Turn off wasm features whose results can differ between hosts.
+///
+/// NaN canonicalization is a Cranelift setting that Winch ignores, so
+/// this is only enough for engines compiling with Cranelift. The builder
+/// refuses deterministic mode with the Winch strategy for that reason.
+pub fn configure_engine(config: &mut Config) {
+    config.cranelift_nan_canonicalization(true);
+    config.relaxed_simd_deterministic(true);
+}
+
+/// Replace every source of randomness and time in `builder` with ones
+/// derived from `seed`.
+pub fn configure_wasi(builder: &mut WasiCtxBuilder, seed: u64) {
+    seed_random(builder, seed);
+    builder
+        .wall_clock(VirtualWallClock::default())
+        .monotonic_clock(VirtualMonotonicClock::default());
+}
+
+/// Replace the random generators in `builder` with ones derived from
+/// `seed`.
+fn seed_random(builder: &mut WasiCtxBuilder, seed: u64) {
+    // Separate streams so the secure and insecure generators don't repeat
+    // each other's output
+    let mut secure = ChaCha20Rng::seed_from_u64(seed);
+    secure.set_stream(0);
+    let mut insecure = ChaCha20Rng::seed_from_u64(seed);
+    insecure.set_stream(1);
+
+    builder
+        .secure_random(secure)
+        .insecure_random(insecure)
+        .insecure_random_seed(u128::from(seed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clocks_advance_on_each_read() {
+        let wall = VirtualWallClock::default();
+        assert_eq!(wall.now(), START_TIME);
+        assert_eq!(wall.now(), START_TIME + CLOCK_STEP);
+
+        let monotonic = VirtualMonotonicClock::default();
+        assert_eq!(monotonic.now(), 0);
+        assert_eq!(monotonic.now(), CLOCK_STEP.as_nanos() as u64);
+    }
+
+    #[test]
+    fn test_fixed_wall_clock_never_moves() {
+        let clock = FixedWallClock::new(UNIX_EPOCH + START_TIME);
+        assert_eq!(clock.now(), START_TIME);
+        assert_eq!(clock.now(), START_TIME);
+        let before_epoch = FixedWallClock::new(UNIX_EPOCH - Duration::from_secs(1));
+        assert_eq!(before_epoch.now(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_guarded_random_fails_when_denied() {
+        use random::Host;
+
+        let mut ctx = WasiRandomCtx::default();
+        let mut allowed = GuardedRandom {
+            random: &mut ctx,
+            denied: false,
+        };
+        assert_eq!(allowed.get_random_bytes(16).unwrap().len(), 16);
+
+        let mut denied = GuardedRandom {
+            random: &mut ctx,
+            denied: true,
+        };
+        let err = denied.get_random_bytes(16).unwrap_err();
+        assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::RandomDenied));
+        assert!(insecure_seed::Host::insecure_seed(&mut denied).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Failures that callers may want to handle differently from a generic
+/// error. These are returned inside `anyhow::Error`, inspect them with
+/// `err.downcast_ref::<PyboxError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PyboxError {
+    /// Execution ran longer than the configured timeout.
+    Timeout,
+    /// Execution used up the fuel budget set with `fuel_limit`.
+    FuelExhausted { limit: u64 },
+    /// Execution ran more lines of Python than the `line_budget`, having
+    /// run `executed` of them when it was stopped.
+    ExecutionBudgetExceeded { budget: u64, executed: u64 },
+    /// Execution was aborted through a `CancelHandle`.
+    Cancelled,
+    /// Execution ran into one of the store limits set on the builder,
+    /// other than the memory limit.
+    LimitExceeded(ResourceLimit),
+    /// The guest tried to grow a linear memory to `requested` bytes past
+    /// the `limit` set with `max_memory_bytes`, and didn't recover from
+    /// the resulting MemoryError.
+    MemoryLimitExceeded { requested: usize, limit: usize },
+    /// The guest trapped for a reason not covered by the variants above,
+    /// such as a crash in the interpreter. The Wasmtime trap is still in
+    /// the error's chain.
+    Trap(TrapKind),
+    /// The code was over a size limit set on the builder and was rejected
+    /// without running.
+    CodeTooLarge {
+        limit: CodeLimit,
+        allowed: usize,
+        actual: usize,
+    },
+    /// The sandbox's `Quota` for the current window is used up, the
+    /// next window starts after `retry_after`.
+    QuotaExceeded { retry_after: Duration },
+    /// The guest asked for random bytes while the sandbox's
+    /// `RandomPolicy` is `Deny`.
+    RandomDenied,
+}
+
+/// The limits on submitted code, see `max_code_bytes` and `max_lines`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeLimit {
+    Bytes,
+    Lines,
+}
+
+impl CodeLimit {
+    /// Check `code` against `allowed`, if set.
+    pub(crate) fn check(self, code: &str, allowed: Option<usize>) -> Result<(), PyboxError> {
+        let Some(allowed) = allowed else {
+            return Ok(());
+        };
+        let actual = match self {
+            CodeLimit::Bytes => code.len(),
+            CodeLimit::Lines => code.lines().count(),
+        };
+        if actual > allowed {
+            return Err(PyboxError::CodeTooLarge {
+                limit: self,
+                allowed,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CodeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodeLimit::Bytes => f.write_str("bytes"),
+            CodeLimit::Lines => f.write_str("lines"),
+        }
+    }
+}
+
+/// Why the guest trapped, from Wasmtime's trap code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapKind {
+    StackOverflow,
+    UnreachableCode,
+    /// An epoch tick stopped the guest, for a timeout or cancellation.
+    EpochInterrupt,
+    OutOfFuel,
+    MemoryOutOfBounds,
+    Other,
+}
+
+impl TrapKind {
+    /// The kind of the trap in `error`'s chain, if there is one.
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        use wasmtime::Trap;
+
+        let kind = match error.downcast_ref::<Trap>()? {
+            Trap::StackOverflow => TrapKind::StackOverflow,
+            Trap::UnreachableCodeReached => TrapKind::UnreachableCode,
+            Trap::Interrupt => TrapKind::EpochInterrupt,
+            Trap::OutOfFuel => TrapKind::OutOfFuel,
+            Trap::MemoryOutOfBounds => TrapKind::MemoryOutOfBounds,
+            _ => TrapKind::Other,
+        };
+        Some(kind)
+    }
+
+    /// Whether the sandbox stopped the guest to enforce a limit, as
+    /// opposed to the guest crashing on its own.
+    pub fn is_enforced_by_sandbox(self) -> bool {
+        matches!(
+            self,
+            TrapKind::StackOverflow | TrapKind::EpochInterrupt | TrapKind::OutOfFuel
+        )
+    }
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TrapKind::StackOverflow => "stack overflow",
+            TrapKind::UnreachableCode => "unreachable code",
+            TrapKind::EpochInterrupt => "epoch interrupt",
+            TrapKind::OutOfFuel => "out of fuel",
+            TrapKind::MemoryOutOfBounds => "out of bounds memory access",
+            TrapKind::Other => "trap",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The store limits that can be set on `PySandboxBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// Size of a linear memory, see `max_memory_bytes`. Executions stopped
+    /// by it fail with `PyboxError::MemoryLimitExceeded`, never
+    /// `LimitExceeded(Memory)`.
+    Memory,
+    /// Elements in a table, see `max_table_elements`.
+    TableElements,
+    /// Number of instances, see `max_instances`.
+    Instances,
+    /// Number of tables, see `max_tables`.
+    Tables,
+    /// Number of linear memories, see `max_memories`.
+    Memories,
+    /// Depth of the wasm stack, see `max_wasm_stack`.
+    Stack,
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResourceLimit::Memory => "memory",
+            ResourceLimit::TableElements => "table element",
+            ResourceLimit::Instances => "instance",
+            ResourceLimit::Tables => "table",
+            ResourceLimit::Memories => "memory count",
+            ResourceLimit::Stack => "stack",
+        };
+        f.write_str(name)
+    }
+}
+
+impl PyboxError {
+    /// Whether the guest was stopped mid-run, leaving its instance and
+    /// the interpreter state in it unusable.
+    pub fn loses_instance(&self) -> bool {
+        matches!(
+            self,
+            PyboxError::Timeout
+                | PyboxError::Cancelled
+                | PyboxError::Trap(_)
+                | PyboxError::LimitExceeded(_)
+                | PyboxError::MemoryLimitExceeded { .. }
+                | PyboxError::RandomDenied
+        )
+    }
+}
+
+impl fmt::Display for PyboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PyboxError::Timeout => write!(f, "Execution timed out"),
+            PyboxError::FuelExhausted { limit } => {
+                write!(f, "Execution exhausted its fuel limit of {}", limit)
+            }
+            PyboxError::ExecutionBudgetExceeded { budget, executed } => write!(
+                f,
+                "Execution ran {} lines of Python, over its budget of {}",
+                executed, budget
+            ),
+            PyboxError::Cancelled => write!(f, "Execution was cancelled"),
+            PyboxError::RandomDenied => {
+                write!(f, "Execution was stopped for requesting random bytes")
+            }
+            PyboxError::LimitExceeded(limit) => {
+                write!(f, "Execution exceeded its {} limit", limit)
+            }
+            PyboxError::Trap(kind) => write!(f, "Execution trapped: {}", kind),
+            PyboxError::CodeTooLarge {
+                limit,
+                allowed,
+                actual,
+            } => write!(f, "Code has {} {}, over the limit of {}", actual, limit, allowed),
+            PyboxError::QuotaExceeded { retry_after } => write!(
+                f,
+                "Execution quota exceeded, retry in {:.1}s",
+                retry_after.as_secs_f64()
+            ),
+            // The `LimitViolation` in the chain has the numbers
+            PyboxError::MemoryLimitExceeded { .. } => {
+                write!(f, "Execution exceeded its {} limit", ResourceLimit::Memory)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PyboxError {}
+
+/// Which store limit stopped an execution and by how much. Found in the
+/// chain of `PyboxError::LimitExceeded` and `MemoryLimitExceeded` errors:
+///
+/// ```no_run
+/// # use pybox::error::LimitViolation;
+/// # use pybox::sandbox::PySandbox;
+/// let mut sandbox = PySandbox::builder().max_memory_bytes(64 << 20).build()?;
+/// let err = sandbox.exec("b'x' * (128 << 20)").unwrap_err();
+/// let violation = err.downcast_ref::<LimitViolation>().unwrap();
+/// assert_eq!(violation.allowed, 64 << 20);
+/// assert!(violation.excess().unwrap() > 0);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitViolation {
+    pub limit: ResourceLimit,
+    /// The limit in effect, in bytes for memory and stack and as a count
+    /// otherwise.
+    pub allowed: usize,
+    /// What the guest tried to grow to, when known. Stack overflows don't
+    /// report it.
+    pub requested: Option<usize>,
+}
+
+impl LimitViolation {
+    /// How far over the limit the guest tried to go.
+    pub fn excess(&self) -> Option<usize> {
+        self.requested.map(|requested| requested.saturating_sub(self.allowed))
+    }
+
+    /// The error executions fail with, a `PyboxError` carrying this
+    /// violation.
+    pub(crate) fn into_error(self) -> anyhow::Error {
+        let error = match self.limit {
+            ResourceLimit::Memory => PyboxError::MemoryLimitExceeded {
+                requested: self.requested.unwrap_or(self.allowed),
+                limit: self.allowed,
+            },
+            limit => PyboxError::LimitExceeded(limit),
+        };
+        anyhow::Error::new(self).context(error)
+    }
+}
+
+impl fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.limit {
+            ResourceLimit::Memory | ResourceLimit::Stack => " bytes",
+            _ => "",
+        };
+        match self.requested {
+            Some(requested) => write!(
+                f,
+                "requested {}{} with a {} limit of {}{}",
+                requested, unit, self.limit, self.allowed, unit
+            ),
+            None => write!(f, "{} limit of {}{}", self.limit, self.allowed, unit),
+        }
+    }
+}
+
+impl std::error::Error for LimitViolation {}
+
+
+/// The component's SHA-256 didn't match the digest it was pinned to with
+/// `PySandboxBuilder::expected_sha256`, so it was not loaded. Returned
+/// inside `anyhow::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestMismatch {
+    /// Hex encoded digest the component was pinned to.
+    pub expected: String,
+    /// Hex encoded digest of the bytes that were found.
+    pub actual: String,
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SHA-256 is {}, expected {}", self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+/// The guest returned a value that doesn't parse as JSON, from
+/// `PySandbox::exec_json`, `ExecOutput::json` or
+/// `Toolbox::invoke_tool`. Returned inside
+/// `anyhow::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidJson {
+    /// What the guest returned.
+    pub value: String,
+    /// Why it didn't parse.
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidJson {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guest returned a value that is not JSON: {}", self.reason)
+    }
+}
+
+impl std::error::Error for InvalidJson {}
+
+/// An exception raised by guest code and not handled by it. Returned
+/// inside `anyhow::Error` like `PyboxError`.
+///
+/// ```no_run
+/// # use pybox::error::{PyException, PythonError};
+/// # use pybox::sandbox::PySandbox;
+/// let mut sandbox = PySandbox::new(None)?;
+/// let err = sandbox.exec("{}['missing']").unwrap_err();
+/// let error = err.downcast_ref::<PythonError>().unwrap();
+/// assert_eq!(error.exception, PyException::Key);
+/// assert_eq!(error.lineno, Some(1));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PythonError {
+    pub exception: PyException,
+    /// The exception's message, `str(e)` in Python.
+    pub message: String,
+    /// Line of the executed code the exception was raised from, `None`
+    /// when it didn't come from there.
+    pub lineno: Option<u32>,
+}
+
+impl fmt::Display for PythonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.exception)
+        } else {
+            write!(f, "{}: {}", self.exception, self.message)
+        }
+    }
+}
+
+impl std::error::Error for PythonError {}
+
+/// Class of a `PythonError`. Common built-in exceptions have their own
+/// variant, anything else is `Other` with the class name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PyException {
+    ZeroDivision,
+    Key,
+    Index,
+    Value,
+    Type,
+    Name,
+    Attribute,
+    Syntax,
+    Import,
+    Memory,
+    Recursion,
+    Other(String),
+}
+
+impl PyException {
+    /// The variant for the exception class named `name`.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "ZeroDivisionError" => PyException::ZeroDivision,
+            "KeyError" => PyException::Key,
+            "IndexError" => PyException::Index,
+            "ValueError" => PyException::Value,
+            "TypeError" => PyException::Type,
+            "NameError" => PyException::Name,
+            "AttributeError" => PyException::Attribute,
+            "SyntaxError" => PyException::Syntax,
+            "ImportError" => PyException::Import,
+            "MemoryError" => PyException::Memory,
+            "RecursionError" => PyException::Recursion,
+            other => PyException::Other(other.to_string()),
+        }
+    }
+
+    /// The Python class name, e.g. `ZeroDivisionError`.
+    pub fn name(&self) -> &str {
+        match self {
+            PyException::ZeroDivision => "ZeroDivisionError",
+            PyException::Key => "KeyError",
+            PyException::Index => "IndexError",
+            PyException::Value => "ValueError",
+            PyException::Type => "TypeError",
+            PyException::Name => "NameError",
+            PyException::Attribute => "AttributeError",
+            PyException::Syntax => "SyntaxError",
+            PyException::Import => "ImportError",
+            PyException::Memory => "MemoryError",
+            PyException::Recursion => "RecursionError",
+            PyException::Other(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for PyException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exception_names_round_trip() {
+        assert_eq!(PyException::from_name("KeyError"), PyException::Key);
+        assert_eq!(PyException::ZeroDivision.name(), "ZeroDivisionError");
+        let custom = PyException::from_name("HostCallError");
+        assert_eq!(custom, PyException::Other("HostCallError".to_string()));
+        assert_eq!(custom.name(), "HostCallError");
+    }
+
+    #[test]
+    fn test_code_limits() {
+        assert_eq!(CodeLimit::Bytes.check("1 + 1", None), Ok(()));
+        assert_eq!(CodeLimit::Bytes.check("1 + 1", Some(5)), Ok(()));
+        let err = CodeLimit::Lines.check("a = 1\nb = 2\na + b\n", Some(2)).unwrap_err();
+        assert_eq!(
+            err,
+            PyboxError::CodeTooLarge {
+                limit: CodeLimit::Lines,
+                allowed: 2,
+                actual: 3
+            }
+        );
+        assert_eq!(err.to_string(), "Code has 3 lines, over the limit of 2");
+    }
+
+    #[test]
+    fn test_python_error_display() {
+        let error = PythonError {
+            exception: PyException::Value,
+            message: "boom".to_string(),
+            lineno: Some(3),
+        };
+        assert_eq!(error.to_string(), "ValueError: boom");
+        let error = PythonError {
+            exception: PyException::Key,
+            message: String::new(),
+            lineno: None,
+        };
+        assert_eq!(error.to_string(), "KeyError");
+    }
+
+    #[test]
+    fn test_trap_kind_of_error() {
+        let err = anyhow::Error::from(wasmtime::Trap::UnreachableCodeReached)
+            .context(PyboxError::Trap(TrapKind::UnreachableCode));
+        assert_eq!(TrapKind::of(&err), Some(TrapKind::UnreachableCode));
+        assert_eq!(err.to_string(), "Execution trapped: unreachable code");
+        assert!(!TrapKind::UnreachableCode.is_enforced_by_sandbox());
+        assert!(TrapKind::OutOfFuel.is_enforced_by_sandbox());
+        assert_eq!(TrapKind::of(&anyhow::anyhow!("not a trap")), None);
+    }
+
+    #[test]
+    fn test_limit_violation_in_error_chain() {
+        let violation = LimitViolation {
+            limit: ResourceLimit::Memory,
+            allowed: 1024,
+            requested: Some(4096),
+        };
+        assert_eq!(violation.excess(), Some(3072));
+        let err = violation.into_error();
+        assert_eq!(
+            err.downcast_ref::<PyboxError>(),
+            Some(&PyboxError::MemoryLimitExceeded {
+                requested: 4096,
+                limit: 1024
+            })
+        );
+        assert_eq!(err.downcast_ref::<LimitViolation>(), Some(&violation));
+
+        let violation = LimitViolation {
+            limit: ResourceLimit::Instances,
+            allowed: 2,
+            requested: Some(3),
+        };
+        assert_eq!(
+            format!("{:#}", violation.into_error()),
+            "Execution exceeded its instance limit: requested 3 with a instance limit of 2"
+        );
+    }
+}
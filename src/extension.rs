@@ -0,0 +1,207 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use wasmtime::component::Linker;
+
+use crate::sandbox::MyWasi;
+
+/// A plugin that gives guest code access to host functionality beyond what
+/// pybox ships with.
+///
+/// Extensions are reached in two ways:
+///
+/// * Through the generic `extension-call` import every sandbox component
+///   has. Guest code calls `pybox.extensions.call(name, method, payload)`
+///   with a string payload and `call` handles it. This works with the
+///   stock `sandbox.wasm`.
+/// * Through their own WIT imports, for components built from a world that
+///   extends `sandbox.wit` and loaded with `PySandboxBuilder::component_file`.
+///   `add_to_linker` defines those imports. Their host functions get the
+///   store's `MyWasi`, and keep per-execution state set up by
+///   `init_state` in `MyWasi::extension_state`.
+///
+/// ```
+/// use anyhow::Result;
+/// use pybox::extension::SandboxExtension;
+///
+/// struct Upper;
+///
+/// impl SandboxExtension for Upper {
+///     fn name(&self) -> &str {
+///         "upper"
+///     }
+///
+///     fn call(&self, _method: &str, payload: &str) -> Result<String> {
+///         Ok(payload.to_uppercase())
+///     }
+/// }
+/// ```
+pub trait SandboxExtension: Send + Sync {
+    /// Name guest code uses to reach this extension.
+    fn name(&self) -> &str;
+
+    /// Handle `pybox.extensions.call(self.name(), method, payload)`.
+    /// Errors are raised in the guest as `pybox.extensions.ExtensionError`.
+    fn call(&self, method: &str, payload: &str) -> Result<String> {
+        let _ = payload;
+        Err(anyhow!("{} has no method '{}'", self.name(), method))
+    }
+
+    /// Define the extra imports of a custom component. Called once per
+    /// linker, the default adds nothing.
+    fn add_to_linker(&self, linker: &mut Linker<MyWasi>) -> Result<()> {
+        let _ = linker;
+        Ok(())
+    }
+
+    /// Add this extension's state to a new store, for the imports defined
+    /// in `add_to_linker`. Called once per store, so cells of a session
+    /// share it. The default adds nothing.
+    fn init_state(&self, state: &mut ExtensionState) {
+        let _ = state;
+    }
+}
+
+/// State extensions keep in a store, one value per type.
+///
+/// ```
+/// use pybox::extension::ExtensionState;
+///
+/// struct Calls(u32);
+///
+/// let mut state = ExtensionState::default();
+/// state.insert(Calls(0));
+/// state.get_mut::<Calls>().unwrap().0 += 1;
+/// assert_eq!(state.get::<Calls>().unwrap().0, 1);
+/// ```
+#[derive(Default)]
+pub struct ExtensionState(HashMap<TypeId, Box<dyn Any + Send>>);
+
+impl ExtensionState {
+    /// Store `value`, returning the one of the same type it replaces.
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        let previous = self.0.insert(TypeId::of::<T>(), Box::new(value))?;
+        previous.downcast().ok().map(|previous| *previous)
+    }
+
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+}
+
+impl fmt::Debug for ExtensionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionState").field("len", &self.0.len()).finish()
+    }
+}
+
+/// The extensions registered on a sandbox, by name.
+#[derive(Clone, Default)]
+pub struct Extensions(Arc<HashMap<String, Arc<dyn SandboxExtension>>>);
+
+impl Extensions {
+    pub fn insert(&mut self, extension: Arc<dyn SandboxExtension>) {
+        Arc::make_mut(&mut self.0).insert(extension.name().to_string(), extension);
+    }
+
+    /// Add the imports of every extension to `linker`.
+    pub fn add_to_linker(&self, linker: &mut Linker<MyWasi>) -> Result<()> {
+        for extension in self.0.values() {
+            extension.add_to_linker(linker)?;
+        }
+        Ok(())
+    }
+
+    /// The state every extension sets up in a new store.
+    pub fn init_state(&self) -> ExtensionState {
+        let mut state = ExtensionState::default();
+        for extension in self.0.values() {
+            extension.init_state(&mut state);
+        }
+        state
+    }
+
+    /// Handle a call from the guest on the generic channel.
+    pub fn call(&self, name: &str, method: &str, payload: &str) -> Result<String, String> {
+        let extension = self
+            .0
+            .get(name)
+            .ok_or_else(|| format!("unknown extension '{}'", name))?;
+        extension.call(method, payload).map_err(|e| format!("{:#}", e))
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl SandboxExtension for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn call(&self, method: &str, payload: &str) -> Result<String> {
+            match method {
+                "echo" => Ok(payload.to_string()),
+                _ => Err(anyhow!("no method '{}'", method)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_call_routes_to_extension() {
+        let mut extensions = Extensions::default();
+        extensions.insert(Arc::new(Echo));
+
+        assert_eq!(extensions.call("echo", "echo", "hi"), Ok("hi".to_string()));
+        assert_eq!(
+            extensions.call("echo", "shout", "hi"),
+            Err("no method 'shout'".to_string())
+        );
+        assert_eq!(
+            extensions.call("missing", "echo", "hi"),
+            Err("unknown extension 'missing'".to_string())
+        );
+    }
+
+    struct Counter;
+
+    struct Count(u32);
+
+    impl SandboxExtension for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn init_state(&self, state: &mut ExtensionState) {
+            state.insert(Count(0));
+        }
+    }
+
+    #[test]
+    fn test_each_store_gets_fresh_state() {
+        let mut extensions = Extensions::default();
+        extensions.insert(Arc::new(Counter));
+
+        let mut state = extensions.init_state();
+        state.get_mut::<Count>().unwrap().0 += 1;
+        assert_eq!(state.get::<Count>().unwrap().0, 1);
+        assert_eq!(extensions.init_state().get::<Count>().unwrap().0, 0);
+        assert!(state.get::<u32>().is_none());
+    }
+}
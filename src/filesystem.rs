@@ -0,0 +1,710 @@
+//! `wasi:filesystem` with a cap on the bytes guest code writes, set with
+//! `PySandboxBuilder::max_write_bytes`. Work directories are memory backed
+//! where possible, so without it code could fill the host's memory.
+//!
+//! Everything else is forwarded to `wasmtime_wasi`. Writes that go over
+//! the cap are cut short and then fail with `insufficient-space`, which
+//! guest code sees as `OSError` with `errno.ENOSPC`, like a full disk.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use wasmtime::component::{HasData, Resource};
+use wasmtime_wasi::filesystem::WasiFilesystemCtxView;
+use wasmtime_wasi::p2::bindings::filesystem::types;
+use wasmtime_wasi::p2::bindings::sync::filesystem::types as sync_types;
+use wasmtime_wasi::p2::{FsError, FsResult};
+use wasmtime_wasi_io::bytes::Bytes;
+use wasmtime_wasi_io::poll::Pollable;
+use wasmtime_wasi_io::streams::{DynOutputStream, OutputStream, StreamError, StreamResult};
+
+/// Number of bytes the guest may still write to files, shared by every
+/// directory of a single store.
+#[derive(Debug)]
+pub(crate) struct WriteQuota {
+    remaining: AtomicU64,
+}
+
+impl WriteQuota {
+    pub(crate) fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            remaining: AtomicU64::new(limit),
+        })
+    }
+
+    /// Reserve up to `len` bytes, returning how many may be written.
+    fn take(&self, len: u64) -> u64 {
+        let update = |remaining: u64| Some(remaining.saturating_sub(len));
+        // `update` never gives up, so this is always `Ok`
+        let remaining = self
+            .remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, update)
+            .unwrap_or_else(|remaining| remaining);
+        remaining.min(len)
+    }
+
+    /// Return bytes reserved for a write that failed.
+    fn give_back(&self, len: u64) {
+        self.remaining.fetch_add(len, Ordering::SeqCst);
+    }
+}
+
+// Error a limited stream fails with, reported to the guest as
+// `insufficient-space`
+#[derive(Debug)]
+struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("write quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The guest's filesystem, charging writes against `quota` when set.
+pub(crate) struct QuotaFilesystem<'a> {
+    pub(crate) inner: WasiFilesystemCtxView<'a>,
+    pub(crate) quota: Option<Arc<WriteQuota>>,
+}
+
+impl QuotaFilesystem<'_> {
+    /// Cut `buf` to what the quota allows, failing once nothing may be
+    /// written. Returns the bytes reserved.
+    fn allow(&self, buf: &mut Vec<u8>) -> FsResult<u64> {
+        let Some(quota) = &self.quota else {
+            return Ok(0);
+        };
+        let allowed = quota.take(buf.len() as u64);
+        if allowed == 0 && !buf.is_empty() {
+            return Err(types::ErrorCode::InsufficientSpace.into());
+        }
+        buf.truncate(allowed as usize);
+        Ok(allowed)
+    }
+
+    fn give_back(&self, reserved: u64) {
+        if let Some(quota) = &self.quota {
+            quota.give_back(reserved);
+        }
+    }
+
+    /// Replace `stream` in the table with one that charges the quota.
+    fn limit(&mut self, stream: Resource<DynOutputStream>) -> FsResult<Resource<DynOutputStream>> {
+        let Some(quota) = &self.quota else {
+            return Ok(stream);
+        };
+        let inner = self.inner.table.delete(stream)?;
+        let limited: DynOutputStream = Box::new(LimitedFileStream {
+            inner,
+            quota: quota.clone(),
+        });
+        Ok(self.inner.table.push(limited)?)
+    }
+
+    fn is_quota_error(&self, err: &Resource<anyhow::Error>) -> anyhow::Result<bool> {
+        Ok(self.inner.table.get(err)?.is::<QuotaExceeded>())
+    }
+}
+
+impl HasData for QuotaFilesystem<'static> {
+    type Data<'a> = QuotaFilesystem<'a>;
+}
+
+/// A stream writing to a file that stops at the quota.
+struct LimitedFileStream {
+    inner: DynOutputStream,
+    quota: Arc<WriteQuota>,
+}
+
+#[wasmtime_wasi_io::async_trait]
+impl Pollable for LimitedFileStream {
+    async fn ready(&mut self) {
+        self.inner.ready().await
+    }
+}
+
+#[wasmtime_wasi_io::async_trait]
+impl OutputStream for LimitedFileStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let len = bytes.len() as u64;
+        let allowed = self.quota.take(len);
+        if allowed > 0 {
+            self.inner.write(bytes.slice(..allowed as usize))?;
+        }
+        if allowed < len {
+            return Err(StreamError::LastOperationFailed(QuotaExceeded.into()));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        self.inner.flush()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.inner.check_write()
+    }
+
+    async fn cancel(&mut self) {
+        self.inner.cancel().await
+    }
+}
+
+impl types::Host for QuotaFilesystem<'_> {
+    fn convert_error_code(&mut self, err: FsError) -> anyhow::Result<types::ErrorCode> {
+        types::Host::convert_error_code(&mut self.inner, err)
+    }
+
+    fn filesystem_error_code(
+        &mut self,
+        err: Resource<anyhow::Error>,
+    ) -> anyhow::Result<Option<types::ErrorCode>> {
+        if self.is_quota_error(&err)? {
+            return Ok(Some(types::ErrorCode::InsufficientSpace));
+        }
+        types::Host::filesystem_error_code(&mut self.inner, err)
+    }
+}
+
+impl types::HostDescriptor for QuotaFilesystem<'_> {
+    async fn advise(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+        len: types::Filesize,
+        advice: types::Advice,
+    ) -> FsResult<()> {
+        types::HostDescriptor::advise(&mut self.inner, fd, offset, len, advice).await
+    }
+
+    async fn sync_data(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        types::HostDescriptor::sync_data(&mut self.inner, fd).await
+    }
+
+    async fn get_flags(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorFlags> {
+        types::HostDescriptor::get_flags(&mut self.inner, fd).await
+    }
+
+    async fn get_type(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorType> {
+        types::HostDescriptor::get_type(&mut self.inner, fd).await
+    }
+
+    async fn set_size(&mut self, fd: Resource<types::Descriptor>, size: types::Filesize) -> FsResult<()> {
+        types::HostDescriptor::set_size(&mut self.inner, fd, size).await
+    }
+
+    async fn set_times(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        types::HostDescriptor::set_times(&mut self.inner, fd, atim, mtim).await
+    }
+
+    async fn read(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        len: types::Filesize,
+        offset: types::Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        types::HostDescriptor::read(&mut self.inner, fd, len, offset).await
+    }
+
+    async fn write(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        mut buf: Vec<u8>,
+        offset: types::Filesize,
+    ) -> FsResult<types::Filesize> {
+        let reserved = self.allow(&mut buf)?;
+        let written = types::HostDescriptor::write(&mut self.inner, fd, buf, offset).await;
+        if written.is_err() {
+            self.give_back(reserved);
+        }
+        written
+    }
+
+    async fn read_directory(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+    ) -> FsResult<Resource<types::DirectoryEntryStream>> {
+        types::HostDescriptor::read_directory(&mut self.inner, fd).await
+    }
+
+    async fn sync(&mut self, fd: Resource<types::Descriptor>) -> FsResult<()> {
+        types::HostDescriptor::sync(&mut self.inner, fd).await
+    }
+
+    async fn create_directory_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        types::HostDescriptor::create_directory_at(&mut self.inner, fd, path).await
+    }
+
+    async fn stat(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::DescriptorStat> {
+        types::HostDescriptor::stat(&mut self.inner, fd).await
+    }
+
+    async fn stat_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::DescriptorStat> {
+        types::HostDescriptor::stat_at(&mut self.inner, fd, path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        atim: types::NewTimestamp,
+        mtim: types::NewTimestamp,
+    ) -> FsResult<()> {
+        types::HostDescriptor::set_times_at(&mut self.inner, fd, path_flags, path, atim, mtim).await
+    }
+
+    async fn link_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path_flags: types::PathFlags,
+        old_path: String,
+        new_descriptor: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        types::HostDescriptor::link_at(
+            &mut self.inner,
+            fd,
+            old_path_flags,
+            old_path,
+            new_descriptor,
+            new_path,
+        )
+        .await
+    }
+
+    async fn open_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+        oflags: types::OpenFlags,
+        flags: types::DescriptorFlags,
+    ) -> FsResult<Resource<types::Descriptor>> {
+        types::HostDescriptor::open_at(&mut self.inner, fd, path_flags, path, oflags, flags).await
+    }
+
+    fn drop(&mut self, fd: Resource<types::Descriptor>) -> anyhow::Result<()> {
+        types::HostDescriptor::drop(&mut self.inner, fd)
+    }
+
+    async fn readlink_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<String> {
+        types::HostDescriptor::readlink_at(&mut self.inner, fd, path).await
+    }
+
+    async fn remove_directory_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        types::HostDescriptor::remove_directory_at(&mut self.inner, fd, path).await
+    }
+
+    async fn rename_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        old_path: String,
+        new_fd: Resource<types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        types::HostDescriptor::rename_at(&mut self.inner, fd, old_path, new_fd, new_path).await
+    }
+
+    async fn symlink_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        src_path: String,
+        dest_path: String,
+    ) -> FsResult<()> {
+        types::HostDescriptor::symlink_at(&mut self.inner, fd, src_path, dest_path).await
+    }
+
+    async fn unlink_file_at(&mut self, fd: Resource<types::Descriptor>, path: String) -> FsResult<()> {
+        types::HostDescriptor::unlink_file_at(&mut self.inner, fd, path).await
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<types::InputStream>> {
+        types::HostDescriptor::read_via_stream(&mut self.inner, fd, offset)
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        offset: types::Filesize,
+    ) -> FsResult<Resource<types::OutputStream>> {
+        let stream = types::HostDescriptor::write_via_stream(&mut self.inner, fd, offset)?;
+        self.limit(stream)
+    }
+
+    fn append_via_stream(&mut self, fd: Resource<types::Descriptor>) -> FsResult<Resource<types::OutputStream>> {
+        let stream = types::HostDescriptor::append_via_stream(&mut self.inner, fd)?;
+        self.limit(stream)
+    }
+
+    async fn is_same_object(
+        &mut self,
+        a: Resource<types::Descriptor>,
+        b: Resource<types::Descriptor>,
+    ) -> anyhow::Result<bool> {
+        types::HostDescriptor::is_same_object(&mut self.inner, a, b).await
+    }
+
+    async fn metadata_hash(&mut self, fd: Resource<types::Descriptor>) -> FsResult<types::MetadataHashValue> {
+        types::HostDescriptor::metadata_hash(&mut self.inner, fd).await
+    }
+
+    async fn metadata_hash_at(
+        &mut self,
+        fd: Resource<types::Descriptor>,
+        path_flags: types::PathFlags,
+        path: String,
+    ) -> FsResult<types::MetadataHashValue> {
+        types::HostDescriptor::metadata_hash_at(&mut self.inner, fd, path_flags, path).await
+    }
+}
+
+impl types::HostDirectoryEntryStream for QuotaFilesystem<'_> {
+    async fn read_directory_entry(
+        &mut self,
+        stream: Resource<types::DirectoryEntryStream>,
+    ) -> FsResult<Option<types::DirectoryEntry>> {
+        types::HostDirectoryEntryStream::read_directory_entry(&mut self.inner, stream).await
+    }
+
+    fn drop(&mut self, stream: Resource<types::DirectoryEntryStream>) -> anyhow::Result<()> {
+        types::HostDirectoryEntryStream::drop(&mut self.inner, stream)
+    }
+}
+
+impl sync_types::Host for QuotaFilesystem<'_> {
+    fn convert_error_code(&mut self, err: FsError) -> anyhow::Result<sync_types::ErrorCode> {
+        sync_types::Host::convert_error_code(&mut self.inner, err)
+    }
+
+    fn filesystem_error_code(
+        &mut self,
+        err: Resource<anyhow::Error>,
+    ) -> anyhow::Result<Option<sync_types::ErrorCode>> {
+        if self.is_quota_error(&err)? {
+            return Ok(Some(sync_types::ErrorCode::InsufficientSpace));
+        }
+        sync_types::Host::filesystem_error_code(&mut self.inner, err)
+    }
+}
+
+impl sync_types::HostDescriptor for QuotaFilesystem<'_> {
+    fn advise(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        offset: sync_types::Filesize,
+        len: sync_types::Filesize,
+        advice: sync_types::Advice,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::advise(&mut self.inner, fd, offset, len, advice)
+    }
+
+    fn sync_data(&mut self, fd: Resource<sync_types::Descriptor>) -> FsResult<()> {
+        sync_types::HostDescriptor::sync_data(&mut self.inner, fd)
+    }
+
+    fn get_flags(&mut self, fd: Resource<sync_types::Descriptor>) -> FsResult<sync_types::DescriptorFlags> {
+        sync_types::HostDescriptor::get_flags(&mut self.inner, fd)
+    }
+
+    fn get_type(&mut self, fd: Resource<sync_types::Descriptor>) -> FsResult<sync_types::DescriptorType> {
+        sync_types::HostDescriptor::get_type(&mut self.inner, fd)
+    }
+
+    fn set_size(&mut self, fd: Resource<sync_types::Descriptor>, size: sync_types::Filesize) -> FsResult<()> {
+        sync_types::HostDescriptor::set_size(&mut self.inner, fd, size)
+    }
+
+    fn set_times(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        atim: sync_types::NewTimestamp,
+        mtim: sync_types::NewTimestamp,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::set_times(&mut self.inner, fd, atim, mtim)
+    }
+
+    fn read(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        len: sync_types::Filesize,
+        offset: sync_types::Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        sync_types::HostDescriptor::read(&mut self.inner, fd, len, offset)
+    }
+
+    fn write(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        mut buf: Vec<u8>,
+        offset: sync_types::Filesize,
+    ) -> FsResult<sync_types::Filesize> {
+        let reserved = self.allow(&mut buf)?;
+        let written = sync_types::HostDescriptor::write(&mut self.inner, fd, buf, offset);
+        if written.is_err() {
+            self.give_back(reserved);
+        }
+        written
+    }
+
+    fn read_directory(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+    ) -> FsResult<Resource<sync_types::DirectoryEntryStream>> {
+        sync_types::HostDescriptor::read_directory(&mut self.inner, fd)
+    }
+
+    fn sync(&mut self, fd: Resource<sync_types::Descriptor>) -> FsResult<()> {
+        sync_types::HostDescriptor::sync(&mut self.inner, fd)
+    }
+
+    fn create_directory_at(&mut self, fd: Resource<sync_types::Descriptor>, path: String) -> FsResult<()> {
+        sync_types::HostDescriptor::create_directory_at(&mut self.inner, fd, path)
+    }
+
+    fn stat(&mut self, fd: Resource<sync_types::Descriptor>) -> FsResult<sync_types::DescriptorStat> {
+        sync_types::HostDescriptor::stat(&mut self.inner, fd)
+    }
+
+    fn stat_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        path_flags: sync_types::PathFlags,
+        path: String,
+    ) -> FsResult<sync_types::DescriptorStat> {
+        sync_types::HostDescriptor::stat_at(&mut self.inner, fd, path_flags, path)
+    }
+
+    fn set_times_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        path_flags: sync_types::PathFlags,
+        path: String,
+        atim: sync_types::NewTimestamp,
+        mtim: sync_types::NewTimestamp,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::set_times_at(&mut self.inner, fd, path_flags, path, atim, mtim)
+    }
+
+    fn link_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        old_path_flags: sync_types::PathFlags,
+        old_path: String,
+        new_descriptor: Resource<sync_types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::link_at(
+            &mut self.inner,
+            fd,
+            old_path_flags,
+            old_path,
+            new_descriptor,
+            new_path,
+        )
+    }
+
+    fn open_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        path_flags: sync_types::PathFlags,
+        path: String,
+        oflags: sync_types::OpenFlags,
+        flags: sync_types::DescriptorFlags,
+    ) -> FsResult<Resource<sync_types::Descriptor>> {
+        sync_types::HostDescriptor::open_at(&mut self.inner, fd, path_flags, path, oflags, flags)
+    }
+
+    fn drop(&mut self, fd: Resource<sync_types::Descriptor>) -> anyhow::Result<()> {
+        sync_types::HostDescriptor::drop(&mut self.inner, fd)
+    }
+
+    fn readlink_at(&mut self, fd: Resource<sync_types::Descriptor>, path: String) -> FsResult<String> {
+        sync_types::HostDescriptor::readlink_at(&mut self.inner, fd, path)
+    }
+
+    fn remove_directory_at(&mut self, fd: Resource<sync_types::Descriptor>, path: String) -> FsResult<()> {
+        sync_types::HostDescriptor::remove_directory_at(&mut self.inner, fd, path)
+    }
+
+    fn rename_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        old_path: String,
+        new_fd: Resource<sync_types::Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::rename_at(&mut self.inner, fd, old_path, new_fd, new_path)
+    }
+
+    fn symlink_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        src_path: String,
+        dest_path: String,
+    ) -> FsResult<()> {
+        sync_types::HostDescriptor::symlink_at(&mut self.inner, fd, src_path, dest_path)
+    }
+
+    fn unlink_file_at(&mut self, fd: Resource<sync_types::Descriptor>, path: String) -> FsResult<()> {
+        sync_types::HostDescriptor::unlink_file_at(&mut self.inner, fd, path)
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        offset: sync_types::Filesize,
+    ) -> FsResult<Resource<sync_types::InputStream>> {
+        sync_types::HostDescriptor::read_via_stream(&mut self.inner, fd, offset)
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        offset: sync_types::Filesize,
+    ) -> FsResult<Resource<sync_types::OutputStream>> {
+        let stream = sync_types::HostDescriptor::write_via_stream(&mut self.inner, fd, offset)?;
+        self.limit(stream)
+    }
+
+    fn append_via_stream(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+    ) -> FsResult<Resource<sync_types::OutputStream>> {
+        let stream = sync_types::HostDescriptor::append_via_stream(&mut self.inner, fd)?;
+        self.limit(stream)
+    }
+
+    fn is_same_object(
+        &mut self,
+        a: Resource<sync_types::Descriptor>,
+        b: Resource<sync_types::Descriptor>,
+    ) -> anyhow::Result<bool> {
+        sync_types::HostDescriptor::is_same_object(&mut self.inner, a, b)
+    }
+
+    fn metadata_hash(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+    ) -> FsResult<sync_types::MetadataHashValue> {
+        sync_types::HostDescriptor::metadata_hash(&mut self.inner, fd)
+    }
+
+    fn metadata_hash_at(
+        &mut self,
+        fd: Resource<sync_types::Descriptor>,
+        path_flags: sync_types::PathFlags,
+        path: String,
+    ) -> FsResult<sync_types::MetadataHashValue> {
+        sync_types::HostDescriptor::metadata_hash_at(&mut self.inner, fd, path_flags, path)
+    }
+}
+
+impl sync_types::HostDirectoryEntryStream for QuotaFilesystem<'_> {
+    fn read_directory_entry(
+        &mut self,
+        stream: Resource<sync_types::DirectoryEntryStream>,
+    ) -> FsResult<Option<sync_types::DirectoryEntry>> {
+        sync_types::HostDirectoryEntryStream::read_directory_entry(&mut self.inner, stream)
+    }
+
+    fn drop(&mut self, stream: Resource<sync_types::DirectoryEntryStream>) -> anyhow::Result<()> {
+        sync_types::HostDirectoryEntryStream::drop(&mut self.inner, stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_allows_up_to_the_limit() {
+        let quota = WriteQuota::new(10);
+        assert_eq!(quota.take(4), 4);
+        assert_eq!(quota.take(8), 6);
+        assert_eq!(quota.take(1), 0);
+        quota.give_back(3);
+        assert_eq!(quota.take(5), 3);
+    }
+
+    #[test]
+    fn test_writes_past_the_quota_fail_with_insufficient_space() {
+        use sync_types::{DescriptorFlags, ErrorCode, HostDescriptor, OpenFlags, PathFlags};
+        use wasmtime_wasi::filesystem::WasiFilesystemView;
+        use wasmtime_wasi::p2::bindings::filesystem::preopens;
+        use wasmtime_wasi::{DirPerms, FilePerms, ResourceTable, WasiCtx, WasiCtxView, WasiView};
+
+        struct State {
+            ctx: WasiCtx,
+            table: ResourceTable,
+        }
+
+        impl WasiView for State {
+            fn ctx(&mut self) -> WasiCtxView<'_> {
+                WasiCtxView {
+                    ctx: &mut self.ctx,
+                    table: &mut self.table,
+                }
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = WasiCtx::builder();
+        builder
+            .preopened_dir(dir.path(), "/work", DirPerms::all(), FilePerms::all())
+            .unwrap();
+        let mut state = State {
+            ctx: builder.build(),
+            table: ResourceTable::new(),
+        };
+        let mut fs = QuotaFilesystem {
+            inner: state.filesystem(),
+            quota: Some(WriteQuota::new(10)),
+        };
+        let (root, _) = preopens::Host::get_directories(&mut fs.inner).unwrap().remove(0);
+        let open = |fs: &mut QuotaFilesystem, name: &str| {
+            let flags = DescriptorFlags::READ | DescriptorFlags::WRITE;
+            let root = Resource::new_borrow(root.rep());
+            fs.open_at(root, PathFlags::empty(), name.into(), OpenFlags::CREATE, flags)
+                .unwrap()
+        };
+
+        // Writes are cut at the quota, then fail
+        let file = open(&mut fs, "a.txt");
+        let written = fs.write(Resource::new_borrow(file.rep()), vec![b'a'; 6], 0).unwrap();
+        assert_eq!(written, 6);
+        let written = fs.write(Resource::new_borrow(file.rep()), vec![b'a'; 6], 6).unwrap();
+        assert_eq!(written, 4);
+        let err = fs.write(file, vec![b'a'; 1], 10).unwrap_err();
+        let code = sync_types::Host::convert_error_code(&mut fs, err).unwrap();
+        assert_eq!(code, ErrorCode::InsufficientSpace);
+
+        // So are writes through streams
+        let file = open(&mut fs, "b.txt");
+        let stream = fs.write_via_stream(file, 0).unwrap();
+        let err = match fs.inner.table.get_mut(&stream).unwrap().write(Bytes::from_static(b"b")) {
+            Err(StreamError::LastOperationFailed(err)) => err,
+            other => panic!("expected the write to fail, got {other:?}"),
+        };
+        let err = fs.inner.table.push(err).unwrap();
+        let code = sync_types::Host::filesystem_error_code(&mut fs, err).unwrap();
+        assert_eq!(code, Some(ErrorCode::InsufficientSpace));
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap().len(), 10);
+    }
+}
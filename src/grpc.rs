@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tonic::codegen::tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::error::{self, PyboxError};
+use crate::output::{OutputSink, Stream};
+use crate::pool::SandboxPool;
+use crate::sandbox::{self, ExecOptions, PySandbox};
+use crate::session::{lock, CellResult, Session, SessionManager};
+
+pub mod proto;
+
+use proto::exec_event::Event;
+use proto::{ExecEvent, ExecEventStream, ExecRequest, ExecResponse, SandboxServer};
+
+/// Settings of the gRPC service. Requests can lower the timeout of their
+/// code but never raise it past `max_timeout`.
+#[derive(Debug, Clone)]
+pub struct GrpcConfig {
+    pub addr: SocketAddr,
+    /// Warm sandboxes for executions outside of sessions.
+    pub workers: usize,
+    /// Sessions kept at once, `CreateSession` fails beyond that until one
+    /// is closed or expires.
+    pub max_sessions: usize,
+    /// How long a session may sit unused before it is dropped, `None`
+    /// keeps sessions until they are closed.
+    pub session_idle_ttl: Option<Duration>,
+    /// Longest timeout a request may ask for with `timeout_ms`.
+    pub max_timeout: Duration,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 50051)),
+            workers: 4,
+            max_sessions: 64,
+            session_idle_ttl: Some(Duration::from_secs(30 * 60)),
+            max_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Serve the `pybox.v1.Sandbox` gRPC service from proto/pybox.proto on
+/// `config.addr` until the process is stopped.
+pub fn run(sandbox: PySandbox, config: GrpcConfig) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the gRPC runtime")?;
+    let addr = config.addr;
+    runtime.block_on(async {
+        eprintln!("pybox grpc: listening on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(service(sandbox, config))
+            .serve(addr)
+            .await
+            .context("gRPC server failed")
+    })
+}
+
+/// The service, for adding it to an existing tonic server.
+pub fn service(sandbox: PySandbox, config: GrpcConfig) -> SandboxServer<SandboxService> {
+    SandboxServer::new(SandboxService {
+        pool: Arc::new(SandboxPool::new(sandbox.clone(), config.workers)),
+        sessions: SessionManager::new(sandbox.clone(), config.max_sessions, config.session_idle_ttl),
+        template: Mutex::new(sandbox),
+        config,
+    })
+}
+
+pub struct SandboxService {
+    pool: Arc<SandboxPool>,
+    // Source of cancel handles, behind a lock because sandboxes are
+    // `Send` but not `Sync`
+    template: Mutex<PySandbox>,
+    sessions: SessionManager,
+    config: GrpcConfig,
+}
+
+impl SandboxService {
+    fn options(&self, request: &ExecRequest) -> ExecOptions {
+        let timeout = match request.timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms).min(self.config.max_timeout)),
+        };
+        ExecOptions {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    fn session(&self, id: &str) -> Result<Arc<Mutex<Session>>, Status> {
+        self.sessions
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("No session {}", id)))
+    }
+}
+
+impl proto::Sandbox for SandboxService {
+    async fn exec(&self, request: Request<ExecRequest>) -> Result<Response<ExecResponse>, Status> {
+        let request = request.into_inner();
+        let options = ExecOptions {
+            capture_output: true,
+            ..self.options(&request)
+        };
+        let response = if request.session_id.is_empty() {
+            let pool = self.pool.clone();
+            let result =
+                blocking(move || Ok(pool.exec_with_options(&request.code, &options))).await?;
+            exec_response(result)?
+        } else {
+            let session = self.session(&request.session_id)?;
+            let cell = blocking(move || {
+                Ok(lock(&session).exec_cell_with_options(&request.code, &options))
+            })
+            .await?;
+            cell_response(cell)
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn exec_stream(
+        &self,
+        request: Request<ExecRequest>,
+    ) -> Result<Response<ExecEventStream>, Status> {
+        let request = request.into_inner();
+        let (events, received) = mpsc::unbounded_channel();
+        let mut options = self.options(&request);
+
+        if request.session_id.is_empty() {
+            // Stop the code once the client stops listening
+            let cancel = lock(&self.template).cancel_handle();
+            let sink: OutputSink = {
+                let events = events.clone();
+                let cancel = cancel.clone();
+                Arc::new(move |stream, bytes: &[u8]| {
+                    let text = String::from_utf8_lossy(bytes).into_owned();
+                    let event = match stream {
+                        Stream::Stdout => Event::Stdout(text),
+                        Stream::Stderr => Event::Stderr(text),
+                    };
+                    if events.send(Ok(ExecEvent { event: Some(event) })).is_err() {
+                        cancel.cancel();
+                    }
+                })
+            };
+            options.cancel = Some(cancel);
+            options.on_output = Some(sink);
+            let pool = self.pool.clone();
+            tokio::task::spawn_blocking(move || {
+                let result = pool.exec_with_options(&request.code, &options);
+                let event = exec_response(result).map(|response| ExecEvent {
+                    event: Some(Event::Result(response)),
+                });
+                let _ = events.send(event);
+            });
+        } else {
+            // Cells capture their own output, it arrives with the result
+            let session = self.session(&request.session_id)?;
+            tokio::task::spawn_blocking(move || {
+                let cell = lock(&session).exec_cell_with_options(&request.code, &options);
+                let event = ExecEvent {
+                    event: Some(Event::Result(cell_response(cell))),
+                };
+                let _ = events.send(Ok(event));
+            });
+        }
+        Ok(Response::new(UnboundedReceiverStream::new(received)))
+    }
+
+    async fn create_session(
+        &self,
+        _request: Request<proto::CreateSessionRequest>,
+    ) -> Result<Response<proto::CreateSessionResponse>, Status> {
+        let session_id = uuid::Uuid::new_v4().to_string();
+        self.sessions
+            .get_or_create(&session_id)
+            .map_err(|limit| Status::resource_exhausted(limit.to_string()))?;
+        Ok(Response::new(proto::CreateSessionResponse { session_id }))
+    }
+
+    async fn close_session(
+        &self,
+        request: Request<proto::CloseSessionRequest>,
+    ) -> Result<Response<proto::CloseSessionResponse>, Status> {
+        let id = request.into_inner().session_id;
+        if self.sessions.remove(&id) {
+            Ok(Response::new(proto::CloseSessionResponse {}))
+        } else {
+            Err(Status::not_found(format!("No session {}", id)))
+        }
+    }
+}
+
+/// Run `f` on the blocking thread pool, executions hold their thread for
+/// as long as the code runs.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(Status::internal(format!("Execution panicked: {}", e))))
+}
+
+/// The response to an execution. Failures caused by the code are a
+/// normal response with `ok` false, anything else is a gRPC error.
+fn exec_response(result: Result<sandbox::ExecOutput>) -> Result<ExecResponse, Status> {
+    let e = match result {
+        Ok(output) => {
+            return Ok(ExecResponse {
+                ok: true,
+                output: Some(output_message(output)),
+                ..Default::default()
+            });
+        }
+        Err(e) => e,
+    };
+    if let Some(exception) = e.downcast_ref::<error::PythonError>() {
+        return Ok(ExecResponse {
+            ok: false,
+            error: exception.to_string(),
+            exception: Some(proto::PythonError {
+                exception_type: exception.exception.name().to_string(),
+                message: exception.message.clone(),
+                lineno: exception.lineno,
+            }),
+            ..Default::default()
+        });
+    }
+    if e.downcast_ref::<PyboxError>().is_some() {
+        return Ok(ExecResponse {
+            ok: false,
+            error: e.to_string(),
+            ..Default::default()
+        });
+    }
+    Err(Status::internal(format!("{:#}", e)))
+}
+
+fn cell_response(cell: CellResult) -> ExecResponse {
+    ExecResponse {
+        ok: cell.error.is_none(),
+        output: Some(proto::ExecOutput {
+            value: cell.value.unwrap_or_default(),
+            stdout: cell.stdout,
+            stderr: cell.stderr,
+            stats: Some(proto::ExecStats {
+                wall_time_us: cell.duration.as_micros() as u64,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        error: cell.error.unwrap_or_default(),
+        exception: None,
+    }
+}
+
+fn output_message(output: sandbox::ExecOutput) -> proto::ExecOutput {
+    let stats = output.stats;
+    proto::ExecOutput {
+        value: output.value,
+        stdout: output.stdout,
+        stderr: output.stderr,
+        stats: Some(proto::ExecStats {
+            wall_time_us: stats.wall_time.as_micros() as u64,
+            fuel_consumed: stats.fuel_consumed,
+            fuel_remaining: stats.fuel_remaining,
+            peak_memory_bytes: stats.peak_memory_bytes as u64,
+            epoch_interrupted: stats.epoch_interrupted,
+            output_truncated: stats.output_truncated,
+        }),
+        figures: output.figures,
+        displays: output
+            .displays
+            .into_iter()
+            .map(|display| proto::DisplayData {
+                mime_type: display.mime_type,
+                data: display.data,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PyException;
+
+    #[test]
+    fn test_exec_response_by_error_kind() {
+        let exception = anyhow::Error::from(error::PythonError {
+            exception: PyException::Key,
+            message: "'a'".to_string(),
+            lineno: Some(2),
+        });
+        let response = exec_response(Err(exception)).unwrap();
+        assert!(!response.ok);
+        assert_eq!(response.error, "KeyError: 'a'");
+        assert_eq!(response.exception.unwrap().lineno, Some(2));
+
+        let response = exec_response(Err(PyboxError::Timeout.into())).unwrap();
+        assert_eq!(response.error, "Execution timed out");
+        assert!(response.exception.is_none());
+
+        let status = exec_response(Err(anyhow::anyhow!("no sandbox.wasm"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        use prost::Message;
+
+        let response = ExecResponse {
+            ok: true,
+            output: Some(output_message(sandbox::ExecOutput {
+                value: "42".to_string(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let event = ExecEvent {
+            event: Some(Event::Result(response)),
+        };
+        assert_eq!(ExecEvent::decode(&event.encode_to_vec()[..]).unwrap(), event);
+    }
+}
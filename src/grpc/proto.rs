@@ -0,0 +1,251 @@
+//! Messages and service plumbing for proto/pybox.proto, written by hand
+//! in the shape `tonic-build` generates so building doesn't need protoc.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecRequest {
+    #[prost(string, tag = "1")]
+    pub code: String,
+    #[prost(uint64, tag = "2")]
+    pub timeout_ms: u64,
+    #[prost(string, tag = "3")]
+    pub session_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecResponse {
+    #[prost(bool, tag = "1")]
+    pub ok: bool,
+    #[prost(message, optional, tag = "2")]
+    pub output: Option<ExecOutput>,
+    #[prost(string, tag = "3")]
+    pub error: String,
+    #[prost(message, optional, tag = "4")]
+    pub exception: Option<PythonError>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecOutput {
+    #[prost(string, tag = "1")]
+    pub value: String,
+    #[prost(string, tag = "2")]
+    pub stdout: String,
+    #[prost(string, tag = "3")]
+    pub stderr: String,
+    #[prost(message, optional, tag = "4")]
+    pub stats: Option<ExecStats>,
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    pub figures: Vec<Vec<u8>>,
+    #[prost(message, repeated, tag = "6")]
+    pub displays: Vec<DisplayData>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecStats {
+    #[prost(uint64, tag = "1")]
+    pub wall_time_us: u64,
+    #[prost(uint64, optional, tag = "2")]
+    pub fuel_consumed: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub fuel_remaining: Option<u64>,
+    #[prost(uint64, tag = "4")]
+    pub peak_memory_bytes: u64,
+    #[prost(bool, tag = "5")]
+    pub epoch_interrupted: bool,
+    #[prost(bool, tag = "6")]
+    pub output_truncated: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DisplayData {
+    #[prost(string, tag = "1")]
+    pub mime_type: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PythonError {
+    #[prost(string, tag = "1")]
+    pub exception_type: String,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(uint32, optional, tag = "3")]
+    pub lineno: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ExecEvent {
+    #[prost(oneof = "exec_event::Event", tags = "1, 2, 3")]
+    pub event: Option<exec_event::Event>,
+}
+
+pub mod exec_event {
+    // The result is sent once per stream, boxing it would not save much
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Event {
+        #[prost(string, tag = "1")]
+        Stdout(String),
+        #[prost(string, tag = "2")]
+        Stderr(String),
+        #[prost(message, tag = "3")]
+        Result(super::ExecResponse),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateSessionRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateSessionResponse {
+    #[prost(string, tag = "1")]
+    pub session_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CloseSessionRequest {
+    #[prost(string, tag = "1")]
+    pub session_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CloseSessionResponse {}
+
+pub type ExecEventStream = tonic::codegen::tokio_stream::wrappers::UnboundedReceiverStream<
+    Result<ExecEvent, Status>,
+>;
+
+/// The methods of the `pybox.v1.Sandbox` service.
+pub trait Sandbox: Send + Sync + 'static {
+    fn exec(
+        &self,
+        request: Request<ExecRequest>,
+    ) -> impl Future<Output = Result<Response<ExecResponse>, Status>> + Send;
+    fn exec_stream(
+        &self,
+        request: Request<ExecRequest>,
+    ) -> impl Future<Output = Result<Response<ExecEventStream>, Status>> + Send;
+    fn create_session(
+        &self,
+        request: Request<CreateSessionRequest>,
+    ) -> impl Future<Output = Result<Response<CreateSessionResponse>, Status>> + Send;
+    fn close_session(
+        &self,
+        request: Request<CloseSessionRequest>,
+    ) -> impl Future<Output = Result<Response<CloseSessionResponse>, Status>> + Send;
+}
+
+pub const SERVICE_NAME: &str = "pybox.v1.Sandbox";
+
+/// Routes gRPC requests to a `Sandbox` implementation, for
+/// `tonic::transport::Server::add_service`.
+pub struct SandboxServer<T>(Arc<T>);
+
+impl<T> SandboxServer<T> {
+    pub fn new(inner: T) -> Self {
+        Self(Arc::new(inner))
+    }
+}
+
+impl<T> Clone for SandboxServer<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> NamedService for SandboxServer<T> {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+/// A method of `T` as a tonic service, `F` picks which.
+struct Method<T, F>(Arc<T>, F);
+
+impl<T, F, Req, Res, Fut> UnaryService<Req> for Method<T, F>
+where
+    T: Sandbox,
+    F: Fn(Arc<T>, Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>> + Send + 'static,
+{
+    type Response = Res;
+    type Future = BoxFuture<Response<Res>, Status>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        Box::pin((self.1)(self.0.clone(), request))
+    }
+}
+
+impl<T, F, Req, Fut> ServerStreamingService<Req> for Method<T, F>
+where
+    T: Sandbox,
+    F: Fn(Arc<T>, Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<ExecEventStream>, Status>> + Send + 'static,
+{
+    type Response = ExecEvent;
+    type ResponseStream = ExecEventStream;
+    type Future = BoxFuture<Response<ExecEventStream>, Status>;
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        Box::pin((self.1)(self.0.clone(), request))
+    }
+}
+
+impl<T, B> Service<http::Request<B>> for SandboxServer<T>
+where
+    T: Sandbox,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let inner = self.0.clone();
+        match req.uri().path() {
+            "/pybox.v1.Sandbox/Exec" => Box::pin(async move {
+                let method = Method(inner, |inner: Arc<T>, request| async move {
+                    inner.exec(request).await
+                });
+                Ok(Grpc::new(ProstCodec::default()).unary(method, req).await)
+            }),
+            "/pybox.v1.Sandbox/ExecStream" => Box::pin(async move {
+                let method = Method(inner, |inner: Arc<T>, request| async move {
+                    inner.exec_stream(request).await
+                });
+                Ok(Grpc::new(ProstCodec::default()).server_streaming(method, req).await)
+            }),
+            "/pybox.v1.Sandbox/CreateSession" => Box::pin(async move {
+                let method = Method(inner, |inner: Arc<T>, request| async move {
+                    inner.create_session(request).await
+                });
+                Ok(Grpc::new(ProstCodec::default()).unary(method, req).await)
+            }),
+            "/pybox.v1.Sandbox/CloseSession" => Box::pin(async move {
+                let method = Method(inner, |inner: Arc<T>, request| async move {
+                    inner.close_session(request).await
+                });
+                Ok(Grpc::new(ProstCodec::default()).unary(method, req).await)
+            }),
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(tonic::body::Body::default());
+                let headers = response.headers_mut();
+                headers.insert(Status::GRPC_STATUS, (tonic::Code::Unimplemented as i32).into());
+                headers.insert(http::header::CONTENT_TYPE, tonic::metadata::GRPC_CONTENT_TYPE);
+                Ok(response)
+            }),
+        }
+    }
+}
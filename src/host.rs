@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde_json::Value;
+
+/// A host function guest code can call with `pybox.host.call(name, args)`.
+/// It receives the JSON arguments and returns a JSON result, errors are
+/// raised in the guest as `pybox.host.HostCallError`.
+pub type HostFn = Arc<dyn Fn(Value) -> Result<Value> + Send + Sync>;
+
+/// The host functions registered on a sandbox, by name.
+#[derive(Clone, Default)]
+pub struct HostFns(Arc<HashMap<String, HostFn>>);
+
+impl HostFns {
+    pub fn insert(&mut self, name: String, function: HostFn) {
+        Arc::make_mut(&mut self.0).insert(name, function);
+    }
+
+    /// Handle a call from the guest, `args` and the result are JSON.
+    pub fn call(&self, name: &str, args: &str) -> Result<String, String> {
+        let function = self
+            .0
+            .get(name)
+            .ok_or_else(|| format!("unknown host function '{}'", name))?;
+        let args = serde_json::from_str(args).map_err(|e| format!("invalid arguments: {}", e))?;
+        let result = function(args).map_err(|e| format!("{:#}", e))?;
+        Ok(result.to_string())
+    }
+}
+
+impl fmt::Debug for HostFns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Answers guest calls to `input()` with the line typed in response to
+/// the prompt, or `None` for end of input. See
+/// `PySandboxBuilder::on_input`.
+pub type InputFn = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// The sandbox's `on_input` callback, if any.
+#[derive(Clone, Default)]
+pub(crate) struct InputHandler(Option<InputFn>);
+
+impl InputHandler {
+    pub(crate) fn new(function: InputFn) -> Self {
+        Self(Some(function))
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Handle a call to `input()` from the guest. The line is returned
+    /// without its line ending, like `input()` does.
+    pub(crate) fn read(&self, prompt: &str) -> Option<String> {
+        let mut line = (self.0.as_ref()?)(prompt)?;
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Some(line)
+    }
+}
+
+impl fmt::Debug for InputHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = if self.is_set() { "Some(..)" } else { "None" };
+        write!(f, "InputHandler({})", set)
+    }
+}
+
+/// Receives progress guest code reports with `pybox.progress(percent,
+/// message)`, with `percent` between 0 and 100. See
+/// `PySandboxBuilder::on_progress`.
+pub type ProgressFn = Arc<dyn Fn(f32, &str) + Send + Sync>;
+
+/// The sandbox's `on_progress` callback, if any.
+#[derive(Clone, Default)]
+pub(crate) struct ProgressHandler(Option<ProgressFn>);
+
+impl ProgressHandler {
+    pub(crate) fn new(function: ProgressFn) -> Self {
+        Self(Some(function))
+    }
+
+    /// Handle a report from the guest. Reports are dropped without a
+    /// callback.
+    pub(crate) fn report(&self, percent: f32, message: &str) {
+        if let Some(function) = &self.0 {
+            function(percent.clamp(0.0, 100.0), message);
+        }
+    }
+}
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = if self.0.is_some() { "Some(..)" } else { "None" };
+        write!(f, "ProgressHandler({})", set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    #[test]
+    fn test_call_dispatches_by_name() {
+        let mut fns = HostFns::default();
+        fns.insert(
+            "double".to_string(),
+            Arc::new(|args| Ok(json!(args["n"].as_i64().unwrap() * 2))),
+        );
+        fns.insert("fail".to_string(), Arc::new(|_| Err(anyhow!("no such user"))));
+
+        assert_eq!(fns.call("double", r#"{"n": 21}"#), Ok("42".to_string()));
+        assert_eq!(fns.call("fail", "null"), Err("no such user".to_string()));
+        assert_eq!(
+            fns.call("missing", "null"),
+            Err("unknown host function 'missing'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_input_strips_line_ending() {
+        let handler = InputHandler::new(Arc::new(|prompt: &str| match prompt {
+            "name? " => Some("Ada\r\n".to_string()),
+            _ => None,
+        }));
+        assert_eq!(handler.read("name? ").as_deref(), Some("Ada"));
+        assert_eq!(handler.read("age? "), None);
+        assert_eq!(InputHandler::default().read("name? "), None);
+    }
+
+    #[test]
+    fn test_progress_is_clamped() {
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = reports.clone();
+        let handler = ProgressHandler::new(Arc::new(move |percent, message: &str| {
+            seen.lock().unwrap().push((percent, message.to_string()));
+        }));
+        handler.report(50.0, "half");
+        handler.report(120.0, "");
+        ProgressHandler::default().report(10.0, "dropped");
+        assert_eq!(
+            *reports.lock().unwrap(),
+            [(50.0, "half".to_string()), (100.0, String::new())]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::BodyExt;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::{HyperIncomingBody, HyperOutgoingBody};
+use wasmtime_wasi_http::types::{
+    HostFutureIncomingResponse, OutgoingRequestConfig, default_send_request_handler,
+};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx};
+
+/// Which outbound HTTP requests guest code may make and how much data
+/// they may move. Checked by the host for every request, guest code has
+/// no way to change it.
+#[derive(Debug, Clone, Default)]
+pub struct HttpPolicy {
+    allowed: Vec<AllowedHost>,
+    /// Largest response body a single request may read.
+    pub max_request_bytes: Option<u64>,
+    /// Largest total of response bodies across one execution.
+    pub max_total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AllowedHost {
+    host: String,
+    // None allows any port
+    port: Option<u16>,
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpDenied {
+    /// The host or port is not on the allowlist.
+    HostNotAllowed { authority: String },
+    /// A response body went over `max_request_bytes`.
+    RequestBudgetExceeded { limit: u64 },
+    /// Responses went over `max_total_bytes` for the execution.
+    TotalBudgetExceeded { limit: u64 },
+}
+
+impl fmt::Display for HttpDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpDenied::HostNotAllowed { authority } => {
+                write!(f, "HTTP requests to {} are not allowed", authority)
+            }
+            HttpDenied::RequestBudgetExceeded { limit } => {
+                write!(f, "HTTP response exceeded the per-request limit of {} bytes", limit)
+            }
+            HttpDenied::TotalBudgetExceeded { limit } => {
+                write!(f, "HTTP responses exceeded the total limit of {} bytes", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HttpDenied {}
+
+impl HttpDenied {
+    /// The `wasi:http` error guest code sees.
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            HttpDenied::HostNotAllowed { .. } => ErrorCode::HttpRequestDenied,
+            HttpDenied::RequestBudgetExceeded { limit }
+            | HttpDenied::TotalBudgetExceeded { limit } => {
+                ErrorCode::HttpResponseBodySize(Some(*limit))
+            }
+        }
+    }
+}
+
+impl HttpPolicy {
+    /// Allow requests to each entry, either `host` for any port or
+    /// `host:port` for one port. Hosts are matched exactly, ignoring case.
+    pub fn allow<I, S>(&mut self, hosts: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for entry in hosts {
+            let entry = entry.as_ref().to_ascii_lowercase();
+            let allowed = match entry.rsplit_once(':') {
+                Some((host, port)) if port.parse::<u16>().is_ok() => AllowedHost {
+                    host: host.to_string(),
+                    port: port.parse().ok(),
+                },
+                _ => AllowedHost {
+                    host: entry,
+                    port: None,
+                },
+            };
+            self.allowed.push(allowed);
+        }
+    }
+
+    /// Whether any host has been allowed.
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Check a request to `host` on `port` against the allowlist.
+    pub fn check_host(&self, host: &str, port: u16) -> Result<(), HttpDenied> {
+        let host = host.to_ascii_lowercase();
+        let allowed = self
+            .allowed
+            .iter()
+            .any(|a| a.host == host && a.port.is_none_or(|p| p == port));
+        if allowed {
+            Ok(())
+        } else {
+            Err(HttpDenied::HostNotAllowed {
+                authority: format!("{}:{}", host, port),
+            })
+        }
+    }
+}
+
+/// The `wasi:http` state of one execution, sending the requests the
+/// policy allows and refusing the rest.
+pub(crate) struct HttpState {
+    pub(crate) ctx: WasiHttpCtx,
+    policy: Arc<HttpPolicy>,
+    // Response bytes read by all requests of the execution
+    total_bytes: Arc<AtomicU64>,
+}
+
+impl HttpState {
+    pub(crate) fn new(policy: &HttpPolicy) -> Self {
+        Self {
+            ctx: WasiHttpCtx::new(),
+            policy: Arc::new(policy.clone()),
+            total_bytes: Arc::default(),
+        }
+    }
+
+    /// Send `request` if its host is on the allowlist, reading the
+    /// response body within the byte budgets.
+    pub(crate) fn send_request(
+        &self,
+        request: http::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        let uri = request.uri();
+        let host = uri.host().unwrap_or_default();
+        let port = uri
+            .port_u16()
+            .unwrap_or(if config.use_tls { 443 } else { 80 });
+        if let Err(denied) = self.policy.check_host(host, port) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!("{}", denied);
+            return Err(denied.error_code().into());
+        }
+
+        let budget = ResponseBudget {
+            policy: self.policy.clone(),
+            total_bytes: self.total_bytes.clone(),
+            request_bytes: 0,
+        };
+        let handle = wasmtime_wasi::runtime::spawn(async move {
+            let response = default_send_request_handler(request, config).await.map(|mut response| {
+                response.resp = response.resp.map(|body| {
+                    LimitedBody {
+                        inner: body,
+                        budget,
+                    }
+                    .boxed_unsync()
+                });
+                response
+            });
+            Ok(response)
+        });
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
+}
+
+/// Byte counts of one response checked against the policy.
+struct ResponseBudget {
+    policy: Arc<HttpPolicy>,
+    total_bytes: Arc<AtomicU64>,
+    request_bytes: u64,
+}
+
+impl ResponseBudget {
+    /// Record `len` more bytes read, failing once either budget would be
+    /// exceeded.
+    fn record(&mut self, len: u64) -> Result<(), HttpDenied> {
+        if let Some(limit) = self.policy.max_request_bytes
+            && self.request_bytes + len > limit
+        {
+            return Err(HttpDenied::RequestBudgetExceeded { limit });
+        }
+        let max_total = self.policy.max_total_bytes.unwrap_or(u64::MAX);
+        self.total_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                total.checked_add(len).filter(|&total| total <= max_total)
+            })
+            .map_err(|_| HttpDenied::TotalBudgetExceeded { limit: max_total })?;
+        self.request_bytes += len;
+        Ok(())
+    }
+}
+
+/// A response body that fails once it goes over its budget.
+struct LimitedBody {
+    inner: HyperIncomingBody,
+    budget: ResponseBudget,
+}
+
+impl Body for LimitedBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, ErrorCode>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+            && let Err(denied) = self.budget.record(data.len() as u64)
+        {
+            return Poll::Ready(Some(Err(denied.error_code())));
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> OutgoingRequestConfig {
+        OutgoingRequestConfig {
+            use_tls: true,
+            connect_timeout: Duration::from_secs(1),
+            first_byte_timeout: Duration::from_secs(1),
+            between_bytes_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn body(data: &'static [u8]) -> HyperIncomingBody {
+        http_body_util::Full::new(Bytes::from_static(data))
+            .map_err(|never| match never {})
+            .boxed_unsync()
+    }
+
+    #[test]
+    fn test_allowlist_matches_host_and_port() {
+        let mut policy = HttpPolicy::default();
+        policy.allow(["API.example.com", "internal.example.com:8080"]);
+
+        assert!(policy.check_host("api.example.com", 443).is_ok());
+        assert!(policy.check_host("api.example.com", 80).is_ok());
+        assert!(policy.check_host("internal.example.com", 8080).is_ok());
+        assert!(policy.check_host("internal.example.com", 443).is_err());
+        assert!(policy.check_host("evil.example.com", 443).is_err());
+        assert!(policy.check_host("sub.api.example.com", 443).is_err());
+    }
+
+    #[test]
+    fn test_requests_to_other_hosts_are_refused() {
+        let mut policy = HttpPolicy::default();
+        policy.allow(["api.example.com"]);
+        let state = HttpState::new(&policy);
+
+        let request = http::Request::builder()
+            .uri("https://evil.example.com/exfiltrate")
+            .body(body(b""))
+            .unwrap();
+        let Err(err) = state.send_request(request, config()) else {
+            panic!("request to a host off the allowlist should be refused");
+        };
+        assert!(matches!(err.downcast(), Ok(ErrorCode::HttpRequestDenied)));
+    }
+
+    #[tokio::test]
+    async fn test_response_bodies_are_cut_at_the_budgets() {
+        let policy = Arc::new(HttpPolicy {
+            max_request_bytes: Some(10),
+            max_total_bytes: Some(15),
+            ..Default::default()
+        });
+        let total_bytes = Arc::new(AtomicU64::new(0));
+        let limited = |data| LimitedBody {
+            inner: body(data),
+            budget: ResponseBudget {
+                policy: policy.clone(),
+                total_bytes: total_bytes.clone(),
+                request_bytes: 0,
+            },
+        };
+
+        assert!(limited(b"0123456789").collect().await.is_ok());
+        assert!(matches!(
+            limited(b"0123456789a").collect().await,
+            Err(ErrorCode::HttpResponseBodySize(Some(10)))
+        ));
+        assert!(limited(b"01234").collect().await.is_ok());
+        assert!(matches!(
+            limited(b"0").collect().await,
+            Err(ErrorCode::HttpResponseBodySize(Some(15)))
+        ));
+    }
+}
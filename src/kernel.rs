@@ -0,0 +1,486 @@
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+
+use crate::sandbox::{CancelHandle, ExecOptions, PySandbox};
+use crate::session::Session;
+use crate::trace;
+
+mod wire;
+mod zmtp;
+
+use wire::{Message, Signer};
+use zmtp::{Connection, SocketType};
+
+/// Version of the Jupyter messaging protocol the kernel speaks.
+pub const PROTOCOL_VERSION: &str = "5.3";
+
+/// The parts of a Jupyter connection file the kernel needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub ip: String,
+    pub key: String,
+    pub shell_port: u16,
+    pub iopub_port: u16,
+    pub stdin_port: u16,
+    pub control_port: u16,
+    pub hb_port: u16,
+}
+
+impl ConnectionInfo {
+    pub fn parse(json: &str) -> Result<Self> {
+        let info: Value = serde_json::from_str(json).context("Invalid connection file")?;
+        let text = |key: &str| {
+            info[key]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("Connection file has no {}", key))
+        };
+        let port = |key: &str| {
+            info[key]
+                .as_u64()
+                .and_then(|port| u16::try_from(port).ok())
+                .with_context(|| format!("Connection file has no valid {}", key))
+        };
+
+        let transport = text("transport")?;
+        if transport != "tcp" {
+            return Err(anyhow!("Unsupported transport {}, only tcp is", transport));
+        }
+        let key = text("key")?;
+        let scheme = text("signature_scheme").unwrap_or_else(|_| "hmac-sha256".to_string());
+        if !key.is_empty() && scheme != "hmac-sha256" {
+            return Err(anyhow!("Unsupported signature scheme {}", scheme));
+        }
+
+        Ok(Self {
+            ip: text("ip")?,
+            key,
+            shell_port: port("shell_port")?,
+            iopub_port: port("iopub_port")?,
+            stdin_port: port("stdin_port")?,
+            control_port: port("control_port")?,
+            hb_port: port("hb_port")?,
+        })
+    }
+
+    fn bind(&self, port: u16) -> Result<TcpListener> {
+        TcpListener::bind((self.ip.as_str(), port))
+            .with_context(|| format!("Failed to bind {}:{}", self.ip, port))
+    }
+}
+
+/// Run a Jupyter kernel backed by `sandbox` until a frontend asks it to
+/// shut down. Every cell runs in the sandbox and cells share state like
+/// they would in a regular Python kernel.
+///
+/// Register it with Jupyter through a `kernel.json` such as:
+///
+/// ```json
+/// {"argv": ["pybox", "kernel", "{connection_file}"],
+///  "display_name": "Python (pybox)", "language": "python",
+///  "interrupt_mode": "message"}
+/// ```
+///
+/// Interrupting a cell cancels it, which loses the interpreter state like
+/// any other aborted `Session` cell.
+///
+/// Input requests are not supported. The kernel never sends
+/// `input_request`, so `input()` in a cell goes to the sandbox's
+/// `on_input` callback and raises `EOFError` without one.
+pub fn run(connection_file: impl AsRef<Path>, sandbox: PySandbox) -> Result<()> {
+    let path = connection_file.as_ref();
+    let json =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let info = ConnectionInfo::parse(&json)?;
+
+    let signer = Signer::new(&info.key);
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let interrupter = Interrupter {
+        running: Arc::default(),
+        signer: signer.clone(),
+        session_id: session_id.clone(),
+    };
+
+    let (requests, incoming) = mpsc::channel();
+    serve(info.bind(info.hb_port)?, SocketType::Rep, echo);
+    serve(info.bind(info.stdin_port)?, SocketType::Router, discard);
+    let iopub = Publisher::start(info.bind(info.iopub_port)?);
+    {
+        let requests = requests.clone();
+        serve(info.bind(info.shell_port)?, SocketType::Router, move |connection| {
+            forward(connection, &requests, None)
+        });
+    }
+    {
+        let interrupter = interrupter.clone();
+        serve(info.bind(info.control_port)?, SocketType::Router, move |connection| {
+            forward(connection, &requests, Some(&interrupter))
+        });
+    }
+
+    let mut kernel = Kernel {
+        session: Session::new(sandbox),
+        signer,
+        session_id,
+        running: interrupter.running,
+        iopub,
+        execution_count: 0,
+        language_version: String::new(),
+    };
+    kernel.language_version = kernel.python_version();
+    kernel.serve(incoming)
+}
+
+/// Accept connections on `listener` forever, handling each on its own
+/// thread after the ZMTP handshake.
+fn serve<F>(listener: TcpListener, socket_type: SocketType, handle: F)
+where
+    F: Fn(Connection) -> Result<()> + Send + Clone + 'static,
+{
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let handle = handle.clone();
+            thread::spawn(move || {
+                let result = Connection::handshake(stream, socket_type).and_then(handle);
+                if let Err(e) = result {
+                    trace::warn("kernel connection closed", &e);
+                }
+            });
+        }
+    });
+}
+
+/// Heartbeat, reply to every message with itself.
+fn echo(mut connection: Connection) -> Result<()> {
+    loop {
+        let message = connection.recv_multipart()?;
+        connection.send_multipart(&message)?;
+    }
+}
+
+/// Stdin, input requests are not supported so nothing arrives here.
+fn discard(mut connection: Connection) -> Result<()> {
+    loop {
+        connection.recv_multipart()?;
+    }
+}
+
+/// A request from a shell or control connection, with the connection to
+/// reply on.
+struct Request {
+    frames: Vec<Vec<u8>>,
+    reply_to: Arc<Mutex<Connection>>,
+}
+
+/// Pass requests from `connection` on to the kernel thread. Control
+/// connections have an `interrupter` and answer interrupts themselves,
+/// since the kernel thread is busy running the cell they are meant for.
+fn forward(
+    mut connection: Connection,
+    requests: &Sender<Request>,
+    interrupter: Option<&Interrupter>,
+) -> Result<()> {
+    let reply_to = Arc::new(Mutex::new(connection.try_clone()?));
+    loop {
+        let frames = connection.recv_multipart()?;
+        if let Some(interrupter) = interrupter
+            && let Some(reply) = interrupter.handle(&frames)
+        {
+            let mut connection = reply_to.lock().unwrap_or_else(|e| e.into_inner());
+            connection.send_multipart(&reply)?;
+            continue;
+        }
+        let request = Request {
+            frames,
+            reply_to: reply_to.clone(),
+        };
+        if requests.send(request).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Cancels the cell the kernel is running on `interrupt_request`.
+#[derive(Clone)]
+struct Interrupter {
+    running: Arc<Mutex<Option<CancelHandle>>>,
+    signer: Signer,
+    session_id: String,
+}
+
+impl Interrupter {
+    /// The reply frames if `frames` are an interrupt request, which is
+    /// then carried out.
+    fn handle(&self, frames: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+        let message = Message::decode(frames.to_vec(), &self.signer).ok()?;
+        if message.msg_type() != "interrupt_request" {
+            return None;
+        }
+        if let Some(handle) = &*self.running.lock().unwrap_or_else(|e| e.into_inner()) {
+            handle.cancel();
+        }
+        let content = json!({"status": "ok"});
+        let reply = Message::new(&self.session_id, "interrupt_reply", Some(&message), content);
+        Some(reply.encode(&self.signer))
+    }
+}
+
+/// Broadcasts iopub messages to every connected subscriber.
+#[derive(Clone)]
+struct Publisher {
+    subscribers: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl Publisher {
+    fn start(listener: TcpListener) -> Self {
+        let publisher = Self {
+            subscribers: Arc::default(),
+        };
+        let subscribers = publisher.subscribers.clone();
+        serve(listener, SocketType::Pub, move |connection| {
+            let reader = connection.try_clone()?;
+            subscribers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(connection);
+            // Subscriptions are ignored, every subscriber gets everything
+            discard(reader)
+        });
+        publisher
+    }
+
+    fn publish(&self, frames: &[Vec<u8>]) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.retain_mut(|subscriber| subscriber.send_multipart(frames).is_ok());
+    }
+}
+
+struct Kernel {
+    session: Session,
+    signer: Signer,
+    session_id: String,
+    // Cancels the cell that is running, if any, on interrupt
+    running: Arc<Mutex<Option<CancelHandle>>>,
+    iopub: Publisher,
+    execution_count: u64,
+    language_version: String,
+}
+
+impl Kernel {
+    fn serve(&mut self, incoming: Receiver<Request>) -> Result<()> {
+        for request in incoming {
+            let message = match Message::decode(request.frames, &self.signer) {
+                Ok(message) => message,
+                Err(e) => {
+                    trace::warn("dropping kernel message", &e);
+                    continue;
+                }
+            };
+
+            self.publish_status(&message, "busy");
+            let reply = self.handle(&message);
+            if let Some(content) = reply {
+                let reply_type = message.msg_type().replace("_request", "_reply");
+                let reply = Message::new(&self.session_id, &reply_type, Some(&message), content);
+                let frames = reply.encode(&self.signer);
+                let mut connection = request.reply_to.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = connection.send_multipart(&frames) {
+                    trace::warn("failed to send kernel reply", &e);
+                }
+            }
+            self.publish_status(&message, "idle");
+
+            if message.msg_type() == "shutdown_request" {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a request and return the content of the reply, if any.
+    fn handle(&mut self, request: &Message) -> Option<Value> {
+        let content = &request.content;
+        let reply = match request.msg_type() {
+            "kernel_info_request" => self.kernel_info(),
+            "execute_request" => self.execute(request),
+            "shutdown_request" => json!({"status": "ok", "restart": content["restart"]}),
+            "is_complete_request" => json!({"status": "unknown"}),
+            "comm_info_request" => json!({"status": "ok", "comms": {}}),
+            "history_request" => json!({"status": "ok", "history": []}),
+            "complete_request" => json!({
+                "status": "ok",
+                "matches": [],
+                "cursor_start": content["cursor_pos"],
+                "cursor_end": content["cursor_pos"],
+                "metadata": {},
+            }),
+            "inspect_request" => {
+                json!({"status": "ok", "found": false, "data": {}, "metadata": {}})
+            }
+            // Control connections answer interrupts, one sent on the shell
+            // channel only arrives once nothing is running
+            "interrupt_request" => json!({"status": "ok"}),
+            _ => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(msg_type = request.msg_type(), "ignoring kernel message");
+                return None;
+            }
+        };
+        Some(reply)
+    }
+
+    fn kernel_info(&self) -> Value {
+        json!({
+            "status": "ok",
+            "protocol_version": PROTOCOL_VERSION,
+            "implementation": "pybox",
+            "implementation_version": env!("CARGO_PKG_VERSION"),
+            "language_info": {
+                "name": "python",
+                "version": self.language_version,
+                "mimetype": "text/x-python",
+                "file_extension": ".py",
+            },
+            "banner": "pybox: Python in a WebAssembly sandbox",
+            "help_links": [],
+        })
+    }
+
+    fn execute(&mut self, request: &Message) -> Value {
+        let code = request.content["code"].as_str().unwrap_or_default();
+        let silent = request.content["silent"].as_bool().unwrap_or(false);
+        if !silent {
+            self.execution_count += 1;
+            self.publish(
+                request,
+                "execute_input",
+                json!({"code": code, "execution_count": self.execution_count}),
+            );
+        }
+
+        let handle = self.session.sandbox().cancel_handle();
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle.clone());
+        let options = ExecOptions {
+            cancel: Some(handle.clone()),
+            ..Default::default()
+        };
+        let mut result = self.session.exec_cell_with_options(code, &options);
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        if handle.is_cancelled() {
+            result.error = Some("KeyboardInterrupt: the interpreter was reset".to_string());
+        }
+        if !silent {
+            for (name, text) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+                if !text.is_empty() {
+                    self.publish(request, "stream", json!({"name": name, "text": text}));
+                }
+            }
+        }
+
+        if let Some(error) = result.error {
+            let (ename, evalue) = error.split_once(": ").unwrap_or((error.as_str(), ""));
+            let error = json!({
+                "ename": ename,
+                "evalue": evalue,
+                "traceback": [error],
+            });
+            if !silent {
+                self.publish(request, "error", error.clone());
+            }
+            let mut reply = json!({"status": "error", "execution_count": self.execution_count});
+            reply.as_object_mut().unwrap().extend(error.as_object().unwrap().clone());
+            return reply;
+        }
+
+        if let Some(value) = result.value.filter(|value| value != "null")
+            && !silent
+        {
+            self.publish(
+                request,
+                "execute_result",
+                json!({
+                    "execution_count": self.execution_count,
+                    "data": {"text/plain": value},
+                    "metadata": {},
+                }),
+            );
+        }
+        json!({
+            "status": "ok",
+            "execution_count": self.execution_count,
+            "user_expressions": {},
+            "payload": [],
+        })
+    }
+
+    /// Python version inside the sandbox, for `kernel_info_reply`.
+    fn python_version(&mut self) -> String {
+        let result = self.session.exec_cell("__import__('sys').version.split()[0]");
+        result
+            .value
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .unwrap_or_else(|| "3".to_string())
+    }
+
+    fn publish(&self, parent: &Message, msg_type: &str, content: Value) {
+        let mut message = Message::new(&self.session_id, msg_type, Some(parent), content);
+        message.identities = vec![format!("kernel.{}.{}", self.session_id, msg_type).into_bytes()];
+        self.iopub.publish(&message.encode(&self.signer));
+    }
+
+    fn publish_status(&self, parent: &Message, state: &str) {
+        self.publish(parent, "status", json!({"execution_state": state}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connection_file() {
+        let json = r#"{
+            "shell_port": 50001, "iopub_port": 50002, "stdin_port": 50003,
+            "control_port": 50004, "hb_port": 50005, "ip": "127.0.0.1",
+            "key": "a0436f6c", "transport": "tcp",
+            "signature_scheme": "hmac-sha256", "kernel_name": "pybox"
+        }"#;
+        let info = ConnectionInfo::parse(json).unwrap();
+        assert_eq!(info.ip, "127.0.0.1");
+        assert_eq!(info.key, "a0436f6c");
+        assert_eq!(info.shell_port, 50001);
+        assert_eq!(info.hb_port, 50005);
+    }
+
+    #[test]
+    fn test_control_connections_answer_interrupts() {
+        let signer = Signer::new("secret");
+        let interrupter = Interrupter {
+            running: Arc::default(),
+            signer: signer.clone(),
+            session_id: "kernel".to_string(),
+        };
+        let request = |msg_type| Message::new("client", msg_type, None, json!({})).encode(&signer);
+
+        let reply = interrupter.handle(&request("interrupt_request")).unwrap();
+        let reply = Message::decode(reply, &signer).unwrap();
+        assert_eq!(reply.msg_type(), "interrupt_reply");
+        assert_eq!(reply.content["status"], "ok");
+        assert!(interrupter.handle(&request("shutdown_request")).is_none());
+    }
+
+    #[test]
+    fn test_parse_connection_file_rejects_ipc() {
+        let json = r#"{"transport": "ipc", "ip": "kernel", "key": ""}"#;
+        let err = ConnectionInfo::parse(json).unwrap_err();
+        assert!(err.to_string().contains("Unsupported transport"));
+    }
+}
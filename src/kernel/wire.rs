@@ -0,0 +1,182 @@
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use super::PROTOCOL_VERSION;
+use crate::audit::iso_timestamp;
+
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Signs and checks messages with the key from the connection file. An
+/// empty key disables signing, as the protocol specifies.
+#[derive(Debug, Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> String {
+        if self.key.is_empty() {
+            return String::new();
+        }
+        to_hex(&self.mac(parts).finalize().into_bytes())
+    }
+
+    fn verify(&self, signature: &[u8], parts: &[&[u8]]) -> bool {
+        if self.key.is_empty() {
+            return signature.is_empty();
+        }
+        // `verify_slice` compares in constant time so timing doesn't leak
+        // the expected signature
+        from_hex(signature).is_some_and(|signature| self.mac(parts).verify_slice(&signature).is_ok())
+    }
+}
+
+/// A Jupyter protocol message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Frames before the delimiter, the topic on iopub.
+    pub identities: Vec<Vec<u8>>,
+    pub header: Value,
+    pub parent_header: Value,
+    pub metadata: Value,
+    pub content: Value,
+}
+
+impl Message {
+    /// Start a message of `msg_type` in reply to `parent`, or with no
+    /// parent when it is `None`.
+    pub fn new(session: &str, msg_type: &str, parent: Option<&Message>, content: Value) -> Self {
+        Self {
+            identities: parent.map(|p| p.identities.clone()).unwrap_or_default(),
+            header: json!({
+                "msg_id": uuid::Uuid::new_v4().to_string(),
+                "session": session,
+                "username": "pybox",
+                "date": iso_timestamp(SystemTime::now()),
+                "msg_type": msg_type,
+                "version": PROTOCOL_VERSION,
+            }),
+            parent_header: parent.map(|p| p.header.clone()).unwrap_or_else(|| json!({})),
+            metadata: json!({}),
+            content,
+        }
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or_default()
+    }
+
+    /// Decode the frames of a message, checking its signature.
+    pub fn decode(frames: Vec<Vec<u8>>, signer: &Signer) -> Result<Self> {
+        let delimiter = frames
+            .iter()
+            .position(|frame| frame == DELIMITER)
+            .context("Message has no delimiter")?;
+        let mut frames = frames.into_iter();
+        let identities = frames.by_ref().take(delimiter).collect();
+        frames.next();
+
+        let signature = frames.next().context("Message has no signature")?;
+        let parts: Vec<Vec<u8>> = frames.by_ref().take(4).collect();
+        if parts.len() != 4 {
+            return Err(anyhow!("Message is missing parts"));
+        }
+        let part_refs: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+        if !signer.verify(&signature, &part_refs) {
+            return Err(anyhow!("Message signature does not match"));
+        }
+
+        let parse = |part: &[u8]| serde_json::from_slice::<Value>(part);
+        Ok(Self {
+            identities,
+            header: parse(&parts[0]).context("Invalid header")?,
+            parent_header: parse(&parts[1]).context("Invalid parent header")?,
+            metadata: parse(&parts[2]).context("Invalid metadata")?,
+            content: parse(&parts[3]).context("Invalid content")?,
+        })
+    }
+
+    /// Encode the message into signed frames.
+    pub fn encode(&self, signer: &Signer) -> Vec<Vec<u8>> {
+        let parts = [
+            self.header.to_string().into_bytes(),
+            self.parent_header.to_string().into_bytes(),
+            self.metadata.to_string().into_bytes(),
+            self.content.to_string().into_bytes(),
+        ];
+        let part_refs: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+        let signature = signer.sign(&part_refs);
+
+        let mut frames = self.identities.clone();
+        frames.push(DELIMITER.to_vec());
+        frames.push(signature.into_bytes());
+        frames.extend(parts);
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_matches_rfc_4231() {
+        let signer = Signer::new("Jefe");
+        let parts: [&[u8]; 2] = [b"what do ya want ", b"for nothing?"];
+        let signature = signer.sign(&parts);
+        assert_eq!(
+            signature,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(signer.verify(signature.as_bytes(), &parts));
+        assert!(!signer.verify(b"5bdcc146", &parts));
+        assert!(!signer.verify(b"not hex", &parts));
+    }
+
+    #[test]
+    fn test_message_roundtrip_and_signature_check() {
+        let signer = Signer::new("secret");
+        let mut message = Message::new("session", "execute_request", None, json!({"code": "1"}));
+        message.identities = vec![b"client".to_vec()];
+
+        let decoded = Message::decode(message.encode(&signer), &signer).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.msg_type(), "execute_request");
+
+        let err = Message::decode(message.encode(&signer), &Signer::new("other")).unwrap_err();
+        assert!(err.to_string().contains("signature"));
+    }
+}
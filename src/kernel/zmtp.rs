@@ -0,0 +1,203 @@
+//! The subset of ZMTP 3.0 (https://rfc.zeromq.org/spec/23/) Jupyter
+//! frontends need: the NULL security mechanism over TCP, multipart
+//! messages, and one peer per connection.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use anyhow::{anyhow, Context, Result};
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+// Frames larger than this are rejected rather than allocated
+const MAX_FRAME_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The socket types pybox plays in the Jupyter protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    Router,
+    Pub,
+    Rep,
+}
+
+impl SocketType {
+    fn name(self) -> &'static str {
+        match self {
+            SocketType::Router => "ROUTER",
+            SocketType::Pub => "PUB",
+            SocketType::Rep => "REP",
+        }
+    }
+}
+
+/// A connection to one peer after the ZMTP handshake.
+pub struct Connection<S = TcpStream> {
+    stream: S,
+}
+
+impl Connection<TcpStream> {
+    /// Clone the connection so one thread can read while another writes.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+        })
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Exchange greetings and READY commands with the peer.
+    pub fn handshake(mut stream: S, socket_type: SocketType) -> Result<Self> {
+        let mut greeting = [0u8; 64];
+        greeting[0] = 0xFF;
+        greeting[9] = 0x7F;
+        greeting[10] = 3;
+        greeting[12..16].copy_from_slice(b"NULL");
+        stream.write_all(&greeting)?;
+
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer).context("Failed to read ZMTP greeting")?;
+        if peer[0] != 0xFF || peer[9] != 0x7F {
+            return Err(anyhow!("Peer is not speaking ZMTP"));
+        }
+        if peer[10] < 3 {
+            return Err(anyhow!("Peer uses unsupported ZMTP version {}", peer[10]));
+        }
+        if &peer[12..16] != b"NULL" || peer[16..32].iter().any(|b| *b != 0) {
+            return Err(anyhow!("Peer requires an unsupported security mechanism"));
+        }
+
+        let mut connection = Self { stream };
+        let mut ready = Vec::new();
+        push_property(&mut ready, "Socket-Type", socket_type.name().as_bytes());
+        connection.send_command("READY", &ready)?;
+
+        let (name, _) = connection
+            .recv_command()
+            .context("Failed to complete ZMTP handshake")?;
+        if name != "READY" {
+            return Err(anyhow!("Expected READY from peer, got {}", name));
+        }
+        Ok(connection)
+    }
+
+    /// Send a multipart message.
+    pub fn send_multipart<T: AsRef<[u8]>>(&mut self, parts: &[T]) -> Result<()> {
+        let mut buffer = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let more = if i + 1 < parts.len() { FLAG_MORE } else { 0 };
+            push_frame(&mut buffer, more, part.as_ref());
+        }
+        self.stream.write_all(&buffer)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Receive the next multipart message, skipping any commands the
+    /// peer sends in between, such as PUB subscriptions.
+    pub fn recv_multipart(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut parts = Vec::new();
+        loop {
+            let (flags, body) = self.recv_frame()?;
+            if flags & FLAG_COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn send_command(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut body = vec![name.len() as u8];
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(data);
+        let mut buffer = Vec::new();
+        push_frame(&mut buffer, FLAG_COMMAND, &body);
+        self.stream.write_all(&buffer)?;
+        Ok(())
+    }
+
+    fn recv_command(&mut self) -> Result<(String, Vec<u8>)> {
+        let (flags, body) = self.recv_frame()?;
+        if flags & FLAG_COMMAND == 0 {
+            return Err(anyhow!("Expected a ZMTP command"));
+        }
+        let name_len = *body.first().context("Empty ZMTP command")? as usize;
+        let name = body
+            .get(1..1 + name_len)
+            .context("Truncated ZMTP command name")?;
+        Ok((
+            String::from_utf8_lossy(name).into_owned(),
+            body[1 + name_len..].to_vec(),
+        ))
+    }
+
+    fn recv_frame(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut flags = [0u8; 1];
+        self.stream.read_exact(&mut flags)?;
+        let flags = flags[0];
+        let size = if flags & FLAG_LONG != 0 {
+            let mut size = [0u8; 8];
+            self.stream.read_exact(&mut size)?;
+            u64::from_be_bytes(size)
+        } else {
+            let mut size = [0u8; 1];
+            self.stream.read_exact(&mut size)?;
+            u64::from(size[0])
+        };
+        if size > MAX_FRAME_BYTES {
+            return Err(anyhow!("ZMTP frame of {} bytes is too large", size));
+        }
+        let mut body = vec![0u8; size as usize];
+        self.stream.read_exact(&mut body)?;
+        Ok((flags, body))
+    }
+}
+
+fn push_frame(buffer: &mut Vec<u8>, flags: u8, body: &[u8]) {
+    if body.len() > 255 {
+        buffer.push(flags | FLAG_LONG);
+        buffer.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        buffer.push(flags);
+        buffer.push(body.len() as u8);
+    }
+    buffer.extend_from_slice(body);
+}
+
+fn push_property(buffer: &mut Vec<u8>, name: &str, value: &[u8]) {
+    buffer.push(name.len() as u8);
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_handshake_and_multipart_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Connection::handshake(stream, SocketType::Rep).unwrap();
+            let message = connection.recv_multipart().unwrap();
+            connection.send_multipart(&message).unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = Connection::handshake(stream, SocketType::Router).unwrap();
+        let long = vec![7u8; 1000];
+        client.send_multipart(&[&b""[..], b"ping", &long]).unwrap();
+        let echoed = client.recv_multipart().unwrap();
+        assert_eq!(echoed, vec![b"".to_vec(), b"ping".to_vec(), long]);
+        server.join().unwrap();
+    }
+}
@@ -1,2 +1,36 @@
 // Re-export the sandbox module for library use
-pub mod sandbox;
\ No newline at end of file
+pub mod audit;
+mod counts;
+#[cfg(unix)]
+pub mod daemon;
+pub mod deterministic;
+pub mod error;
+pub mod extension;
+mod filesystem;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod host;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "kernel")]
+pub mod kernel;
+pub mod metrics;
+pub mod output;
+pub mod policy;
+pub mod pool;
+pub mod quota;
+#[cfg(feature = "cli")]
+pub mod repl;
+pub mod report;
+pub mod rpc;
+pub mod sandbox;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+mod timer;
+pub mod toolcall;
+mod trace;
+#[cfg(feature = "validation")]
+pub mod validate;
+pub mod variant;
+pub mod watch;
@@ -1,35 +1,577 @@
-mod sandbox;
-
 use anyhow::Result;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use pybox::error::{PyboxError, PythonError};
+use pybox::report::exec_report;
+use pybox::sandbox::{ExecOptions, ExecOutput, Mount, MountMode, PySandbox};
+use pybox::watch::ComponentWatcher;
 use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+// Exit codes of `run`, invalid arguments exit with clap's usage code 2
+// The code raised an exception
+const EXIT_GUEST_ERROR: u8 = 1;
+// The code ran past its timeout
+const EXIT_TIMEOUT: u8 = 3;
+// The sandbox failed, e.g. the component or script could not be loaded
+const EXIT_HOST_ERROR: u8 = 4;
+
+// How often `--watch-wasm` checks the component for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Run Python code in a WebAssembly sandbox.
+#[derive(Debug, Parser)]
+#[command(name = "pybox")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    sandbox: SandboxArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Execute statements and print the value of the last expression.
+    Exec(CodeArgs),
+    /// Evaluate a single expression and print its value.
+    Eval(CodeArgs),
+    /// Run a script, code from stdin or inline code.
+    Run(RunArgs),
+    /// Interactive prompt backed by a persistent session.
+    Repl,
+    /// Serve JSON-RPC on stdin and stdout, one message per line.
+    Rpc,
+    /// Compile the component ahead of time for `PySandbox::from_precompiled`.
+    Precompile {
+        /// Where to write the compiled component, e.g. sandbox.cwasm.
+        output: PathBuf,
+    },
+    /// Serve a Jupyter kernel for the given connection file.
+    #[cfg(feature = "kernel")]
+    Kernel { connection_file: PathBuf },
+    /// Serve executions from warm sandboxes on a Unix socket.
+    #[cfg(unix)]
+    Daemon {
+        /// Path of the socket to listen on.
+        #[arg(long, default_value = "/run/pybox.sock")]
+        socket: PathBuf,
+        /// Warm sandboxes, which is how many executions run at once.
+        #[arg(long, default_value_t = 4)]
+        workers: usize,
+        /// Longest timeout a request may ask for, in seconds.
+        #[arg(long, default_value_t = 60, value_name = "SECONDS")]
+        max_timeout: u64,
+    },
+    /// Serve code execution over HTTP.
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+    /// Serve code execution over gRPC, see proto/pybox.proto.
+    #[cfg(feature = "grpc")]
+    Grpc(GrpcArgs),
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8000")]
+    addr: std::net::SocketAddr,
+    /// Warm sandboxes for one-off executions.
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+    /// Sessions kept at once.
+    #[arg(long, default_value_t = 64)]
+    max_sessions: usize,
+    /// Drop sessions unused for this long, in seconds, 0 keeps them.
+    #[arg(long, default_value_t = 1800, value_name = "SECONDS")]
+    session_ttl: u64,
+    /// Longest timeout a request may ask for, in seconds.
+    #[arg(long, default_value_t = 60, value_name = "SECONDS")]
+    max_timeout: u64,
+    /// Largest request body, in bytes or with a K, M or G suffix.
+    #[arg(long, default_value = "1M", value_name = "BYTES", value_parser = parse_bytes)]
+    max_body: usize,
+}
+
+#[cfg(feature = "server")]
+impl ServeArgs {
+    fn config(self) -> pybox::server::ServerConfig {
+        pybox::server::ServerConfig {
+            addr: self.addr,
+            workers: self.workers,
+            max_sessions: self.max_sessions,
+            session_idle_ttl: (self.session_ttl > 0)
+                .then(|| std::time::Duration::from_secs(self.session_ttl)),
+            max_timeout: std::time::Duration::from_secs(self.max_timeout),
+            max_body_bytes: self.max_body,
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Debug, Args)]
+struct GrpcArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:50051")]
+    addr: std::net::SocketAddr,
+    /// Warm sandboxes for executions outside of sessions.
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+    /// Sessions kept at once.
+    #[arg(long, default_value_t = 64)]
+    max_sessions: usize,
+    /// Drop sessions unused for this long, in seconds, 0 keeps them.
+    #[arg(long, default_value_t = 1800, value_name = "SECONDS")]
+    session_ttl: u64,
+    /// Longest timeout a request may ask for, in seconds.
+    #[arg(long, default_value_t = 60, value_name = "SECONDS")]
+    max_timeout: u64,
+}
+
+#[cfg(feature = "grpc")]
+impl GrpcArgs {
+    fn config(self) -> pybox::grpc::GrpcConfig {
+        pybox::grpc::GrpcConfig {
+            addr: self.addr,
+            workers: self.workers,
+            max_sessions: self.max_sessions,
+            session_idle_ttl: (self.session_ttl > 0)
+                .then(|| std::time::Duration::from_secs(self.session_ttl)),
+            max_timeout: std::time::Duration::from_secs(self.max_timeout),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct CodeArgs {
+    /// Print a single JSON object with the result, output and error.
+    #[arg(long)]
+    json: bool,
+    /// The code, `-` reads it from stdin.
+    code: String,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Print a single JSON object with the result, output and error.
+    #[arg(long)]
+    json: bool,
+    /// Run this code.
+    #[arg(short = 'c', value_name = "CODE", conflicts_with = "script")]
+    code: Option<String>,
+    /// Script to run, `-` reads the code from stdin.
+    script: Option<String>,
+}
+
+/// Where the code to run comes from.
+#[derive(Debug, PartialEq)]
+enum Source {
+    File(PathBuf),
+    Stdin,
+    Inline(String),
+}
+
+impl CodeArgs {
+    fn source(self) -> Source {
+        match self.code.as_str() {
+            "-" => Source::Stdin,
+            _ => Source::Inline(self.code),
+        }
+    }
+}
+
+impl RunArgs {
+    fn source(self) -> Option<Source> {
+        match (self.code, self.script) {
+            (Some(code), _) => Some(Source::Inline(code)),
+            (None, Some(script)) if script == "-" => Some(Source::Stdin),
+            (None, Some(script)) => Some(Source::File(script.into())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// How the code of a command is run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Exec,
+    Eval,
+}
+
+/// Limits and resources of the sandbox, accepted by every command.
+#[derive(Debug, Args)]
+struct SandboxArgs {
+    /// Wall-clock limit of an execution in seconds.
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Limit of each linear memory, in bytes or with a K, M or G suffix.
+    #[arg(long, global = true, value_name = "BYTES", value_parser = parse_bytes)]
+    memory_limit: Option<usize>,
+    /// Make a host directory visible to the code, read-write unless `:ro`
+    /// is added. Can be repeated.
+    #[arg(long, global = true, value_name = "HOST:GUEST[:ro]", value_parser = parse_mount)]
+    mount: Vec<Mount>,
+    /// Set an environment variable for the code. Can be repeated.
+    #[arg(long, global = true, value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+    /// Load this component instead of searching for sandbox.wasm.
+    #[arg(long, global = true, value_name = "PATH")]
+    wasm: Option<PathBuf>,
+    /// Reload the `--wasm` component whenever the file changes, for the
+    /// commands serving requests.
+    #[arg(long, global = true, requires = "wasm")]
+    watch_wasm: bool,
+}
+
+impl SandboxArgs {
+    fn build(self) -> Result<PySandbox> {
+        let mut builder = PySandbox::builder().envs(self.env);
+        if let Some(seconds) = self.timeout {
+            builder = builder.timeout_seconds(seconds);
+        }
+        if let Some(bytes) = self.memory_limit {
+            builder = builder.max_memory_bytes(bytes);
+        }
+        for mount in self.mount {
+            builder = builder.mount(mount.host_path, mount.guest_path, mount.mode);
+        }
+        if let Some(path) = self.wasm {
+            builder = builder.component_file(path);
+        }
+        builder.build()
+    }
+
+    /// Build the sandbox of a long running command, reloading its
+    /// component on change when asked to.
+    fn build_watched(self) -> Result<(PySandbox, Option<ComponentWatcher>)> {
+        let watch = self.watch_wasm.then(|| self.wasm.clone()).flatten();
+        let sandbox = self.build()?;
+        let watcher = match watch {
+            Some(path) => Some(ComponentWatcher::start(&sandbox, path, WATCH_INTERVAL, |path, result| {
+                match result {
+                    Ok(()) => eprintln!("pybox: reloaded {}", path.display()),
+                    Err(e) => eprintln!("pybox: failed to reload {}: {:#}", path.display(), e),
+                }
+            })?),
+            None => None,
+        };
+        Ok((sandbox, watcher))
+    }
+}
+
+/// Parse a byte count such as `4096`, `512K`, `64M` or `1G`.
+fn parse_bytes(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid byte count '{}'", value))
+}
+
+/// Parse `host:guest` or `host:guest:ro`.
+fn parse_mount(value: &str) -> Result<Mount, String> {
+    let (spec, mode) = match value.rsplit_once(':') {
+        Some((spec, "ro")) => (spec, MountMode::ReadOnly),
+        Some((spec, "rw")) => (spec, MountMode::ReadWrite),
+        _ => (value, MountMode::ReadWrite),
+    };
+    match spec.split_once(':') {
+        Some((host, guest)) if !host.is_empty() && guest.starts_with('/') => Ok(Mount {
+            host_path: PathBuf::from(host),
+            guest_path: guest.to_string(),
+            mode,
+        }),
+        _ => Err(format!("expected HOST:GUEST[:ro] with an absolute GUEST path, got '{}'", value)),
+    }
+}
 
-fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().skip(1).collect();
+/// Parse `KEY=VALUE`.
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", value)),
+    }
+}
 
-    if args.is_empty() {
-        eprintln!("Usage: pybox2 [-] <code | stdin>");
-        std::process::exit(-1);
+/// Exit code for a failed run, see the `EXIT_*` constants.
+fn exit_code(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<PythonError>().is_some() {
+        EXIT_GUEST_ERROR
+    } else if error.downcast_ref::<PyboxError>() == Some(&PyboxError::Timeout) {
+        EXIT_TIMEOUT
+    } else {
+        EXIT_HOST_ERROR
     }
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+
+    let (mode, json, source) = match cli.command {
+        Command::Exec(args) => (Mode::Exec, args.json, args.source()),
+        Command::Eval(args) => (Mode::Eval, args.json, args.source()),
+        Command::Run(args) => {
+            let json = args.json;
+            let Some(source) = args.source() else {
+                Cli::command()
+                    .error(ErrorKind::MissingRequiredArgument, "pass a script, `-` or -c <CODE>")
+                    .exit();
+            };
+            (Mode::Exec, json, source)
+        }
+        Command::Repl => {
+            pybox::repl::run(cli.sandbox.build()?)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Rpc => {
+            pybox::rpc::run(cli.sandbox.build()?)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Precompile { output } => {
+            cli.sandbox.build()?.precompile_to(output)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(feature = "kernel")]
+        Command::Kernel { connection_file } => {
+            pybox::kernel::run(connection_file, cli.sandbox.build()?)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(unix)]
+        Command::Daemon {
+            socket,
+            workers,
+            max_timeout,
+        } => {
+            let (sandbox, _watcher) = cli.sandbox.build_watched()?;
+            let max_timeout = Duration::from_secs(max_timeout);
+            pybox::daemon::run(sandbox, socket, workers, max_timeout)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(feature = "server")]
+        Command::Serve(args) => {
+            let (sandbox, _watcher) = cli.sandbox.build_watched()?;
+            pybox::server::run(sandbox, args.config())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(feature = "grpc")]
+        Command::Grpc(args) => {
+            let (sandbox, _watcher) = cli.sandbox.build_watched()?;
+            pybox::grpc::run(sandbox, args.config())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+    };
+
+    let started = Instant::now();
+    let result = exec(cli.sandbox, mode, source, json);
+    let duration = started.elapsed();
 
-    // Read code from stdin if '-' is passed
-    let code = if args.len() == 1 && args[0] == "-" {
-        let mut input = String::new();
-        io::stdin().read_to_string(&mut input)?;
-        input.trim().to_string()
+    if json {
+        println!("{}", exec_report(&result, duration));
     } else {
-        args.last().unwrap().clone()
+        match &result {
+            Ok(output) => println!("{}", output.value),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+    Ok(match &result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => ExitCode::from(exit_code(e)),
+    })
+}
+
+/// Create a sandbox and run the code from `source`. Printed output is
+/// captured for `--json`, otherwise it goes straight to the terminal.
+fn exec(
+    sandbox: SandboxArgs,
+    mode: Mode,
+    source: Source,
+    capture_output: bool,
+) -> Result<ExecOutput> {
+    let options = ExecOptions {
+        capture_output,
+        ..Default::default()
+    };
+    let mut sandbox = sandbox.build()?;
+    let code = match source {
+        // Scripts run with their directory mounted so they can import
+        // sibling modules
+        Source::File(path) => return sandbox.exec_file_with_options(path, &options),
+        Source::Stdin => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input.trim().to_string()
+        }
+        Source::Inline(code) => code,
     };
+    match mode {
+        Mode::Exec => sandbox.exec_with_options(&code, &options),
+        Mode::Eval => sandbox.eval(&code).map(|value| ExecOutput {
+            value,
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
 
-    // Create sandbox and execute code
-    let mut sandbox = sandbox::PySandbox::new(None)?;
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("pybox").chain(args.iter().copied())).unwrap()
+    }
 
-    match sandbox.exec(&code) {
-        Ok(result) => println!("{}", result),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(-1);
+    fn source(args: &[&str]) -> Option<(Mode, Source)> {
+        match parse(args).command {
+            Command::Exec(args) => Some((Mode::Exec, args.source())),
+            Command::Eval(args) => Some((Mode::Eval, args.source())),
+            Command::Run(args) => args.source().map(|source| (Mode::Exec, source)),
+            _ => None,
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_cli_is_well_formed() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_run_sources() {
+        assert_eq!(
+            source(&["run", "script.py"]),
+            Some((Mode::Exec, Source::File(PathBuf::from("script.py"))))
+        );
+        assert_eq!(source(&["run", "-"]), Some((Mode::Exec, Source::Stdin)));
+        assert_eq!(
+            source(&["run", "-c", "1 + 1"]),
+            Some((Mode::Exec, Source::Inline("1 + 1".to_string())))
+        );
+        assert_eq!(source(&["run"]), None);
+    }
+
+    #[test]
+    fn test_parse_exec_and_eval() {
+        assert_eq!(
+            source(&["exec", "x = 1\nx"]),
+            Some((Mode::Exec, Source::Inline("x = 1\nx".to_string())))
+        );
+        assert_eq!(source(&["eval", "-"]), Some((Mode::Eval, Source::Stdin)));
+    }
+
+    #[test]
+    fn test_parse_other_commands() {
+        assert!(matches!(parse(&["repl"]).command, Command::Repl));
+        assert!(matches!(parse(&["rpc"]).command, Command::Rpc));
+        assert!(matches!(
+            parse(&["precompile", "sandbox.cwasm"]).command,
+            Command::Precompile { output } if output == Path::new("sandbox.cwasm")
+        ));
+        assert!(Cli::try_parse_from(["pybox"]).is_err());
+        assert!(Cli::try_parse_from(["pybox", "1 + 1"]).is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_parse_serve() {
+        let Command::Serve(args) = parse(&["serve", "--workers", "2", "--max-body", "64K"]).command
+        else {
+            panic!("expected serve");
+        };
+        let config = args.config();
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.max_body_bytes, 64 << 10);
+        assert_eq!(config.addr.port(), 8000);
+        assert_eq!(config.session_idle_ttl, Some(std::time::Duration::from_secs(1800)));
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_parse_grpc() {
+        let Command::Grpc(args) = parse(&["grpc", "--max-timeout", "5", "--session-ttl", "0"]).command
+        else {
+            panic!("expected grpc");
+        };
+        let config = args.config();
+        assert_eq!(config.max_timeout, std::time::Duration::from_secs(5));
+        assert_eq!(config.session_idle_ttl, None);
+        assert_eq!(config.addr.port(), 50051);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_daemon() {
+        assert!(matches!(
+            parse(&["daemon", "--socket", "/tmp/pybox.sock"]).command,
+            Command::Daemon { socket, workers: 4, max_timeout: 60 } if socket == Path::new("/tmp/pybox.sock")
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch_wasm_needs_wasm() {
+        assert!(Cli::try_parse_from(["pybox", "daemon", "--watch-wasm"]).is_err());
+        let cli = parse(&["daemon", "--wasm", "guest.wasm", "--watch-wasm"]);
+        assert!(cli.sandbox.watch_wasm);
+    }
+
+    #[test]
+    fn test_parse_rejects_conflicting_sources() {
+        let args = ["pybox", "run", "-c", "1", "script.py"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_sandbox_flags_before_and_after_command() {
+        let cli = parse(&["--timeout", "5", "run", "--json", "--memory-limit", "64M", "x.py"]);
+        assert_eq!(cli.sandbox.timeout, Some(5));
+        assert_eq!(cli.sandbox.memory_limit, Some(64 << 20));
+        let cli = parse(&["run", "--env", "A=1", "--env", "B=x=y", "--mount", "./data:/data:ro", "-"]);
+        assert_eq!(
+            cli.sandbox.env,
+            vec![("A".to_string(), "1".to_string()), ("B".to_string(), "x=y".to_string())]
+        );
+        assert_eq!(cli.sandbox.mount[0].host_path, PathBuf::from("./data"));
+        assert_eq!(cli.sandbox.mount[0].guest_path, "/data");
+        assert_eq!(cli.sandbox.mount[0].mode, MountMode::ReadOnly);
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("4096"), Ok(4096));
+        assert_eq!(parse_bytes("512K"), Ok(512 << 10));
+        assert_eq!(parse_bytes("1g"), Ok(1 << 30));
+        assert!(parse_bytes("lots").is_err());
+        assert!(parse_bytes("M").is_err());
+    }
+
+    #[test]
+    fn test_parse_mount() {
+        let mount = parse_mount("/tmp/out:/out").unwrap();
+        assert_eq!(mount.mode, MountMode::ReadWrite);
+        assert_eq!(mount.guest_path, "/out");
+        assert!(parse_mount("/tmp/out").is_err());
+        assert!(parse_mount("/tmp/out:relative").is_err());
+    }
+
+    #[test]
+    fn test_exit_codes_by_error_kind() {
+        let timeout = anyhow::Error::from(PyboxError::Timeout);
+        assert_eq!(exit_code(&timeout), EXIT_TIMEOUT);
+        let exception = anyhow::Error::from(PythonError {
+            exception: pybox::error::PyException::Value,
+            message: "boom".to_string(),
+            lineno: Some(1),
+        });
+        assert_eq!(exit_code(&exception), EXIT_GUEST_ERROR);
+        assert_eq!(exit_code(&anyhow::anyhow!("no sandbox.wasm")), EXIT_HOST_ERROR);
+    }
 }
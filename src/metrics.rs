@@ -0,0 +1,248 @@
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::error::PyboxError;
+
+/// Upper bounds of the exec duration histogram buckets, in seconds.
+pub const DURATION_BUCKETS: [f64; 12] =
+    [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// How an execution ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecOutcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    Cancelled,
+    FuelExhausted,
+    LimitExceeded,
+}
+
+impl ExecOutcome {
+    pub const ALL: [ExecOutcome; 6] = [
+        ExecOutcome::Succeeded,
+        ExecOutcome::Failed,
+        ExecOutcome::TimedOut,
+        ExecOutcome::Cancelled,
+        ExecOutcome::FuelExhausted,
+        ExecOutcome::LimitExceeded,
+    ];
+
+    /// Classify the result of an execution.
+    pub fn of<T>(result: &Result<T>) -> Self {
+        let Err(err) = result else {
+            return ExecOutcome::Succeeded;
+        };
+        match err.downcast_ref::<PyboxError>() {
+            Some(PyboxError::Timeout) => ExecOutcome::TimedOut,
+            Some(PyboxError::Cancelled) => ExecOutcome::Cancelled,
+            Some(PyboxError::FuelExhausted { .. }) => ExecOutcome::FuelExhausted,
+            Some(
+                PyboxError::LimitExceeded(_)
+                | PyboxError::ExecutionBudgetExceeded { .. }
+                | PyboxError::MemoryLimitExceeded { .. }
+                | PyboxError::CodeTooLarge { .. }
+                | PyboxError::QuotaExceeded { .. },
+            ) => ExecOutcome::LimitExceeded,
+            Some(PyboxError::Trap(_) | PyboxError::RandomDenied) | None => ExecOutcome::Failed,
+        }
+    }
+
+    /// Label used for this outcome in metrics and traces.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExecOutcome::Succeeded => "succeeded",
+            ExecOutcome::Failed => "failed",
+            ExecOutcome::TimedOut => "timed_out",
+            ExecOutcome::Cancelled => "cancelled",
+            ExecOutcome::FuelExhausted => "fuel_exhausted",
+            ExecOutcome::LimitExceeded => "limit_exceeded",
+        }
+    }
+}
+
+/// Receives operational metrics from sandboxes and pools. Register an
+/// implementation with `PySandboxBuilder::metrics`, clones of the sandbox
+/// and pools built from it report to the same one.
+///
+/// Methods are called on the executing thread, so they should be cheap.
+pub trait Metrics: Send + Sync {
+    /// An execution is about to start.
+    fn exec_started(&self) {}
+
+    /// An execution finished after `duration`.
+    fn exec_finished(&self, outcome: ExecOutcome, duration: Duration) {
+        let _ = (outcome, duration);
+    }
+
+    /// `busy` of a pool's `size` workers are executing code.
+    fn pool_utilization(&self, busy: usize, size: usize) {
+        let _ = (busy, size);
+    }
+}
+
+/// The `Metrics` a sandbox reports to, if any.
+#[derive(Clone, Default)]
+pub(crate) struct MetricsSink(Option<Arc<dyn Metrics>>);
+
+impl MetricsSink {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> Self {
+        Self(Some(metrics))
+    }
+
+    pub(crate) fn exec_started(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.exec_started();
+        }
+    }
+
+    pub(crate) fn exec_finished<T>(&self, result: &Result<T>, duration: Duration) {
+        if let Some(metrics) = &self.0 {
+            metrics.exec_finished(ExecOutcome::of(result), duration);
+        }
+    }
+
+    pub(crate) fn pool_utilization(&self, busy: usize, size: usize) {
+        if let Some(metrics) = &self.0 {
+            metrics.pool_utilization(busy, size);
+        }
+    }
+}
+
+impl fmt::Debug for MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
+}
+
+/// `Metrics` kept in memory and rendered in the Prometheus text format,
+/// for services that expose a `/metrics` endpoint.
+///
+/// ```
+/// use std::sync::Arc;
+/// use pybox::metrics::PrometheusMetrics;
+///
+/// let metrics = Arc::new(PrometheusMetrics::default());
+/// // PySandbox::builder().metrics(metrics.clone())
+/// assert!(metrics.render().contains("pybox_executions_started_total 0"));
+/// ```
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    started: AtomicU64,
+    finished: [AtomicU64; ExecOutcome::ALL.len()],
+    // Cumulative counts, the last slot is the +Inf bucket
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_micros: AtomicU64,
+    pool_busy: AtomicUsize,
+    pool_size: AtomicUsize,
+}
+
+impl PrometheusMetrics {
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        out.push_str("# TYPE pybox_executions_started_total counter\n");
+        let _ = writeln!(out, "pybox_executions_started_total {}", load(&self.started));
+
+        out.push_str("# TYPE pybox_executions_total counter\n");
+        for (outcome, count) in ExecOutcome::ALL.iter().zip(&self.finished) {
+            let _ = writeln!(
+                out,
+                "pybox_executions_total{{outcome=\"{}\"}} {}",
+                outcome.as_str(),
+                load(count)
+            );
+        }
+
+        out.push_str("# TYPE pybox_exec_duration_seconds histogram\n");
+        for (le, count) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "pybox_exec_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le,
+                load(count)
+            );
+        }
+        let total = load(&self.buckets[DURATION_BUCKETS.len()]);
+        let _ = writeln!(out, "pybox_exec_duration_seconds_bucket{{le=\"+Inf\"}} {}", total);
+        let sum = load(&self.duration_micros) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "pybox_exec_duration_seconds_sum {}", sum);
+        let _ = writeln!(out, "pybox_exec_duration_seconds_count {}", total);
+
+        out.push_str("# TYPE pybox_pool_workers_busy gauge\n");
+        let busy = self.pool_busy.load(Ordering::Relaxed);
+        let _ = writeln!(out, "pybox_pool_workers_busy {}", busy);
+        out.push_str("# TYPE pybox_pool_workers gauge\n");
+        let _ = writeln!(out, "pybox_pool_workers {}", self.pool_size.load(Ordering::Relaxed));
+        out
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn exec_started(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn exec_finished(&self, outcome: ExecOutcome, duration: Duration) {
+        let index = ExecOutcome::ALL.iter().position(|o| *o == outcome).unwrap_or(0);
+        self.finished[index].fetch_add(1, Ordering::Relaxed);
+
+        let seconds = duration.as_secs_f64();
+        for (le, count) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *le {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.buckets[DURATION_BUCKETS.len()].fetch_add(1, Ordering::Relaxed);
+        self.duration_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn pool_utilization(&self, busy: usize, size: usize) {
+        self.pool_busy.store(busy, Ordering::Relaxed);
+        self.pool_size.store(size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_outcome_of_results() {
+        assert_eq!(ExecOutcome::of(&Ok(())), ExecOutcome::Succeeded);
+        let timeout: Result<()> = Err(PyboxError::Timeout.into());
+        assert_eq!(ExecOutcome::of(&timeout), ExecOutcome::TimedOut);
+        let failed: Result<()> = Err(anyhow!("NameError"));
+        assert_eq!(ExecOutcome::of(&failed), ExecOutcome::Failed);
+    }
+
+    #[test]
+    fn test_prometheus_render() {
+        let metrics = PrometheusMetrics::default();
+        metrics.exec_started();
+        metrics.exec_finished(ExecOutcome::Succeeded, Duration::from_millis(20));
+        metrics.exec_started();
+        metrics.exec_finished(ExecOutcome::TimedOut, Duration::from_secs(60));
+        metrics.pool_utilization(3, 8);
+
+        let text = metrics.render();
+        assert!(text.contains("pybox_executions_started_total 2\n"));
+        assert!(text.contains("pybox_executions_total{outcome=\"succeeded\"} 1\n"));
+        assert!(text.contains("pybox_executions_total{outcome=\"timed_out\"} 1\n"));
+        assert!(text.contains("pybox_exec_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("pybox_exec_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("pybox_exec_duration_seconds_bucket{le=\"30\"} 1\n"));
+        assert!(text.contains("pybox_exec_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("pybox_exec_duration_seconds_sum 60.02\n"));
+        assert!(text.contains("pybox_pool_workers_busy 3\n"));
+        assert!(text.contains("pybox_pool_workers 8\n"));
+    }
+}
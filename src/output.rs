@@ -0,0 +1,329 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
+use wasmtime_wasi::cli::{IsTerminal, StdoutStream};
+use wasmtime_wasi_io::bytes::{Bytes, BytesMut};
+use wasmtime_wasi_io::poll::Pollable;
+use wasmtime_wasi_io::streams::{OutputStream, StreamResult};
+
+/// Appended to the output once the limit is reached.
+pub const TRUNCATION_MARKER: &str = "\n[pybox: output truncated]\n";
+
+/// Number of bytes the guest may still write, shared by stdout and
+/// stderr of a single execution.
+#[derive(Debug)]
+pub struct OutputBudget {
+    remaining: Mutex<usize>,
+    truncated: AtomicBool,
+}
+
+impl OutputBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            remaining: Mutex::new(limit),
+            truncated: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether any output was dropped.
+    pub fn truncated(&self) -> bool {
+        self.truncated.load(Ordering::SeqCst)
+    }
+
+    /// Charge `text` captured from the guest against the budget, cutting
+    /// it at the limit and marking where it was cut.
+    pub fn truncate(&self, mut text: String) -> String {
+        let (allowed, hit_limit) = self.take(text.len());
+        if allowed < text.len() {
+            let mut end = allowed;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            if hit_limit {
+                text.push_str(TRUNCATION_MARKER);
+            }
+        }
+        text
+    }
+
+    /// Reserve up to `len` bytes. Returns how many may be written and
+    /// whether this call is the one that hit the limit.
+    fn take(&self, len: usize) -> (usize, bool) {
+        let mut remaining = self.remaining.lock().unwrap_or_else(|e| e.into_inner());
+        let allowed = len.min(*remaining);
+        *remaining -= allowed;
+        if allowed < len {
+            (allowed, !self.truncated.swap(true, Ordering::SeqCst))
+        } else {
+            (allowed, false)
+        }
+    }
+}
+
+/// An output stream that forwards to `inner` until the budget runs out,
+/// then writes a truncation marker and silently drops the rest.
+pub struct LimitedOutput<S> {
+    inner: S,
+    budget: Arc<OutputBudget>,
+}
+
+impl<S> LimitedOutput<S> {
+    pub fn new(inner: S, budget: Arc<OutputBudget>) -> Self {
+        Self { inner, budget }
+    }
+}
+
+impl<S: IsTerminal> IsTerminal for LimitedOutput<S> {
+    fn is_terminal(&self) -> bool {
+        self.inner.is_terminal()
+    }
+}
+
+impl<S: StdoutStream> StdoutStream for LimitedOutput<S> {
+    fn async_stream(&self) -> Box<dyn AsyncWrite + Send + Sync> {
+        Box::new(LimitedAsyncWrite {
+            inner: Pin::from(self.inner.async_stream()),
+            budget: self.budget.clone(),
+        })
+    }
+
+    fn p2_stream(&self) -> Box<dyn OutputStream> {
+        Box::new(LimitedStream {
+            inner: self.inner.p2_stream(),
+            budget: self.budget.clone(),
+            permit: 0,
+        })
+    }
+}
+
+struct LimitedStream {
+    inner: Box<dyn OutputStream>,
+    budget: Arc<OutputBudget>,
+    // Last permit granted by `inner`, the marker must fit within it
+    permit: usize,
+}
+
+#[wasmtime_wasi_io::async_trait]
+impl Pollable for LimitedStream {
+    async fn ready(&mut self) {
+        self.inner.ready().await
+    }
+}
+
+#[wasmtime_wasi_io::async_trait]
+impl OutputStream for LimitedStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let (allowed, hit_limit) = self.budget.take(bytes.len());
+        if !hit_limit {
+            return match allowed {
+                0 => Ok(()),
+                _ => self.inner.write(bytes),
+            };
+        }
+
+        let mut out = BytesMut::from(&bytes[..allowed]);
+        let room = self.permit.saturating_sub(allowed);
+        let marker = TRUNCATION_MARKER.as_bytes();
+        out.extend_from_slice(&marker[..marker.len().min(room)]);
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.inner.write(out.freeze())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        self.inner.flush()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.permit = self.inner.check_write()?;
+        Ok(self.permit)
+    }
+
+    async fn cancel(&mut self) {
+        self.inner.cancel().await
+    }
+}
+
+struct LimitedAsyncWrite {
+    inner: Pin<Box<dyn AsyncWrite + Send + Sync>>,
+    budget: Arc<OutputBudget>,
+}
+
+impl AsyncWrite for LimitedAsyncWrite {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Peek at the budget without consuming it, a pending write must
+        // not use up bytes that were never written
+        let allowed = {
+            let remaining = self.budget.remaining.lock().unwrap_or_else(|e| e.into_inner());
+            buf.len().min(*remaining)
+        };
+        if allowed == 0 {
+            if !buf.is_empty() {
+                self.budget.truncated.store(true, Ordering::SeqCst);
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+        match self.inner.as_mut().poll_write(cx, &buf[..allowed]) {
+            Poll::Ready(Ok(written)) => {
+                self.budget.take(written);
+                Poll::Ready(Ok(written))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Which stream guest output was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Receives guest output as it is written, see `ExecOptions::on_output`.
+/// Called on the thread running the code, so it should hand the bytes
+/// off rather than block.
+pub type OutputSink = Arc<dyn Fn(Stream, &[u8]) + Send + Sync>;
+
+/// An output stream that passes every write to a sink instead of the
+/// process streams.
+pub struct StreamingOutput {
+    stream: Stream,
+    sink: OutputSink,
+}
+
+impl StreamingOutput {
+    pub fn new(stream: Stream, sink: OutputSink) -> Self {
+        Self { stream, sink }
+    }
+}
+
+impl IsTerminal for StreamingOutput {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl StdoutStream for StreamingOutput {
+    fn async_stream(&self) -> Box<dyn AsyncWrite + Send + Sync> {
+        Box::new(StreamingWrite {
+            stream: self.stream,
+            sink: self.sink.clone(),
+        })
+    }
+
+    fn p2_stream(&self) -> Box<dyn OutputStream> {
+        Box::new(StreamingWrite {
+            stream: self.stream,
+            sink: self.sink.clone(),
+        })
+    }
+}
+
+struct StreamingWrite {
+    stream: Stream,
+    sink: OutputSink,
+}
+
+// How much the guest may write at once, the sink never pushes back
+const STREAMING_PERMIT: usize = 1 << 20;
+
+#[wasmtime_wasi_io::async_trait]
+impl Pollable for StreamingWrite {
+    async fn ready(&mut self) {}
+}
+
+#[wasmtime_wasi_io::async_trait]
+impl OutputStream for StreamingWrite {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        (self.sink)(self.stream, &bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(STREAMING_PERMIT)
+    }
+}
+
+impl AsyncWrite for StreamingWrite {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        (self.sink)(self.stream, buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_reports_the_write_that_hits_the_limit() {
+        let budget = OutputBudget::new(10);
+        assert_eq!(budget.take(6), (6, false));
+        assert_eq!(budget.take(6), (4, true));
+        assert_eq!(budget.take(6), (0, false));
+        assert!(budget.truncated());
+    }
+
+    #[test]
+    fn test_budget_not_truncated_at_exact_limit() {
+        let budget = OutputBudget::new(4);
+        assert_eq!(budget.take(4), (4, false));
+        assert!(!budget.truncated());
+    }
+
+    #[test]
+    fn test_streaming_output_passes_writes_to_sink() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink: OutputSink = {
+            let written = written.clone();
+            Arc::new(move |stream, bytes: &[u8]| {
+                written.lock().unwrap().push((stream, bytes.to_vec()));
+            })
+        };
+        let mut stream = StreamingOutput::new(Stream::Stderr, sink).p2_stream();
+        assert!(stream.check_write().unwrap() > 0);
+        stream.write(Bytes::from_static(b"oops")).unwrap();
+        assert_eq!(*written.lock().unwrap(), vec![(Stream::Stderr, b"oops".to_vec())]);
+    }
+
+    #[test]
+    fn test_truncate_captured_text_on_char_boundary() {
+        let budget = OutputBudget::new(4);
+        assert_eq!(budget.truncate("ab".to_string()), "ab");
+        let expected = format!("a{}", TRUNCATION_MARKER);
+        assert_eq!(budget.truncate("aé!".to_string()), expected);
+        assert_eq!(budget.truncate("more".to_string()), "");
+        assert!(budget.truncated());
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+
+use crate::sandbox::{Mount, MountMode};
+
+const MIB: usize = 1024 * 1024;
+
+/// Everything a sandbox may do, in one value that can be reviewed, stored
+/// and applied with `PySandboxBuilder::policy`.
+///
+/// ```no_run
+/// use pybox::policy::SandboxPolicy;
+/// use pybox::sandbox::PySandbox;
+///
+/// let policy = SandboxPolicy::data_analysis().read_only_mount("./datasets", "/data");
+/// let mut sandbox = PySandbox::builder().policy(policy).build()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    /// Wall-clock limit for each execution.
+    pub timeout_seconds: u64,
+    pub max_memory_bytes: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    /// Bytes guest code may write to files, see
+    /// `PySandboxBuilder::max_write_bytes`.
+    pub max_write_bytes: Option<usize>,
+    pub fuel_limit: Option<u64>,
+    /// Host directories visible to guest code, nothing else is.
+    pub mounts: Vec<Mount>,
+    /// Whether each execution gets a private `/work` directory.
+    pub work_dir: bool,
+    /// Environment variables set for guest code. The host environment is
+    /// never inherited.
+    pub env: Vec<(String, String)>,
+    /// Run with virtual clocks and seeded randomness, see
+    /// `PySandboxBuilder::deterministic`.
+    pub deterministic_seed: Option<u64>,
+    /// Top level modules guest code may not import, best-effort, see
+    /// `PySandboxBuilder::blocked_imports`.
+    pub blocked_imports: Vec<String>,
+    /// Hosts guest code may send HTTP requests to, as `host` or
+    /// `host:port`, see `PySandboxBuilder::allow_http`. Empty disables
+    /// networking.
+    #[cfg(feature = "http")]
+    pub http_allowlist: Vec<String>,
+}
+
+impl SandboxPolicy {
+    /// For untrusted code that only computes: no filesystem, environment
+    /// or network, a virtual clock so results don't depend on when they
+    /// run, 64 MiB of memory and a 10 second timeout.
+    pub fn pure_compute() -> Self {
+        Self {
+            timeout_seconds: 10,
+            max_memory_bytes: Some(64 * MIB),
+            max_output_bytes: Some(MIB),
+            max_write_bytes: None,
+            fuel_limit: None,
+            mounts: Vec::new(),
+            work_dir: false,
+            env: Vec::new(),
+            deterministic_seed: Some(0),
+            blocked_imports: Vec::new(),
+            #[cfg(feature = "http")]
+            http_allowlist: Vec::new(),
+        }
+    }
+
+    /// For analysis over datasets: read-only mounts added with
+    /// `read_only_mount`, a private `/work` directory for up to 256 MiB of
+    /// results, 1 GiB of memory and a 2 minute timeout.
+    pub fn data_analysis() -> Self {
+        Self {
+            timeout_seconds: 120,
+            max_memory_bytes: Some(1024 * MIB),
+            max_output_bytes: Some(16 * MIB),
+            max_write_bytes: Some(256 * MIB),
+            work_dir: true,
+            deterministic_seed: None,
+            ..Self::pure_compute()
+        }
+    }
+
+    /// For code that calls out to the hosts in `allowlist` and nowhere
+    /// else, with 256 MiB of memory and a 30 second timeout to wait on
+    /// them.
+    #[cfg(feature = "http")]
+    pub fn networked<I, S>(allowlist: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            timeout_seconds: 30,
+            max_memory_bytes: Some(256 * MIB),
+            max_output_bytes: Some(4 * MIB),
+            deterministic_seed: None,
+            http_allowlist: allowlist.into_iter().map(Into::into).collect(),
+            ..Self::pure_compute()
+        }
+    }
+
+    /// Add a host directory guest code can read but not modify.
+    pub fn read_only_mount(
+        mut self,
+        host_path: impl Into<PathBuf>,
+        guest_path: impl Into<String>,
+    ) -> Self {
+        self.mounts.push(Mount {
+            host_path: host_path.into(),
+            guest_path: guest_path.into(),
+            mode: MountMode::ReadOnly,
+        });
+        self
+    }
+
+    /// Whether guest code can change anything on the host filesystem.
+    pub fn allows_host_writes(&self) -> bool {
+        self.mounts.iter().any(|m| m.mode == MountMode::ReadWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pure_compute_reaches_nothing() {
+        let policy = SandboxPolicy::pure_compute();
+        assert!(policy.mounts.is_empty());
+        assert!(!policy.work_dir);
+        assert!(policy.env.is_empty());
+        assert!(policy.deterministic_seed.is_some());
+        assert!(policy.max_memory_bytes.is_some());
+    }
+
+    #[test]
+    fn test_data_analysis_mounts_are_read_only() {
+        let policy = SandboxPolicy::data_analysis()
+            .read_only_mount("./a", "/a")
+            .read_only_mount("./b", "/b");
+        assert_eq!(policy.mounts.len(), 2);
+        assert!(!policy.allows_host_writes());
+        assert!(policy.max_memory_bytes > SandboxPolicy::pure_compute().max_memory_bytes);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_networked_only_allows_listed_hosts() {
+        let policy = SandboxPolicy::networked(["api.example.com:443"]);
+        assert_eq!(policy.http_allowlist, ["api.example.com:443"]);
+        assert!(policy.mounts.is_empty());
+        assert!(policy.env.is_empty());
+        assert!(SandboxPolicy::pure_compute().http_allowlist.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::Result;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::metrics::MetricsSink;
+use crate::sandbox::{ExecOptions, ExecOutput, PySandbox};
+
+/// A fixed number of sandboxes sharing one engine and compiled
+/// component, so executions can run concurrently from many threads.
+///
+/// `exec` blocks until a worker is free. Utilization is reported to the
+/// sandbox's `Metrics` whenever a worker is checked out or returned.
+///
+/// Workers are never health-checked or replaced. Every execution
+/// instantiates the component into a fresh store, and a worker keeps
+/// nothing of it once the execution ends. A worker whose execution
+/// trapped, timed out or ran out of memory is therefore as good as new
+/// and goes straight back to the pool.
+pub struct SandboxPool {
+    size: usize,
+    idle: Mutex<Vec<PySandbox>>,
+    available: Condvar,
+    metrics: MetricsSink,
+}
+
+impl SandboxPool {
+    /// Create a pool of `size` workers cloned from `sandbox`. Workers
+    /// share its engine, component and settings.
+    pub fn new(sandbox: PySandbox, size: usize) -> Self {
+        assert!(size > 0, "SandboxPool needs at least one worker");
+        let idle = (0..size).map(|_| sandbox.clone()).collect();
+        let metrics = sandbox.metrics().clone();
+        metrics.pool_utilization(0, size);
+        Self {
+            size,
+            idle: Mutex::new(idle),
+            available: Condvar::new(),
+            metrics,
+        }
+    }
+
+    /// Execute Python code on the next free worker. See `PySandbox::exec`.
+    pub fn exec(&self, code: &str) -> Result<String> {
+        self.exec_with_options(code, &ExecOptions::default())
+            .map(|output| output.value)
+    }
+
+    /// Execute Python code with per-call settings on the next free
+    /// worker. See `PySandbox::exec_with_options`.
+    pub fn exec_with_options(&self, code: &str, options: &ExecOptions) -> Result<ExecOutput> {
+        self.checkout().sandbox().exec_with_options(code, options)
+    }
+
+    /// Total number of workers.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of workers not currently executing code.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn checkout(&self) -> Worker<'_> {
+        let mut idle = self.lock();
+        loop {
+            if let Some(sandbox) = idle.pop() {
+                self.metrics.pool_utilization(self.size - idle.len(), self.size);
+                return Worker {
+                    pool: self,
+                    sandbox: Some(sandbox),
+                };
+            }
+            idle = self
+                .available
+                .wait(idle)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<PySandbox>> {
+        // Workers are only pushed and popped under the lock so the list
+        // is still valid even if a holder panicked
+        self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A checked out worker, returned to the pool when dropped.
+struct Worker<'a> {
+    pool: &'a SandboxPool,
+    sandbox: Option<PySandbox>,
+}
+
+impl Worker<'_> {
+    fn sandbox(&mut self) -> &mut PySandbox {
+        self.sandbox.as_mut().expect("worker already returned")
+    }
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        if let Some(sandbox) = self.sandbox.take() {
+            let mut idle = self.pool.lock();
+            idle.push(sandbox);
+            let busy = self.pool.size - idle.len();
+            drop(idle);
+            self.pool.metrics.pool_utilization(busy, self.pool.size);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_is_shareable_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SandboxPool>();
+    }
+}
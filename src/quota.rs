@@ -0,0 +1,222 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::PyboxError;
+
+/// How much a sandbox may run within each `window`, set with
+/// `PySandboxBuilder::quota`. The budget is shared by clones of the
+/// sandbox, so all workers of a `SandboxPool` draw from it.
+///
+/// Once either budget is used up executions fail with
+/// `PyboxError::QuotaExceeded` until the window ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Executions started per window.
+    pub max_executions: Option<u64>,
+    /// CPU time spent running guest code per window, counting
+    /// instantiation.
+    pub max_total_cpu: Option<Duration>,
+    pub window: Duration,
+}
+
+/// Counts usage against a `Quota`. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct QuotaTracker(Option<Arc<Tracked>>);
+
+#[derive(Debug)]
+struct Tracked {
+    quota: Quota,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    window_started: Instant,
+    executions: u64,
+    cpu: Duration,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: Quota) -> Self {
+        Self(Some(Arc::new(Tracked {
+            quota,
+            usage: Mutex::new(Usage {
+                window_started: Instant::now(),
+                executions: 0,
+                cpu: Duration::ZERO,
+            }),
+        })))
+    }
+
+    /// Count an execution about to start, or refuse it if the budget of
+    /// the current window is used up.
+    pub(crate) fn start(&self) -> Result<(), PyboxError> {
+        let Some(tracked) = &self.0 else {
+            return Ok(());
+        };
+        let quota = &tracked.quota;
+        let mut usage = tracked.usage();
+        let now = Instant::now();
+        if now.duration_since(usage.window_started) >= quota.window {
+            *usage = Usage {
+                window_started: now,
+                executions: 0,
+                cpu: Duration::ZERO,
+            };
+        }
+
+        let executions_left = quota.max_executions.is_none_or(|max| usage.executions < max);
+        let cpu_left = quota.max_total_cpu.is_none_or(|max| usage.cpu < max);
+        if !executions_left || !cpu_left {
+            let window_ends = usage.window_started + quota.window;
+            return Err(PyboxError::QuotaExceeded {
+                retry_after: window_ends.saturating_duration_since(now),
+            });
+        }
+        usage.executions += 1;
+        Ok(())
+    }
+
+    /// Charge the CPU time of a finished execution.
+    pub(crate) fn finish(&self, cpu: Duration) {
+        if let Some(tracked) = &self.0 {
+            tracked.usage().cpu += cpu;
+        }
+    }
+}
+
+impl Tracked {
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Measures the CPU time the calling thread spends between `start` and
+/// `elapsed`. Guest code runs on the thread that calls into the sandbox,
+/// so unlike wall time this doesn't count waiting. Platforms without a
+/// per-thread clock fall back to wall time.
+pub(crate) struct CpuTimer {
+    #[cfg(unix)]
+    started: Duration,
+    #[cfg(not(unix))]
+    started: Instant,
+}
+
+impl CpuTimer {
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(unix)]
+            started: thread_cpu_time(),
+            #[cfg(not(unix))]
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(unix)]
+        return thread_cpu_time().saturating_sub(self.started);
+        #[cfg(not(unix))]
+        return self.started.elapsed();
+    }
+}
+
+/// Run `future` and measure the CPU time spent polling it. Each poll runs
+/// on a single thread, so summing the polls counts the work of futures
+/// that move between threads and leaves out the time spent suspended.
+#[cfg(feature = "async")]
+pub(crate) async fn measure_cpu<F: Future>(future: F) -> (F::Output, Duration) {
+    let mut future = std::pin::pin!(future);
+    let mut cpu = Duration::ZERO;
+    let output = std::future::poll_fn(|cx| {
+        let timer = CpuTimer::start();
+        let poll = future.as_mut().poll(cx);
+        cpu += timer.elapsed();
+        poll
+    })
+    .await;
+    (output, cpu)
+}
+
+#[cfg(unix)]
+fn thread_cpu_time() -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec to write to
+    let status = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut now) };
+    if status != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execution_budget_is_shared_by_clones() {
+        let tracker = QuotaTracker::new(Quota {
+            max_executions: Some(2),
+            max_total_cpu: None,
+            window: Duration::from_secs(60),
+        });
+        let clone = tracker.clone();
+        assert!(tracker.start().is_ok());
+        assert!(clone.start().is_ok());
+        let Err(PyboxError::QuotaExceeded { retry_after }) = tracker.start() else {
+            panic!("third execution should be refused");
+        };
+        assert!(retry_after <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_cpu_budget_resets_with_window() {
+        let tracker = QuotaTracker::new(Quota {
+            max_executions: None,
+            max_total_cpu: Some(Duration::from_millis(10)),
+            window: Duration::from_millis(50),
+        });
+        assert!(tracker.start().is_ok());
+        tracker.finish(Duration::from_millis(10));
+        assert!(tracker.start().is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(tracker.start().is_ok());
+    }
+
+    #[test]
+    fn test_no_quota_allows_everything() {
+        let tracker = QuotaTracker::default();
+        for _ in 0..100 {
+            assert!(tracker.start().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_cpu_timer_ignores_sleep() {
+        let timer = CpuTimer::start();
+        let mut x = 0u64;
+        for i in 0..5_000_000 {
+            x = std::hint::black_box(x.wrapping_add(i));
+        }
+        let busy = timer.elapsed();
+        assert!(busy > Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(100));
+        if cfg!(unix) {
+            assert!(timer.elapsed() - busy < Duration::from_millis(50));
+        }
+    }
+
+    #[cfg(all(feature = "async", unix))]
+    #[tokio::test]
+    async fn test_measure_cpu_ignores_suspended_time() {
+        let ((), cpu) = measure_cpu(async {
+            tokio::task::spawn_blocking(|| std::thread::sleep(Duration::from_millis(100)))
+                .await
+                .unwrap();
+        })
+        .await;
+        assert!(cpu < Duration::from_millis(50));
+    }
+}
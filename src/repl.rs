@@ -0,0 +1,150 @@
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::{CancelHandle, ExecOptions, PySandbox};
+use crate::session::Session;
+
+const PROMPT: &str = ">>> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+/// Run an interactive prompt on stdin and stdout until end of input or
+/// `:quit`. Input runs as notebook cells of one `Session`, so statements
+/// change its state and the value of an expression is printed.
+///
+/// Ctrl-C aborts the code that is running, which loses the interpreter
+/// state but keeps the prompt going. `:reset` starts over with a fresh
+/// interpreter.
+pub fn run(sandbox: PySandbox) -> Result<()> {
+    let running: Arc<Mutex<Option<CancelHandle>>> = Arc::default();
+    {
+        let running = running.clone();
+        ctrlc::set_handler(move || {
+            if let Some(handle) = &*running.lock().unwrap_or_else(|e| e.into_inner()) {
+                handle.cancel();
+            }
+        })
+        .context("Failed to install Ctrl-C handler")?;
+    }
+
+    let mut repl = Repl {
+        session: Session::new(sandbox),
+        running,
+    };
+    repl.serve(io::stdin().lock(), io::stdout())
+}
+
+struct Repl {
+    session: Session,
+    // Cancels the cell that is running, if any, on Ctrl-C
+    running: Arc<Mutex<Option<CancelHandle>>>,
+}
+
+impl Repl {
+    fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> Result<()> {
+        while let Some(code) = read_input(&mut input, &mut output)? {
+            match code.trim() {
+                "" => {}
+                ":quit" | ":exit" => break,
+                ":reset" => {
+                    self.session.reset();
+                    writeln!(output, "Interpreter reset")?;
+                }
+                command if command.starts_with(':') => {
+                    writeln!(output, "Unknown command {}, try :reset or :quit", command)?;
+                }
+                _ => self.exec(&code, &mut output)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `code` as the next cell and print what it printed, then its
+    /// value or error.
+    fn exec(&mut self, code: &str, output: &mut impl Write) -> Result<()> {
+        let handle = self.session.sandbox().cancel_handle();
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle.clone());
+        let options = ExecOptions {
+            cancel: Some(handle.clone()),
+            ..Default::default()
+        };
+        let result = self.session.exec_cell_with_options(code, &options);
+        *self.running.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+        write!(output, "{}{}", result.stdout, result.stderr)?;
+        if handle.is_cancelled() {
+            writeln!(output, "KeyboardInterrupt, the interpreter was reset")?;
+        } else if let Some(error) = result.error {
+            writeln!(output, "{}", error)?;
+        } else if let Some(value) = result.value.filter(|value| value != "null") {
+            writeln!(output, "{}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prompt for and read the next complete input, which spans several
+/// lines for blocks and open brackets. `None` at the end of input.
+fn read_input(input: &mut impl BufRead, output: &mut impl Write) -> Result<Option<String>> {
+    let mut lines: Vec<String> = Vec::new();
+    loop {
+        let prompt = if lines.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        write!(output, "{}", prompt)?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(output)?;
+            return Ok((!lines.is_empty()).then(|| lines.join("\n")));
+        }
+        lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        if is_complete(&lines) {
+            return Ok(Some(lines.join("\n")));
+        }
+    }
+}
+
+/// Whether `lines` can run as is. Brackets must be closed and, like in
+/// the Python prompt, a block only ends with an empty line.
+fn is_complete(lines: &[String]) -> bool {
+    let depth = lines.iter().flat_map(|line| line.chars()).fold(0, |depth, c| match c {
+        '(' | '[' | '{' => depth + 1,
+        ')' | ']' | '}' => depth - 1,
+        _ => depth,
+    });
+    let last = lines.last().map(|line| line.trim_end()).unwrap_or_default();
+    if depth > 0 || last.ends_with('\\') {
+        return false;
+    }
+    let in_block = lines.iter().any(|line| line.trim_end().ends_with(':'));
+    !in_block || last.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(text: &str) -> Vec<String> {
+        let mut input = text.as_bytes();
+        let mut inputs = Vec::new();
+        while let Some(code) = read_input(&mut input, &mut io::sink()).unwrap() {
+            inputs.push(code);
+        }
+        inputs
+    }
+
+    #[test]
+    fn test_read_input_groups_blocks_and_brackets() {
+        let text = "x = 1\ndef f():\n    return x\n\nf(\n  1,\n)\nf()\n";
+        assert_eq!(
+            read_all(text),
+            vec!["x = 1", "def f():\n    return x\n", "f(\n  1,\n)", "f()"]
+        );
+    }
+
+    #[test]
+    fn test_read_input_runs_unfinished_block_at_end_of_input() {
+        assert_eq!(read_all("for i in range(3):\n    i"), vec!["for i in range(3):\n    i"]);
+    }
+}
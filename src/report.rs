@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+#[cfg(any(unix, feature = "server"))]
+use crate::sandbox::ExecOptions;
+use crate::sandbox::ExecOutput;
+use crate::session::CellResult;
+
+/// The JSON object clients get for an execution, shared by `--json`, the
+/// server, the daemon and `pybox rpc`: `ok`, `result` (the decoded value),
+/// `stdout`, `stderr`, `error` and `duration_ms`.
+pub fn exec_report(result: &Result<ExecOutput>, duration: Duration) -> Value {
+    match result {
+        Ok(output) => json!({
+            "ok": true,
+            "result": output.json().unwrap_or(Value::Null),
+            "stdout": output.stdout,
+            "stderr": output.stderr,
+            "error": null,
+            "duration_ms": duration.as_millis(),
+        }),
+        Err(e) => json!({
+            "ok": false,
+            "result": null,
+            "stdout": "",
+            "stderr": "",
+            "error": format!("{:#}", e),
+            "duration_ms": duration.as_millis(),
+        }),
+    }
+}
+
+/// An exec request as the server and the daemon take it:
+/// `{"code": "...", "timeout_ms": 500}`.
+#[cfg(any(unix, feature = "server"))]
+#[derive(Debug, PartialEq)]
+pub(crate) struct ExecRequest {
+    pub(crate) code: String,
+    pub(crate) timeout: Option<Duration>,
+}
+
+#[cfg(any(unix, feature = "server"))]
+impl ExecRequest {
+    /// Parse `body`, lowering a requested timeout to `max_timeout` so
+    /// clients can't hold on to a worker for longer.
+    pub(crate) fn parse(body: &Value, max_timeout: Duration) -> Result<Self, String> {
+        let code = body["code"]
+            .as_str()
+            .ok_or("expected a string \"code\"")?
+            .to_string();
+        let timeout = match &body["timeout_ms"] {
+            Value::Null => None,
+            value => {
+                let ms = value
+                    .as_u64()
+                    .ok_or("expected \"timeout_ms\" to be a number of milliseconds")?;
+                Some(Duration::from_millis(ms).min(max_timeout))
+            }
+        };
+        Ok(Self { code, timeout })
+    }
+
+    /// Options running the request with its output captured.
+    pub(crate) fn options(&self) -> ExecOptions {
+        ExecOptions {
+            timeout: self.timeout,
+            capture_output: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// `exec_report` for a notebook cell.
+pub fn cell_report(cell: &CellResult) -> Value {
+    let result = cell
+        .value
+        .as_deref()
+        .and_then(|value| serde_json::from_str::<Value>(value).ok())
+        .unwrap_or(Value::Null);
+    json!({
+        "ok": cell.error.is_none(),
+        "result": result,
+        "stdout": cell.stdout,
+        "stderr": cell.stderr,
+        "error": cell.error,
+        "duration_ms": cell.duration.as_millis(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PyboxError;
+
+    #[test]
+    fn test_exec_report_of_failure() {
+        let result: Result<ExecOutput> = Err(PyboxError::Timeout.into());
+        assert_eq!(
+            exec_report(&result, Duration::from_millis(5)),
+            json!({
+                "ok": false,
+                "result": null,
+                "stdout": "",
+                "stderr": "",
+                "error": "Execution timed out",
+                "duration_ms": 5,
+            })
+        );
+    }
+
+    #[cfg(any(unix, feature = "server"))]
+    #[test]
+    fn test_parse_request_bounds_timeout() {
+        let max = Duration::from_secs(10);
+        assert_eq!(
+            ExecRequest::parse(&json!({"code": "1 + 1", "timeout_ms": 500}), max),
+            Ok(ExecRequest {
+                code: "1 + 1".to_string(),
+                timeout: Some(Duration::from_millis(500)),
+            })
+        );
+        let request = ExecRequest::parse(&json!({"code": "", "timeout_ms": 60_000}), max).unwrap();
+        assert_eq!(request.timeout, Some(max));
+        let request = ExecRequest::parse(&json!({"code": ""}), max).unwrap();
+        assert_eq!(request.timeout, None);
+        assert!(request.options().capture_output);
+
+        assert!(ExecRequest::parse(&json!({}), max).is_err());
+        assert!(ExecRequest::parse(&json!({"code": "", "timeout_ms": -1}), max).is_err());
+    }
+
+    #[test]
+    fn test_cell_report_decodes_value() {
+        let cell = CellResult {
+            value: Some("[1, 2]".to_string()),
+            stdout: "hi\n".to_string(),
+            stderr: String::new(),
+            error: None,
+            duration: Duration::from_millis(2),
+        };
+        let report = cell_report(&cell);
+        assert_eq!(report["ok"], json!(true));
+        assert_eq!(report["result"], json!([1, 2]));
+        assert_eq!(report["stdout"], json!("hi\n"));
+    }
+}
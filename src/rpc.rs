@@ -0,0 +1,205 @@
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::report::cell_report;
+use crate::sandbox::{ExecOptions, PySandbox};
+use crate::session::Session;
+
+// Error codes defined by JSON-RPC 2.0
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serve JSON-RPC 2.0 on stdin and stdout, one request or response per
+/// line, until `shutdown` or the end of input. All code runs in one
+/// `Session`, so variables defined by one request are visible to the next.
+///
+/// Methods:
+/// - `exec {"code", "timeout_ms"?}` runs statements as the next cell.
+/// - `eval {"code", "timeout_ms"?}` evaluates a single expression.
+/// - `reset` starts over with a fresh interpreter.
+/// - `shutdown` replies and exits.
+///
+/// `exec` and `eval` answer with the object described by
+/// `report::exec_report`. An exception raised by the code is a successful
+/// call with `ok` false, JSON-RPC errors are only used for invalid
+/// requests.
+///
+/// ```text
+/// > {"jsonrpc": "2.0", "id": 1, "method": "exec", "params": {"code": "x = 41\nx + 1"}}
+/// < {"jsonrpc":"2.0","id":1,"result":{"ok":true,"result":42,"stdout":"","stderr":"","error":null,"duration_ms":3}}
+/// ```
+pub fn run(sandbox: PySandbox) -> Result<()> {
+    let mut rpc = Rpc {
+        session: Session::new(sandbox),
+    };
+    rpc.serve(io::stdin().lock(), io::stdout().lock())
+}
+
+struct Rpc {
+    session: Session,
+}
+
+impl Rpc {
+    fn serve(&mut self, input: impl BufRead, mut output: impl Write) -> Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (response, shutdown) = match parse_request(&line) {
+                Ok(request) => {
+                    let shutdown = request.method == Method::Shutdown;
+                    let result = self.call(request.method);
+                    // Notifications have no id and get no response
+                    let response = request.id.map(|id| success(id, result));
+                    (response, shutdown)
+                }
+                Err((id, code, message)) => (Some(failure(id, code, &message)), false),
+            };
+            if let Some(response) = response {
+                writeln!(output, "{}", response)?;
+                output.flush()?;
+            }
+            if shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, method: Method) -> Value {
+        match method {
+            Method::Exec { code, timeout } => {
+                cell_report(&self.session.exec_cell_with_options(&code, &options(timeout)))
+            }
+            Method::Eval { code, timeout } => {
+                cell_report(&self.session.eval_cell_with_options(&code, &options(timeout)))
+            }
+            Method::Reset => {
+                self.session.reset();
+                Value::Null
+            }
+            Method::Shutdown => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    // None for notifications
+    id: Option<Value>,
+    method: Method,
+}
+
+#[derive(Debug, PartialEq)]
+enum Method {
+    Exec { code: String, timeout: Option<Duration> },
+    Eval { code: String, timeout: Option<Duration> },
+    Reset,
+    Shutdown,
+}
+
+/// Parse a line into a request, or the id, code and message of the error
+/// to answer it with.
+fn parse_request(line: &str) -> Result<Request, (Value, i64, String)> {
+    let request: Value = serde_json::from_str(line)
+        .map_err(|e| (Value::Null, PARSE_ERROR, format!("Invalid JSON: {}", e)))?;
+    let id = request.get("id").cloned();
+    let invalid = |code, message: &str| (id.clone().unwrap_or(Value::Null), code, message.to_string());
+
+    if request["jsonrpc"] != "2.0" {
+        return Err(invalid(INVALID_REQUEST, "expected \"jsonrpc\": \"2.0\""));
+    }
+    let Some(name) = request["method"].as_str() else {
+        return Err(invalid(INVALID_REQUEST, "expected a string \"method\""));
+    };
+    let params = &request["params"];
+    let code = || {
+        params["code"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| invalid(INVALID_PARAMS, "expected a string \"code\" param"))
+    };
+    let timeout = || match &params["timeout_ms"] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .map(|ms| Some(Duration::from_millis(ms)))
+            .ok_or_else(|| invalid(INVALID_PARAMS, "expected \"timeout_ms\" to be a number")),
+    };
+
+    let method = match name {
+        "exec" => Method::Exec {
+            code: code()?,
+            timeout: timeout()?,
+        },
+        "eval" => Method::Eval {
+            code: code()?,
+            timeout: timeout()?,
+        },
+        "reset" => Method::Reset,
+        "shutdown" => Method::Shutdown,
+        _ => return Err(invalid(METHOD_NOT_FOUND, &format!("unknown method {}", name))),
+    };
+    Ok(Request { id, method })
+}
+
+fn options(timeout: Option<Duration>) -> ExecOptions {
+    ExecOptions {
+        timeout,
+        ..Default::default()
+    }
+}
+
+fn success(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn failure(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let line = r#"{"jsonrpc": "2.0", "id": 7, "method": "exec", "params": {"code": "1", "timeout_ms": 250}}"#;
+        assert_eq!(
+            parse_request(line),
+            Ok(Request {
+                id: Some(json!(7)),
+                method: Method::Exec {
+                    code: "1".to_string(),
+                    timeout: Some(Duration::from_millis(250)),
+                },
+            })
+        );
+        let line = r#"{"jsonrpc": "2.0", "method": "reset"}"#;
+        assert_eq!(
+            parse_request(line),
+            Ok(Request {
+                id: None,
+                method: Method::Reset,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_request_errors() {
+        let code = |line: &str| parse_request(line).unwrap_err().1;
+        assert_eq!(code("{"), PARSE_ERROR);
+        assert_eq!(code(r#"{"id": 1, "method": "exec"}"#), INVALID_REQUEST);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "run"}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "eval"}"#), INVALID_PARAMS);
+
+        let (id, _, _) = parse_request(r#"{"jsonrpc": "2.0", "id": "a", "method": "run"}"#).unwrap_err();
+        assert_eq!(id, json!("a"));
+    }
+}
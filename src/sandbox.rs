@@ -1,171 +1,3763 @@
 use anyhow::{anyhow, Context, Result};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+#[cfg(not(feature = "embedded-wasm"))]
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use wasmtime::{Cache, Config, Engine, Store, Strategy};
-use wasmtime::component::{Component, Linker};
-use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxBuilder, WasiCtxView};
+use wasmtime::{
+    Cache, CacheConfig, Config, Engine, PoolingAllocationConfig, ResourceLimiter, Store, StoreLimits,
+    StoreLimitsBuilder, Trap, UpdateDeadline, DEFAULT_INSTANCE_LIMIT, DEFAULT_MEMORY_LIMIT,
+    DEFAULT_TABLE_LIMIT,
+};
+pub use wasmtime::{OptLevel, Strategy};
+use wasmtime::component::{Component, HasSelf, Linker};
+use wasmtime_wasi::filesystem::WasiFilesystemView;
+use wasmtime_wasi::{DirPerms, FilePerms, ResourceTable, WasiCtx, WasiCtxBuilder, WasiCtxView};
+
+use crate::audit::{self, AuditRecord, AuditSink, Auditor};
+use crate::counts::ComponentCounts;
+use crate::deterministic::{self, ClockPolicy, GuardedRandom, RandomPolicy};
+use crate::error::{self, CodeLimit, DigestMismatch, InvalidJson, LimitViolation, PyException, PyboxError, ResourceLimit, TrapKind};
+use crate::extension::{ExtensionState, Extensions, SandboxExtension};
+use crate::filesystem::{QuotaFilesystem, WriteQuota};
+#[cfg(feature = "http")]
+use crate::http::{HttpPolicy, HttpState};
+use crate::host::{HostFn, HostFns, InputFn, InputHandler, ProgressFn, ProgressHandler};
+use crate::metrics::{ExecOutcome, Metrics, MetricsSink};
+use crate::output::{LimitedOutput, OutputBudget, OutputSink, Stream, StreamingOutput};
+use crate::policy::SandboxPolicy;
+use crate::quota::{CpuTimer, Quota, QuotaTracker};
+use crate::timer::{DeadlineGuard, DeadlineTimer};
+use crate::variant::{ComponentRegistry, ComponentVersion};
+use crate::trace::{self, ExecSpan};
+#[cfg(feature = "validation")]
+use crate::validate::CodeValidator;
+#[cfg(feature = "arrow")]
+use arrow_array::RecordBatch;
+#[cfg(feature = "arrow")]
+use arrow_ipc::{reader::StreamReader, writer::StreamWriter};
 
 // Default timeout in seconds
 const DEFAULT_TIMEOUT_SECONDS: u64 = 40;
-const EPOCH_DEADLINE_BASE: u64 = 1; // Additional epoch deadline buffer
+// How often async execution yields back to the runtime
+#[cfg(feature = "async")]
+const ASYNC_YIELD_INTERVAL: Duration = Duration::from_millis(10);
+
+// Where `exec_project` mounts the project directory in the guest
+const PROJECT_GUEST_DIR: &str = "/project";
+// Where the per-execution work directory is mounted in the guest
+const WORK_GUEST_DIR: &str = "/work";
+// Where the guest saves matplotlib figures, see `capture_figures`
+const FIGURES_GUEST_DIR: &str = "/figures";
+// Memory backed filesystem used for work directories when available
+const SHM_DIR: &str = "/dev/shm";
+// Component loaded when no other is configured
+#[cfg(not(feature = "embedded-wasm"))]
+const DEFAULT_WASM: &str = "sandbox.wasm";
+// Environment variable pointing at the default component
+#[cfg(not(feature = "embedded-wasm"))]
+const WASM_PATH_ENV: &str = "PYBOX_WASM_PATH";
+// Component with numpy and pandas built in, see `with_scientific_stack`
+const SCIENTIFIC_WASM: &str = "sandbox-scientific.wasm";
+// Where packages added with `add_package` and modules added with
+// `add_module` are mounted in the guest
+const SITE_PACKAGES_GUEST_DIR: &str = "/site-packages";
+
+// Core instances and memories one component instance of the guest needs,
+// used to size the pooling allocator
+const CORE_INSTANCES_PER_SANDBOX: u32 = 32;
+const MEMORIES_PER_SANDBOX: u32 = 2;
+const WASM_PAGE_BYTES: u64 = 64 * 1024;
+
+// Header written in front of precompiled components so that artifacts
+// produced by a differently configured engine are rejected up front.
+const PRECOMPILED_MAGIC: &[u8; 8] = b"PYBOXCW\0";
+const PRECOMPILED_HEADER_LEN: usize = PRECOMPILED_MAGIC.len() + 8;
 
-struct MyWasi {
+// The component is compiled into the binary when the `embedded-wasm`
+// feature is enabled so no runtime file lookup is needed.
+#[cfg(feature = "embedded-wasm")]
+static SANDBOX_WASM: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/sandbox.wasm"));
+
+/// Host state of a single execution's store. Extensions see it as the
+/// data type of the linker they add imports to.
+pub struct MyWasi {
     wasi_ctx: WasiCtx,
     table: ResourceTable,
+    tracker: ResourceTracker,
+    // Backing directory for `/work`, removed when the store is dropped
+    work_dir: Option<TempDir>,
+    // Backing directory for `/figures`, removed when the store is dropped
+    figure_dir: Option<TempDir>,
+    output: Option<Arc<OutputBudget>>,
+    write_quota: Option<Arc<WriteQuota>>,
+    // Rich outputs sent with `pybox.display`, in order
+    displays: Vec<DisplayData>,
+    host_fns: HostFns,
+    extensions: Extensions,
+    extension_state: ExtensionState,
+    on_input: InputHandler,
+    on_progress: ProgressHandler,
+    // Set once the run is past its timeout when a grace period is allowed
+    interrupt: Arc<AtomicBool>,
+    // Lines run when the guest reported going over the line budget,
+    // shared so it can be read while the store is borrowed
+    over_budget: Arc<Mutex<Option<u64>>>,
+    deny_random: bool,
+    #[cfg(feature = "http")]
+    http: HttpState,
+}
+
+impl MyWasi {
+    /// The files the execution left in `/work`, if it has one.
+    fn artifacts(&self) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        match &self.work_dir {
+            Some(dir) => collect_artifacts(dir.path()).context("Failed to collect artifacts"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The figures the execution rendered to `/figures`, if it has one.
+    fn figures(&self) -> Result<Vec<Vec<u8>>> {
+        match &self.figure_dir {
+            Some(dir) => Ok(collect_artifacts(dir.path())
+                .context("Failed to collect figures")?
+                .into_iter()
+                .map(|(_, png)| png)
+                .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Records how much linear memory the guest allocates and enforces the
+/// store limits set on the builder.
+struct ResourceTracker {
+    memory_bytes: usize,
+    peak_memory_bytes: usize,
+    limits: StoreLimits,
+    config: Limits,
+    // First limit that denied a growth, shared so it can be read while
+    // the store is borrowed
+    exceeded: Arc<Mutex<Option<LimitViolation>>>,
+}
+
+impl ResourceTracker {
+    fn new(limits: &Limits) -> Self {
+        Self {
+            memory_bytes: 0,
+            peak_memory_bytes: 0,
+            limits: limits.store_limits(),
+            config: *limits,
+            exceeded: Arc::default(),
+        }
+    }
+
+    fn exceeded(&self) -> Option<LimitViolation> {
+        *self.exceeded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, limit: ResourceLimit, allowed: Option<usize>, requested: usize) {
+        let violation = LimitViolation {
+            limit,
+            allowed: allowed.unwrap_or(usize::MAX),
+            requested: Some(requested),
+        };
+        let mut exceeded = self.exceeded.lock().unwrap_or_else(|e| e.into_inner());
+        exceeded.get_or_insert(violation);
+    }
+}
+
+impl ResourceLimiter for ResourceTracker {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        // A denied growth makes the guest raise MemoryError
+        if !self.limits.memory_growing(current, desired, maximum)? {
+            // Past the memory's own maximum rather than the configured one
+            let allowed = self.config.memory_bytes.or(maximum);
+            self.record(ResourceLimit::Memory, allowed, desired);
+            return Ok(false);
+        }
+        self.memory_bytes += desired.saturating_sub(current);
+        self.peak_memory_bytes = self.peak_memory_bytes.max(self.memory_bytes);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool> {
+        if !self.limits.table_growing(current, desired, maximum)? {
+            let allowed = self.config.table_elements.or(maximum);
+            self.record(ResourceLimit::TableElements, allowed, desired);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+/// Store limits for `PySandboxBuilder::limits`, unlimited when `None`.
+/// An execution that runs into one fails with a `PyboxError` limit error
+/// and a `LimitViolation` describing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreLimitsConfig {
+    /// Bytes each linear memory can grow to.
+    pub memory: Option<usize>,
+    /// Elements each table can grow to.
+    pub table_elements: Option<usize>,
+    /// Core instances created per execution.
+    pub instances: Option<usize>,
+    /// Tables created per execution.
+    pub tables: Option<usize>,
+    /// Linear memories created per execution.
+    pub memories: Option<usize>,
+}
+
+/// Store limits set on the builder, unlimited when `None`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    memory_bytes: Option<usize>,
+    table_elements: Option<usize>,
+    instances: Option<usize>,
+    tables: Option<usize>,
+    memories: Option<usize>,
+    wasm_stack: Option<usize>,
+}
+
+impl Limits {
+    fn store_limits(&self) -> StoreLimits {
+        let mut builder = StoreLimitsBuilder::new();
+        if let Some(bytes) = self.memory_bytes {
+            builder = builder.memory_size(bytes);
+        }
+        if let Some(elements) = self.table_elements {
+            builder = builder.table_elements(elements);
+        }
+        if let Some(instances) = self.instances {
+            builder = builder.instances(instances);
+        }
+        if let Some(tables) = self.tables {
+            builder = builder.tables(tables);
+        }
+        if let Some(memories) = self.memories {
+            builder = builder.memories(memories);
+        }
+        builder.build()
+    }
+
+    /// The limit that caused `error`, for failures the store reports
+    /// without consulting the limiter.
+    fn violated_by(&self, error: &anyhow::Error) -> Option<LimitViolation> {
+        if let Some(bytes) = self.wasm_stack
+            && error.downcast_ref::<Trap>() == Some(&Trap::StackOverflow)
+        {
+            return Some(LimitViolation {
+                limit: ResourceLimit::Stack,
+                allowed: bytes,
+                requested: None,
+            });
+        }
+        // Attached by `count_violation` when instantiation failed
+        error.downcast_ref::<LimitViolation>().copied()
+    }
+
+    /// The count limit a component needing `counts` goes over, which is
+    /// why instantiating it failed. Wasmtime checks the counts itself and
+    /// only says so in its message.
+    fn count_violation(&self, counts: ComponentCounts) -> Option<LimitViolation> {
+        [
+            (ResourceLimit::Instances, self.instances, DEFAULT_INSTANCE_LIMIT, counts.instances),
+            (ResourceLimit::Memories, self.memories, DEFAULT_MEMORY_LIMIT, counts.memories),
+            (ResourceLimit::Tables, self.tables, DEFAULT_TABLE_LIMIT, counts.tables),
+        ]
+        .into_iter()
+        .find_map(|(limit, allowed, default, needed)| {
+            let allowed = allowed.unwrap_or(default);
+            let needed = needed.filter(|&needed| needed > allowed)?;
+            Some(LimitViolation {
+                limit,
+                allowed,
+                requested: Some(needed),
+            })
+        })
+    }
+
+    /// `error` from a failed instantiation, with the count limit it ran
+    /// into attached when there is one.
+    fn instantiation_error(&self, error: anyhow::Error, counts: ComponentCounts) -> anyhow::Error {
+        match self.count_violation(counts) {
+            Some(violation) => error.context(violation),
+            None => error,
+        }
+    }
+}
+
+impl wasmtime_wasi::WasiView for MyWasi {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        WasiCtxView {
+            ctx: &mut self.wasi_ctx,
+            table: &mut self.table,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl wasmtime_wasi_http::WasiHttpView for MyWasi {
+    fn ctx(&mut self) -> &mut wasmtime_wasi_http::WasiHttpCtx {
+        &mut self.http.ctx
+    }
+
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn send_request(
+        &mut self,
+        request: http::Request<wasmtime_wasi_http::body::HyperOutgoingBody>,
+        config: wasmtime_wasi_http::types::OutgoingRequestConfig,
+    ) -> wasmtime_wasi_http::HttpResult<wasmtime_wasi_http::types::HostFutureIncomingResponse> {
+        self.http.send_request(request, config)
+    }
+}
+
+// NOTE: This generates a `Sandbox` type automatically and your IDE
+// might not pick it up
+wasmtime::component::bindgen!({
+    path: "sandbox.wit",
+    world: "sandbox",
+});
+
+impl From<PythonError> for error::PythonError {
+    fn from(e: PythonError) -> Self {
+        Self {
+            exception: PyException::from_name(&e.exception_type),
+            message: e.message,
+            lineno: e.lineno,
+        }
+    }
+}
+
+impl SandboxImports for MyWasi {
+    fn host_call(&mut self, name: String, args: String) -> Result<String, String> {
+        self.host_fns.call(&name, &args)
+    }
+
+    fn extension_call(
+        &mut self,
+        extension: String,
+        method: String,
+        payload: String,
+    ) -> Result<String, String> {
+        self.extensions.call(&extension, &method, &payload)
+    }
+
+    fn display(&mut self, mime_type: String, data: Vec<u8>) {
+        self.displays.push(DisplayData { mime_type, data });
+    }
+
+    fn read_input(&mut self, prompt: String) -> Option<String> {
+        self.on_input.read(&prompt)
+    }
+
+    fn report_progress(&mut self, percent: f32, message: String) {
+        self.on_progress.report(percent, &message);
+    }
+
+    fn interrupt_requested(&mut self) -> bool {
+        self.interrupt.load(Ordering::SeqCst)
+    }
+
+    fn line_budget_exceeded(&mut self, executed: u64) {
+        self.report_over_budget(executed);
+    }
+}
+
+impl MyWasi {
+    /// State set up by `SandboxExtension::init_state`, for host functions
+    /// an extension adds in `SandboxExtension::add_to_linker`.
+    pub fn extension_state(&mut self) -> &mut ExtensionState {
+        &mut self.extension_state
+    }
+
+    /// Resources of the store, for extension imports that hand them out.
+    pub fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    // Only the first report counts, a later one would come from the
+    // code's own call
+    fn report_over_budget(&self, executed: u64) {
+        let mut over_budget = self.over_budget.lock().unwrap_or_else(|e| e.into_inner());
+        over_budget.get_or_insert(executed);
+    }
+
+    fn over_budget(&self) -> Option<u64> {
+        *self.over_budget.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Load the sandbox component, either from the bytes embedded at
+/// compile time or from the first `sandbox.wasm` found on the search path.
+#[cfg(feature = "embedded-wasm")]
+fn load_component(engine: &Engine) -> Result<(Component, ComponentCounts)> {
+    let component = Component::from_binary(engine, SANDBOX_WASM)
+        .context("Failed to load embedded sandbox.wasm")?;
+    let counts = ComponentCounts::of(&component, SANDBOX_WASM);
+    Ok((component, counts))
+}
+
+#[cfg(not(feature = "embedded-wasm"))]
+fn load_component(engine: &Engine) -> Result<(Component, ComponentCounts)> {
+    let path = resolve_component_path()?;
+    load_component_bytes(engine, &path)
+}
+
+/// Compile the component at `path`, along with its counts.
+fn load_component_bytes(engine: &Engine, path: &Path) -> Result<(Component, ComponentCounts)> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let component = Component::from_binary(engine, &bytes)
+        .with_context(|| format!("Failed to load {}", path.display()))?;
+    let counts = ComponentCounts::of(&component, &bytes);
+    Ok((component, counts))
+}
+
+#[cfg(not(feature = "embedded-wasm"))]
+/// Places `sandbox.wasm` is looked for, in order: `PYBOX_WASM_PATH`, the
+/// working directory, next to the executable, then the XDG data
+/// directory.
+fn component_search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(path) = env::var_os(WASM_PATH_ENV).filter(|p| !p.is_empty()) {
+        paths.push(PathBuf::from(path));
+    }
+    paths.push(PathBuf::from(DEFAULT_WASM));
+    if let Some(dir) = env::current_exe().ok().as_deref().and_then(Path::parent) {
+        paths.push(dir.join(DEFAULT_WASM));
+    }
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    if let Some(dir) = data_dir {
+        paths.push(dir.join("pybox").join(DEFAULT_WASM));
+    }
+    paths
+}
+
+#[cfg(not(feature = "embedded-wasm"))]
+/// The first of `component_search_paths` that exists, or an error listing
+/// every place that was searched.
+fn resolve_component_path() -> Result<PathBuf> {
+    let paths = component_search_paths();
+    if let Some(path) = paths.iter().find(|path| path.is_file()) {
+        return Ok(path.clone());
+    }
+    let searched: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    Err(anyhow!(
+        "Failed to load sandbox.wasm, searched: {}. Build it with build_component.py \
+         or set {}",
+        searched.join(", "),
+        WASM_PATH_ENV
+    ))
+}
+
+/// Load the component at `path`, or the default one when it is `None`.
+/// Resolve the component's imports once, so each execution only needs a
+/// store and an instantiation.
+fn link(
+    engine: &Engine,
+    component: &Component,
+    extensions: &Extensions,
+) -> Result<SandboxPre<MyWasi>> {
+    #[cfg(test)]
+    tests::LINKS.with(|links| links.set(links.get() + 1));
+
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::p2::add_to_linker_sync(&mut linker)?;
+    add_random_to_linker(&mut linker)?;
+    linker.allow_shadowing(true);
+    wasmtime_wasi::p2::bindings::sync::filesystem::types::add_to_linker::<_, QuotaFilesystem<'static>>(&mut linker, quota_filesystem)?;
+    linker.allow_shadowing(false);
+    #[cfg(feature = "http")]
+    wasmtime_wasi_http::add_only_http_to_linker_sync(&mut linker)?;
+    Sandbox::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| state)?;
+    extensions.add_to_linker(&mut linker)?;
+    SandboxPre::new(linker.instantiate_pre(component)?)
+}
+
+/// Replace the `wasi:random` interfaces from `wasmtime_wasi` with ones
+/// that honor `RandomPolicy::Deny`.
+fn add_random_to_linker(linker: &mut Linker<MyWasi>) -> Result<()> {
+    use wasmtime_wasi::p2::bindings::random::{insecure, insecure_seed, random};
+
+    fn guarded(state: &mut MyWasi) -> GuardedRandom<'_> {
+        GuardedRandom {
+            denied: state.deny_random,
+            random: state.wasi_ctx.random(),
+        }
+    }
+
+    linker.allow_shadowing(true);
+    random::add_to_linker::<_, GuardedRandom<'static>>(linker, guarded)?;
+    insecure::add_to_linker::<_, GuardedRandom<'static>>(linker, guarded)?;
+    insecure_seed::add_to_linker::<_, GuardedRandom<'static>>(linker, guarded)?;
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+/// The guest's filesystem, with writes charged against the execution's
+/// `max_write_bytes` quota.
+fn quota_filesystem(state: &mut MyWasi) -> QuotaFilesystem<'_> {
+    QuotaFilesystem {
+        quota: state.write_quota.clone(),
+        inner: state.filesystem(),
+    }
+}
+
+/// The timer that interrupts runs on `engine` past their deadline.
+fn deadline_timer(engine: &Engine) -> Arc<DeadlineTimer> {
+    let engine = engine.clone();
+    Arc::new(DeadlineTimer::new(move || engine.increment_epoch()))
+}
+
+fn load_component_file(
+    engine: &Engine,
+    path: Option<&Path>,
+    expected_sha256: Option<&str>,
+) -> Result<(Component, ComponentCounts)> {
+    if let Some(expected) = expected_sha256 {
+        return load_verified_component(engine, path, expected);
+    }
+    match path {
+        Some(path) => load_component_bytes(engine, path),
+        None => load_component(engine),
+    }
+}
+
+/// Like `load_component_file`, but refuses a component whose SHA-256 is
+/// not `expected`. The bytes that were checked are the ones compiled, so
+/// the file can't be swapped in between.
+fn load_verified_component(
+    engine: &Engine,
+    path: Option<&Path>,
+    expected: &str,
+) -> Result<(Component, ComponentCounts)> {
+    #[cfg(feature = "embedded-wasm")]
+    let default = || Ok::<_, anyhow::Error>((SANDBOX_WASM.to_vec(), "embedded sandbox.wasm".into()));
+    #[cfg(not(feature = "embedded-wasm"))]
+    let default = || {
+        let path = resolve_component_path()?;
+        let bytes = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok::<_, anyhow::Error>((bytes, path.display().to_string()))
+    };
+    let (bytes, name) = match path {
+        Some(path) => (
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+            path.display().to_string(),
+        ),
+        None => default()?,
+    };
+    verify_sha256(&bytes, expected).with_context(|| format!("Refusing to load {}", name))?;
+    let component = Component::from_binary(engine, &bytes).with_context(|| format!("Failed to load {}", name))?;
+    let counts = ComponentCounts::of(&component, &bytes);
+    Ok((component, counts))
+}
+
+/// Check that `bytes` hash to `expected`, a hex encoded SHA-256.
+fn verify_sha256(bytes: &[u8], expected: &str) -> Result<()> {
+    let expected = expected.trim().to_ascii_lowercase();
+    if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("{:?} is not a hex encoded SHA-256 digest", expected));
+    }
+    let actual = audit::to_hex(&Sha256::digest(bytes));
+    if actual != expected {
+        return Err(DigestMismatch { expected, actual }.into());
+    }
+    Ok(())
+}
+
+/// The MessagePack encoded value of the global `name`, read from the
+/// instance `with_last_run` kept.
+#[cfg(feature = "msgpack")]
+fn read_global_msgpack(
+    sandbox: &Sandbox,
+    store: &mut Store<MyWasi>,
+    finish: &dyn Fn(Result<Result<String, PythonError>>) -> Result<String>,
+    name: &str,
+) -> Result<Vec<u8>> {
+    let mut bytes = None;
+    let result = sandbox.call_get_global_msgpack(store, name).map(|result| {
+        result.map(|value| {
+            bytes = Some(value);
+            String::new()
+        })
+    });
+    finish(result).with_context(|| format!("Failed to read global {}", name))?;
+    bytes.context("Guest returned no value")
+}
+
+/// Encode `batch` as an Arrow IPC stream for the guest.
+#[cfg(feature = "arrow")]
+fn write_ipc_stream(batch: &RecordBatch) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut writer = StreamWriter::try_new(&mut bytes, &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    drop(writer);
+    Ok(bytes)
+}
+
+/// Decode the single record batch of an Arrow IPC stream from the guest.
+#[cfg(feature = "arrow")]
+fn read_ipc_stream(bytes: &[u8]) -> Result<RecordBatch> {
+    let mut reader = StreamReader::try_new(bytes, None)?;
+    let schema = reader.schema();
+    match reader.next() {
+        Some(batch) => Ok(batch?),
+        None => Ok(RecordBatch::new_empty(schema)),
+    }
+}
+
+// Files in a pybox checkout a preinitialized component is built from.
+// uv.lock pins componentize-py.
+const PREINIT_INPUTS: [&str; 4] = ["build_component.py", "guest.py", "sandbox.wit", "uv.lock"];
+
+/// Whether `name` is a dotted Python module name, so every part is an
+/// identifier and none can climb out of a directory.
+fn is_module_name(name: &str) -> bool {
+    name.split('.').all(|part| {
+        part.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// Where the component built from the checkout at `root` and preloaded
+/// with `modules` is cached. The name is a digest of the build's inputs
+/// and the set of modules, not their order, so editing the guest or
+/// bumping componentize-py builds a new one.
+fn preinitialized_path(root: &Path, modules: &[&str]) -> Result<PathBuf> {
+    let mut modules = modules.to_vec();
+    modules.sort_unstable();
+    modules.dedup();
+    for module in &modules {
+        if !is_module_name(module) {
+            return Err(anyhow!("Invalid module name {:?}", module));
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    for input in PREINIT_INPUTS {
+        let path = root.join(input);
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        // Lengths keep one input's bytes from passing for another's
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+    for module in &modules {
+        hasher.update(module.as_bytes());
+        hasher.update([0]);
+    }
+    let digest = audit::to_hex(&hasher.finalize());
+    Ok(root.join(format!("sandbox-preinit-{}.wasm", &digest[..16])))
+}
+
+/// Build a component with `modules` imported before componentize-py
+/// snapshots the interpreter, running the checkout's
+/// `build_component.py` with the componentize-py locked in its uv.lock.
+/// It is written next to `output` and moved there once complete.
+fn build_preinitialized(root: &Path, modules: &[&str], output: &Path) -> Result<()> {
+    let partial = output.with_extension("wasm.partial");
+    let result = Command::new("uv")
+        .current_dir(root)
+        .args(["run", "--frozen", "build_component.py", "-o"])
+        .arg(&partial)
+        .arg("--preload")
+        .args(modules)
+        .output()
+        .context("Failed to run build_component.py with uv")?;
+    if !result.status.success() {
+        let _ = fs::remove_file(&partial);
+        return Err(anyhow!(
+            "Failed to build preinitialized component: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    fs::rename(&partial, output).with_context(|| format!("Failed to write {}", output.display()))
+}
+
+/// How the engine compiles and caches the component. The default favours
+/// execution speed, `EngineOptions::fast` favours startup.
+///
+/// ```no_run
+/// use pybox::sandbox::{EngineOptions, OptLevel, PySandbox};
+///
+/// let sandbox = PySandbox::builder()
+///     .engine_options(EngineOptions {
+///         cranelift_opt_level: OptLevel::SpeedAndSize,
+///         ..EngineOptions::default()
+///     })
+///     .build()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineOptions {
+    /// Compiler used for the component.
+    pub strategy: Strategy,
+    /// Optimization level when compiling with Cranelift.
+    pub cranelift_opt_level: OptLevel,
+    /// Compile functions on multiple threads.
+    pub parallel_compilation: bool,
+    /// Cache compiled code on disk. If the cache can't be used, e.g.
+    /// because its directory isn't writable, compilation goes on without
+    /// it and a warning is logged.
+    pub cache: bool,
+    /// Where the cache is and how large it may grow.
+    pub cache_options: CacheOptions,
+    /// Preallocate instance slots instead of allocating on demand, see
+    /// `PoolingOptions`.
+    pub pooling: Option<PoolingOptions>,
+}
+
+/// Settings of the compilation cache. Unset fields come from Wasmtime's
+/// cache configuration file, if there is one, or its defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Directory compiled code is kept in, e.g. a writable volume in a
+    /// container. Defaults to the user's cache directory.
+    pub directory: Option<PathBuf>,
+    /// Total size in bytes the cache is trimmed back to, oldest first.
+    pub max_size_bytes: Option<u64>,
+    /// Number of files the cache is trimmed back to, oldest first.
+    pub max_files: Option<u64>,
+}
+
+impl CacheOptions {
+    fn cache(&self) -> Result<Cache> {
+        let mut config = CacheConfig::from_file(None)?;
+        if let Some(directory) = &self.directory {
+            config.with_directory(std::path::absolute(directory)?);
+        }
+        if let Some(bytes) = self.max_size_bytes {
+            config.with_files_total_size_soft_limit(bytes);
+        }
+        if let Some(files) = self.max_files {
+            config.with_file_count_soft_limit(files);
+        }
+        let cache = Cache::new(config)?;
+        // Wasmtime only checks that the directory exists
+        tempfile::tempfile_in(cache.directory()).with_context(|| {
+            format!("Cache directory {} is not writable", cache.directory().display())
+        })?;
+        Ok(cache)
+    }
+}
+
+/// Limits of the pooling instance allocator. Memory and tables for
+/// `max_instances` concurrent sandboxes are reserved up front and reused,
+/// which makes instantiation cheaper for services running many short
+/// executions. Instantiating beyond `max_instances` at once fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolingOptions {
+    /// Sandboxes that can be executing at the same time.
+    pub max_instances: u32,
+    /// Linear memory limit of each sandbox, in 64 KiB wasm pages.
+    pub max_memory_pages: u64,
+    /// Tables a sandbox can use.
+    pub max_tables: u32,
+    /// Elements each table can hold.
+    pub max_table_elements: usize,
+}
+
+impl Default for PoolingOptions {
+    fn default() -> Self {
+        Self {
+            max_instances: 100,
+            // 256 MiB
+            max_memory_pages: 4096,
+            max_tables: 8,
+            max_table_elements: 100_000,
+        }
+    }
+}
+
+impl PoolingOptions {
+    fn allocation_config(&self) -> PoolingAllocationConfig {
+        let instances = self.max_instances;
+        let mut pooling = PoolingAllocationConfig::new();
+        pooling
+            .total_component_instances(instances)
+            .total_core_instances(instances.saturating_mul(CORE_INSTANCES_PER_SANDBOX))
+            .total_memories(instances.saturating_mul(MEMORIES_PER_SANDBOX))
+            .total_tables(instances.saturating_mul(self.max_tables))
+            .max_memories_per_component(MEMORIES_PER_SANDBOX)
+            .max_tables_per_component(self.max_tables)
+            .max_tables_per_module(self.max_tables)
+            .table_elements(self.max_table_elements)
+            .max_memory_size((self.max_memory_pages * WASM_PAGE_BYTES) as usize);
+        pooling
+    }
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::Auto,
+            cranelift_opt_level: OptLevel::Speed,
+            parallel_compilation: true,
+            cache: true,
+            cache_options: CacheOptions::default(),
+            pooling: None,
+        }
+    }
+}
+
+impl EngineOptions {
+    /// Compile with Winch, Wasmtime's baseline compiler, for much faster
+    /// startup at the cost of execution speed.
+    pub fn fast() -> Self {
+        Self {
+            strategy: Strategy::Winch,
+            ..Self::default()
+        }
+    }
+
+    fn config(&self) -> Result<Config> {
+        let mut config = Config::new();
+        config.strategy(self.strategy);
+        config.cranelift_opt_level(self.cranelift_opt_level);
+        config.parallel_compilation(self.parallel_compilation);
+        if self.cache {
+            match self.cache_options.cache() {
+                Ok(cache) => {
+                    config.cache(Some(cache));
+                }
+                Err(e) => warn_cache_disabled(&e),
+            }
+        }
+        if let Some(pooling) = &self.pooling {
+            config.allocation_strategy(pooling.allocation_config());
+        }
+        // Enable epoch interruption for timeout support
+        config.epoch_interruption(true);
+        Ok(config)
+    }
+}
+
+/// Report that compiled code won't be cached, which only costs startup
+/// time so it isn't worth failing for.
+fn warn_cache_disabled(error: &anyhow::Error) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(error = %format!("{:#}", error), "compilation cache disabled");
+    #[cfg(not(feature = "tracing"))]
+    eprintln!("pybox: compilation cache disabled: {:#}", error);
+}
+
+/// What the guest may do with a mounted directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A host directory made visible to the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub host_path: PathBuf,
+    pub guest_path: String,
+    pub mode: MountMode,
+}
+
+/// Settings for the WASI context each execution gets.
+#[derive(Debug, Clone, Default)]
+struct WasiConfig {
+    mounts: Vec<Mount>,
+    work_dir: bool,
+    capture_figures: bool,
+    env: Vec<(String, String)>,
+    inherit_env: bool,
+    args: Vec<String>,
+    max_output_bytes: Option<usize>,
+    max_write_bytes: Option<usize>,
+    allowed_imports: Option<Vec<String>>,
+    blocked_imports: Vec<String>,
+    python_path: Vec<String>,
+    deterministic_seed: Option<u64>,
+    clock_policy: ClockPolicy,
+    random_policy: RandomPolicy,
+    host_fns: HostFns,
+    extensions: Extensions,
+    on_input: InputHandler,
+    on_progress: ProgressHandler,
+    timeout_grace: Option<Duration>,
+    line_budget: Option<u64>,
+    limits: Limits,
+    max_code_bytes: Option<usize>,
+    max_code_lines: Option<usize>,
+    #[cfg(feature = "validation")]
+    validator: Option<CodeValidator>,
+    #[cfg(feature = "msgpack")]
+    transport: Transport,
+    #[cfg(feature = "http")]
+    http: HttpPolicy,
+}
+
+/// How `exec_with_inputs` and `get_globals` move values across the
+/// component boundary.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    #[default]
+    Json,
+    /// Faster to encode and decode for large inputs, values still have
+    /// to be representable as JSON. Use `exec_with_inputs_msgpack` to
+    /// exchange bytes.
+    MessagePack,
+}
+
+impl WasiConfig {
+    /// Settings the guest applies to itself before running any code.
+    fn guest_settings(&self) -> String {
+        let mut settings = serde_json::Map::new();
+        if !self.args.is_empty() {
+            settings.insert("argv".to_string(), self.args.clone().into());
+        }
+        if !self.python_path.is_empty() {
+            settings.insert("path".to_string(), self.python_path.clone().into());
+        }
+        if let Some(allowed) = &self.allowed_imports {
+            settings.insert("allowed_imports".to_string(), allowed.clone().into());
+        }
+        if !self.blocked_imports.is_empty() {
+            settings.insert("blocked_imports".to_string(), self.blocked_imports.clone().into());
+        }
+        if self.capture_figures {
+            settings.insert("figure_dir".to_string(), FIGURES_GUEST_DIR.into());
+        }
+        if self.on_input.is_set() {
+            settings.insert("input".to_string(), true.into());
+        }
+        if self.timeout_grace.is_some() {
+            settings.insert("interrupt".to_string(), true.into());
+        }
+        if let Some(budget) = self.line_budget {
+            settings.insert("line_budget".to_string(), budget.into());
+        }
+        serde_json::Value::Object(settings).to_string()
+    }
+}
+
+/// Where per-execution work directories are created. Prefers a memory
+/// backed filesystem so artifacts never hit the disk.
+fn create_work_dir() -> Result<TempDir> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("pybox-work-");
+    let shm = Path::new(SHM_DIR);
+    let dir = if shm.is_dir() {
+        builder.tempdir_in(shm).or_else(|_| builder.tempdir())
+    } else {
+        builder.tempdir()
+    };
+    dir.context("Failed to create work directory")
+}
+
+/// Read every file under `root` into memory, keyed by path relative to
+/// `root` and sorted by path.
+fn collect_artifacts(root: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut artifacts = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                let relative = path.strip_prefix(root)?.to_path_buf();
+                artifacts.push((relative, fs::read(&path)?));
+            }
+        }
+    }
+    artifacts.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(artifacts)
+}
+
+/// Recursively copy the directory `src` to `dst`.
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `packages` and write `modules` into a fresh directory to mount at
+/// `SITE_PACKAGES_GUEST_DIR` and return it with the guest paths to add to
+/// `sys.path`. Wheels are imported in place with `zipimport` so only pure
+/// Python wheels work.
+fn build_site_packages(
+    packages: &[PathBuf],
+    modules: &[(String, String)],
+) -> Result<(TempDir, Vec<String>)> {
+    let dir = tempfile::Builder::new()
+        .prefix("pybox-site-")
+        .tempdir()
+        .context("Failed to create site-packages directory")?;
+    let mut python_path = vec![SITE_PACKAGES_GUEST_DIR.to_string()];
+
+    for package in packages {
+        let name = package
+            .file_name()
+            .ok_or_else(|| anyhow!("Package path {} has no file name", package.display()))?;
+        let target = dir.path().join(name);
+        if target.exists() {
+            return Err(anyhow!("Package {} was added twice", name.to_string_lossy()));
+        }
+
+        if package.is_dir() {
+            copy_dir(package, &target)
+                .with_context(|| format!("Failed to copy package {}", package.display()))?;
+        } else if package.is_file() && package.extension().is_some_and(|ext| ext == "whl") {
+            fs::copy(package, &target)
+                .with_context(|| format!("Failed to copy package {}", package.display()))?;
+            python_path.push(format!("{}/{}", SITE_PACKAGES_GUEST_DIR, name.to_string_lossy()));
+        } else {
+            return Err(anyhow!(
+                "Package {} must be a directory or a .whl file",
+                package.display()
+            ));
+        }
+    }
+
+    for (name, source) in modules {
+        write_module(dir.path(), name, source)?;
+    }
+
+    Ok((dir, python_path))
+}
+
+/// Write `source` as the module `name` under `dir`, creating the packages
+/// of a dotted name. Fails when the module or one of its packages would
+/// shadow, or be shadowed by, something already there.
+fn write_module(dir: &Path, name: &str, source: &str) -> Result<()> {
+    if !is_module_name(name) {
+        return Err(anyhow!("Module name '{}' is not a valid Python module name", name));
+    }
+
+    let parts: Vec<&str> = name.split('.').collect();
+    let (module, packages) = parts.split_last().expect("split always yields a part");
+    let mut parent = dir.to_path_buf();
+    for package in packages {
+        parent.push(package);
+        if parent.with_extension("py").exists() {
+            return Err(anyhow!("Module {} conflicts with module {}", name, package));
+        }
+        if !parent.is_dir() {
+            fs::create_dir(&parent)
+                .with_context(|| format!("Failed to create package for module {}", name))?;
+            fs::write(parent.join("__init__.py"), "")
+                .with_context(|| format!("Failed to create package for module {}", name))?;
+        }
+    }
+
+    let target = parent.join(format!("{}.py", module));
+    if target.exists() || parent.join(module).exists() {
+        return Err(anyhow!("Module {} was added twice", name));
+    }
+    fs::write(&target, source).with_context(|| format!("Failed to write module {}", name))
 }
 
-impl wasmtime_wasi::WasiView for MyWasi {
-    fn ctx(&mut self) -> WasiCtxView<'_> {
-        WasiCtxView {
-            ctx: &mut self.wasi_ctx,
-            table: &mut self.table,
+/// Create a fresh WASI state for a single execution. `extra_mounts` are
+/// added on top of the configured ones for this execution only, and
+/// printed output goes to `on_output` when set.
+fn wasi_state(
+    config: &WasiConfig,
+    extra_mounts: &[Mount],
+    on_output: Option<&OutputSink>,
+) -> Result<MyWasi> {
+    // Create a WASI context
+    let mut builder = WasiCtxBuilder::new();
+    // Print to the host's stdout and stderr by default. Stdin is left
+    // empty: the host's belongs to the embedder, e.g. `pybox rpc` reads
+    // its requests from it
+    builder.inherit_stdout();
+    builder.inherit_stderr();
+    let output = config.max_output_bytes.map(OutputBudget::new);
+    match (on_output, &output) {
+        (Some(sink), Some(budget)) => {
+            let stdout = StreamingOutput::new(Stream::Stdout, sink.clone());
+            let stderr = StreamingOutput::new(Stream::Stderr, sink.clone());
+            builder.stdout(LimitedOutput::new(stdout, budget.clone()));
+            builder.stderr(LimitedOutput::new(stderr, budget.clone()));
+        }
+        (Some(sink), None) => {
+            builder.stdout(StreamingOutput::new(Stream::Stdout, sink.clone()));
+            builder.stderr(StreamingOutput::new(Stream::Stderr, sink.clone()));
+        }
+        (None, Some(budget)) => {
+            builder.stdout(LimitedOutput::new(wasmtime_wasi::cli::stdout(), budget.clone()));
+            builder.stderr(LimitedOutput::new(wasmtime_wasi::cli::stderr(), budget.clone()));
+        }
+        (None, None) => {}
+    }
+
+    // Explicit variables are added last so they override inherited ones
+    if config.inherit_env {
+        builder.inherit_env();
+    }
+    for (key, value) in &config.env {
+        builder.env(key, value);
+    }
+    builder.args(&config.args);
+    if let Some(seed) = config.deterministic_seed {
+        deterministic::configure_wasi(&mut builder, seed);
+    }
+    config.clock_policy.configure_wasi(&mut builder);
+    config.random_policy.configure_wasi(&mut builder);
+
+    for mount in config.mounts.iter().chain(extra_mounts) {
+        let (dir_perms, file_perms) = match mount.mode {
+            MountMode::ReadOnly => (DirPerms::READ, FilePerms::READ),
+            MountMode::ReadWrite => (DirPerms::all(), FilePerms::all()),
+        };
+        builder
+            .preopened_dir(&mount.host_path, &mount.guest_path, dir_perms, file_perms)
+            .with_context(|| format!("Failed to mount {}", mount.host_path.display()))?;
+    }
+
+    let work_dir = if config.work_dir {
+        let dir = create_work_dir()?;
+        builder
+            .preopened_dir(dir.path(), WORK_GUEST_DIR, DirPerms::all(), FilePerms::all())
+            .context("Failed to mount work directory")?;
+        Some(dir)
+    } else {
+        None
+    };
+    let figure_dir = if config.capture_figures {
+        let dir = create_work_dir()?;
+        builder
+            .preopened_dir(dir.path(), FIGURES_GUEST_DIR, DirPerms::all(), FilePerms::all())
+            .context("Failed to mount figure directory")?;
+        Some(dir)
+    } else {
+        None
+    };
+
+    Ok(MyWasi {
+        wasi_ctx: builder.build(),
+        table: ResourceTable::new(),
+        tracker: ResourceTracker::new(&config.limits),
+        work_dir,
+        figure_dir,
+        output,
+        write_quota: config.max_write_bytes.map(|limit| WriteQuota::new(limit as u64)),
+        displays: Vec::new(),
+        host_fns: config.host_fns.clone(),
+        extensions: config.extensions.clone(),
+        extension_state: config.extensions.init_state(),
+        on_input: config.on_input.clone(),
+        on_progress: config.on_progress.clone(),
+        interrupt: Arc::default(),
+        over_budget: Arc::default(),
+        deny_random: config.random_policy == RandomPolicy::Deny,
+        #[cfg(feature = "http")]
+        http: HttpState::new(&config.http),
+    })
+}
+
+/// Hash of the engine settings that affect whether a precompiled
+/// component can be loaded by `engine`.
+fn engine_hash(engine: &Engine) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Check the header of a precompiled artifact and return the
+/// serialized component that follows it.
+fn check_precompiled<'a>(engine: &Engine, bytes: &'a [u8], path: &Path) -> Result<&'a [u8]> {
+    if bytes.len() < PRECOMPILED_HEADER_LEN
+        || &bytes[..PRECOMPILED_MAGIC.len()] != PRECOMPILED_MAGIC
+    {
+        return Err(anyhow!(
+            "{} is not a precompiled pybox component",
+            path.display()
+        ));
+    }
+    let mut hash = [0u8; 8];
+    hash.copy_from_slice(&bytes[PRECOMPILED_MAGIC.len()..PRECOMPILED_HEADER_LEN]);
+    if u64::from_le_bytes(hash) != engine_hash(engine) {
+        return Err(anyhow!(
+            "{} was precompiled with an incompatible engine configuration, rebuild it with `PySandbox::precompile_to`",
+            path.display()
+        ));
+    }
+    Ok(&bytes[PRECOMPILED_HEADER_LEN..])
+}
+
+// Async bindings for the same world, only usable with an engine that
+// has async support enabled.
+#[cfg(feature = "async")]
+mod async_bindings {
+    wasmtime::component::bindgen!({
+        path: "sandbox.wit",
+        world: "sandbox",
+        exports: { default: async },
+    });
+
+    impl From<PythonError> for crate::error::PythonError {
+        fn from(e: PythonError) -> Self {
+            Self {
+                exception: crate::error::PyException::from_name(&e.exception_type),
+                message: e.message,
+                lineno: e.lineno,
+            }
+        }
+    }
+
+    impl SandboxImports for super::MyWasi {
+        fn host_call(&mut self, name: String, args: String) -> Result<String, String> {
+            self.host_fns.call(&name, &args)
+        }
+
+        fn extension_call(
+            &mut self,
+            extension: String,
+            method: String,
+            payload: String,
+        ) -> Result<String, String> {
+            self.extensions.call(&extension, &method, &payload)
+        }
+
+        fn display(&mut self, mime_type: String, data: Vec<u8>) {
+            self.displays.push(super::DisplayData { mime_type, data });
+        }
+
+        fn read_input(&mut self, prompt: String) -> Option<String> {
+            self.on_input.read(&prompt)
+        }
+
+        fn report_progress(&mut self, percent: f32, message: String) {
+            self.on_progress.report(percent, &message);
+        }
+
+        fn interrupt_requested(&mut self) -> bool {
+            self.interrupt.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn line_budget_exceeded(&mut self, executed: u64) {
+            self.report_over_budget(executed);
+        }
+    }
+}
+
+/// Aborts a running `exec_with_handle` call from another thread.
+///
+/// Handles are cheap to clone and can be sent across threads.
+#[derive(Clone)]
+pub struct CancelHandle {
+    engine: Engine,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Stop the execution using this handle as soon as possible. The
+    /// call returns `PyboxError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Wake up the store so the epoch callback sees the flag
+        self.engine.increment_epoch();
+    }
+
+    /// Whether `cancel` has been called on this handle.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Cancels an execution when dropped unless disarmed, for executions
+/// whose future was dropped.
+#[cfg(feature = "tokio-rt")]
+struct CancelOnDrop(Option<CancelHandle>);
+
+#[cfg(feature = "tokio-rt")]
+impl CancelOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+#[cfg(feature = "tokio-rt")]
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.0 {
+            handle.cancel();
+        }
+    }
+}
+
+/// Settings that apply to a single execution, for running snippets with
+/// different budgets on the same warm sandbox.
+#[derive(Clone, Default)]
+pub struct ExecOptions {
+    /// Overrides the sandbox's `timeout_seconds` for this call.
+    pub timeout: Option<Duration>,
+    /// Lets another thread abort this call, see `PySandbox::cancel_handle`.
+    pub cancel: Option<CancelHandle>,
+    /// Return printed output in `ExecOutput::stdout` and `stderr` instead
+    /// of writing it to the process streams.
+    pub capture_output: bool,
+    /// Receive printed output while the code runs instead of it going to
+    /// the process streams. Ignored with `capture_output`, and for
+    /// notebook cells, which capture their own output.
+    pub on_output: Option<OutputSink>,
+}
+
+/// The result of an execution along with statistics about the run.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutput {
+    /// The json serialized value of the last expression.
+    pub value: String,
+    /// Text printed to stdout, only set with `ExecOptions::capture_output`.
+    pub stdout: String,
+    /// Text printed to stderr, only set with `ExecOptions::capture_output`.
+    pub stderr: String,
+    pub stats: ExecStats,
+    /// Files the code left in `/work`, relative to it. Only collected
+    /// when the builder enables `work_dir`.
+    pub artifacts: Vec<(PathBuf, Vec<u8>)>,
+    /// PNGs of the matplotlib figures the code rendered, in order. Only
+    /// collected when the builder enables `capture_figures`.
+    pub figures: Vec<Vec<u8>>,
+    /// Rich outputs the code sent with `pybox.display(obj, mime=...)`, in
+    /// the order they were displayed.
+    pub displays: Vec<DisplayData>,
+}
+
+impl ExecOutput {
+    /// The value of the last expression, parsed. Fails with an
+    /// `InvalidJson` error if the guest returned something else.
+    pub fn json(&self) -> Result<Value> {
+        parse_json(&self.value)
+    }
+}
+
+fn parse_json(value: &str) -> Result<Value> {
+    serde_json::from_str(value).map_err(|e| {
+        InvalidJson {
+            value: value.to_string(),
+            reason: e.to_string(),
+        }
+        .into()
+    })
+}
+
+/// A MIME typed output such as `text/html`, `image/png` or
+/// `application/json`, for notebook style rendering.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayData {
+    pub mime_type: String,
+    /// The output in its MIME type's encoding, UTF-8 for text types.
+    pub data: Vec<u8>,
+}
+
+/// Resources used by a single execution, for metering untrusted
+/// workloads and tuning limits.
+#[derive(Debug, Clone, Default)]
+pub struct ExecStats {
+    /// Time spent in the call including instantiation.
+    pub wall_time: Duration,
+    /// Fuel used, only set when a fuel limit is configured.
+    pub fuel_consumed: Option<u64>,
+    /// Fuel left over, only set when a fuel limit is configured.
+    pub fuel_remaining: Option<u64>,
+    /// Total linear memory the guest had allocated at its peak.
+    pub peak_memory_bytes: usize,
+    /// Whether an epoch tick interrupted the guest to check for timeouts
+    /// or cancellation.
+    pub epoch_interrupted: bool,
+    /// Whether the code ran past its timeout and stopped within the
+    /// `timeout_grace` period after being interrupted.
+    pub interrupted: bool,
+    /// Whether stdout or stderr hit the `max_output_bytes` limit.
+    pub output_truncated: bool,
+    /// CPU time of the calling thread during the call, which is what a
+    /// `Quota` charges.
+    pub cpu_time: Duration,
+}
+
+/// A sandboxed Python execution environment using WebAssembly.
+///
+/// Cloning is cheap and shares the compiled component with the clone.
+#[derive(Clone)]
+pub struct PySandbox {
+    // Kept so the async engine can be derived from the same settings
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    config: Config,
+    engine: Engine,
+    // Shared by clones and swapped by `reload_component`, executions hold
+    // on to the one they started with
+    loaded: Arc<RwLock<Arc<Loaded>>>,
+    fuel_limit: Option<u64>,
+    wasi: WasiConfig,
+    component_variant: Option<String>,
+    // Interrupts runs past their timeout, shared by clones
+    timer: Arc<DeadlineTimer>,
+    // Copies of the packages from `add_package`, shared between clones
+    site_packages: Option<Arc<TempDir>>,
+    metrics: MetricsSink,
+    audit: Auditor,
+    quota: QuotaTracker,
+    last_run: LastRun,
+    // Whether `last_run` is kept after a plain execution
+    keep_globals: bool,
+    auto_recover: bool,
+    pub timeout_seconds: u64,
+}
+
+/// A compiled component and everything derived from it.
+struct Loaded {
+    component: Component,
+    // Linked once up front so each exec only needs a store and instantiation
+    instance_pre: SandboxPre<MyWasi>,
+    // Kept so the async engine can load the same component, and reloads
+    // are checked against its digest
+    source: ComponentSource,
+    // Tells which count limit a failed instantiation ran into
+    counts: ComponentCounts,
+    // Compiled on first use
+    #[cfg(feature = "async")]
+    async_runtime: Mutex<Option<AsyncRuntime>>,
+}
+
+/// The async engine, its pre-linked component and the timer that ticks
+/// its epoch so async runs yield.
+#[cfg(feature = "async")]
+#[derive(Clone)]
+struct AsyncRuntime {
+    engine: Engine,
+    instance_pre: async_bindings::SandboxPre<MyWasi>,
+    timer: Arc<DeadlineTimer>,
+}
+
+impl Loaded {
+    fn new(
+        engine: &Engine,
+        (component, counts): (Component, ComponentCounts),
+        source: ComponentSource,
+        extensions: &Extensions,
+    ) -> Result<Self> {
+        Ok(Self {
+            instance_pre: link(engine, &component, extensions)?,
+            component,
+            source,
+            counts,
+            #[cfg(feature = "async")]
+            async_runtime: Mutex::default(),
+        })
+    }
+}
+
+/// Where a component was loaded from, `sandbox.wasm` unless `path` is
+/// set, and the digest it was pinned to.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "async"), allow(dead_code))]
+struct ComponentSource {
+    path: Option<PathBuf>,
+    sha256: Option<String>,
+}
+
+/// The store and instance left behind by the most recent notebook cell,
+/// or execution with `keep_globals`. Clones of a sandbox start without one.
+#[derive(Default)]
+struct LastRun(Option<(Store<MyWasi>, Sandbox)>);
+
+impl Clone for LastRun {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+/// Flags set by the epoch callback installed by `arm_deadline`. The
+/// timeout stops being tracked once this is dropped.
+struct Deadline {
+    timeout_triggered: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>,
+    epoch_interrupted: Arc<AtomicBool>,
+    _timer: DeadlineGuard,
+    _grace_timer: Option<DeadlineGuard>,
+}
+
+impl Deadline {
+    /// Whether the run went past its timeout, even if it then stopped
+    /// within the grace period.
+    fn passed(&self) -> bool {
+        self.timeout_triggered.load(Ordering::SeqCst) || self.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+/// Configures and creates a `PySandbox`.
+///
+/// ```no_run
+/// use pybox::sandbox::PySandbox;
+///
+/// let mut sandbox = PySandbox::builder()
+///     .timeout_seconds(5)
+///     .fuel_limit(1_000_000_000)
+///     .build()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PySandboxBuilder {
+    timeout_seconds: Option<u64>,
+    fuel_limit: Option<u64>,
+    engine: EngineOptions,
+    packages: Vec<PathBuf>,
+    // Sources from `add_module` by module name
+    modules: Vec<(String, String)>,
+    // Load this component instead of `sandbox.wasm`
+    component_path: Option<PathBuf>,
+    // Hex SHA-256 the component must have
+    component_sha256: Option<String>,
+    // Looked up in `registry`, or the `PYBOX_COMPONENTS` file, on build
+    component_variant: Option<String>,
+    registry: Option<ComponentRegistry>,
+    wasi: WasiConfig,
+    metrics: MetricsSink,
+    audit: Auditor,
+    quota: Option<Quota>,
+    keep_globals: bool,
+    auto_recover: bool,
+    // Set by `SandboxFactory`, reused instead of compiling the component
+    compiled: Option<Arc<Compiled>>,
+}
+
+impl PySandboxBuilder {
+    /// Wall-clock limit for each execution. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
+    pub fn timeout_seconds(mut self, timeout_seconds: u64) -> Self {
+        self.timeout_seconds = Some(timeout_seconds);
+        self
+    }
+
+    /// Let code that runs past its timeout stop on its own within `grace`
+    /// before it is killed. At the timeout `KeyboardInterrupt` is raised
+    /// in the running code, so `finally` blocks run and handlers can
+    /// return partial results. Unhandled, it fails with
+    /// `PyboxError::Timeout`. Code still running at the end of `grace`,
+    /// e.g. stuck in a long call into C, is killed as usual.
+    ///
+    /// Watching for the interrupt traces the running Python code, which
+    /// slows it down, so there is no grace period unless set.
+    pub fn timeout_grace(mut self, grace: Duration) -> Self {
+        self.wasi.timeout_grace = Some(grace);
+        self
+    }
+
+    /// Limit each execution to `fuel` units of work, roughly one per wasm
+    /// instruction. Running out fails with `PyboxError::FuelExhausted`.
+    ///
+    /// Fuel metering slows down execution so it is disabled unless set.
+    pub fn fuel_limit(mut self, fuel: u64) -> Self {
+        self.fuel_limit = Some(fuel);
+        self
+    }
+
+    /// Limit each execution to running `lines` lines of Python, counted
+    /// in the guest, including lines of the modules the code calls into.
+    /// Past it `pybox.ExecutionBudgetExceeded` is raised in the running
+    /// code. Handlers can clean up, but the execution fails with
+    /// `PyboxError::ExecutionBudgetExceeded` either way.
+    ///
+    /// Unlike `fuel_limit` this counts Python-level work, independent of
+    /// how much wasm the interpreter runs for it. Counting traces the
+    /// running code, which slows it down, so there is no budget unless set.
+    ///
+    /// The budget is not a security boundary: code can stop the count
+    /// with `sys.settrace(None)`. Bound untrusted code with `fuel_limit`
+    /// and the timeout, which the host enforces.
+    pub fn line_budget(mut self, lines: u64) -> Self {
+        self.wasi.line_budget = Some(lines);
+        self
+    }
+
+    /// Limit each linear memory of the guest to `bytes`. Allocations past
+    /// it raise MemoryError in the guest, and if the code doesn't recover
+    /// the execution fails with `PyboxError::MemoryLimitExceeded`.
+    pub fn max_memory_bytes(mut self, bytes: usize) -> Self {
+        self.wasi.limits.memory_bytes = Some(bytes);
+        self
+    }
+
+    /// Limit the number of elements each table can grow to.
+    pub fn max_table_elements(mut self, elements: usize) -> Self {
+        self.wasi.limits.table_elements = Some(elements);
+        self
+    }
+
+    /// Limit the number of core instances created per execution.
+    pub fn max_instances(mut self, instances: usize) -> Self {
+        self.wasi.limits.instances = Some(instances);
+        self
+    }
+
+    /// Limit the number of tables created per execution.
+    pub fn max_tables(mut self, tables: usize) -> Self {
+        self.wasi.limits.tables = Some(tables);
+        self
+    }
+
+    /// Limit the number of linear memories created per execution.
+    pub fn max_memories(mut self, memories: usize) -> Self {
+        self.wasi.limits.memories = Some(memories);
+        self
+    }
+
+    /// Set all the store limits at once, replacing the ones set with the
+    /// `max_*` methods. `None` leaves a resource unlimited.
+    ///
+    /// ```no_run
+    /// use pybox::sandbox::{PySandbox, StoreLimitsConfig};
+    ///
+    /// let mut sandbox = PySandbox::builder()
+    ///     .limits(StoreLimitsConfig {
+    ///         memory: Some(256 << 20),
+    ///         instances: Some(64),
+    ///         ..StoreLimitsConfig::default()
+    ///     })
+    ///     .build()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn limits(mut self, limits: StoreLimitsConfig) -> Self {
+        self.wasi.limits = Limits {
+            memory_bytes: limits.memory,
+            table_elements: limits.table_elements,
+            instances: limits.instances,
+            tables: limits.tables,
+            memories: limits.memories,
+            wasm_stack: self.wasi.limits.wasm_stack,
+        };
+        self
+    }
+
+    /// Limit the wasm stack to `bytes`. Deep recursion past it fails with
+    /// `PyboxError::LimitExceeded(ResourceLimit::Stack)`.
+    pub fn max_wasm_stack(mut self, bytes: usize) -> Self {
+        self.wasi.limits.wasm_stack = Some(bytes);
+        self
+    }
+
+    /// Make the host directory `host_path` available to guest code at
+    /// `guest_path`. Nothing else on the host filesystem is reachable.
+    ///
+    /// ```no_run
+    /// use pybox::sandbox::{MountMode, PySandbox};
+    ///
+    /// let mut sandbox = PySandbox::builder()
+    ///     .mount("./datasets", "/data", MountMode::ReadOnly)
+    ///     .mount("./scratch", "/out", MountMode::ReadWrite)
+    ///     .build()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn mount(
+        mut self,
+        host_path: impl Into<PathBuf>,
+        guest_path: impl Into<String>,
+        mode: MountMode,
+    ) -> Self {
+        self.wasi.mounts.push(Mount {
+            host_path: host_path.into(),
+            guest_path: guest_path.into(),
+            mode,
+        });
+        self
+    }
+
+    /// Set an environment variable visible to guest code via `os.environ`.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.wasi.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set several environment variables, see `env`.
+    pub fn envs<K, V>(mut self, vars: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.wasi
+            .env
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Expose the host process's entire environment to guest code. This
+    /// can leak secrets such as API keys, prefer `env` for anything
+    /// running untrusted code.
+    pub fn inherit_env(mut self) -> Self {
+        self.wasi.inherit_env = true;
+        self
+    }
+
+    /// Cap the combined bytes guest code can write to stdout and stderr in
+    /// a single execution. Output past the limit is replaced with a
+    /// truncation marker and `ExecStats::output_truncated` is set.
+    pub fn max_output_bytes(mut self, limit: usize) -> Self {
+        self.wasi.max_output_bytes = Some(limit);
+        self
+    }
+
+    /// Cap the bytes guest code can write to files in a single execution,
+    /// across `/work`, `/figures` and read-write mounts. Writes past the
+    /// limit fail with `OSError` (`ENOSPC`) as if the disk were full.
+    /// Work directories live in `/dev/shm` where it exists, so without a
+    /// cap guest code can use up the host's memory through them. Bytes
+    /// are counted as written, deleting a file doesn't give them back.
+    pub fn max_write_bytes(mut self, limit: usize) -> Self {
+        self.wasi.max_write_bytes = Some(limit);
+        self
+    }
+
+    /// Let guest code make outbound HTTP requests with `wasi:http` to these
+    /// hosts, given as `host` or `host:port`. Requests anywhere else are
+    /// refused with `HTTP-request-denied`, and without this call every
+    /// request is.
+    #[cfg(feature = "http")]
+    pub fn allow_http<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.wasi.http.allow(hosts);
+        self
+    }
+
+    /// Limit how many response bytes a single HTTP request, and all
+    /// requests in one execution, may read. Reads past a limit fail with
+    /// `HTTP-response-body-size`.
+    #[cfg(feature = "http")]
+    pub fn http_byte_limits(mut self, per_request: Option<u64>, total: Option<u64>) -> Self {
+        self.wasi.http.max_request_bytes = per_request;
+        self.wasi.http.max_total_bytes = total;
+        self
+    }
+
+    /// Only let guest code import these top level modules, anything else
+    /// raises `ImportError`. Modules imported this way can still use their
+    /// own dependencies. The check is made by the guest's `import`
+    /// statement, so code can get around it, e.g. through `sys.modules` or
+    /// `importlib`: it is a best-effort guard against mistakes, not a
+    /// replacement for the wasm sandbox.
+    pub fn allowed_imports<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.wasi.allowed_imports = Some(modules.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Keep guest code from importing these top level modules with an
+    /// `import` statement, best-effort like `allowed_imports`. It doesn't
+    /// stop code that is determined to reach them, e.g. through
+    /// `sys.modules` or `importlib`; what the guest can see is bounded by
+    /// the mounts it is given, so leave out anything it must not read.
+    pub fn blocked_imports<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.wasi.blocked_imports = modules.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the command line seen by guest code as `sys.argv`. The first
+    /// element is conventionally the script name.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.wasi.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Make every execution reproducible: randomness comes from `seed`,
+    /// clocks start at 2000-01-01 and only advance when read, and wasm
+    /// features with host dependent results are disabled. The same code
+    /// and inputs then always produce the same result.
+    ///
+    /// Only Cranelift canonicalizes NaNs, so `build` fails when this is
+    /// combined with the Winch strategy of `fast_compilation`.
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.wasi.deterministic_seed = Some(seed);
+        self
+    }
+
+    /// Control what guest code can read from the clocks, e.g. freeze
+    /// `time.time()` for replays or hide the wall clock from untrusted
+    /// code. Overrides the wall clock of `deterministic`.
+    ///
+    /// ```no_run
+    /// # use pybox::deterministic::ClockPolicy;
+    /// # use pybox::sandbox::PySandbox;
+    /// use std::time::{Duration, UNIX_EPOCH};
+    ///
+    /// let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    /// let mut sandbox = PySandbox::builder().clock_policy(ClockPolicy::FrozenAt(at)).build()?;
+    /// assert_eq!(sandbox.exec("import time\nint(time.time())")?, "1700000000");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn clock_policy(mut self, policy: ClockPolicy) -> Self {
+        self.wasi.clock_policy = policy;
+        self
+    }
+
+    /// Control where `random`, `os.urandom`, `secrets` and the rest get
+    /// entropy: seed it so runs are reproducible without virtual clocks,
+    /// or deny it so code that needs it fails instead of running.
+    /// Overrides the randomness of `deterministic`.
+    ///
+    /// ```no_run
+    /// # use pybox::deterministic::RandomPolicy;
+    /// # use pybox::error::PyboxError;
+    /// # use pybox::sandbox::PySandbox;
+    /// let mut sandbox = PySandbox::builder().random(RandomPolicy::Deny).build()?;
+    /// let err = sandbox.exec("import os\nos.urandom(8)").unwrap_err();
+    /// assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::RandomDenied));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn random(mut self, policy: RandomPolicy) -> Self {
+        self.wasi.random_policy = policy;
+        self
+    }
+
+    /// Apply a `SandboxPolicy`. It replaces whatever the builder had for
+    /// the timeout, fuel, store limits, output and write caps, mounts,
+    /// `/work`, environment, clock, randomness, imports and network, so
+    /// store limits and clock or import settings the policy doesn't
+    /// name go back to their defaults. Later calls can still adjust them.
+    pub fn policy(mut self, policy: SandboxPolicy) -> Self {
+        self.timeout_seconds = Some(policy.timeout_seconds);
+        self.fuel_limit = policy.fuel_limit;
+        self.wasi.limits = Limits {
+            memory_bytes: policy.max_memory_bytes,
+            ..Limits::default()
+        };
+        self.wasi.max_output_bytes = policy.max_output_bytes;
+        self.wasi.max_write_bytes = policy.max_write_bytes;
+        self.wasi.mounts = policy.mounts;
+        self.wasi.work_dir = policy.work_dir;
+        self.wasi.env = policy.env;
+        self.wasi.inherit_env = false;
+        self.wasi.deterministic_seed = policy.deterministic_seed;
+        self.wasi.clock_policy = ClockPolicy::default();
+        self.wasi.random_policy = RandomPolicy::default();
+        self.wasi.allowed_imports = None;
+        self.wasi.blocked_imports = policy.blocked_imports;
+        #[cfg(feature = "http")]
+        {
+            self.wasi.http = HttpPolicy::default();
+            self.wasi.http.allow(&policy.http_allowlist);
+        }
+        self
+    }
+
+    /// Reject code longer than `bytes` with `PyboxError::CodeTooLarge`
+    /// before it reaches the runtime.
+    pub fn max_code_bytes(mut self, bytes: usize) -> Self {
+        self.wasi.max_code_bytes = Some(bytes);
+        self
+    }
+
+    /// Reject code with more than `lines` lines, like `max_code_bytes`.
+    pub fn max_lines(mut self, lines: usize) -> Self {
+        self.wasi.max_code_lines = Some(lines);
+        self
+    }
+
+    /// Choose how `exec_with_inputs` and `get_globals` exchange values
+    /// with the guest, JSON by default.
+    #[cfg(feature = "msgpack")]
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.wasi.transport = transport;
+        self
+    }
+
+    /// Check code with `validator` before running it. Code it rejects
+    /// fails with a `ValidationError` without using the sandbox.
+    #[cfg(feature = "validation")]
+    pub fn validator(mut self, validator: CodeValidator) -> Self {
+        self.wasi.validator = Some(validator);
+        self
+    }
+
+    /// Register a function guest code can call as
+    /// `pybox.host.call(name, args)`. It receives the JSON arguments and
+    /// returns a JSON result, errors are raised in the guest as
+    /// `pybox.host.HostCallError`. Host functions are the only way code in
+    /// the sandbox can reach the outside world, so treat `args` as
+    /// untrusted input.
+    pub fn host_fn<F>(mut self, name: impl Into<String>, function: F) -> Self
+    where
+        F: Fn(Value) -> Result<Value> + Send + Sync + 'static,
+    {
+        self.wasi.host_fns.insert(name.into(), Arc::new(function) as HostFn);
+        self
+    }
+
+    /// Answer guest calls to `input()` with `callback`, which receives the
+    /// prompt and returns the line entered, or `None` to raise `EOFError`
+    /// as at the end of input. Without it `input()` reads the empty stdin
+    /// and raises `EOFError` straight away.
+    ///
+    /// The prompt goes to the callback instead of stdout. Time spent in
+    /// the callback counts toward the timeout, raise it when waiting on a
+    /// person.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let mut sandbox = PySandbox::builder()
+    ///     .on_input(|prompt| {
+    ///         assert_eq!(prompt, "Name? ");
+    ///         Some("Ada".to_string())
+    ///     })
+    ///     .build()?;
+    /// assert_eq!(sandbox.exec("'Hello ' + input('Name? ')")?, "\"Hello Ada\"");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn on_input<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.wasi.on_input = InputHandler::new(Arc::new(callback) as InputFn);
+        self
+    }
+
+    /// Call `callback` with the progress guest code reports with
+    /// `pybox.progress(percent, message)`, as it runs. `percent` is
+    /// clamped to 0 to 100. The guest waits for the callback, so keep it
+    /// quick, e.g. by forwarding to a channel.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let mut sandbox = PySandbox::builder()
+    ///     .on_progress(|percent, message| eprintln!("{:.0}% {}", percent, message))
+    ///     .build()?;
+    /// sandbox.exec("import pybox\nfor i in range(10):\n    pybox.progress(i * 10, f'step {i}')")?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(f32, &str) + Send + Sync + 'static,
+    {
+        self.wasi.on_progress = ProgressHandler::new(Arc::new(callback) as ProgressFn);
+        self
+    }
+
+    /// Add an extension, see `SandboxExtension`. Registering a second
+    /// extension with the same name replaces the first.
+    pub fn extension(mut self, extension: impl SandboxExtension + 'static) -> Self {
+        self.wasi.extensions.insert(Arc::new(extension));
+        self
+    }
+
+    /// Report executions to `metrics`, see `Metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = MetricsSink::new(metrics);
+        self
+    }
+
+    /// Limit how much this sandbox and its clones may run, see `Quota`.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Keep the interpreter of the most recent execution once it finishes,
+    /// so `get_globals`, `get_global_msgpack` and `call_function` can read
+    /// from it. It holds on to the instance's memory until the next
+    /// execution, which is why it is off by default. `Session`s always
+    /// keep theirs.
+    pub fn keep_globals(mut self, enabled: bool) -> Self {
+        self.keep_globals = enabled;
+        self
+    }
+
+    /// Have `Session`s over this sandbox snapshot their variables after
+    /// every cell and, when a cell traps or times out and takes the
+    /// interpreter with it, restore them into a fresh one, see
+    /// `Session::recover`. Snapshots pickle every variable, which adds to
+    /// each cell's run time.
+    pub fn auto_recover(mut self, enabled: bool) -> Self {
+        self.auto_recover = enabled;
+        self
+    }
+
+    /// Record every execution to `sink`, see `AuditSink`.
+    pub fn audit(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.audit = Auditor::new(Arc::new(sink));
+        self
+    }
+
+    /// Load the component from `path` instead of `sandbox.wasm`, for
+    /// example one built from a world that extends `sandbox.wit` with the
+    /// imports of an extension. It must still export the `sandbox` world.
+    pub fn component_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.component_path = Some(path.into());
+        self
+    }
+
+    /// Refuse to load a component whose SHA-256 isn't `digest`, given as
+    /// hex. The component runs with whatever the host grants it, so pin
+    /// it when `sandbox.wasm` could be replaced by someone else.
+    pub fn expected_sha256(mut self, digest: impl Into<String>) -> Self {
+        self.component_sha256 = Some(digest.into());
+        self
+    }
+
+    /// Load the component registered as `name`, e.g. `py312-datasci`,
+    /// in the builder's `component_registry` or, without one, the
+    /// registry file `PYBOX_COMPONENTS` points to. Takes the place of
+    /// `component_file` and `expected_sha256`. `PySandbox::version`
+    /// reports the variant in use.
+    pub fn component_variant(mut self, name: impl Into<String>) -> Self {
+        self.component_variant = Some(name.into());
+        self
+    }
+
+    /// Look up `component_variant` names in `registry`, see
+    /// `ComponentRegistry`.
+    pub fn component_registry(mut self, registry: ComponentRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Use the component with numpy and pandas built in. Native extensions
+    /// can't be loaded at runtime in wasm, so they are linked in when the
+    /// component is built from wasi wheels:
+    ///
+    /// ```text
+    /// python build_component.py --scientific path/to/wasi-wheels
+    /// ```
+    ///
+    /// which writes `sandbox-scientific.wasm` with both modules already
+    /// imported.
+    pub fn with_scientific_stack(self) -> Self {
+        self.component_file(SCIENTIFIC_WASM)
+    }
+
+    /// Make a pure Python package importable by guest code. `path` is either
+    /// a package directory, which is copied in under its own name, or a
+    /// `.whl` file. Packages are mounted read-only at `/site-packages`.
+    pub fn add_package(mut self, path: impl Into<PathBuf>) -> Self {
+        self.packages.push(path.into());
+        self
+    }
+
+    /// Make `source` importable by guest code as the module `name`, e.g.
+    /// to give user code a curated set of helpers without rebuilding the
+    /// component. A dotted name like `helpers.text` creates the packages
+    /// above the module. Modules are mounted read-only at `/site-packages`
+    /// along with the packages from `add_package`.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let mut sandbox = PySandbox::builder()
+    ///     .add_module("helpers", "def double(x):\n    return 2 * x\n")
+    ///     .build()?;
+    /// assert_eq!(sandbox.exec("import helpers\nhelpers.double(21)")?, "42");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn add_module(mut self, name: impl Into<String>, source: impl Into<String>) -> Self {
+        self.modules.push((name.into(), source.into()));
+        self
+    }
+
+    /// Give each execution an empty, private `/work` directory and return
+    /// the files the code leaves there as `ExecOutput::artifacts`. Nothing
+    /// is shared between executions or with the host filesystem. The
+    /// directory is memory backed where possible, cap what code can write
+    /// to it with `max_write_bytes`.
+    pub fn work_dir(mut self, enabled: bool) -> Self {
+        self.wasi.work_dir = enabled;
+        self
+    }
+
+    /// Render matplotlib figures with the headless Agg backend and return
+    /// them as PNGs in `ExecOutput::figures`. A figure is captured when
+    /// it is shown, saved, or still open when the code finishes. The
+    /// component must bundle matplotlib, otherwise nothing is captured.
+    pub fn capture_figures(mut self, enabled: bool) -> Self {
+        self.wasi.capture_figures = enabled;
+        self
+    }
+
+    /// Compile with the Winch baseline compiler, trading execution speed
+    /// for much faster startup. This is what `new_for_test` uses.
+    pub fn fast_compilation(mut self, enabled: bool) -> Self {
+        self.engine = if enabled {
+            EngineOptions::fast()
+        } else {
+            EngineOptions::default()
+        };
+        self
+    }
+
+    /// Cache compiled code on disk, which is on by default. See
+    /// `EngineOptions::cache`.
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.engine.cache = enabled;
+        self
+    }
+
+    /// Keep the compilation cache in `directory`, created if needed,
+    /// instead of the user's cache directory.
+    pub fn cache_dir(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.engine.cache_options.directory = Some(directory.into());
+        self
+    }
+
+    /// Trim the compilation cache back to about `bytes` when it grows
+    /// past that.
+    pub fn cache_size_limit(mut self, bytes: u64) -> Self {
+        self.engine.cache_options.max_size_bytes = Some(bytes);
+        self
+    }
+
+    /// Choose the compiler and its settings, see `EngineOptions`.
+    pub fn engine_options(mut self, options: EngineOptions) -> Self {
+        self.engine = options;
+        self
+    }
+
+    /// Point the component settings at the registered `component_variant`.
+    fn resolve_variant(&mut self) -> Result<()> {
+        let Some(name) = &self.component_variant else {
+            return Ok(());
+        };
+        let registry = match &self.registry {
+            Some(registry) => registry.clone(),
+            None => ComponentRegistry::from_env()?,
+        };
+        let variant = registry.resolve(name)?;
+        self.component_path = Some(variant.path.clone());
+        self.component_sha256 = variant.sha256.clone();
+        Ok(())
+    }
+
+    /// Create the engine, compile the component and return the sandbox.
+    pub fn build(mut self) -> Result<PySandbox> {
+        self.resolve_variant()?;
+        if self.wasi.deterministic_seed.is_some() && matches!(self.engine.strategy, Strategy::Winch)
+        {
+            return Err(anyhow!(
+                "Deterministic mode needs the Cranelift compiler, Winch doesn't canonicalize NaNs"
+            ));
+        }
+        if let Some(path) = &self.component_path
+            && path == Path::new(SCIENTIFIC_WASM)
+            && !path.exists()
+        {
+            return Err(anyhow!(
+                "{} not found, build it with `python build_component.py --scientific <wheels>`",
+                SCIENTIFIC_WASM
+            ));
+        }
+
+        for mount in &self.wasi.mounts {
+            if !mount.host_path.is_dir() {
+                return Err(anyhow!(
+                    "Mount source {} is not a directory",
+                    mount.host_path.display()
+                ));
+            }
+        }
+
+        let compiled = match self.compiled.take() {
+            Some(compiled) => {
+                if compiled.settings != self.compile_settings() {
+                    return Err(anyhow!(
+                        "The engine options, component, fuel metering, wasm stack and \
+                         deterministic mode of a factory's sandboxes can't be changed"
+                    ));
+                }
+                compiled
+            }
+            None => Arc::new(self.compile()?),
+        };
+
+        let site_packages = if self.packages.is_empty() && self.modules.is_empty() {
+            None
+        } else {
+            let (dir, python_path) = build_site_packages(&self.packages, &self.modules)?;
+            self.wasi.mounts.push(Mount {
+                host_path: dir.path().to_path_buf(),
+                guest_path: SITE_PACKAGES_GUEST_DIR.to_string(),
+                mode: MountMode::ReadOnly,
+            });
+            self.wasi.python_path = python_path;
+            Some(Arc::new(dir))
+        };
+
+        let timeout_seconds = self.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+        let source = ComponentSource {
+            path: self.component_path,
+            sha256: self.component_sha256,
+        };
+        let mut sandbox = PySandbox::from_parts(
+            compiled.config.clone(),
+            compiled.engine.clone(),
+            ((compiled.component.clone(), compiled.counts), source),
+            compiled.timer.clone(),
+            timeout_seconds,
+            self.wasi,
+        )?;
+        sandbox.fuel_limit = self.fuel_limit;
+        sandbox.component_variant = self.component_variant;
+        sandbox.site_packages = site_packages;
+        sandbox.metrics = self.metrics;
+        sandbox.audit = self.audit;
+        sandbox.quota = self.quota.map(QuotaTracker::new).unwrap_or_default();
+        sandbox.keep_globals = self.keep_globals;
+        sandbox.auto_recover = self.auto_recover;
+        Ok(sandbox)
+    }
+
+    /// The settings that go into compiling the component.
+    fn compile_settings(&self) -> CompileSettings {
+        CompileSettings {
+            engine: self.engine.clone(),
+            component_path: self.component_path.clone(),
+            component_sha256: self.component_sha256.clone(),
+            fuel: self.fuel_limit.is_some(),
+            wasm_stack: self.wasi.limits.wasm_stack,
+            deterministic: self.wasi.deterministic_seed.is_some(),
+        }
+    }
+
+    fn compile(&self) -> Result<Compiled> {
+        let mut config = self.engine.config()?;
+        if self.fuel_limit.is_some() {
+            config.consume_fuel(true);
+        }
+        if let Some(bytes) = self.wasi.limits.wasm_stack {
+            config.max_wasm_stack(bytes);
+        }
+        if self.wasi.deterministic_seed.is_some() {
+            deterministic::configure_engine(&mut config);
+        }
+
+        let engine = Engine::new(&config).context("Failed to create wasm engine")?;
+        let (component, counts) = trace::stage("load_component", || {
+            load_component_file(
+                &engine,
+                self.component_path.as_deref(),
+                self.component_sha256.as_deref(),
+            )
+        })?;
+        Ok(Compiled {
+            timer: deadline_timer(&engine),
+            config,
+            engine,
+            component,
+            counts,
+            settings: self.compile_settings(),
+        })
+    }
+}
+
+/// An engine and the component compiled with it, shared by the sandboxes
+/// of a `SandboxFactory`.
+struct Compiled {
+    config: Config,
+    engine: Engine,
+    component: Component,
+    counts: ComponentCounts,
+    // One timeout thread for every sandbox on the engine
+    timer: Arc<DeadlineTimer>,
+    settings: CompileSettings,
+}
+
+impl std::fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compiled")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CompileSettings {
+    engine: EngineOptions,
+    component_path: Option<PathBuf>,
+    component_sha256: Option<String>,
+    fuel: bool,
+    wasm_stack: Option<usize>,
+    deterministic: bool,
+}
+
+/// Compiles the component once and builds any number of sandboxes from
+/// it, so a process running many differently configured sandboxes pays
+/// for one engine and one copy of the compiled code.
+///
+/// Sandboxes start from the settings of the builder the factory was made
+/// from and can change any of them except the ones that go into
+/// compiling: `engine_options`, the component, whether a fuel limit is
+/// set, `max_wasm_stack` and `deterministic`.
+///
+/// ```no_run
+/// use pybox::sandbox::{PySandbox, SandboxFactory};
+///
+/// let factory = SandboxFactory::new(PySandbox::builder().fuel_limit(1_000_000_000))?;
+/// let mut quick = factory.builder().timeout_seconds(1).build()?;
+/// let mut thorough = factory
+///     .builder()
+///     .timeout_seconds(60)
+///     .fuel_limit(50_000_000_000)
+///     .build()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct SandboxFactory {
+    template: PySandboxBuilder,
+}
+
+impl SandboxFactory {
+    /// Compile the component with the settings of `builder`.
+    pub fn new(mut builder: PySandboxBuilder) -> Result<Self> {
+        builder.resolve_variant()?;
+        let compiled = Arc::new(builder.compile()?);
+        Ok(Self {
+            template: PySandboxBuilder {
+                compiled: Some(compiled),
+                ..builder
+            },
+        })
+    }
+
+    /// A builder for a sandbox sharing the compiled component.
+    pub fn builder(&self) -> PySandboxBuilder {
+        self.template.clone()
+    }
+
+    /// A sandbox with the factory's settings.
+    pub fn sandbox(&self) -> Result<PySandbox> {
+        self.builder().build()
+    }
+}
+
+impl PySandbox {
+    /// Create a new webassembly sandbox for executing untrusted python code.
+    ///
+    /// # Arguments
+    /// * `timeout_secs` - Optional timeout in seconds. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
+    pub fn new(timeout_secs: Option<u64>) -> Result<Self> {
+        Self::builder_with_timeout(timeout_secs).build()
+    }
+
+    /// Start configuring a sandbox with more options than `new` accepts.
+    pub fn builder() -> PySandboxBuilder {
+        PySandboxBuilder::default()
+    }
+
+    fn builder_with_timeout(timeout_secs: Option<u64>) -> PySandboxBuilder {
+        let builder = Self::builder();
+        match timeout_secs {
+            Some(timeout_seconds) => builder.timeout_seconds(timeout_seconds),
+            None => builder,
+        }
+    }
+
+    /// Create a sandbox whose interpreter already has `modules` imported.
+    ///
+    /// `sandbox.wasm` is snapshotted right after CPython starts, this goes
+    /// further and snapshots after importing `modules` so code using them
+    /// skips the import cost on every execution. The component is built
+    /// from the pybox checkout at `root` with its `build_component.py` on
+    /// first use, which needs uv, and cached there as
+    /// `sandbox-preinit-<digest>.wasm`. The digest covers the guest, the
+    /// WIT, the locked componentize-py and `modules`, so a change to any
+    /// of them builds a new component.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let mut sandbox = PySandbox::new_preinitialized("/opt/pybox", &["json", "re"])?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn new_preinitialized(root: impl AsRef<Path>, modules: &[&str]) -> Result<Self> {
+        let root = root.as_ref();
+        let path = preinitialized_path(root, modules)?;
+        if !path.exists() {
+            build_preinitialized(root, modules, &path)?;
+        }
+        let mut builder = Self::builder();
+        builder.component_path = Some(path);
+        builder.build()
+    }
+
+    /// Create a sandbox from the component at `path`, failing with a
+    /// `DigestMismatch` unless its SHA-256 is `expected_sha256`.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let digest = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    /// let mut sandbox = PySandbox::from_path_verified("sandbox.wasm", digest, None)?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn from_path_verified(
+        path: impl Into<PathBuf>,
+        expected_sha256: &str,
+        timeout_secs: Option<u64>,
+    ) -> Result<Self> {
+        Self::builder_with_timeout(timeout_secs)
+            .component_file(path)
+            .expected_sha256(expected_sha256)
+            .build()
+    }
+
+    /// Create a sandbox from a component previously written by
+    /// `precompile_to`, skipping compilation entirely.
+    ///
+    /// Artifacts produced by a different engine configuration or
+    /// Wasmtime version are rejected with an error.
+    ///
+    /// # Arguments
+    /// * `path` - Path to the precompiled `.cwasm` file.
+    /// * `timeout_secs` - Optional timeout in seconds. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
+    pub fn from_precompiled(path: impl AsRef<Path>, timeout_secs: Option<u64>) -> Result<Self> {
+        let path = path.as_ref();
+        let timeout_seconds = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+        let config = EngineOptions::default().config()?;
+        let engine = Engine::new(&config).context("Failed to create wasm engine")?;
+
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let serialized = check_precompiled(&engine, &bytes, path)?;
+
+        // SAFETY: the artifact is only trusted as far as the header check
+        // above, callers must not load precompiled files from untrusted
+        // sources.
+        let component = unsafe { Component::deserialize(&engine, serialized) }
+            .with_context(|| format!("Failed to load precompiled {}", path.display()))?;
+
+        let timer = deadline_timer(&engine);
+        // The async engine can't load a precompiled artifact, it falls
+        // back to `sandbox.wasm`
+        let counts = ComponentCounts::compiled(&component);
+        let component = ((component, counts), ComponentSource::default());
+        Self::from_parts(config, engine, component, timer, timeout_seconds, WasiConfig::default())
+    }
+
+    fn from_parts(
+        config: Config,
+        engine: Engine,
+        (component, source): ((Component, ComponentCounts), ComponentSource),
+        timer: Arc<DeadlineTimer>,
+        timeout_seconds: u64,
+        wasi: WasiConfig,
+    ) -> Result<Self> {
+        let loaded = Loaded::new(&engine, component, source, &wasi.extensions)?;
+
+        Ok(Self {
+            config,
+            engine,
+            loaded: Arc::new(RwLock::new(Arc::new(loaded))),
+            fuel_limit: None,
+            wasi,
+            component_variant: None,
+            timer,
+            site_packages: None,
+            metrics: MetricsSink::default(),
+            audit: Auditor::default(),
+            quota: QuotaTracker::default(),
+            last_run: LastRun::default(),
+            keep_globals: false,
+            auto_recover: false,
+            timeout_seconds,
+        })
+    }
+
+    /// Compile the component at `path` and switch this sandbox and its
+    /// clones over to it, e.g. when a long running service is shipped an
+    /// updated guest. Executions already running finish on the previous
+    /// component, later ones start on the new one. Notebook cells and
+    /// sessions keep their interpreter until it is reset.
+    ///
+    /// The new component must export the same world, and a sandbox built
+    /// with `expected_sha256` only accepts a component with that digest,
+    /// see `reload_component_pinned` to ship one with another. If it
+    /// fails to verify, compile or link, the sandbox keeps running the
+    /// previous one.
+    pub fn reload_component(&self, path: impl AsRef<Path>) -> Result<()> {
+        let pinned = self.loaded().source.sha256.clone();
+        self.swap_component(path.as_ref(), pinned)
+    }
+
+    /// Like `reload_component` for a component whose SHA-256 must be
+    /// `digest`, given as hex. Later reloads are checked against it in
+    /// place of `expected_sha256`.
+    pub fn reload_component_pinned(&self, path: impl AsRef<Path>, digest: impl Into<String>) -> Result<()> {
+        self.swap_component(path.as_ref(), Some(digest.into()))
+    }
+
+    fn swap_component(&self, path: &Path, sha256: Option<String>) -> Result<()> {
+        let component = trace::stage("load_component", || {
+            load_component_file(&self.engine, Some(path), sha256.as_deref())
+        })?;
+        let source = ComponentSource {
+            path: Some(path.to_path_buf()),
+            sha256,
+        };
+        let loaded = Loaded::new(&self.engine, component, source, &self.wasi.extensions)?;
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+        Ok(())
+    }
+
+    /// The component executions starting now run.
+    fn loaded(&self) -> Arc<Loaded> {
+        self.loaded.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Write the compiled component to `path` so later processes can
+    /// start from it with `from_precompiled`.
+    pub fn precompile_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let serialized = self.loaded().component.serialize()?;
+
+        let mut bytes = Vec::with_capacity(PRECOMPILED_HEADER_LEN + serialized.len());
+        bytes.extend_from_slice(PRECOMPILED_MAGIC);
+        bytes.extend_from_slice(&engine_hash(&self.engine).to_le_bytes());
+        bytes.extend_from_slice(&serialized);
+
+        fs::write(path, bytes).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Create a sandbox with fast compilation settings for tests.
+    ///
+    /// Uses Winch baseline compiler, parallel compilation, and caching
+    /// to significantly speed up test execution.
+    ///
+    /// # Arguments
+    /// * `timeout_secs` - Optional timeout in seconds. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
+    #[allow(dead_code)]
+    pub fn new_for_test(timeout_secs: Option<u64>) -> Result<Self> {
+        Self::builder_with_timeout(timeout_secs)
+            .fast_compilation(true)
+            .build()
+    }
+
+    /// Execute Python code in the sandbox. Returns the result of the
+    /// execution as a json serialized string, or an error if
+    /// execution fails or timed out.
+    pub fn exec(&mut self, code: &str) -> Result<String> {
+        self.exec_with_options(code, &ExecOptions::default())
+            .map(|output| output.value)
+    }
+
+    /// Execute Python code like `exec` but with a different timeout for
+    /// this call only.
+    pub fn exec_with_timeout(&mut self, code: &str, timeout: Duration) -> Result<String> {
+        let options = ExecOptions {
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        self.exec_with_options(code, &options)
+            .map(|output| output.value)
+    }
+
+    /// Create a handle that can abort an execution from another thread.
+    /// Pass it to `exec_with_handle`.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            engine: self.engine.clone(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Execute Python code like `exec`, stopping early with
+    /// `PyboxError::Cancelled` if `handle` is cancelled while it runs.
+    ///
+    /// If the handle was already cancelled nothing is executed.
+    pub fn exec_with_handle(&mut self, code: &str, handle: &CancelHandle) -> Result<String> {
+        let options = ExecOptions {
+            cancel: Some(handle.clone()),
+            ..Default::default()
+        };
+        self.exec_with_options(code, &options)
+            .map(|output| output.value)
+    }
+
+    /// Evaluate a single Python expression and return its value as a
+    /// json serialized string. Statements are rejected, use `exec` or
+    /// `run` for those.
+    pub fn eval(&mut self, expression: &str) -> Result<String> {
+        self.validate(expression)?;
+        self.invoke(&ExecOptions::default(), &[], expression, |sandbox, store| {
+            sandbox.call_eval(store, expression)
+        })
+        .map(|output| output.value)
+    }
+
+    /// Execute `statements` and then evaluate `final_expr` against the
+    /// variables they defined, returning the expression's json
+    /// serialized value.
+    ///
+    /// This keeps the statement and expression parts separate instead of
+    /// relying on `exec` to detect a trailing expression.
+    pub fn run(&mut self, statements: &str, final_expr: &str) -> Result<String> {
+        self.validate(final_expr)?;
+        let result = self.keeping_instance(|sandbox| {
+            sandbox.exec(statements)?;
+            sandbox.with_last_run(|sandbox, store, finish| {
+                finish(sandbox.call_eval(&mut *store, final_expr))
+            })
+        });
+        if !self.keep_globals {
+            self.last_run = LastRun::default();
+        }
+        result
+    }
+
+    /// Execute Python code with per-call settings, see `ExecOptions`.
+    /// Returns the result along with statistics about the run.
+    pub fn exec_with_options(&mut self, code: &str, options: &ExecOptions) -> Result<ExecOutput> {
+        self.validate(code)?;
+        self.exec_mounted(code, options, &[])
+    }
+
+    /// Execute independent snippets on up to `parallelism` threads, each
+    /// on a fresh interpreter, and return their results in the order of
+    /// `codes`. Output is captured per snippet and each one gets the
+    /// sandbox's timeout on its own.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// let sandbox = PySandbox::new(Some(5))?;
+    /// let results = sandbox.exec_many(&["1 + 1", "print('hi')", "1 / 0"], 4);
+    /// assert_eq!(results[0].as_ref().unwrap().value, "2");
+    /// assert!(results[2].is_err());
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn exec_many(&self, codes: &[&str], parallelism: usize) -> Vec<Result<ExecOutput>> {
+        let options = ExecOptions {
+            capture_output: true,
+            ..Default::default()
+        };
+        self.exec_many_with_options(codes, parallelism, &options)
+    }
+
+    /// `exec_many` with per-snippet settings. A `cancel` handle in
+    /// `options` aborts every snippet still running or queued.
+    pub fn exec_many_with_options(
+        &self,
+        codes: &[&str],
+        parallelism: usize,
+        options: &ExecOptions,
+    ) -> Vec<Result<ExecOutput>> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new((0..codes.len()).map(|_| None).collect::<Vec<_>>());
+        let workers = parallelism.clamp(1, codes.len().max(1));
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                // Clones share the compiled component
+                let mut sandbox = self.clone();
+                let (next, results) = (&next, &results);
+                scope.spawn(move || {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(code) = codes.get(index) else { break };
+                        let result = sandbox.exec_with_options(code, options);
+                        results.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(result);
+                    }
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .map(|result| result.expect("every snippet is run"))
+            .collect()
+    }
+
+    /// `exec_with_options` with `extra_mounts` added for this call.
+    fn exec_mounted(
+        &mut self,
+        code: &str,
+        options: &ExecOptions,
+        extra_mounts: &[Mount],
+    ) -> Result<ExecOutput> {
+        if !options.capture_output {
+            return self.invoke(options, extra_mounts, code, |sandbox, store| {
+                sandbox.call_exec(store, code)
+            });
+        }
+
+        let mut streams = None;
+        let mut output = self.invoke(options, extra_mounts, code, |sandbox, store| {
+            let captured = sandbox.call_exec_captured(&mut *store, code)?;
+            let budget = store.data().output.clone();
+            Ok(captured.map(|captured| {
+                let limit = |text| match &budget {
+                    Some(budget) => budget.truncate(text),
+                    None => text,
+                };
+                streams = Some((limit(captured.stdout), limit(captured.stderr)));
+                captured.value
+            }))
+        })?;
+        if let Some((stdout, stderr)) = streams {
+            output.stdout = stdout;
+            output.stderr = stderr;
+        }
+        Ok(output)
+    }
+
+    /// Execute Python code like `exec` and return the value of its last
+    /// expression as JSON, so callers don't have to parse the string
+    /// themselves.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # use serde_json::json;
+    /// let mut sandbox = PySandbox::new(None)?;
+    /// assert_eq!(sandbox.exec_json("{'a': [1, 2]}")?, json!({"a": [1, 2]}));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn exec_json(&mut self, code: &str) -> Result<Value> {
+        parse_json(&self.exec(code)?)
+    }
+
+    /// Execute Python code whose last expression is `bytes`, such as a
+    /// rendered image or serialized blob, and return them unchanged.
+    /// Fails if the last expression is anything else.
+    pub fn exec_bytes(&mut self, code: &str) -> Result<Vec<u8>> {
+        self.validate(code)?;
+        let mut bytes = None;
+        self.invoke(&ExecOptions::default(), &[], code, |sandbox, store| {
+            Ok(sandbox.call_exec_bytes(store, code)?.map(|value| {
+                bytes = Some(value);
+                String::new()
+            }))
+        })?;
+        bytes.context("Guest returned no bytes")
+    }
+
+    /// Execute Python code with `inputs` bound as variables before it
+    /// runs, so structured data never has to be interpolated into code.
+    ///
+    /// Keys must be valid Python identifiers.
+    pub fn exec_with_inputs(&mut self, code: &str, inputs: &Map<String, Value>) -> Result<String> {
+        #[cfg(feature = "msgpack")]
+        if self.wasi.transport == Transport::MessagePack {
+            return self
+                .exec_with_inputs_msgpack::<_, Value>(code, inputs)
+                .map(|value| value.to_string());
+        }
+        self.validate(code)?;
+        let inputs = serde_json::to_string(inputs)?;
+        self.invoke(&ExecOptions::default(), &[], code, |sandbox, store| {
+            sandbox.call_exec_with_inputs(store, code, &inputs)
+        })
+        .map(|output| output.value)
+    }
+
+    /// Execute Python code with `inputs`, which must serialize to a map,
+    /// bound as variables, exchanging inputs and the result as
+    /// MessagePack. Unlike JSON this carries bytes both ways, use
+    /// `serde_bytes` for byte fields.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # use std::collections::HashMap;
+    /// let mut sandbox = PySandbox::new(None)?;
+    /// let inputs = HashMap::from([("n", 2)]);
+    /// let total: i64 = sandbox.exec_with_inputs_msgpack("n * 21", &inputs)?;
+    /// assert_eq!(total, 42);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[cfg(feature = "msgpack")]
+    pub fn exec_with_inputs_msgpack<I, O>(&mut self, code: &str, inputs: &I) -> Result<O>
+    where
+        I: serde::Serialize + ?Sized,
+        O: serde::de::DeserializeOwned,
+    {
+        self.validate(code)?;
+        let inputs = rmp_serde::to_vec_named(inputs).context("Failed to encode inputs")?;
+        let mut bytes = None;
+        self.invoke(&ExecOptions::default(), &[], code, |sandbox, store| {
+            Ok(sandbox
+                .call_exec_with_inputs_msgpack(store, code, &inputs)?
+                .map(|value| {
+                    bytes = Some(value);
+                    String::new()
+                }))
+        })?;
+        let bytes = bytes.context("Guest returned no value")?;
+        rmp_serde::from_slice(&bytes).context("Failed to decode the guest's value")
+    }
+
+    /// Read a variable left behind by the most recent execution, like
+    /// `get_globals`, transferred as MessagePack.
+    #[cfg(feature = "msgpack")]
+    pub fn get_global_msgpack<O>(&mut self, name: &str) -> Result<O>
+    where
+        O: serde::de::DeserializeOwned,
+    {
+        self.with_last_run(|sandbox, store, finish| {
+            let bytes = read_global_msgpack(sandbox, store, finish, name)?;
+            rmp_serde::from_slice(&bytes)
+                .with_context(|| format!("Failed to decode global {}", name))
+        })
+    }
+
+    /// Execute Python code with `table` bound to `batch`, as a
+    /// `pybox.Table` of column lists, and return the table its last
+    /// expression evaluates to. That may be a `pybox.Table`, a dict of
+    /// columns, a list of row dicts or a pandas DataFrame.
+    ///
+    /// The tables cross the component boundary as Arrow IPC. Int, float,
+    /// bool, utf8 and null columns are supported. Returned columns are
+    /// int64, float64, bool or utf8 depending on their values.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # use arrow_array::{Int64Array, RecordBatch};
+    /// # use std::sync::Arc;
+    /// let mut sandbox = PySandbox::new(None)?;
+    /// let batch = RecordBatch::try_from_iter([("n", Arc::new(Int64Array::from(vec![1, 2])) as _)])?;
+    /// let doubled = sandbox.exec_with_table("{'n': [n * 2 for n in table['n']]}", &batch)?;
+    /// assert_eq!(doubled.num_rows(), 2);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[cfg(feature = "arrow")]
+    pub fn exec_with_table(&mut self, code: &str, batch: &RecordBatch) -> Result<RecordBatch> {
+        self.validate(code)?;
+        let table = write_ipc_stream(batch).context("Failed to encode table")?;
+        let mut bytes = None;
+        self.invoke(&ExecOptions::default(), &[], code, |sandbox, store| {
+            Ok(sandbox.call_exec_with_table(store, code, &table)?.map(|value| {
+                bytes = Some(value);
+                String::new()
+            }))
+        })?;
+        let bytes = bytes.context("Guest returned no table")?;
+        read_ipc_stream(&bytes).context("Failed to decode the guest's table")
+    }
+
+    /// Run a Python script from the host filesystem and return the
+    /// value of its last expression like `exec`.
+    ///
+    /// The script's directory is mounted read-only in the guest so it
+    /// can import sibling modules and open files relative to itself.
+    pub fn exec_file(&mut self, path: impl AsRef<Path>) -> Result<String> {
+        self.exec_file_with_options(path, &ExecOptions::default())
+            .map(|output| output.value)
+    }
+
+    /// Run a Python script like `exec_file` with per-call settings, see
+    /// `ExecOptions`.
+    pub fn exec_file_with_options(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: &ExecOptions,
+    ) -> Result<ExecOutput> {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let entrypoint = path
+            .file_name()
+            .with_context(|| format!("{} is not a file", path.display()))?;
+        self.exec_project_with_options(dir, entrypoint, options)
+    }
+
+    /// Run `entrypoint`, a script path relative to `dir`, with the whole
+    /// project directory mounted read-only in the guest at
+    /// `/project`. The directory is the working directory and first
+    /// `sys.path` entry, so the entrypoint can import the project's
+    /// modules.
+    pub fn exec_project(
+        &mut self,
+        dir: impl AsRef<Path>,
+        entrypoint: impl AsRef<Path>,
+    ) -> Result<String> {
+        self.exec_project_with_options(dir, entrypoint, &ExecOptions::default())
+            .map(|output| output.value)
+    }
+
+    /// Run a project like `exec_project` with per-call settings, see
+    /// `ExecOptions`.
+    pub fn exec_project_with_options(
+        &mut self,
+        dir: impl AsRef<Path>,
+        entrypoint: impl AsRef<Path>,
+        options: &ExecOptions,
+    ) -> Result<ExecOutput> {
+        let dir = dir.as_ref();
+        let script = dir.join(entrypoint);
+        let source = fs::read_to_string(&script)
+            .with_context(|| format!("Failed to read {}", script.display()))?;
+        self.validate(&source)?;
+
+        let mounts = [Mount {
+            host_path: dir.to_path_buf(),
+            guest_path: PROJECT_GUEST_DIR.to_string(),
+            mode: MountMode::ReadOnly,
+        }];
+        let code = format!(
+            "__import__('sys').path.insert(0, '{dir}')\n__import__('os').chdir('{dir}')\n{source}",
+            dir = PROJECT_GUEST_DIR,
+        );
+        self.exec_mounted(&code, options, &mounts)
+    }
+
+    /// Instantiate the component in a fresh store and invoke one of its
+    /// exports with `call`, enforcing the timeout and collecting stats.
+    /// `code` is what `call` runs, it identifies the execution in traces.
+    fn invoke(
+        &mut self,
+        options: &ExecOptions,
+        extra_mounts: &[Mount],
+        code: &str,
+        call: impl FnOnce(&Sandbox, &mut Store<MyWasi>) -> Result<Result<String, PythonError>>,
+    ) -> Result<ExecOutput> {
+        self.quota.start()?;
+        let started = Instant::now();
+        let timeout = options
+            .timeout
+            .unwrap_or(Duration::from_secs(self.timeout_seconds));
+        let timestamp = SystemTime::now();
+        let span = ExecSpan::new(code, timeout);
+        self.metrics.exec_started();
+        let cpu = CpuTimer::start();
+        let mut output =
+            span.in_scope(|| self.invoke_in_store(options, extra_mounts, timeout, call));
+        let cpu_time = cpu.elapsed();
+        self.quota.finish(cpu_time);
+        if let Ok(output) = &mut output {
+            output.stats.cpu_time = cpu_time;
+        }
+        span.finish(&output, started.elapsed());
+        self.metrics.exec_finished(&output, started.elapsed());
+        self.record_audit(code, timestamp, timeout, &output, started.elapsed())?;
+        output
+    }
+
+    fn invoke_in_store(
+        &mut self,
+        options: &ExecOptions,
+        extra_mounts: &[Mount],
+        timeout: Duration,
+        call: impl FnOnce(&Sandbox, &mut Store<MyWasi>) -> Result<Result<String, PythonError>>,
+    ) -> Result<ExecOutput> {
+        let started = Instant::now();
+        let handle = match &options.cancel {
+            Some(handle) => handle.clone(),
+            None => self.cancel_handle(),
+        };
+
+        // Release the previous run's instance before creating a new one
+        self.last_run = LastRun::default();
+
+        // Create a store with WASI context
+        let mut store = self.new_store(&self.engine, extra_mounts, options.on_output.as_ref())?;
+        let deadline = self.arm_deadline(&mut store, timeout, &handle);
+        // Checked after the deadline is armed so a concurrent cancel is
+        // never missed
+        if handle.is_cancelled() {
+            return Err(PyboxError::Cancelled.into());
+        }
+
+        // Instantiate the component and execute the code
+        let mut instance = None;
+        let loaded = self.loaded();
+        let instance_pre = &loaded.instance_pre;
+        let instantiated = trace::stage("instantiate", || instance_pre.instantiate(&mut store));
+        let result = match instantiated {
+            Ok(wasm_sandbox) => {
+                let settings = self.wasi.guest_settings();
+                let result = wasm_sandbox
+                    .call_configure(&mut store, &settings)
+                    .and_then(|configured| {
+                        configured
+                            .map_err(|e| {
+                                anyhow!("Failed to configure sandbox: {}", error::PythonError::from(e))
+                            })
+                    })
+                    .and_then(|()| call(&wasm_sandbox, &mut store));
+                instance = Some(wasm_sandbox);
+                result
+            }
+            Err(e) => Err(self.wasi.limits.instantiation_error(e, loaded.counts)),
+        };
+        if handle.is_cancelled() && result.is_err() {
+            return Err(PyboxError::Cancelled.into());
+        }
+        let value = self.finish(
+            result,
+            deadline.passed(),
+            store.data().tracker.exceeded(),
+            store.data().over_budget(),
+        );
+
+        let fuel_remaining = match self.fuel_limit {
+            Some(_) => Some(store.get_fuel()?),
+            None => None,
+        };
+        let stats = ExecStats {
+            wall_time: started.elapsed(),
+            fuel_consumed: self.fuel_limit.zip(fuel_remaining).map(|(limit, left)| limit - left),
+            fuel_remaining,
+            peak_memory_bytes: store.data().tracker.peak_memory_bytes,
+            epoch_interrupted: deadline.epoch_interrupted.load(Ordering::SeqCst),
+            interrupted: deadline.interrupted.load(Ordering::SeqCst),
+            output_truncated: store.data().output.as_ref().is_some_and(|b| b.truncated()),
+            // Measured by `invoke`, which also counts creating the store
+            cpu_time: Duration::ZERO,
+        };
+
+        let (artifacts, figures) = match &value {
+            Ok(_) => (store.data().artifacts()?, store.data().figures()?),
+            Err(_) => Default::default(),
+        };
+        let displays = std::mem::take(&mut store.data_mut().displays);
+
+        // Keep the instance around so `get_globals` can read from it,
+        // otherwise it is released along with the store here
+        if self.keep_globals {
+            self.last_run = LastRun(instance.map(|instance| (store, instance)));
+        }
+
+        Ok(ExecOutput {
+            value: value?,
+            stdout: String::new(),
+            stderr: String::new(),
+            stats,
+            artifacts,
+            figures,
+            displays,
+        })
+    }
+
+    /// Read variables left behind by the most recent execution on this
+    /// sandbox, returning each one's JSON value. The sandbox must be built
+    /// with `keep_globals`, or run the code as a `Session` cell.
+    ///
+    /// Fails if nothing has been executed yet, a variable is not defined
+    /// or its value is not JSON serializable.
+    pub fn get_globals(&mut self, names: &[&str]) -> Result<HashMap<String, Value>> {
+        #[cfg(feature = "msgpack")]
+        let transport = self.wasi.transport;
+        self.with_last_run(|sandbox, store, finish| {
+            let mut globals = HashMap::with_capacity(names.len());
+            for name in names {
+                #[cfg(feature = "msgpack")]
+                if transport == Transport::MessagePack {
+                    let bytes = read_global_msgpack(sandbox, store, finish, name)?;
+                    let value = rmp_serde::from_slice(&bytes)
+                        .with_context(|| format!("Failed to decode global {}", name))?;
+                    globals.insert(name.to_string(), value);
+                    continue;
+                }
+                let json = finish(sandbox.call_get_global(&mut *store, name))
+                    .with_context(|| format!("Failed to read global {}", name))?;
+                let value = serde_json::from_str(&json)
+                    .with_context(|| format!("Global {} is not valid JSON", name))?;
+                globals.insert(name.to_string(), value);
+            }
+            Ok(globals)
+        })
+    }
+
+    /// Call a function defined by the most recent execution on this
+    /// sandbox with JSON arguments and return its JSON result. Like
+    /// `get_globals`, it needs `keep_globals` or a `Session`.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # use serde_json::{json, Map};
+    /// let mut sandbox = PySandbox::builder().keep_globals(true).build()?;
+    /// sandbox.exec("def add(a, b=0):\n    return a + b")?;
+    /// let mut kwargs = Map::new();
+    /// kwargs.insert("b".to_string(), json!(2));
+    /// assert_eq!(sandbox.call_function("add", &[json!(1)], &kwargs)?, json!(3));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn call_function(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        kwargs: &Map<String, Value>,
+    ) -> Result<Value> {
+        let args = serde_json::to_string(args)?;
+        let kwargs = serde_json::to_string(kwargs)?;
+        self.with_last_run(|sandbox, store, finish| {
+            let json = finish(sandbox.call_call_function(&mut *store, name, &args, &kwargs))
+                .with_context(|| format!("Failed to call {}", name))?;
+            serde_json::from_str(&json)
+                .with_context(|| format!("Result of {} is not valid JSON", name))
+        })
+    }
+
+    /// Check `code` against the size limits and the builder's
+    /// `CodeValidator`, if any, before anything runs.
+    fn validate(&self, code: &str) -> Result<()> {
+        CodeLimit::Bytes.check(code, self.wasi.max_code_bytes)?;
+        CodeLimit::Lines.check(code, self.wasi.max_code_lines)?;
+        #[cfg(feature = "validation")]
+        if let Some(validator) = &self.wasi.validator {
+            validator.validate(code)?;
         }
+        Ok(())
     }
-}
 
-// NOTE: This generates a `Sandbox` type automatically and your IDE
-// might not pick it up
-wasmtime::component::bindgen!({
-    path: "sandbox.wit",
-    world: "sandbox",
-});
+    /// Send the audit record of an execution of `code` to the audit sink.
+    fn record_audit<T>(
+        &self,
+        code: &str,
+        timestamp: SystemTime,
+        timeout: Duration,
+        result: &Result<T>,
+        duration: Duration,
+    ) -> Result<()> {
+        if !self.audit.is_enabled() {
+            return Ok(());
+        }
+        self.audit.record(&AuditRecord {
+            timestamp,
+            code_sha256: audit::code_sha256(code),
+            timeout,
+            fuel_limit: self.fuel_limit,
+            max_memory_bytes: self.wasi.limits.memory_bytes,
+            max_output_bytes: self.wasi.max_output_bytes,
+            outcome: ExecOutcome::of(result),
+            duration,
+        })
+    }
 
-/// A sandboxed Python execution environment using WebAssembly.
-pub struct PySandbox {
-    engine: Engine,
-    component: Component,
-    pub timeout_seconds: u64,
-}
+    /// Where this sandbox reports metrics, shared with pools built from it.
+    pub(crate) fn metrics(&self) -> &MetricsSink {
+        &self.metrics
+    }
 
-impl PySandbox {
-    /// Create a new webassembly sandbox for executing untrusted python code.
-    ///
-    /// # Arguments
-    /// * `timeout_secs` - Optional timeout in seconds. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
-    pub fn new(timeout_secs: Option<u64>) -> Result<Self> {
-        let timeout_seconds = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+    /// Run a notebook cell against the instance kept by the previous cells,
+    /// instantiating one for the first cell. Returns the guest's JSON cell
+    /// report. Any failure here is fatal to the instance, so it is dropped
+    /// and the next cell starts from a fresh interpreter.
+    pub(crate) fn exec_cell(&mut self, code: &str, options: &ExecOptions) -> Result<String> {
+        self.run_cell(code, options, |sandbox, store| sandbox.call_exec_cell(store, code))
+    }
+
+    /// Like `exec_cell` for a single expression, which is evaluated
+    /// against the variables of the previous cells.
+    pub(crate) fn eval_cell(&mut self, expression: &str, options: &ExecOptions) -> Result<String> {
+        self.run_cell(expression, options, |sandbox, store| {
+            sandbox.call_eval_cell(store, expression)
+        })
+    }
 
-        let mut cfg = Config::new();
-        // Enable timeouts
-        cfg.epoch_interruption(true);
-        // Enable the compilation cache, using the default cache configuration
-        // settings.
-        cfg.cache(Some(Cache::from_file(None)?));
-        let engine = Engine::new(&cfg).expect("Failed to create wasm engine");
+    /// Pickle the variables of the notebook cells run so far, see
+    /// `Session::snapshot`.
+    pub(crate) fn snapshot_cells(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = None;
+        self.run_cell("# snapshot session", &ExecOptions::default(), |sandbox, store| {
+            Ok(sandbox.call_snapshot_globals(store)?.map(|value| {
+                bytes = Some(value);
+                String::new()
+            }))
+        })?;
+        bytes.context("Guest returned no snapshot")
+    }
 
-        let component = Component::from_file(&engine, "sandbox.wasm")
-            .context("Failed to load sandbox.wasm")?;
+    /// Replace the variables of the notebook cells with those of a
+    /// `snapshot_cells` snapshot, see `Session::restore`.
+    pub(crate) fn restore_cells(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.run_cell("# restore session", &ExecOptions::default(), |sandbox, store| {
+            Ok(sandbox
+                .call_restore_globals(store, snapshot)?
+                .map(|()| String::new()))
+        })?;
+        Ok(())
+    }
 
-        Ok(Self {
-            engine,
-            component,
-            timeout_seconds,
+    /// The Python version, installed packages and `component_variant` of
+    /// the component this sandbox runs. Instantiates the component, so
+    /// ask once rather than per execution.
+    pub fn version(&mut self) -> Result<ComponentVersion> {
+        let output = self.invoke(&ExecOptions::default(), &[], "# version()", |sandbox, store| {
+            sandbox.call_version(store)
+        })?;
+        ComponentVersion::from_json(self.component_variant.clone(), &output.value)
+    }
+
+    /// Run `source` and return the guest's JSON description of the
+    /// tools it defines, see `Toolbox::describe_tools`.
+    pub(crate) fn describe_tools(&mut self, source: &str) -> Result<String> {
+        self.validate(source)?;
+        self.invoke(&ExecOptions::default(), &[], source, |sandbox, store| {
+            sandbox.call_describe_tools(store, source)
         })
+        .map(|output| output.value)
     }
 
-    /// Create a sandbox with fast compilation settings for tests.
-    ///
-    /// Uses Winch baseline compiler, parallel compilation, and caching
-    /// to significantly speed up test execution.
-    ///
-    /// # Arguments
-    /// * `timeout_secs` - Optional timeout in seconds. Defaults to `DEFAULT_TIMEOUT_SECONDS`.
-    #[allow(dead_code)]
-    pub fn new_for_test(timeout_secs: Option<u64>) -> Result<Self> {
-        let timeout_seconds = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+    /// Run `source` and call its tool `name` with `arguments`, a JSON
+    /// object, returning the JSON result. Audited as the source followed
+    /// by the call, so each call is told apart.
+    pub(crate) fn invoke_tool(&mut self, source: &str, name: &str, arguments: &str) -> Result<String> {
+        self.validate(source)?;
+        let audited = format!("{}\n# Tool call: {}({})", source, name, arguments);
+        self.invoke(&ExecOptions::default(), &[], &audited, |sandbox, store| {
+            sandbox.call_invoke_tool(store, source, name, arguments)
+        })
+        .map(|output| output.value)
+    }
 
-        let mut config = Config::new();
+    fn run_cell(
+        &mut self,
+        code: &str,
+        options: &ExecOptions,
+        call: impl FnOnce(&Sandbox, &mut Store<MyWasi>) -> Result<Result<String, PythonError>>,
+    ) -> Result<String> {
+        self.validate(code)?;
+        let result = if self.last_run.0.is_some() {
+            self.with_last_run_options(options, |sandbox, store, finish| {
+                let report = finish(call(sandbox, &mut *store));
+                // Cells don't report rich outputs, don't let them pile up
+                store.data_mut().displays.clear();
+                report
+            })
+        } else {
+            self.keeping_instance(|sandbox| sandbox.invoke(options, &[], code, call))
+                .map(|output| output.value)
+        };
+        if result.is_err() {
+            self.last_run = LastRun::default();
+        }
+        result
+    }
 
-        // Enable the compilation cache, using the default cache configuration
-        // settings.
-        config.cache(Some(Cache::from_file(None)?));
+    /// Run `f` as if `keep_globals` were set, so the instance of an
+    /// execution it starts is kept for `with_last_run`.
+    fn keeping_instance<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let keep = std::mem::replace(&mut self.keep_globals, true);
+        let result = f(self);
+        self.keep_globals = keep;
+        result
+    }
 
-        // Enable Winch, Wasmtime's baseline compiler.
-        config.strategy(Strategy::Winch);
+    /// Drop the instance kept by the most recent execution, so the next
+    /// notebook cell starts from a fresh interpreter.
+    pub(crate) fn clear_last_run(&mut self) {
+        self.last_run = LastRun::default();
+    }
 
-        // Enable parallel compilation.
-        config.parallel_compilation(true);
+    /// Whether `Session`s recover on their own, see `auto_recover`.
+    pub(crate) fn auto_recovers(&self) -> bool {
+        self.auto_recover
+    }
 
-        // Enable epoch interruption for timeout support
-        config.epoch_interruption(true);
+    /// Run `f` against the instance left by the most recent execution,
+    /// with the sandbox timeout enforced again. `f` receives a function
+    /// that converts raw guest results like `exec` does.
+    fn with_last_run<T>(
+        &mut self,
+        f: impl FnOnce(
+            &Sandbox,
+            &mut Store<MyWasi>,
+            &dyn Fn(Result<Result<String, PythonError>>) -> Result<String>,
+        ) -> Result<T>,
+    ) -> Result<T> {
+        self.with_last_run_options(&ExecOptions::default(), f)
+    }
 
-        let engine = Engine::new(&config).expect("Failed to create wasm engine");
+    /// Like `with_last_run` with the timeout and cancel handle of `options`.
+    fn with_last_run_options<T>(
+        &mut self,
+        options: &ExecOptions,
+        f: impl FnOnce(
+            &Sandbox,
+            &mut Store<MyWasi>,
+            &dyn Fn(Result<Result<String, PythonError>>) -> Result<String>,
+        ) -> Result<T>,
+    ) -> Result<T> {
+        let timeout = options
+            .timeout
+            .unwrap_or(Duration::from_secs(self.timeout_seconds));
+        let handle = match &options.cancel {
+            Some(handle) => handle.clone(),
+            None => self.cancel_handle(),
+        };
+        let (mut store, wasm_sandbox) = self
+            .last_run
+            .0
+            .take()
+            .context("No interpreter to read from, run code with `keep_globals` or in a `Session` first")?;
+        let deadline = self.arm_deadline(&mut store, timeout, &handle);
+        let exceeded = store.data().tracker.exceeded.clone();
+        // Reported again by this call if it goes over
+        store.data_mut().over_budget = Arc::default();
+        let over_budget = store.data().over_budget.clone();
 
-        let component = Component::from_file(&engine, "sandbox.wasm")
-            .context("Failed to load sandbox.wasm")?;
+        let finish = |result| {
+            let timed_out = deadline.passed();
+            let limit = *exceeded.lock().unwrap_or_else(|e| e.into_inner());
+            let lines = *over_budget.lock().unwrap_or_else(|e| e.into_inner());
+            match self.finish(result, timed_out, limit, lines) {
+                Err(_) if handle.is_cancelled() => Err(PyboxError::Cancelled.into()),
+                result => result,
+            }
+        };
+        let result = f(&wasm_sandbox, &mut store, &finish);
 
-        Ok(Self {
-            engine,
-            component,
-            timeout_seconds,
-        })
+        self.last_run = LastRun(Some((store, wasm_sandbox)));
+        result
     }
 
-    /// Execute Python code in the sandbox. Returns the result of the
-    /// execution as a json serialized string, or an error if
-    /// execution fails or timed out.
-    pub fn exec(&mut self, code: &str) -> Result<String> {
-        let timeout_seconds = self.timeout_seconds;
-        let epoch_deadline = timeout_seconds + EPOCH_DEADLINE_BASE;
+    /// Start the timeout timer for a store and install the epoch
+    /// callback that enforces it and the cancel handle.
+    fn arm_deadline(
+        &self,
+        store: &mut Store<MyWasi>,
+        timeout: Duration,
+        handle: &CancelHandle,
+    ) -> Deadline {
+        // The shared timer bumps the epoch once this run's deadline
+        // passes, and again when its grace period, if any, is over
+        let deadline = Instant::now() + timeout;
+        let kill_at = deadline + self.wasi.timeout_grace.unwrap_or_default();
+        let timer = self.timer.schedule(deadline);
+        let grace_timer = (kill_at > deadline).then(|| self.timer.schedule(kill_at));
 
-        // Set up timeout handling
+        // Every epoch increment checks whether this run was cancelled or
+        // is past its own deadline and otherwise lets it continue, so
+        // other runs' deadlines don't affect it
         let timeout_triggered = Arc::new(AtomicBool::new(false));
+        let epoch_interrupted = Arc::new(AtomicBool::new(false));
+        // Reset for stores reused by notebook cells
+        let interrupted = store.data().interrupt.clone();
+        interrupted.store(false, Ordering::SeqCst);
+        store.set_epoch_deadline(1);
         {
-            let engine_clone = self.engine.clone();
-            let timeout_triggered_clone = timeout_triggered.clone();
-
-            thread::spawn(move || {
-                thread::sleep(Duration::from_secs(timeout_seconds));
-                timeout_triggered_clone.store(true, Ordering::SeqCst);
-                for _ in 0..epoch_deadline {
-                    engine_clone.increment_epoch();
+            let timeout_triggered = timeout_triggered.clone();
+            let cancelled = handle.cancelled.clone();
+            let epoch_interrupted = epoch_interrupted.clone();
+            let interrupted = interrupted.clone();
+            store.epoch_deadline_callback(move |_| {
+                epoch_interrupted.store(true, Ordering::SeqCst);
+                if cancelled.load(Ordering::SeqCst) {
+                    return Err(PyboxError::Cancelled.into());
                 }
+                let now = Instant::now();
+                if now >= kill_at {
+                    timeout_triggered.store(true, Ordering::SeqCst);
+                    return Err(PyboxError::Timeout.into());
+                }
+                // Only reached with a grace period, the guest polls this
+                if now >= deadline {
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                Ok(UpdateDeadline::Continue(1))
             });
         }
 
-        // Create a WASI context
-        let mut builder = WasiCtxBuilder::new();
-        // Enable stdio access by default
-        builder.inherit_stdio();
+        Deadline {
+            timeout_triggered,
+            interrupted,
+            epoch_interrupted,
+            _timer: timer,
+            _grace_timer: grace_timer,
+        }
+    }
+
+    /// Execute Python code on tokio's blocking thread pool, so async code
+    /// can await it without stalling the runtime. Runs on a clone of the
+    /// sandbox, the returned future doesn't borrow it.
+    ///
+    /// The execution starts right away and must be started from within a
+    /// tokio runtime. Dropping the future cancels it.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let sandbox = PySandbox::new(None)?;
+    /// let output = sandbox.spawn_exec("1 + 1").await?;
+    /// assert_eq!(output.value, "2");
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio-rt")]
+    pub fn spawn_exec(
+        &self,
+        code: impl Into<String>,
+    ) -> impl Future<Output = Result<ExecOutput>> + Send + 'static {
+        self.spawn_exec_with_options(code, ExecOptions::default())
+    }
 
-        let wasi_ctx = MyWasi {
-            wasi_ctx: builder.build(),
-            table: ResourceTable::new(),
-        };
+    /// Like `spawn_exec` with per-execution options. A `cancel` handle in
+    /// `options` is kept, and also cancelled when the future is dropped.
+    #[cfg(feature = "tokio-rt")]
+    pub fn spawn_exec_with_options(
+        &self,
+        code: impl Into<String>,
+        mut options: ExecOptions,
+    ) -> impl Future<Output = Result<ExecOutput>> + Send + 'static {
+        let mut sandbox = self.clone();
+        let code = code.into();
+        let cancel = options
+            .cancel
+            .get_or_insert_with(|| sandbox.cancel_handle())
+            .clone();
+        let task = tokio::task::spawn_blocking(move || sandbox.exec_with_options(&code, &options));
+        // Created here so a future dropped before it was polled cancels too
+        let mut guard = CancelOnDrop(Some(cancel));
+        async move {
+            let result = task.await;
+            guard.disarm();
+            result.map_err(|e| anyhow!("Execution panicked: {}", e))?
+        }
+    }
 
-        // Create a store with WASI context
-        let mut store = Store::new(&self.engine, wasi_ctx);
-        store.set_epoch_deadline(epoch_deadline);
+    /// Execute Python code without blocking the calling thread. Returns
+    /// the same json serialized result as `exec`.
+    ///
+    /// Execution periodically yields back to the async runtime, so
+    /// dropping the returned future aborts the run.
+    #[cfg(feature = "async")]
+    pub async fn exec_async(&mut self, code: &str) -> Result<String> {
+        let output = self
+            .exec_async_with_options(code, &ExecOptions::default())
+            .await?;
+        Ok(output.value)
+    }
+
+    /// Execute Python code without blocking the calling thread, with
+    /// per-call settings like `exec_with_options`. A `CancelHandle` in
+    /// `options` stops the run at its next yield, within a few
+    /// milliseconds, with `PyboxError::Cancelled`.
+    #[cfg(feature = "async")]
+    pub async fn exec_async_with_options(
+        &mut self,
+        code: &str,
+        options: &ExecOptions,
+    ) -> Result<ExecOutput> {
+        self.validate(code)?;
+        self.quota.start()?;
+        let started = Instant::now();
+        let timestamp = SystemTime::now();
+        let timeout = options
+            .timeout
+            .unwrap_or(Duration::from_secs(self.timeout_seconds));
+        let span = ExecSpan::new(code, timeout);
+        self.metrics.exec_started();
+        // Async runs hop between threads, so their CPU time is summed
+        // over the polls
+        let (mut output, cpu_time) =
+            crate::quota::measure_cpu(self.exec_async_in_store(code, options, timeout)).await;
+        self.quota.finish(cpu_time);
+        if let Ok(output) = &mut output {
+            output.stats.cpu_time = cpu_time;
+        }
+        span.finish(&output, started.elapsed());
+        self.metrics.exec_finished(&output, started.elapsed());
+        self.record_audit(code, timestamp, timeout, &output, started.elapsed())?;
+        output
+    }
+
+    #[cfg(feature = "async")]
+    async fn exec_async_in_store(
+        &mut self,
+        code: &str,
+        options: &ExecOptions,
+        timeout: Duration,
+    ) -> Result<ExecOutput> {
+        let started = Instant::now();
+        let AsyncRuntime {
+            engine,
+            instance_pre,
+            timer,
+        } = self.async_runtime()?;
+        let deadline = Instant::now() + timeout;
+        let kill_at = deadline + self.wasi.timeout_grace.unwrap_or_default();
+        let timeout_triggered = Arc::new(AtomicBool::new(false));
+        let epoch_interrupted = Arc::new(AtomicBool::new(false));
+        let cancelled = options
+            .cancel
+            .as_ref()
+            .map(|handle| handle.cancelled.clone())
+            .unwrap_or_default();
 
-        // Set up linker with WASI
-        let mut linker = Linker::new(&self.engine);
-        wasmtime_wasi::p2::add_to_linker_sync(&mut linker)?;
+        let mut store = self.new_store(&engine, &[], options.on_output.as_ref())?;
+        let interrupted = store.data().interrupt.clone();
+        store.set_epoch_deadline(1);
+        {
+            let timeout_triggered = timeout_triggered.clone();
+            let interrupted = interrupted.clone();
+            let cancelled = cancelled.clone();
+            let epoch_interrupted = epoch_interrupted.clone();
+            // The shared timer ticks the epoch so the guest yields
+            // regularly. Every yield checks for a cancel and the deadline
+            // and schedules the next tick, dropping the store cancels it.
+            let mut next_tick = timer.schedule(Instant::now() + ASYNC_YIELD_INTERVAL);
+            store.epoch_deadline_callback(move |_| {
+                epoch_interrupted.store(true, Ordering::SeqCst);
+                if cancelled.load(Ordering::SeqCst) {
+                    return Err(PyboxError::Cancelled.into());
+                }
+                let now = Instant::now();
+                if now >= kill_at {
+                    timeout_triggered.store(true, Ordering::SeqCst);
+                    return Err(PyboxError::Timeout.into());
+                }
+                if now >= deadline {
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                let fired = std::mem::replace(&mut next_tick, timer.schedule(now + ASYNC_YIELD_INTERVAL));
+                drop(fired);
+                Ok(UpdateDeadline::Yield(1))
+            });
+        }
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(PyboxError::Cancelled.into());
+        }
+
+        let (limits, counts) = (self.wasi.limits, self.loaded().counts);
+        let mut streams = None;
+        let result = async {
+            let wasm_sandbox = instance_pre
+                .instantiate_async(&mut store)
+                .await
+                .map_err(|e| limits.instantiation_error(e, counts))?;
+            wasm_sandbox
+                .call_configure(&mut store, &self.wasi.guest_settings())
+                .await?
+                .map_err(|e| {
+                    anyhow!("Failed to configure sandbox: {}", error::PythonError::from(e))
+                })?;
+            if !options.capture_output {
+                return wasm_sandbox.call_exec(&mut store, code).await;
+            }
+            let captured = wasm_sandbox.call_exec_captured(&mut store, code).await?;
+            let budget = store.data().output.clone();
+            Ok(captured.map(|captured| {
+                let limit = |text| match &budget {
+                    Some(budget) => budget.truncate(text),
+                    None => text,
+                };
+                streams = Some((limit(captured.stdout), limit(captured.stderr)));
+                captured.value
+            }))
+        }
+        .await;
+        if cancelled.load(Ordering::SeqCst) && result.is_err() {
+            return Err(PyboxError::Cancelled.into());
+        }
+
+        let value = self.finish(
+            result,
+            timeout_triggered.load(Ordering::SeqCst) || interrupted.load(Ordering::SeqCst),
+            store.data().tracker.exceeded(),
+            store.data().over_budget(),
+        )?;
+        let fuel_remaining = match self.fuel_limit {
+            Some(_) => Some(store.get_fuel()?),
+            None => None,
+        };
+        let stats = ExecStats {
+            wall_time: started.elapsed(),
+            fuel_consumed: self.fuel_limit.zip(fuel_remaining).map(|(limit, left)| limit - left),
+            fuel_remaining,
+            peak_memory_bytes: store.data().tracker.peak_memory_bytes,
+            epoch_interrupted: epoch_interrupted.load(Ordering::SeqCst),
+            interrupted: interrupted.load(Ordering::SeqCst),
+            output_truncated: store.data().output.as_ref().is_some_and(|b| b.truncated()),
+            // Measured by `exec_async_with_options` over the polls
+            cpu_time: Duration::ZERO,
+        };
+        let (stdout, stderr) = streams.unwrap_or_default();
+        Ok(ExecOutput {
+            value,
+            stdout,
+            stderr,
+            stats,
+            artifacts: store.data().artifacts()?,
+            figures: store.data().figures()?,
+            displays: std::mem::take(&mut store.data_mut().displays),
+        })
+    }
 
-        // Instantiate the component
-        let wasm_sandbox = Sandbox::instantiate(&mut store, &self.component, &linker)?;
+    /// Create a store for a single execution with fuel applied.
+    fn new_store(
+        &self,
+        engine: &Engine,
+        extra_mounts: &[Mount],
+        on_output: Option<&OutputSink>,
+    ) -> Result<Store<MyWasi>> {
+        let mut store = Store::new(engine, wasi_state(&self.wasi, extra_mounts, on_output)?);
+        store.limiter(|state| &mut state.tracker);
+        if let Some(fuel) = self.fuel_limit {
+            store.set_fuel(fuel)?;
+        }
+        Ok(store)
+    }
 
-        // Execute the code
-        let result = wasm_sandbox.call_exec(&mut store, code);
+    /// Convert the outcome of a guest call into the public result,
+    /// translating interruptions into `PyboxError` variants and exceptions
+    /// into `PythonError`. `exceeded` is
+    /// the store limit that denied a growth during the call, if any, and
+    /// `over_budget` the lines run when the guest reported going over the
+    /// line budget.
+    fn finish<E: Into<error::PythonError>>(
+        &self,
+        result: Result<Result<String, E>>,
+        timed_out: bool,
+        exceeded: Option<LimitViolation>,
+        over_budget: Option<u64>,
+    ) -> Result<String> {
         match result {
             Ok(Ok(val)) => Ok(val),
-            Ok(Err(e)) => Err(anyhow!("exec error: {}", e)),
+            Ok(Err(e)) => {
+                let error: error::PythonError = e.into();
+                match exceeded {
+                    // The guest reports a denied growth as a MemoryError,
+                    // anything else was raised after the code caught it
+                    Some(violation) if error.exception == PyException::Memory => {
+                        Err(violation.into_error())
+                    }
+                    _ => match (self.wasi.line_budget, over_budget) {
+                        (Some(budget), Some(executed)) => {
+                            Err(PyboxError::ExecutionBudgetExceeded { budget, executed }.into())
+                        }
+                        // Raised by the interrupt at the timeout, or while handling it
+                        _ if timed_out => Err(PyboxError::Timeout.into()),
+                        _ => Err(anyhow::Error::new(error)),
+                    },
+                }
+            }
             Err(e) => {
-                if timeout_triggered.load(Ordering::SeqCst) {
-                    return Err(anyhow!("Execution timed out"));
+                if timed_out {
+                    return Err(PyboxError::Timeout.into());
+                }
+                if let Some(violation) = exceeded.or_else(|| self.wasi.limits.violated_by(&e)) {
+                    return Err(violation.into_error());
+                }
+                let out_of_fuel = e.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel);
+                if let (true, Some(limit)) = (out_of_fuel, self.fuel_limit) {
+                    return Err(PyboxError::FuelExhausted { limit }.into());
+                }
+                match TrapKind::of(&e) {
+                    Some(kind) => Err(e.context(PyboxError::Trap(kind))),
+                    None => Err(e),
                 }
-                Err(e)
             }
         }
     }
+
+    /// Engine and pre-linked component used for async execution,
+    /// compiled on first use from the same configuration as the sync
+    /// engine.
+    #[cfg(feature = "async")]
+    fn async_runtime(&self) -> Result<AsyncRuntime> {
+        let loaded = self.loaded();
+        let mut runtime = loaded.async_runtime.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(runtime) = &*runtime {
+            return Ok(runtime.clone());
+        }
+
+        let mut config = self.config.clone();
+        config.async_support(true);
+        let engine = Engine::new(&config).context("Failed to create async wasm engine")?;
+        let (component, _) = load_component_file(
+            &engine,
+            loaded.source.path.as_deref(),
+            loaded.source.sha256.as_deref(),
+        )?;
+
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+        add_random_to_linker(&mut linker)?;
+        linker.allow_shadowing(true);
+        wasmtime_wasi::p2::bindings::filesystem::types::add_to_linker::<_, QuotaFilesystem<'static>>(
+            &mut linker,
+            quota_filesystem,
+        )?;
+        linker.allow_shadowing(false);
+        #[cfg(feature = "http")]
+        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
+        async_bindings::Sandbox::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| state)?;
+        self.wasi.extensions.add_to_linker(&mut linker)?;
+        let instance_pre = async_bindings::SandboxPre::new(linker.instantiate_pre(&component)?)?;
+
+        let async_runtime = AsyncRuntime {
+            timer: deadline_timer(&engine),
+            engine,
+            instance_pre,
+        };
+        *runtime = Some(async_runtime.clone());
+        Ok(async_runtime)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::Path;
+    use std::cell::Cell;
+
+    thread_local! {
+        // Calls to `link` on this thread
+        pub(super) static LINKS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn test_exec_reuses_linked_component() {
+        if !Path::new("sandbox.wasm").exists() {
+            return;
+        }
+
+        let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+        let linked = LINKS.with(Cell::get);
+        for _ in 0..3 {
+            sandbox.exec("1 + 1").unwrap();
+        }
+        sandbox.clone().exec("1 + 1").unwrap();
+        assert_eq!(LINKS.with(Cell::get), linked);
+    }
 
     #[test]
     fn test_sandbox_default_timeout() {
@@ -189,6 +3781,430 @@ mod tests {
         assert_eq!(sandbox.timeout_seconds, 10);
     }
 
+    #[test]
+    fn test_policy_replaces_builder_settings() {
+        let builder = PySandbox::builder()
+            .mount("/tmp", "/tmp", MountMode::ReadWrite)
+            .env("API_KEY", "secret")
+            .inherit_env()
+            .policy(SandboxPolicy::pure_compute());
+        assert!(builder.wasi.mounts.is_empty());
+        assert!(builder.wasi.env.is_empty());
+        assert!(!builder.wasi.inherit_env);
+        assert_eq!(builder.timeout_seconds, Some(10));
+    }
+
+    #[test]
+    fn test_policy_resets_settings_it_does_not_name() {
+        let builder = PySandbox::builder()
+            .clock_policy(ClockPolicy::MonotonicOnly)
+            .random(RandomPolicy::Deny)
+            .allowed_imports(["json"])
+            .max_wasm_stack(1 << 20)
+            .max_instances(4)
+            .policy(SandboxPolicy::pure_compute());
+        assert_eq!(builder.wasi.clock_policy, ClockPolicy::default());
+        assert_eq!(builder.wasi.random_policy, RandomPolicy::default());
+        assert_eq!(builder.wasi.allowed_imports, None);
+        assert_eq!(builder.wasi.limits.wasm_stack, None);
+        assert_eq!(builder.wasi.limits.instances, None);
+        assert_eq!(builder.wasi.limits.memory_bytes, Some(64 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_deterministic_mode_rejects_winch() {
+        let result = PySandbox::builder()
+            .fast_compilation(true)
+            .deterministic(7)
+            .build();
+        let Err(e) = result else {
+            panic!("deterministic mode should not build with Winch");
+        };
+        assert!(e.to_string().contains("Winch"));
+    }
+
+    #[test]
+    fn test_timeout_grace_enables_guest_interrupts() {
+        let settings = |builder: PySandboxBuilder| -> serde_json::Value {
+            serde_json::from_str(&builder.wasi.guest_settings()).unwrap()
+        };
+        assert_eq!(settings(PySandbox::builder()).get("interrupt"), None);
+        let builder = PySandbox::builder().timeout_grace(Duration::from_secs(2));
+        assert_eq!(settings(builder)["interrupt"], true);
+    }
+
+    #[test]
+    fn test_line_budget_is_reported_out_of_band() {
+        let builder = PySandbox::builder().line_budget(50);
+        let settings: serde_json::Value = serde_json::from_str(&builder.wasi.guest_settings()).unwrap();
+        assert_eq!(settings["line_budget"], 50);
+
+        let state = wasi_state(&WasiConfig::default(), &[], None).unwrap();
+        assert_eq!(state.over_budget(), None);
+        state.report_over_budget(51);
+        state.report_over_budget(99);
+        assert_eq!(state.over_budget(), Some(51));
+    }
+
+    #[test]
+    fn test_stores_get_extension_state() {
+        struct Tokens;
+        struct Issued(Vec<String>);
+
+        impl SandboxExtension for Tokens {
+            fn name(&self) -> &str {
+                "tokens"
+            }
+
+            fn init_state(&self, state: &mut ExtensionState) {
+                state.insert(Issued(Vec::new()));
+            }
+        }
+
+        let builder = PySandbox::builder().extension(Tokens);
+        let mut state = wasi_state(&builder.wasi, &[], None).unwrap();
+        let issued = state.extension_state().get_mut::<Issued>().unwrap();
+        issued.0.push("a".to_string());
+        assert_eq!(state.extension_state().get::<Issued>().unwrap().0, ["a"]);
+    }
+
+    #[test]
+    fn test_unknown_component_variant_fails_build() {
+        let registry = ComponentRegistry::new()
+            .register("py311", crate::variant::ComponentVariant::new("py311.wasm"));
+        let err = PySandbox::builder()
+            .component_registry(registry)
+            .component_variant("py312-datasci")
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Unknown component variant 'py312-datasci'"), "{}", err);
+    }
+
+    #[test]
+    fn test_exec_output_json() {
+        let mut output = ExecOutput {
+            value: "{\"a\": [1, null]}".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(output.json().unwrap(), serde_json::json!({"a": [1, null]}));
+
+        output.value = "NaN".to_string();
+        let err = output.json().unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidJson>().unwrap().value, "NaN");
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_matches_guest_encoding() {
+        // The same bytes test_guest.py expects from msgpack_pack
+        let value = serde_json::json!({"a": [1, null]});
+        assert_eq!(rmp_serde::to_vec_named(&value).unwrap(), b"\x81\xa1a\x92\x01\xc0");
+        let decoded: Value = rmp_serde::from_slice(b"\x81\xa1a\x92\x01\xc0").unwrap();
+        assert_eq!(decoded, value);
+        // The guest encodes every int above 127 as a uint 64
+        let decoded: u16 = rmp_serde::from_slice(b"\xcf\0\0\0\0\0\0\x01\0").unwrap();
+        assert_eq!(decoded, 256);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn test_ipc_stream_round_trip() {
+        use arrow_array::{Array, Int64Array, StringArray};
+
+        let batch = RecordBatch::try_from_iter([
+            ("id", Arc::new(Int64Array::from(vec![Some(1), None])) as Arc<dyn Array>),
+            ("name", Arc::new(StringArray::from(vec!["a", "b"])) as Arc<dyn Array>),
+        ])
+        .unwrap();
+        let bytes = write_ipc_stream(&batch).unwrap();
+        assert_eq!(read_ipc_stream(&bytes).unwrap(), batch);
+
+        // A stream without batches decodes to an empty table
+        let empty = RecordBatch::new_empty(batch.schema());
+        let mut bytes = Vec::new();
+        StreamWriter::try_new(&mut bytes, &batch.schema()).unwrap().finish().unwrap();
+        assert_eq!(read_ipc_stream(&bytes).unwrap(), empty);
+        assert!(read_ipc_stream(b"not arrow").is_err());
+    }
+
+    #[test]
+    fn test_handles_are_send() {
+        fn assert_send<T: Send + 'static>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<PySandbox>();
+        assert_send::<ExecOptions>();
+        assert_send::<ExecOutput>();
+        assert_send::<CancelHandle>();
+        assert_sync::<CancelHandle>();
+        assert_send::<crate::pool::SandboxPool>();
+        assert_sync::<crate::pool::SandboxPool>();
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify_sha256(b"hello", digest).is_ok());
+        assert!(verify_sha256(b"hello", &digest.to_uppercase()).is_ok());
+        let err = verify_sha256(b"hellO", digest).unwrap_err();
+        assert_eq!(err.downcast_ref::<DigestMismatch>().unwrap().expected, digest);
+        assert!(verify_sha256(b"hello", "abc").is_err());
+    }
+
+    #[test]
+    fn test_resource_tracker_records_denied_growth() {
+        let limits = Limits {
+            memory_bytes: Some(1 << 20),
+            ..Limits::default()
+        };
+        let mut tracker = ResourceTracker::new(&limits);
+        assert!(tracker.memory_growing(0, 1 << 20, None).unwrap());
+        assert_eq!(tracker.exceeded(), None);
+        assert!(!tracker.memory_growing(1 << 20, 2 << 20, None).unwrap());
+        assert_eq!(
+            tracker.exceeded(),
+            Some(LimitViolation {
+                limit: ResourceLimit::Memory,
+                allowed: 1 << 20,
+                requested: Some(2 << 20),
+            })
+        );
+        assert_eq!(tracker.peak_memory_bytes, 1 << 20);
+    }
+
+    #[test]
+    fn test_limits_name_count_violations() {
+        let limits = Limits {
+            instances: Some(2),
+            ..Limits::default()
+        };
+        let counts = ComponentCounts {
+            instances: Some(3),
+            memories: Some(1),
+            tables: None,
+        };
+        let err = limits.instantiation_error(anyhow!("resource limit exceeded"), counts);
+        let violation = limits.violated_by(&err).unwrap();
+        assert_eq!(violation.limit, ResourceLimit::Instances);
+        assert_eq!((violation.allowed, violation.excess()), (2, Some(1)));
+
+        let limits = Limits {
+            memories: Some(0),
+            ..Limits::default()
+        };
+        let violation = limits.count_violation(counts).unwrap();
+        assert_eq!(violation.limit, ResourceLimit::Memories);
+        assert_eq!((violation.allowed, violation.requested), (0, Some(1)));
+        // Within the limits, or not known, the error is left as it is
+        assert_eq!(Limits::default().count_violation(counts), None);
+        let err = Limits::default().instantiation_error(anyhow!("unrelated"), counts);
+        assert_eq!(limits.violated_by(&err), None);
+    }
+
+    #[test]
+    fn test_cache_uses_configured_directory_and_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CacheOptions {
+            directory: Some(dir.path().join("cache")),
+            max_size_bytes: Some(1 << 20),
+            max_files: Some(10),
+        };
+        let cache = options.cache().unwrap();
+        assert!(dir.path().join("cache").is_dir());
+        assert_eq!(cache.files_total_size_soft_limit(), 1 << 20);
+        assert_eq!(cache.file_count_soft_limit(), 10);
+    }
+
+    #[test]
+    fn test_unusable_cache_falls_back_to_no_cache() {
+        // A directory can't be created under a file
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let options = CacheOptions {
+            directory: Some(file.path().join("cache")),
+            ..CacheOptions::default()
+        };
+        assert!(options.cache().is_err());
+        let engine = EngineOptions {
+            cache_options: options,
+            ..EngineOptions::default()
+        };
+        assert!(engine.config().is_ok());
+    }
+
+    #[test]
+    fn test_pooling_engine_can_be_created() {
+        let options = EngineOptions {
+            pooling: Some(PoolingOptions {
+                max_instances: 2,
+                ..PoolingOptions::default()
+            }),
+            ..EngineOptions::fast()
+        };
+        Engine::new(&options.config().unwrap()).expect("Failed to create pooling engine");
+    }
+
+    #[test]
+    fn test_precompiled_rejects_foreign_file() {
+        let config = EngineOptions::default().config().unwrap();
+        let engine = Engine::new(&config).expect("Failed to create engine");
+        let path = Path::new("not-a-component.cwasm");
+        let err = check_precompiled(&engine, b"\0asm garbage", path).unwrap_err();
+        assert!(err.to_string().contains("not a precompiled pybox component"));
+    }
+
+    #[test]
+    fn test_precompiled_rejects_stale_engine_hash() {
+        let config = EngineOptions::default().config().unwrap();
+        let engine = Engine::new(&config).expect("Failed to create engine");
+        let mut bytes = PRECOMPILED_MAGIC.to_vec();
+        bytes.extend_from_slice(&engine_hash(&engine).wrapping_add(1).to_le_bytes());
+        let err = check_precompiled(&engine, &bytes, Path::new("stale.cwasm")).unwrap_err();
+        assert!(err.to_string().contains("incompatible engine configuration"));
+    }
+
+    #[test]
+    fn test_mount_source_must_be_a_directory() {
+        let result = PySandbox::builder()
+            .mount("does/not/exist", "/data", MountMode::ReadOnly)
+            .build();
+        let err = result.err().expect("Expected mount validation to fail");
+        assert!(err.to_string().contains("is not a directory"));
+    }
+
+    #[test]
+    fn test_collect_artifacts_reads_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        fs::write(dir.path().join("nested/a.txt"), "a").unwrap();
+
+        let artifacts = collect_artifacts(dir.path()).unwrap();
+        assert_eq!(
+            artifacts,
+            vec![
+                (PathBuf::from("b.txt"), b"b".to_vec()),
+                (PathBuf::from("nested/a.txt"), b"a".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_site_packages_copies_dirs_and_wheels() {
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir(src.path().join("greeting")).unwrap();
+        fs::write(src.path().join("greeting/__init__.py"), "").unwrap();
+        fs::write(src.path().join("tool-1.0-py3-none-any.whl"), "wheel").unwrap();
+
+        let (dir, python_path) = build_site_packages(
+            &[
+                src.path().join("greeting"),
+                src.path().join("tool-1.0-py3-none-any.whl"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert!(dir.path().join("greeting/__init__.py").is_file());
+        assert!(dir.path().join("tool-1.0-py3-none-any.whl").is_file());
+        assert_eq!(
+            python_path,
+            vec!["/site-packages", "/site-packages/tool-1.0-py3-none-any.whl"]
+        );
+    }
+
+    #[test]
+    fn test_build_site_packages_rejects_other_files() {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("module.py"), "").unwrap();
+
+        let err = build_site_packages(&[src.path().join("module.py")], &[]).unwrap_err();
+        assert!(err.to_string().contains("must be a directory or a .whl file"));
+    }
+
+    #[test]
+    fn test_build_site_packages_writes_modules() {
+        let modules = [
+            ("helpers".to_string(), "X = 1".to_string()),
+            ("tools.text".to_string(), "Y = 2".to_string()),
+        ];
+        let (dir, _) = build_site_packages(&[], &modules).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("helpers.py")).unwrap(), "X = 1");
+        assert!(dir.path().join("tools/__init__.py").is_file());
+        assert_eq!(fs::read_to_string(dir.path().join("tools/text.py")).unwrap(), "Y = 2");
+    }
+
+    #[test]
+    fn test_build_site_packages_rejects_bad_modules() {
+        let module = |name: &str| (name.to_string(), String::new());
+        for modules in [
+            vec![module("1helpers")],
+            vec![module("helpers..text")],
+            vec![module("../escape")],
+            vec![module("helpers"), module("helpers")],
+            vec![module("helpers"), module("helpers.text")],
+            vec![module("helpers.text"), module("helpers")],
+        ] {
+            assert!(build_site_packages(&[], &modules).is_err(), "{:?}", modules);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "embedded-wasm"))]
+    fn test_component_search_paths_include_working_and_exe_dirs() {
+        let paths = component_search_paths();
+        assert!(paths.contains(&PathBuf::from("sandbox.wasm")));
+        let exe_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        assert!(paths.contains(&exe_dir.join("sandbox.wasm")));
+    }
+
+    fn preinit_checkout() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for input in PREINIT_INPUTS {
+            fs::write(dir.path().join(input), input).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_preinitialized_path_ignores_order_and_duplicates() {
+        let dir = preinit_checkout();
+        let root = dir.path();
+        let a = preinitialized_path(root, &["json", "math"]).unwrap();
+        let b = preinitialized_path(root, &["math", "json", "math"]).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, preinitialized_path(root, &["json"]).unwrap());
+        assert_eq!(a.parent(), Some(root));
+        assert!(a.file_name().unwrap().to_string_lossy().starts_with("sandbox-preinit-"));
+    }
+
+    #[test]
+    fn test_preinitialized_path_changes_with_the_build_inputs() {
+        let dir = preinit_checkout();
+        let before = preinitialized_path(dir.path(), &["json"]).unwrap();
+        fs::write(dir.path().join("guest.py"), "changed").unwrap();
+        assert_ne!(preinitialized_path(dir.path(), &["json"]).unwrap(), before);
+        fs::remove_file(dir.path().join("uv.lock")).unwrap();
+        assert!(preinitialized_path(dir.path(), &["json"]).is_err());
+    }
+
+    #[test]
+    fn test_preinitialized_path_rejects_invalid_module_names() {
+        let dir = preinit_checkout();
+        let root = dir.path();
+        assert!(preinitialized_path(root, &["os.path"]).is_ok());
+        assert!(preinitialized_path(root, &["os; import sys"]).is_err());
+        assert!(preinitialized_path(root, &["1abc"]).is_err());
+        assert!(preinitialized_path(root, &[""]).is_err());
+    }
+
+    #[test]
+    fn test_is_module_name() {
+        assert!(is_module_name("helpers"));
+        assert!(is_module_name("_pkg.sub_2"));
+        assert!(!is_module_name("pkg..sub"));
+        assert!(!is_module_name("../escape"));
+        assert!(!is_module_name("pkg."));
+    }
+
     #[test]
     fn test_sandbox_creates_successfully_with_wasm() {
         // Test that sandbox creation succeeds when sandbox.wasm exists
@@ -0,0 +1,302 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+
+use crate::error::{PyboxError, PythonError};
+use crate::output::{OutputSink, Stream};
+use crate::pool::SandboxPool;
+use crate::report::{cell_report, exec_report, ExecRequest};
+use crate::sandbox::{ExecOptions, ExecOutput, PySandbox};
+use crate::session::{lock, SessionManager};
+
+/// Settings of the execution server. Requests can lower the timeout of
+/// their code but never raise it past `max_timeout`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    /// Warm sandboxes serving `POST /exec`, which is how many one-off
+    /// executions run at the same time.
+    pub workers: usize,
+    /// Sessions kept at once, new sessions are refused beyond that until
+    /// one is deleted or expires.
+    pub max_sessions: usize,
+    /// How long a session may sit unused before it is dropped, `None`
+    /// keeps sessions until they are deleted.
+    pub session_idle_ttl: Option<Duration>,
+    /// Longest timeout a request may ask for with `timeout_ms`.
+    pub max_timeout: Duration,
+    /// Largest request body accepted, which bounds the size of the code.
+    pub max_body_bytes: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 8000)),
+            workers: 4,
+            max_sessions: 64,
+            session_idle_ttl: Some(Duration::from_secs(30 * 60)),
+            max_timeout: Duration::from_secs(60),
+            max_body_bytes: 1 << 20,
+        }
+    }
+}
+
+/// Serve code execution over HTTP on `config.addr` until the process is
+/// stopped. See `router` for the endpoints.
+pub fn run(sandbox: PySandbox, config: ServerConfig) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the server runtime")?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(config.addr)
+            .await
+            .with_context(|| format!("Failed to bind {}", config.addr))?;
+        eprintln!("pybox serve: listening on {}", config.addr);
+        axum::serve(listener, router(sandbox, config))
+            .await
+            .context("Server failed")
+    })
+}
+
+/// The server's routes, for mounting them in an existing axum app.
+///
+/// - `POST /exec` runs `{"code": "...", "timeout_ms": 500}` on a pooled
+///   sandbox, each request starts from a fresh interpreter.
+/// - `POST /sessions/{id}/exec` runs the code as the next cell of the
+///   session `id`, which is created on first use and dropped after
+///   `session_idle_ttl` without use.
+/// - `DELETE /sessions/{id}` drops a session and its state.
+/// - `GET /exec/stream` is a WebSocket taking the same requests as
+///   `/exec`, one per text message. Output is sent while the code runs
+///   as `{"type": "stdout", "data": "..."}` (or `stderr`) frames,
+///   followed by a `{"type": "result", ...}` frame with the report.
+///   Closing the socket cancels the running code.
+///
+/// Responses are the JSON objects of `report::exec_report`. Code that
+/// raises or runs out of time is still answered with 200, `error` says
+/// what went wrong.
+pub fn router(sandbox: PySandbox, config: ServerConfig) -> Router {
+    let max_body_bytes = config.max_body_bytes;
+    let server = Server {
+        pool: SandboxPool::new(sandbox.clone(), config.workers),
+        sessions: SessionManager::new(sandbox.clone(), config.max_sessions, config.session_idle_ttl),
+        template: Mutex::new(sandbox),
+        config,
+    };
+    Router::new()
+        .route("/exec", post(exec))
+        .route("/exec/stream", get(exec_stream))
+        .route("/sessions/{id}/exec", post(exec_in_session))
+        .route("/sessions/{id}", delete(delete_session))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(Arc::new(server))
+}
+
+struct Server {
+    pool: SandboxPool,
+    // Source of cancel handles, behind a lock because sandboxes are
+    // `Send` but not `Sync`
+    template: Mutex<PySandbox>,
+    sessions: SessionManager,
+    config: ServerConfig,
+}
+
+type Response = (StatusCode, Json<Value>);
+
+async fn exec(State(server): State<Arc<Server>>, Json(body): Json<Value>) -> Response {
+    let request = match ExecRequest::parse(&body, server.config.max_timeout) {
+        Ok(request) => request,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+    let started = Instant::now();
+    let result = blocking(move || server.pool.exec_with_options(&request.code, &request.options()))
+        .await;
+    exec_response(&result, started.elapsed())
+}
+
+async fn exec_stream(
+    State(server): State<Arc<Server>>,
+    upgrade: WebSocketUpgrade,
+) -> axum::response::Response {
+    upgrade.on_upgrade(move |socket| stream_execs(server, socket))
+}
+
+/// Run each request received on `socket` in turn, sending output as it
+/// is printed.
+async fn stream_execs(server: Arc<Server>, mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let request = serde_json::from_str::<Value>(text.as_str())
+            .map_err(|e| format!("Invalid request: {}", e))
+            .and_then(|body| ExecRequest::parse(&body, server.config.max_timeout));
+        let request = match request {
+            Ok(request) => request,
+            Err(message) => {
+                let error = json!({ "type": "result", "ok": false, "error": message });
+                if socket.send(frame(error)).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let (chunks, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let sink: OutputSink = Arc::new(move |stream, bytes: &[u8]| {
+            let _ = chunks.send((stream, String::from_utf8_lossy(bytes).into_owned()));
+        });
+        let cancel = lock(&server.template).cancel_handle();
+        let options = ExecOptions {
+            timeout: request.timeout,
+            cancel: Some(cancel.clone()),
+            on_output: Some(sink),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let worker = server.clone();
+        let mut run = std::pin::pin!(blocking(move || {
+            worker.pool.exec_with_options(&request.code, &options)
+        }));
+
+        let result = loop {
+            tokio::select! {
+                Some((stream, data)) = received.recv() => {
+                    if socket.send(frame(output_frame(stream, data))).await.is_err() {
+                        // Nobody is listening anymore, stop the code
+                        cancel.cancel();
+                        let _ = run.await;
+                        return;
+                    }
+                }
+                result = &mut run => break result,
+            }
+        };
+        // Output written just before the code finished
+        while let Ok((stream, data)) = received.try_recv() {
+            if socket.send(frame(output_frame(stream, data))).await.is_err() {
+                return;
+            }
+        }
+        let mut report = exec_report(&result, started.elapsed());
+        report["type"] = json!("result");
+        if socket.send(frame(report)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn output_frame(stream: Stream, data: String) -> Value {
+    let kind = match stream {
+        Stream::Stdout => "stdout",
+        Stream::Stderr => "stderr",
+    };
+    json!({ "type": kind, "data": data })
+}
+
+fn frame(value: Value) -> Message {
+    Message::Text(value.to_string().into())
+}
+
+async fn exec_in_session(
+    State(server): State<Arc<Server>>,
+    Path(id): Path<String>,
+    Json(body): Json<Value>,
+) -> Response {
+    let request = match ExecRequest::parse(&body, server.config.max_timeout) {
+        Ok(request) => request,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+    let session = match server.sessions.get_or_create(&id) {
+        Ok(session) => session,
+        Err(limit) => {
+            let message = format!("at most {} sessions can be open", limit.max_sessions);
+            return error_response(StatusCode::TOO_MANY_REQUESTS, message);
+        }
+    };
+    let result = blocking(move || {
+        Ok(lock(&session).exec_cell_with_options(&request.code, &request.options()))
+    })
+    .await;
+    match result {
+        Ok(cell) => (StatusCode::OK, Json(cell_report(&cell))),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+    }
+}
+
+async fn delete_session(State(server): State<Arc<Server>>, Path(id): Path<String>) -> StatusCode {
+    if server.sessions.remove(&id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Run `f` on the blocking thread pool, executions hold their thread for
+/// as long as the code runs.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("Execution panicked: {}", e)))
+}
+
+fn exec_response(result: &Result<ExecOutput>, duration: Duration) -> Response {
+    // Failures caused by the code are a normal outcome, anything else is
+    // the server's fault
+    let status = match result {
+        Err(e)
+            if e.downcast_ref::<PythonError>().is_none()
+                && e.downcast_ref::<PyboxError>().is_none() =>
+        {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        _ => StatusCode::OK,
+    };
+    (status, Json(exec_report(result, duration)))
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "ok": false, "error": message.into() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_frames() {
+        assert_eq!(
+            output_frame(Stream::Stderr, "oops\n".to_string()),
+            json!({"type": "stderr", "data": "oops\n"})
+        );
+    }
+
+    #[test]
+    fn test_exec_response_status_by_error_kind() {
+        let timeout: Result<ExecOutput> = Err(PyboxError::Timeout.into());
+        let (status, Json(body)) = exec_response(&timeout, Duration::from_millis(5));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], json!(false));
+        assert_eq!(body["error"], json!("Execution timed out"));
+
+        let host: Result<ExecOutput> = Err(anyhow!("no sandbox.wasm"));
+        let (status, _) = exec_response(&host, Duration::ZERO);
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use crate::error::PyboxError;
+use crate::sandbox::{ExecOptions, PySandbox};
+
+/// The outcome of one notebook cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellResult {
+    /// JSON serialized value of the cell's last expression, `None` when
+    /// the cell failed.
+    pub value: Option<String>,
+    /// Everything the cell printed to stdout.
+    pub stdout: String,
+    /// Everything the cell printed to stderr.
+    pub stderr: String,
+    /// The exception raised by the cell, or why it could not run.
+    pub error: Option<String>,
+    /// Time spent running the cell.
+    pub duration: Duration,
+}
+
+/// Notebook style execution where cells share interpreter state, so a
+/// variable or function defined by one cell is visible to the cells that
+/// run after it.
+///
+/// ```no_run
+/// # use pybox::sandbox::PySandbox;
+/// # use pybox::session::Session;
+/// let mut session = Session::new(PySandbox::new(None)?);
+/// let results = session.exec_cells(&["x = 20", "print('adding')\nx + 22"]);
+/// assert_eq!(results[1].value.as_deref(), Some("42"));
+/// assert_eq!(results[1].stdout, "adding\n");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// A cell that times out or is otherwise aborted loses the session's
+/// state, the next cell starts from a fresh interpreter. `recover` brings
+/// back the variables of the last snapshot, and sandboxes built with
+/// `auto_recover` do so on their own.
+pub struct Session {
+    sandbox: PySandbox,
+    // Variables `recover` restores, from the latest snapshot taken or
+    // restored
+    checkpoint: Option<Vec<u8>>,
+}
+
+impl Session {
+    pub fn new(sandbox: PySandbox) -> Self {
+        Self {
+            sandbox,
+            checkpoint: None,
+        }
+    }
+
+    /// Run `cells` in order, continuing past cells that fail.
+    pub fn exec_cells(&mut self, cells: &[&str]) -> Vec<CellResult> {
+        cells.iter().map(|cell| self.exec_cell(cell)).collect()
+    }
+
+    /// Run a single cell.
+    pub fn exec_cell(&mut self, code: &str) -> CellResult {
+        self.exec_cell_with_options(code, &ExecOptions::default())
+    }
+
+    /// Run a single cell with a different timeout or a cancel handle, see
+    /// `ExecOptions`. Output is always captured, `capture_output` is
+    /// ignored.
+    pub fn exec_cell_with_options(&mut self, code: &str, options: &ExecOptions) -> CellResult {
+        let started = Instant::now();
+        let result = self.sandbox.exec_cell(code, options);
+        let lost = result.as_ref().is_err_and(instance_lost);
+        let mut cell = cell_result(result, started);
+        if let Err(e) = self.after_cell(lost, true) {
+            add_error(&mut cell, e);
+        }
+        cell
+    }
+
+    /// Evaluate a single expression against the variables defined by the
+    /// cells so far. Statements are rejected, run those with `exec_cell`.
+    pub fn eval_cell(&mut self, expression: &str) -> CellResult {
+        self.eval_cell_with_options(expression, &ExecOptions::default())
+    }
+
+    /// Like `eval_cell` with a different timeout or a cancel handle.
+    pub fn eval_cell_with_options(&mut self, expression: &str, options: &ExecOptions) -> CellResult {
+        let started = Instant::now();
+        let result = self.sandbox.eval_cell(expression, options);
+        let lost = result.as_ref().is_err_and(instance_lost);
+        let mut cell = cell_result(result, started);
+        // Expressions don't change the variables, no snapshot needed
+        if let Err(e) = self.after_cell(lost, false) {
+            add_error(&mut cell, e);
+        }
+        cell
+    }
+
+    /// Forget every variable, function and import defined so far. The
+    /// next cell starts from a fresh interpreter.
+    pub fn reset(&mut self) {
+        self.sandbox.clear_last_run();
+        self.checkpoint = None;
+    }
+
+    /// Serialize the session's variables so `restore` can bring them back
+    /// in another session, process or host running the same component.
+    ///
+    /// Values are pickled, so numbers, strings, bytes, containers and
+    /// instances of classes from importable modules (numpy arrays, pandas
+    /// DataFrames, datetimes, ...) are captured. Imported modules are
+    /// recorded by name and imported again on restore. Anything that
+    /// can't be pickled is left out: functions and classes defined by
+    /// the cells themselves, lambdas, open files, sockets, generators and
+    /// values holding any of those. Re-run the cells defining them after
+    /// restoring.
+    pub fn snapshot(&mut self) -> Result<Vec<u8>> {
+        let snapshot = self.sandbox.snapshot_cells()?;
+        self.checkpoint = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    /// Replace the session's variables with those of a `snapshot`.
+    ///
+    /// Restoring unpickles inside the sandbox, under its limits and
+    /// import policy, so a tampered snapshot can do no more than the code
+    /// the session runs. Snapshots taken with another component may not
+    /// load.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        self.sandbox.restore_cells(snapshot)?;
+        self.checkpoint = Some(snapshot.to_vec());
+        Ok(())
+    }
+
+    /// Start over from a fresh interpreter holding the variables of the
+    /// latest snapshot, the one `snapshot` returned or `restore` loaded,
+    /// or none if there isn't one. Use it after a cell traps or times out
+    /// to continue where the session was instead of from scratch.
+    ///
+    /// ```no_run
+    /// # use pybox::sandbox::PySandbox;
+    /// # use pybox::session::Session;
+    /// let mut session = Session::new(PySandbox::builder().timeout_seconds(1).build()?);
+    /// session.exec_cell("x = 41");
+    /// session.snapshot()?;
+    /// let stuck = session.exec_cell("while True:\n    pass");
+    /// assert!(stuck.error.is_some());
+    /// session.recover()?;
+    /// assert_eq!(session.eval_cell("x + 1").value.as_deref(), Some("42"));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn recover(&mut self) -> Result<()> {
+        self.sandbox.clear_last_run();
+        match &self.checkpoint {
+            Some(snapshot) => self.sandbox.restore_cells(snapshot),
+            None => Ok(()),
+        }
+    }
+
+    /// The sandbox the session runs in, e.g. to read variables with
+    /// `get_globals` between cells.
+    pub fn sandbox(&mut self) -> &mut PySandbox {
+        &mut self.sandbox
+    }
+
+    /// With `auto_recover`, take a snapshot after a cell that may have
+    /// changed the variables, or recover after one that took the
+    /// instance with it. Exceptions raised by the cell leave the
+    /// interpreter intact and need neither.
+    fn after_cell(&mut self, lost: bool, changed: bool) -> Result<()> {
+        if !self.sandbox.auto_recovers() {
+            return Ok(());
+        }
+        if !lost {
+            if !changed {
+                return Ok(());
+            }
+            // Taking the snapshot can stop the guest like a cell, otherwise
+            // its failure leaves the previous snapshot in place
+            match self.snapshot() {
+                Ok(_) => return Ok(()),
+                Err(e) if !instance_lost(&e) => {
+                    return Err(e.context("Failed to snapshot the session"));
+                }
+                Err(_) => {}
+            }
+        }
+        self.recover().context("Failed to recover the session")
+    }
+}
+
+/// Whether `error` stopped the guest and lost the session's interpreter.
+fn instance_lost(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<PyboxError>()
+        .is_some_and(PyboxError::loses_instance)
+}
+
+/// Report `error` on `cell` after whatever the cell itself failed with.
+fn add_error(cell: &mut CellResult, error: anyhow::Error) {
+    let error = format!("{:#}", error);
+    cell.error = Some(match cell.error.take() {
+        Some(previous) => format!("{}\n{}", previous, error),
+        None => error,
+    });
+}
+
+/// Sessions by ID for serving many users from one process, as the HTTP
+/// and gRPC servers do. Sessions are created on demand from a template
+/// sandbox, at most `max_sessions` at a time, and those left idle for
+/// longer than `idle_ttl` are evicted, dropping their interpreter.
+///
+/// Eviction happens whenever sessions are looked up, call
+/// `evict_expired` periodically to also reclaim them when traffic stops.
+///
+/// ```no_run
+/// # use pybox::sandbox::PySandbox;
+/// # use pybox::session::SessionManager;
+/// # use std::time::Duration;
+/// let sessions = SessionManager::new(PySandbox::new(None)?, 100, Some(Duration::from_secs(600)));
+/// let session = sessions.get_or_create("user-1")?;
+/// session.lock().unwrap().exec_cell("x = 1");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct SessionManager {
+    // Source of the sandboxes of new sessions, behind a lock because
+    // sandboxes are `Send` but not `Sync`
+    template: Mutex<PySandbox>,
+    max_sessions: usize,
+    idle_ttl: Option<Duration>,
+    sessions: Mutex<HashMap<String, ManagedSession>>,
+}
+
+struct ManagedSession {
+    session: Arc<Mutex<Session>>,
+    last_used: Instant,
+}
+
+impl SessionManager {
+    /// A manager creating sessions from clones of `template`. Sessions
+    /// never expire when `idle_ttl` is `None`.
+    pub fn new(template: PySandbox, max_sessions: usize, idle_ttl: Option<Duration>) -> Self {
+        Self {
+            template: Mutex::new(template),
+            max_sessions,
+            idle_ttl,
+            sessions: Mutex::default(),
+        }
+    }
+
+    /// The session `id`, created if it doesn't exist. Fails with
+    /// `SessionLimit` when `max_sessions` are open and none has expired.
+    pub fn get_or_create(&self, id: &str) -> Result<Arc<Mutex<Session>>, SessionLimit> {
+        let mut sessions = self.sessions();
+        if let Some(session) = self.touch(&mut sessions, id) {
+            return Ok(session);
+        }
+        if sessions.len() >= self.max_sessions {
+            return Err(SessionLimit {
+                max_sessions: self.max_sessions,
+            });
+        }
+        let sandbox = lock(&self.template).clone();
+        let session = Arc::new(Mutex::new(Session::new(sandbox)));
+        sessions.insert(
+            id.to_string(),
+            ManagedSession {
+                session: session.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(session)
+    }
+
+    /// The session `id` if it exists and hasn't expired.
+    pub fn get(&self, id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.touch(&mut self.sessions(), id)
+    }
+
+    /// Drop the session `id` and its state, returning whether it existed.
+    /// A cell it is running finishes first.
+    pub fn remove(&self, id: &str) -> bool {
+        self.sessions().remove(id).is_some()
+    }
+
+    /// Drop every session idle for longer than `idle_ttl`, returning how
+    /// many were dropped. Sessions running a cell are never idle.
+    pub fn evict_expired(&self) -> usize {
+        let mut sessions = lock(&self.sessions);
+        self.evict(&mut sessions)
+    }
+
+    /// Sessions currently open, including expired ones not yet evicted.
+    pub fn len(&self) -> usize {
+        lock(&self.sessions).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The sessions with expired ones evicted.
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, ManagedSession>> {
+        let mut sessions = lock(&self.sessions);
+        self.evict(&mut sessions);
+        sessions
+    }
+
+    fn touch(
+        &self,
+        sessions: &mut HashMap<String, ManagedSession>,
+        id: &str,
+    ) -> Option<Arc<Mutex<Session>>> {
+        let managed = sessions.get_mut(id)?;
+        managed.last_used = Instant::now();
+        Some(managed.session.clone())
+    }
+
+    fn evict(&self, sessions: &mut HashMap<String, ManagedSession>) -> usize {
+        let Some(ttl) = self.idle_ttl else {
+            return 0;
+        };
+        let now = Instant::now();
+        let before = sessions.len();
+        sessions.retain(|_, managed| {
+            // Someone else holding the session is using it right now
+            if Arc::strong_count(&managed.session) > 1 {
+                managed.last_used = now;
+            }
+            now.duration_since(managed.last_used) < ttl
+        });
+        before - sessions.len()
+    }
+}
+
+/// Returned by `SessionManager::get_or_create` when no more sessions can
+/// be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimit {
+    pub max_sessions: usize,
+}
+
+impl fmt::Display for SessionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "At most {} sessions can be open", self.max_sessions)
+    }
+}
+
+impl std::error::Error for SessionLimit {}
+
+/// Lock `mutex` even if a holder panicked. Sessions are swapped in and
+/// out whole, and the sandboxes the servers share are only read through
+/// it, so a panic never leaves them half updated.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The result of a cell from the guest's report, or from the error that
+/// kept the cell from running.
+fn cell_result(report: Result<String>, started: Instant) -> CellResult {
+    let result = report.and_then(|report| parse_report(&report));
+    let duration = started.elapsed();
+    match result {
+        Ok(CellReport { value, stdout, stderr, error }) => CellResult {
+            value,
+            stdout,
+            stderr,
+            error,
+            duration,
+        },
+        Err(e) => CellResult {
+            value: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: Some(format!("{:#}", e)),
+            duration,
+        },
+    }
+}
+
+/// The parts of the guest's cell report.
+#[derive(Debug, PartialEq)]
+struct CellReport {
+    value: Option<String>,
+    stdout: String,
+    stderr: String,
+    error: Option<String>,
+}
+
+/// Split the guest's cell report into value, output and error.
+fn parse_report(report: &str) -> Result<CellReport> {
+    let report: Value = serde_json::from_str(report).context("Invalid cell report")?;
+    let text = |key: &str| match &report[key] {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s.clone())),
+        other => Err(anyhow!("Invalid {} in cell report: {}", key, other)),
+    };
+    Ok(CellReport {
+        value: text("value")?,
+        stdout: text("stdout")?.unwrap_or_default(),
+        // Missing from the reports of components built before it was added
+        stderr: text("stderr")?.unwrap_or_default(),
+        error: text("error")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_stopped_guests_lose_the_instance() {
+        assert!(instance_lost(&PyboxError::Timeout.into()));
+        assert!(instance_lost(&PyboxError::RandomDenied.into()));
+        assert!(!instance_lost(&PyboxError::QuotaExceeded { retry_after: Duration::ZERO }.into()));
+        assert!(!instance_lost(&anyhow!("Invalid cell report")));
+    }
+
+    #[test]
+    fn test_add_error_keeps_the_cells_own() {
+        let mut cell = CellResult {
+            value: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: Some("Execution timed out".to_string()),
+            duration: Duration::ZERO,
+        };
+        add_error(&mut cell, anyhow!("Failed to recover the session"));
+        assert_eq!(
+            cell.error.as_deref(),
+            Some("Execution timed out\nFailed to recover the session")
+        );
+    }
+
+    #[test]
+    fn test_parse_report() {
+        let report = r#"{"value": "42", "stdout": "hi\n", "stderr": "oops\n", "error": null}"#;
+        assert_eq!(
+            parse_report(report).unwrap(),
+            CellReport {
+                value: Some("42".to_string()),
+                stdout: "hi\n".to_string(),
+                stderr: "oops\n".to_string(),
+                error: None,
+            }
+        );
+
+        let report = r#"{"value": null, "stdout": "", "error": "ValueError: boom"}"#;
+        assert_eq!(
+            parse_report(report).unwrap(),
+            CellReport {
+                value: None,
+                stdout: String::new(),
+                stderr: String::new(),
+                error: Some("ValueError: boom".to_string()),
+            }
+        );
+
+        assert!(parse_report(r#"{"value": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_session_manager_is_shareable_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SessionManager>();
+        let limit = SessionLimit { max_sessions: 2 };
+        assert_eq!(limit.to_string(), "At most 2 sessions can be open");
+    }
+}
@@ -0,0 +1,181 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Instant;
+
+// Prune finished deadlines once this many are waiting
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Runs a callback when scheduled deadlines pass, on a single background
+/// thread shared by every execution. A deadline whose guard was dropped,
+/// because the execution finished in time, is skipped.
+pub struct DeadlineTimer {
+    shared: Arc<Shared>,
+}
+
+/// Keeps a scheduled deadline alive, dropping it cancels the deadline.
+pub struct DeadlineGuard {
+    _token: Arc<()>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    deadlines: BinaryHeap<Entry>,
+    shutdown: bool,
+}
+
+struct Entry {
+    at: Instant,
+    token: Weak<()>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        self.token.strong_count() > 0
+    }
+}
+
+// Ordered so the heap pops the earliest deadline first
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Entry {}
+
+impl DeadlineTimer {
+    /// Start the timer thread, which calls `on_deadline` each time a live
+    /// deadline passes. The thread exits when the timer is dropped.
+    pub fn new(on_deadline: impl Fn() + Send + 'static) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::spawn(move || run(&thread_shared, on_deadline));
+        Self { shared }
+    }
+
+    /// Schedule a deadline at `at`, live for as long as the guard is kept.
+    pub fn schedule(&self, at: Instant) -> DeadlineGuard {
+        let token = Arc::new(());
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.deadlines.len() >= PRUNE_THRESHOLD {
+            state.deadlines.retain(Entry::is_live);
+        }
+        state.deadlines.push(Entry {
+            at,
+            token: Arc::downgrade(&token),
+        });
+        self.shared.wake.notify_one();
+        DeadlineGuard { _token: token }
+    }
+}
+
+impl Drop for DeadlineTimer {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.shutdown = true;
+        self.shared.wake.notify_one();
+    }
+}
+
+fn run(shared: &Shared, on_deadline: impl Fn()) {
+    let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+        if state.shutdown {
+            return;
+        }
+        while state.deadlines.peek().is_some_and(|entry| !entry.is_live()) {
+            state.deadlines.pop();
+        }
+
+        let now = Instant::now();
+        state = match state.deadlines.peek() {
+            None => shared.wake.wait(state).unwrap_or_else(|e| e.into_inner()),
+            Some(entry) if entry.at <= now => {
+                state.deadlines.pop();
+                on_deadline();
+                state
+            }
+            Some(entry) => {
+                let wait = entry.at - now;
+                shared
+                    .wake
+                    .wait_timeout(state, wait)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn counting_timer() -> (DeadlineTimer, Arc<AtomicUsize>) {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let timer = DeadlineTimer::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        (timer, fired)
+    }
+
+    #[test]
+    fn test_live_deadline_fires() {
+        let (timer, fired) = counting_timer();
+        let _guard = timer.schedule(Instant::now() + Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_dropped_deadline_is_skipped() {
+        let (timer, fired) = counting_timer();
+        drop(timer.schedule(Instant::now() + Duration::from_millis(10)));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_back_to_back_finished_deadlines_never_fire() {
+        let (timer, fired) = counting_timer();
+        for _ in 0..100 {
+            drop(timer.schedule(Instant::now() + Duration::from_millis(5)));
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_earlier_deadline_scheduled_later_fires_first() {
+        let (timer, fired) = counting_timer();
+        let _late = timer.schedule(Instant::now() + Duration::from_secs(60));
+        let _early = timer.schedule(Instant::now() + Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,138 @@
+//! Running Python functions as tools for a language model: describe them
+//! with JSON Schemas the model can call against, then invoke them with
+//! the JSON arguments it produced.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+
+use crate::error::InvalidJson;
+use crate::sandbox::PySandbox;
+
+/// A tool as shown to a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolSpec {
+    pub name: String,
+    /// The function's docstring up to its first section.
+    pub description: String,
+    /// JSON Schema of the keyword arguments, built from the signature's
+    /// annotations and defaults and the parameters the docstring
+    /// documents.
+    pub parameters: Value,
+}
+
+impl ToolSpec {
+    /// The tool as a JSON object with `name`, `description` and
+    /// `parameters`, the shape most model APIs take tool definitions in.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "parameters": self.parameters,
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let field = |name: &str| {
+            value
+                .get(name)
+                .with_context(|| format!("Tool description has no {}", name))
+        };
+        Ok(Self {
+            name: field("name")?.as_str().context("Tool name is not a string")?.to_string(),
+            description: field("description")?.as_str().unwrap_or_default().to_string(),
+            parameters: field("parameters")?.clone(),
+        })
+    }
+}
+
+/// The public functions defined by some Python source, callable as tools.
+///
+/// Every call runs the source on a fresh interpreter and goes through the
+/// sandbox's usual limits, quota, metrics and audit log, so a tool can't
+/// keep state between calls.
+///
+/// ```no_run
+/// # use pybox::sandbox::PySandbox;
+/// # use pybox::toolcall::Toolbox;
+/// let source = r#"
+/// def add(a: int, b: int = 0) -> int:
+///     """Add two numbers."""
+///     return a + b
+/// "#;
+/// let mut tools = Toolbox::new(PySandbox::new(None)?, source);
+/// assert_eq!(tools.describe_tools()?[0].name, "add");
+/// assert_eq!(tools.invoke_tool("add", r#"{"a": 1, "b": 2}"#)?, 3);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Toolbox {
+    sandbox: PySandbox,
+    source: String,
+}
+
+impl Toolbox {
+    pub fn new(sandbox: PySandbox, source: impl Into<String>) -> Self {
+        Self {
+            sandbox,
+            source: source.into(),
+        }
+    }
+
+    /// Describe each public function the source defines, in the order it
+    /// defines them. Functions it imports and names starting with `_`
+    /// are left out.
+    pub fn describe_tools(&mut self) -> Result<Vec<ToolSpec>> {
+        let json = self.sandbox.describe_tools(&self.source)?;
+        let tools: Value = serde_json::from_str(&json).context("Tool descriptions are not valid JSON")?;
+        tools
+            .as_array()
+            .context("Tool descriptions are not a list")?
+            .iter()
+            .map(ToolSpec::from_json)
+            .collect()
+    }
+
+    /// Call the tool `name` with `json_args`, a JSON object of keyword
+    /// arguments, and return its JSON result. Arguments that don't match
+    /// the signature fail with a `ValueError` before the tool runs.
+    pub fn invoke_tool(&mut self, name: &str, json_args: &str) -> Result<Value> {
+        let arguments: Value = serde_json::from_str(json_args)
+            .with_context(|| format!("Arguments of tool {} are not valid JSON", name))?;
+        if !arguments.is_object() {
+            return Err(anyhow!("Arguments of tool {} must be a JSON object", name));
+        }
+        let result = self
+            .sandbox
+            .invoke_tool(&self.source, name, &arguments.to_string())
+            .with_context(|| format!("Tool {} failed", name))?;
+        serde_json::from_str(&result).map_err(|e| {
+            InvalidJson {
+                value: result.clone(),
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// The sandbox tools run in.
+    pub fn sandbox(&mut self) -> &mut PySandbox {
+        &mut self.sandbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_round_trips_json() {
+        let value = json!({
+            "name": "add",
+            "description": "Add two numbers.",
+            "parameters": {"type": "object", "properties": {"a": {"type": "integer"}}},
+        });
+        let spec = ToolSpec::from_json(&value).unwrap();
+        assert_eq!(spec.name, "add");
+        assert_eq!(spec.to_json(), value);
+        assert!(ToolSpec::from_json(&json!({"name": "add"})).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! `tracing` instrumentation of the execution pipeline. Without the
+//! `tracing` feature every function here compiles to nothing.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+#[cfg(feature = "tracing")]
+use crate::metrics::ExecOutcome;
+
+/// Span covering one execution. Its duration and outcome are recorded by
+/// `finish`.
+pub(crate) struct ExecSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl ExecSpan {
+    pub(crate) fn new(code: &str, timeout: Duration) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::info_span!(
+                "pybox.exec",
+                code_hash = %code_hash(code),
+                timeout_ms = timeout.as_millis() as u64,
+                duration_ms = tracing::field::Empty,
+                outcome = tracing::field::Empty,
+            );
+            Self { span }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (code, timeout);
+            Self {}
+        }
+    }
+
+    /// Run `f` inside the span so spans it creates are nested under it.
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        return self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+
+    pub(crate) fn finish<T>(&self, result: &Result<T>, duration: Duration) {
+        #[cfg(feature = "tracing")]
+        {
+            let outcome = ExecOutcome::of(result);
+            self.span.record("duration_ms", duration.as_millis() as u64);
+            self.span.record("outcome", outcome.as_str());
+            if outcome == ExecOutcome::TimedOut {
+                let _enter = self.span.enter();
+                tracing::warn!(duration_ms = duration.as_millis() as u64, "execution timed out");
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = (result, duration);
+    }
+}
+
+/// Run `f` inside a span named `name`, for the stages of an execution.
+pub(crate) fn stage<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("pybox.stage", stage = name).in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = name;
+        f()
+    }
+}
+
+/// Report an `error` the caller recovers from, such as a client that
+/// went away.
+#[cfg(feature = "kernel")]
+pub(crate) fn warn(message: &'static str, error: &anyhow::Error) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(error = %format!("{:#}", error), "{}", message);
+    #[cfg(not(feature = "tracing"))]
+    let _ = (message, error);
+}
+
+/// Short identifier for a piece of code that doesn't reveal it.
+#[cfg(feature = "tracing")]
+fn code_hash(code: &str) -> String {
+    let mut hash = crate::audit::code_sha256(code);
+    hash.truncate(16);
+    hash
+}
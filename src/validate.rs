@@ -0,0 +1,247 @@
+//! Host-side checks of submitted code, run before anything executes.
+//!
+//! This is a fast way to turn away code that is certain to be refused,
+//! with a clear message, instead of spending a sandbox on it. Python is
+//! far too dynamic for static checks to be a security boundary, the wasm
+//! sandbox remains that.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use anyhow::{Context, Result};
+use tree_sitter::{Node, Parser};
+
+// Builtins that run or load code from strings
+const DYNAMIC_CODE_BUILTINS: [&str; 4] = ["exec", "eval", "compile", "__import__"];
+
+/// Rejects code that uses constructs it is configured to ban.
+///
+/// ```
+/// use pybox::validate::CodeValidator;
+///
+/// let validator = CodeValidator::new().ban_imports(["os", "subprocess"]);
+/// assert!(validator.validate("import json").is_ok());
+/// assert!(validator.validate("from os import path").is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodeValidator {
+    banned_imports: BTreeSet<String>,
+    ban_dynamic_code: bool,
+    ban_dunder_access: bool,
+}
+
+impl CodeValidator {
+    /// A validator that allows everything until told otherwise.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bans dynamic code and dunder attribute access.
+    pub fn strict() -> Self {
+        Self::new().ban_dynamic_code(true).ban_dunder_access(true)
+    }
+
+    /// Reject imports of these top level modules and their submodules.
+    pub fn ban_imports<I, S>(mut self, modules: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.banned_imports
+            .extend(modules.into_iter().map(Into::into));
+        self
+    }
+
+    /// Reject any use of `exec`, `eval`, `compile` and `__import__`.
+    pub fn ban_dynamic_code(mut self, enabled: bool) -> Self {
+        self.ban_dynamic_code = enabled;
+        self
+    }
+
+    /// Reject attribute access to dunder names such as `obj.__class__`,
+    /// the usual first step of escaping restrictions in Python.
+    pub fn ban_dunder_access(mut self, enabled: bool) -> Self {
+        self.ban_dunder_access = enabled;
+        self
+    }
+
+    /// Check `code`, returning every violation found. Code that doesn't
+    /// parse is left for the interpreter to report, the parts that did
+    /// parse are still checked.
+    pub fn validate(&self, code: &str) -> Result<(), ValidationError> {
+        let tree = parse(code).map_err(|e| ValidationError {
+            violations: vec![Violation {
+                line: 1,
+                message: format!("{:#}", e),
+            }],
+        })?;
+
+        let mut violations = Vec::new();
+        let mut cursor = tree.walk();
+        let mut stack = vec![tree.root_node()];
+        while let Some(node) = stack.pop() {
+            self.check(node, code.as_bytes(), &mut violations);
+            stack.extend(node.children(&mut cursor));
+        }
+        if violations.is_empty() {
+            return Ok(());
+        }
+        violations.sort_by_key(|v| v.line);
+        Err(ValidationError { violations })
+    }
+
+    fn check(&self, node: Node, source: &[u8], violations: &mut Vec<Violation>) {
+        let text = |node: Node| node.utf8_text(source).unwrap_or_default();
+        let mut report = |node: Node, message: String| {
+            violations.push(Violation {
+                line: node.start_position().row + 1,
+                message,
+            });
+        };
+
+        match node.kind() {
+            "import_statement" => {
+                let mut cursor = node.walk();
+                for name in node.children_by_field_name("name", &mut cursor) {
+                    let name = match name.kind() {
+                        "aliased_import" => name.child_by_field_name("name").unwrap_or(name),
+                        _ => name,
+                    };
+                    if self.is_banned_module(text(name)) {
+                        report(node, format!("Import of {} is not allowed", text(name)));
+                    }
+                }
+            }
+            "import_from_statement" => {
+                if let Some(module) = node.child_by_field_name("module_name")
+                    && self.is_banned_module(text(module))
+                {
+                    report(node, format!("Import of {} is not allowed", text(module)));
+                }
+            }
+            "identifier" if self.ban_dynamic_code => {
+                let name = text(node);
+                if DYNAMIC_CODE_BUILTINS.contains(&name) && !is_attribute_name(node) {
+                    report(node, format!("Use of {} is not allowed", name));
+                }
+            }
+            "attribute" if self.ban_dunder_access => {
+                if let Some(attribute) = node.child_by_field_name("attribute") {
+                    let name = text(attribute);
+                    if name.len() > 4 && name.starts_with("__") && name.ends_with("__") {
+                        report(node, format!("Access to attribute {} is not allowed", name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_banned_module(&self, module: &str) -> bool {
+        // Relative imports stay within the submitted code
+        if module.starts_with('.') {
+            return false;
+        }
+        let top_level = module.split('.').next().unwrap_or(module);
+        self.banned_imports.contains(top_level.trim())
+    }
+}
+
+fn parse(code: &str) -> Result<tree_sitter::Tree> {
+    let mut parser = Parser::new();
+    parser
+        .set_language(&tree_sitter_python::LANGUAGE.into())
+        .context("Failed to load the Python grammar")?;
+    parser.parse(code, None).context("Failed to parse code")
+}
+
+// `eval` in `obj.eval` names an attribute, not the builtin
+fn is_attribute_name(node: Node) -> bool {
+    node.parent()
+        .and_then(|parent| parent.child_by_field_name("attribute"))
+        .is_some_and(|attribute| attribute.id() == node.id())
+}
+
+/// A construct the validator rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// 1-based line the construct starts on.
+    pub line: usize,
+    pub message: String,
+}
+
+/// Returned inside `anyhow::Error` when code fails validation, inspect
+/// it with `err.downcast_ref::<ValidationError>()`. Nothing was executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Code rejected before execution:")?;
+        for violation in &self.violations {
+            write!(f, " line {}: {}.", violation.line, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(validator: &CodeValidator, code: &str) -> Vec<String> {
+        match validator.validate(code) {
+            Ok(()) => Vec::new(),
+            Err(e) => e.violations.into_iter().map(|v| v.message).collect(),
+        }
+    }
+
+    #[test]
+    fn test_banned_imports() {
+        let validator = CodeValidator::new().ban_imports(["os", "subprocess"]);
+        assert!(validator.validate("import json\nfrom . import os").is_ok());
+        assert_eq!(
+            messages(&validator, "import sys, os.path as p\nfrom subprocess import run"),
+            ["Import of os.path is not allowed", "Import of subprocess is not allowed"]
+        );
+    }
+
+    #[test]
+    fn test_dynamic_code() {
+        let validator = CodeValidator::strict();
+        assert!(validator.validate("df.eval('a + b')").is_ok());
+        assert_eq!(
+            messages(&validator, "x = 1\nrun = eval\nexec('print(1)')"),
+            ["Use of eval is not allowed", "Use of exec is not allowed"]
+        );
+        let err = validator.validate("exec('1')").unwrap_err();
+        assert_eq!(err.violations[0].line, 1);
+        assert!(CodeValidator::new().validate("exec('1')").is_ok());
+    }
+
+    #[test]
+    fn test_dunder_access() {
+        let validator = CodeValidator::strict();
+        assert!(validator.validate("class A:\n    def __init__(self): pass").is_ok());
+        assert_eq!(
+            messages(&validator, "().__class__.__bases__"),
+            [
+                "Access to attribute __bases__ is not allowed",
+                "Access to attribute __class__ is not allowed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display_lists_violations() {
+        let err = CodeValidator::strict().validate("x = 1\neval('x')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Code rejected before execution: line 2: Use of eval is not allowed."
+        );
+    }
+}
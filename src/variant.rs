@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Environment variable naming the registry file `component_variant`
+/// looks variants up in when the builder wasn't given a registry.
+pub const REGISTRY_ENV: &str = "PYBOX_COMPONENTS";
+
+/// A build of the sandbox component, e.g. one for a particular CPython
+/// version or with data science packages preinstalled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentVariant {
+    pub path: PathBuf,
+    /// Hex SHA-256 the component must have, see
+    /// `PySandboxBuilder::expected_sha256`.
+    pub sha256: Option<String>,
+}
+
+impl ComponentVariant {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sha256: None,
+        }
+    }
+
+    /// Pin the component to `digest`, given as hex.
+    pub fn sha256(mut self, digest: impl Into<String>) -> Self {
+        self.sha256 = Some(digest.into());
+        self
+    }
+}
+
+/// Names the component variants a deployment maintains, for
+/// `PySandboxBuilder::component_variant`.
+///
+/// Registries are built in code or loaded from a JSON file mapping names
+/// to a component path, relative to the file, and optionally its digest:
+///
+/// ```json
+/// {
+///   "py311": {"path": "components/py311.wasm"},
+///   "py312-datasci": {"path": "components/py312-datasci.wasm", "sha256": "9f86d0..."}
+/// }
+/// ```
+///
+/// ```no_run
+/// use pybox::sandbox::PySandbox;
+/// use pybox::variant::{ComponentRegistry, ComponentVariant};
+///
+/// let registry = ComponentRegistry::new()
+///     .register("py311", ComponentVariant::new("components/py311.wasm"))
+///     .register("py312-datasci", ComponentVariant::new("components/py312-datasci.wasm"));
+/// let mut sandbox = PySandbox::builder()
+///     .component_registry(registry)
+///     .component_variant("py312-datasci")
+///     .build()?;
+/// println!("{}", sandbox.version()?.python);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComponentRegistry {
+    variants: BTreeMap<String, ComponentVariant>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `variant` as `name`, replacing any variant of that name.
+    pub fn register(mut self, name: impl Into<String>, variant: ComponentVariant) -> Self {
+        self.variants.insert(name.into(), variant);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentVariant> {
+        self.variants.get(name)
+    }
+
+    /// Registered variant names, sorted.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.variants.keys().map(String::as_str)
+    }
+
+    /// Load a registry file, see `ComponentRegistry`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        Self::from_json(&json, base_dir)
+            .with_context(|| format!("Invalid component registry {}", path.display()))
+    }
+
+    /// Parse a registry, resolving relative component paths against
+    /// `base_dir`.
+    pub fn from_json(json: &str, base_dir: &Path) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let entries = value
+            .as_object()
+            .context("Expected an object mapping variant names to components")?;
+        let mut registry = Self::new();
+        for (name, entry) in entries {
+            let path = entry
+                .get("path")
+                .and_then(|path| path.as_str())
+                .with_context(|| format!("Variant '{}' has no \"path\"", name))?;
+            let mut variant = ComponentVariant::new(base_dir.join(path));
+            match entry.get("sha256") {
+                None | Some(serde_json::Value::Null) => {}
+                Some(serde_json::Value::String(digest)) => variant = variant.sha256(digest),
+                Some(_) => return Err(anyhow!("Variant '{}' has a non-string \"sha256\"", name)),
+            }
+            registry = registry.register(name, variant);
+        }
+        Ok(registry)
+    }
+
+    /// The registry in the file `PYBOX_COMPONENTS` points to, or an empty
+    /// one when it isn't set.
+    pub fn from_env() -> Result<Self> {
+        match env::var_os(REGISTRY_ENV).filter(|path| !path.is_empty()) {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::new()),
+        }
+    }
+
+    /// The variant called `name`, or an error listing the known ones.
+    pub(crate) fn resolve(&self, name: &str) -> Result<&ComponentVariant> {
+        self.get(name).with_context(|| {
+            let known: Vec<&str> = self.names().collect();
+            format!(
+                "Unknown component variant '{}', known variants: [{}]. Register it with \
+                 `component_registry` or in the file {} points to",
+                name,
+                known.join(", "),
+                REGISTRY_ENV
+            )
+        })
+    }
+}
+
+/// What a sandbox's component runs, from `PySandbox::version`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentVersion {
+    /// The `component_variant` the sandbox was built with, if any.
+    pub variant: Option<String>,
+    /// Python version, e.g. `3.12.1`.
+    pub python: String,
+    /// Python implementation, e.g. `cpython`.
+    pub implementation: String,
+    /// Versions of the installed distributions, by name.
+    pub packages: BTreeMap<String, String>,
+}
+
+impl ComponentVersion {
+    /// Parse the guest's JSON report.
+    pub(crate) fn from_json(variant: Option<String>, json: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).context("Invalid version report from the guest")?;
+        let field = |name: &str| {
+            value[name]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("Version report has no \"{}\"", name))
+        };
+        let packages = value["packages"]
+            .as_object()
+            .map(|packages| {
+                packages
+                    .iter()
+                    .filter_map(|(name, version)| Some((name.clone(), version.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            variant,
+            python: field("python")?,
+            implementation: field("implementation")?,
+            packages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_from_json_resolves_relative_paths() {
+        let json = r#"{
+            "py311": {"path": "py311.wasm"},
+            "py312-datasci": {"path": "/opt/py312.wasm", "sha256": "abc"}
+        }"#;
+        let registry = ComponentRegistry::from_json(json, Path::new("/etc/pybox")).unwrap();
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["py311", "py312-datasci"]);
+        assert_eq!(
+            registry.get("py311"),
+            Some(&ComponentVariant::new("/etc/pybox/py311.wasm"))
+        );
+        assert_eq!(
+            registry.get("py312-datasci"),
+            Some(&ComponentVariant::new("/opt/py312.wasm").sha256("abc"))
+        );
+    }
+
+    #[test]
+    fn test_registry_rejects_bad_entries() {
+        for json in ["[]", r#"{"a": {}}"#, r#"{"a": {"path": "a.wasm", "sha256": 1}}"#] {
+            assert!(ComponentRegistry::from_json(json, Path::new("")).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_unknown_variant_lists_known_ones() {
+        let registry = ComponentRegistry::new().register("py311", ComponentVariant::new("a.wasm"));
+        let err = registry.resolve("py313").unwrap_err().to_string();
+        assert!(err.contains("'py313'"), "{}", err);
+        assert!(err.contains("[py311]"), "{}", err);
+    }
+
+    #[test]
+    fn test_version_from_json() {
+        let json = r#"{"python": "3.12.1", "implementation": "cpython", "packages": {"numpy": "2.0.0"}}"#;
+        let version = ComponentVersion::from_json(Some("py312".to_string()), json).unwrap();
+        assert_eq!(version.variant.as_deref(), Some("py312"));
+        assert_eq!(version.python, "3.12.1");
+        assert_eq!(version.implementation, "cpython");
+        assert_eq!(version.packages["numpy"], "2.0.0");
+        assert!(ComponentVersion::from_json(None, "{}").is_err());
+    }
+}
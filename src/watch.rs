@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::PySandbox;
+
+/// Reloads a sandbox's component whenever its file changes, so services
+/// pick up an updated guest without restarting. Polls the file's
+/// modification time and size, waits for them to settle so a half-written
+/// file isn't loaded, and stops when dropped.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use pybox::sandbox::PySandbox;
+/// use pybox::watch::ComponentWatcher;
+///
+/// let sandbox = PySandbox::builder().component_file("guest.wasm").build()?;
+/// let _watcher = ComponentWatcher::start(&sandbox, "guest.wasm", Duration::from_secs(2), |path, result| {
+///     if let Err(e) = result {
+///         println!("failed to reload {}: {:#}", path.display(), e);
+///     }
+/// })?;
+/// // Serve requests from clones of `sandbox`
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct ComponentWatcher {
+    // Set to stop the polling thread, which waits on the condvar
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ComponentWatcher {
+    /// Check `path` every `interval` and reload `sandbox` and its clones
+    /// from it once a change has stayed put for a whole interval, see
+    /// `PySandbox::reload_component`. Each attempt is passed to
+    /// `on_reload`; a component that fails to load leaves the previous one
+    /// in use until the file changes again.
+    pub fn start(
+        sandbox: &PySandbox,
+        path: impl Into<PathBuf>,
+        interval: Duration,
+        mut on_reload: impl FnMut(&Path, Result<()>) + Send + 'static,
+    ) -> Result<Self> {
+        let sandbox = sandbox.clone();
+        Self::spawn(path.into(), interval, move |path| {
+            on_reload(path, sandbox.reload_component(path));
+        })
+    }
+
+    fn spawn(
+        path: PathBuf,
+        interval: Duration,
+        mut reload: impl FnMut(&Path) + Send + 'static,
+    ) -> Result<Self> {
+        let mut loaded = stamp(&path)?;
+        // A change seen on the previous check, loaded once it stops changing
+        let mut pending = None;
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let (stopped, wake) = &*thread_stop;
+            let mut stopped = stopped.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                stopped = wake
+                    .wait_timeout(stopped, interval)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                if *stopped {
+                    return;
+                }
+                // Missing while it is being replaced, checked again later
+                let Ok(current) = stamp(&path) else {
+                    pending = None;
+                    continue;
+                };
+                if current == loaded {
+                    pending = None;
+                    continue;
+                }
+                if pending != Some(current) {
+                    pending = Some(current);
+                    continue;
+                }
+                loaded = current;
+                pending = None;
+                reload(&path);
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ComponentWatcher {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The modification time and size, which both stop changing once a writer
+// is done with the file
+fn stamp(path: &Path) -> Result<(SystemTime, u64)> {
+    fs::metadata(path)
+        .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+        .with_context(|| format!("Failed to read the modification time of {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_reloads_after_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("guest.wasm");
+        fs::write(&path, b"v1").unwrap();
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let watcher = ComponentWatcher::spawn(path.clone(), Duration::from_millis(10), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
+
+        let later = SystemTime::now() + Duration::from_secs(5);
+        File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while reloads.load(Ordering::SeqCst) == 0 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_waits_for_writes_to_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("guest.wasm");
+        fs::write(&path, b"v1").unwrap();
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let watcher = ComponentWatcher::spawn(path.clone(), Duration::from_millis(50), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        // Keep growing the file faster than the interval, as a slow copy would
+        let mut file = File::options().append(true).open(&path).unwrap();
+        for _ in 0..20 {
+            std::io::Write::write_all(&mut file, b"more").unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while reloads.load(Ordering::SeqCst) == 0 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = ComponentWatcher::spawn(dir.path().join("missing.wasm"), Duration::from_secs(1), |_| ());
+        assert!(result.is_err());
+    }
+}
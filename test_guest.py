@@ -1,18 +1,24 @@
 """Tests for guest.py"""
 
+import builtins
 import json
+import os
+import pickle
+import shutil
 import sys
+import tempfile
+import types
 
 # We need to mock the WIT imports before importing guest
 
 # Create a proper Err class that supports type hints and can be raised
 class MockErr(Exception):
-    def __init__(self, value: str):
+    def __init__(self, value):
         self.value = value
-        super().__init__(value)
+        super().__init__(str(value))
 
     def __str__(self) -> str:
-        return self.value
+        return str(self.value)
 
     def __repr__(self) -> str:
         return f"Err({self.value!r})"
@@ -30,16 +36,40 @@ class MockWitWorldBase:
 class MockComponentizePyTypes:
     Err = MockErr
 
+# Stand-in for the record generated from `python-error`
+class MockPythonError:
+    def __init__(self, exception_type: str, message: str, lineno: int | None):
+        self.exception_type = exception_type
+        self.message = message
+        self.lineno = lineno
+
+    def __str__(self) -> str:
+        if self.message == "":
+            return self.exception_type
+        return f"{self.exception_type}: {self.message}"
+
+# Stand-in for the record generated from `captured-output`
+class MockCapturedOutput:
+    def __init__(self, value: str, stdout: str, stderr: str):
+        self.value = value
+        self.stdout = stdout
+        self.stderr = stderr
+
 # Create a mock module for wit_world
 class MockWitWorld:
     WitWorld = MockWitWorldBase
+    CapturedOutput = MockCapturedOutput
+    PythonError = MockPythonError
 
 # Set up the mocks
 sys.modules['wit_world'] = MockWitWorld
 sys.modules['componentize_py_types'] = MockComponentizePyTypes
 
 # Now import after mocking
-from guest import WitWorld, handle
+from guest import (
+    Table, WitWorld, arrow_read_stream, arrow_write_stream, handle, msgpack_pack,
+    msgpack_unpack, run_statements,
+)
 Err = MockErr
 
 
@@ -70,6 +100,35 @@ class TestHandleFunction:
         assert isinstance(result, Err)
         assert "NameError: name 'x' is not defined" in str(result)
 
+    def test_handle_reports_type_message_and_line(self):
+        try:
+            WitWorld().exec("x = 1\n\ny = {}\ny['missing']")
+            assert False, "Expected Err"
+        except Err as e:
+            assert e.value.exception_type == "KeyError"
+            assert e.value.message == "'missing'"
+            assert e.value.lineno == 4
+
+    def test_handle_reports_line_inside_block(self):
+        code = "def f():\n    return 1 / 0\n\nf()"
+        try:
+            WitWorld().exec(code)
+            assert False, "Expected Err"
+        except Err as e:
+            assert e.value.exception_type == "ZeroDivisionError"
+            assert e.value.lineno == 2
+
+    def test_handle_reports_syntax_error_line(self):
+        try:
+            WitWorld().exec("x = 1\ny = (")
+            assert False, "Expected Err"
+        except Err as e:
+            assert e.value.exception_type == "SyntaxError"
+            assert e.value.lineno == 2
+
+    def test_handle_without_user_frame_has_no_line(self):
+        assert handle(ValueError("boom")).value.lineno is None
+
 
 class TestWitWorldEval:
     """Tests for the WitWorld.eval method"""
@@ -291,4 +350,886 @@ evens
     def test_exec_with_tabs(self):
         instance = WitWorld()
         result = instance.exec("if True:\n\tx = 42")
-        assert json.loads(result) is None
\ No newline at end of file
+        assert json.loads(result) is None
+
+class TestWitWorldExecWithInputs:
+    """Tests for WitWorld.exec_with_inputs method"""
+
+    def test_inputs_are_bound_as_variables(self):
+        instance = WitWorld()
+        result = instance.exec_with_inputs("a + b", json.dumps({"a": 1, "b": 2}))
+        assert json.loads(result) == 3
+
+    def test_structured_inputs(self):
+        instance = WitWorld()
+        inputs = json.dumps({"rows": [{"x": 1}, {"x": 2}]})
+        result = instance.exec_with_inputs("sum(r['x'] for r in rows)", inputs)
+        assert json.loads(result) == 3
+
+    def test_inputs_are_not_code(self):
+        instance = WitWorld()
+        inputs = json.dumps({"s": "'); import os; ('"})
+        result = instance.exec_with_inputs("s", inputs)
+        assert json.loads(result) == "'); import os; ('"
+
+    def test_invalid_input_name(self):
+        instance = WitWorld()
+        try:
+            instance.exec_with_inputs("1", json.dumps({"not valid": 1}))
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "ValueError" in str(e)
+
+
+class TestMsgpack:
+    """Tests for the MessagePack codec and the methods using it"""
+
+    def test_round_trip(self):
+        values = [
+            None, True, False, 0, 127, 128, -1, -32, -33, 2**64 - 1, -(2**63),
+            1.5, "", "x" * 40, "é" * 70000, b"\x00" * 300,
+            {"a": [1, {"b": b"c"}]}, list(range(20)),
+        ]
+        for value in values:
+            assert msgpack_unpack(msgpack_pack(value)) == value
+
+    def test_encoding_matches_spec(self):
+        assert msgpack_pack({"a": [1, None]}) == b"\x81\xa1a\x92\x01\xc0"
+        assert msgpack_pack(b"hi") == b"\xc4\x02hi"
+        assert msgpack_pack(-1) == b"\xff"
+
+    def test_rejects_bad_input(self):
+        for data in (b"\x92\x01", b"\xc1", b"\x01\x02"):
+            try:
+                msgpack_unpack(data)
+                assert False, "Should have raised an exception"
+            except ValueError:
+                pass
+        try:
+            msgpack_pack(object())
+            assert False, "Should have raised an exception"
+        except TypeError:
+            pass
+
+    def test_exec_with_inputs_keeps_bytes(self):
+        instance = WitWorld()
+        inputs = msgpack_pack({"blob": b"\x00\xff", "n": 2})
+        result = instance.exec_with_inputs_msgpack("blob * n", inputs)
+        assert msgpack_unpack(result) == b"\x00\xff\x00\xff"
+        assert msgpack_unpack(instance.get_global_msgpack("n")) == 2
+
+    def test_inputs_must_be_a_map(self):
+        try:
+            WitWorld().exec_with_inputs_msgpack("1", msgpack_pack([1]))
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "TypeError" in str(e)
+
+
+class TestArrowTable:
+    """Tests for the Arrow IPC codec and exec_with_table"""
+
+    def test_round_trip(self):
+        table = Table({
+            "id": [1, None, -3],
+            "score": [0.5, 2, None],
+            "name": ["a", None, "h\u00e9llo"],
+            "ok": [True, None, False],
+            "empty": [None, None, None],
+        })
+        decoded = arrow_read_stream(arrow_write_stream(table))
+        assert decoded.columns == {**table.columns, "score": [0.5, 2.0, None]}
+        assert arrow_read_stream(arrow_write_stream(Table())).num_rows == 0
+
+    def test_stream_framing(self):
+        data = arrow_write_stream(Table({"n": [1]}))
+        # Each message starts with the continuation marker and the stream
+        # ends with a zero length one
+        assert data[:4] == b"\xff\xff\xff\xff"
+        assert data[-8:] == b"\xff\xff\xff\xff\x00\x00\x00\x00"
+        assert len(data) % 8 == 0
+
+    def test_rejects_mixed_columns(self):
+        for columns in ({"a": [1, "x"]}, {"a": [True, 1]}, {"a": [object()]}):
+            try:
+                arrow_write_stream(Table(columns))
+                assert False, "Should have raised an exception"
+            except TypeError:
+                pass
+        try:
+            Table({"a": [1], "b": []})
+            assert False, "Should have raised an exception"
+        except ValueError:
+            pass
+
+    def test_exec_with_table(self):
+        instance = WitWorld()
+        table = arrow_write_stream(Table({"n": [1, 2, 3]}))
+        result = instance.exec_with_table(
+            "rows = [{'n': n, 'odd': n % 2 == 1} for n in table['n'] if n > 1]\nrows",
+            table,
+        )
+        assert arrow_read_stream(result).rows() == [
+            {"n": 2, "odd": False},
+            {"n": 3, "odd": True},
+        ]
+
+    def test_exec_with_table_requires_a_table(self):
+        try:
+            WitWorld().exec_with_table("42", arrow_write_stream(Table()))
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "TypeError" in str(e)
+
+
+TOOLS_SOURCE = '''
+from json import dumps
+from typing import Literal
+
+def add(a: int, b: float = 1.5) -> float:
+    """Add two numbers.
+
+    Args:
+        a: The first number.
+        b: The second number,
+            which is optional.
+
+    Returns:
+        The sum.
+    """
+    return a + b
+
+def greet(name: str, mood: Literal["happy", "sad"] = "happy", tags: list[str] | None = None):
+    """Greet someone.
+
+    :param name: Who to greet.
+    """
+    return f"{mood} {name}"
+
+def _helper():
+    pass
+'''
+
+
+class TestTools:
+    """Tests for WitWorld.describe_tools and WitWorld.invoke_tool"""
+
+    def test_describes_public_functions(self):
+        tools = json.loads(WitWorld().describe_tools(TOOLS_SOURCE))
+        assert [tool["name"] for tool in tools] == ["add", "greet"]
+        add, greet = tools
+        assert add["description"] == "Add two numbers."
+        assert add["parameters"] == {
+            "type": "object",
+            "properties": {
+                "a": {"type": "integer", "description": "The first number."},
+                "b": {
+                    "type": "number",
+                    "description": "The second number, which is optional.",
+                    "default": 1.5,
+                },
+            },
+            "required": ["a"],
+            "additionalProperties": False,
+        }
+        properties = greet["parameters"]["properties"]
+        assert properties["name"]["description"] == "Who to greet."
+        assert properties["mood"]["enum"] == ["happy", "sad"]
+        assert properties["tags"]["anyOf"][0] == {"type": "array", "items": {"type": "string"}}
+
+    def test_invokes_tool(self):
+        instance = WitWorld()
+        assert json.loads(instance.invoke_tool(TOOLS_SOURCE, "add", '{"a": 2}')) == 3.5
+        result = instance.invoke_tool(TOOLS_SOURCE, "greet", '{"name": "Ada", "mood": "sad"}')
+        assert json.loads(result) == "sad Ada"
+
+    def test_rejects_bad_calls(self):
+        calls = [
+            ("add", '{"a": 1, "c": 2}', "ValueError"),
+            ("add", "{}", "ValueError"),
+            ("add", "[1]", "TypeError"),
+            ("_helper", "{}", "NameError"),
+            ("dumps", "{}", "NameError"),
+        ]
+        for name, arguments, exception in calls:
+            try:
+                WitWorld().invoke_tool(TOOLS_SOURCE, name, arguments)
+                assert False, "Should have raised an exception"
+            except Err as e:
+                assert exception in str(e)
+
+
+class TestSnapshot:
+    """Tests for WitWorld.snapshot_globals and WitWorld.restore_globals"""
+
+    def test_restores_values_and_modules(self):
+        instance = WitWorld()
+        instance.exec_cell(
+            "import json as j\nshared = [1]\nd = {'a': shared, 'b': shared}\nx = 42"
+        )
+        snapshot = instance.snapshot_globals()
+
+        restored = WitWorld()
+        restored.restore_globals(snapshot)
+        report = json.loads(restored.exec_cell("[x, d['a'] is d['b'], j.dumps(d)]"))
+        assert json.loads(report["value"]) == [42, True, '{"a": [1], "b": [1]}']
+
+    def test_leaves_out_unpicklable_values(self):
+        instance = WitWorld()
+        instance.exec_cell("def f():\n    pass\ngen = (i for i in [])\nkept = 1")
+        instance.restore_globals(instance.snapshot_globals())
+        report = json.loads(instance.exec_cell("[k in globals() for k in ('f', 'gen', 'kept')]"))
+        assert json.loads(report["value"]) == [False, False, True]
+
+    def test_rejects_bad_snapshots(self):
+        for snapshot in (b"junk", pickle.dumps({"version": 0})):
+            try:
+                WitWorld().restore_globals(snapshot)
+                assert False, "Should have raised an exception"
+            except Err:
+                pass
+
+
+class TestWitWorldGetGlobal:
+    """Tests for WitWorld.get_global method"""
+
+    def test_reads_variable_from_last_exec(self):
+        instance = WitWorld()
+        instance.exec("result = [1, 2]\nsummary = {'n': 2}")
+        assert json.loads(instance.get_global("result")) == [1, 2]
+        assert json.loads(instance.get_global("summary")) == {"n": 2}
+
+    def test_reads_inputs(self):
+        instance = WitWorld()
+        instance.exec_with_inputs("y = x * 2", json.dumps({"x": 4}))
+        assert json.loads(instance.get_global("y")) == 8
+
+    def test_only_sees_most_recent_exec(self):
+        instance = WitWorld()
+        instance.exec("a = 1")
+        instance.exec("b = 2")
+        try:
+            instance.get_global("a")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "NameError" in str(e)
+
+    def test_variables_set_before_an_error_are_kept(self):
+        instance = WitWorld()
+        try:
+            instance.exec("partial = 1\n1 / 0")
+        except Err:
+            pass
+        assert json.loads(instance.get_global("partial")) == 1
+
+    def test_unserializable_value(self):
+        instance = WitWorld()
+        instance.exec("f = lambda: 1")
+        try:
+            instance.get_global("f")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "TypeError" in str(e)
+
+
+class TestWitWorldCallFunction:
+    """Tests for WitWorld.call_function method"""
+
+    def test_call_with_args_and_kwargs(self):
+        instance = WitWorld()
+        instance.exec("def scale(x, factor=1):\n    return x * factor")
+        result = instance.call_function("scale", json.dumps([3]), json.dumps({"factor": 4}))
+        assert json.loads(result) == 12
+
+    def test_function_sees_top_level_names(self):
+        instance = WitWorld()
+        instance.exec("import math\nOFFSET = 1\ndef f(x):\n    return math.floor(x) + OFFSET")
+        result = instance.call_function("f", json.dumps([2.5]), json.dumps({}))
+        assert json.loads(result) == 3
+
+    def test_undefined_function(self):
+        instance = WitWorld()
+        instance.exec("x = 1")
+        try:
+            instance.call_function("missing", "[]", "{}")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "NameError" in str(e)
+
+    def test_not_callable(self):
+        instance = WitWorld()
+        instance.exec("x = 1")
+        try:
+            instance.call_function("x", "[]", "{}")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "TypeError" in str(e)
+
+    def test_exception_in_function(self):
+        instance = WitWorld()
+        instance.exec("def boom():\n    return 1 / 0")
+        try:
+            instance.call_function("boom", "[]", "{}")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "ZeroDivisionError" in str(e)
+
+
+class TestWitWorldEvalAfterExec:
+    """Tests for evaluating expressions against the last exec"""
+
+    def test_eval_sees_exec_variables(self):
+        instance = WitWorld()
+        instance.exec("x = 20\ny = 22")
+        assert json.loads(instance.eval("x + y")) == 42
+
+    def test_eval_does_not_see_guest_internals(self):
+        instance = WitWorld()
+        instance.exec("")
+        try:
+            instance.eval("WitWorld")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "NameError" in str(e)
+
+
+class TestWitWorldConfigure:
+    """Tests for WitWorld.configure method"""
+
+    def test_configure_sets_argv(self):
+        instance = WitWorld()
+        original = sys.argv
+        try:
+            instance.configure(json.dumps({"argv": ["script.py", "--flag", "x"]}))
+            assert json.loads(instance.exec("import sys\nsys.argv")) == ["script.py", "--flag", "x"]
+        finally:
+            sys.argv = original
+
+    def test_configure_empty_settings(self):
+        instance = WitWorld()
+        original = list(sys.argv)
+        instance.configure("{}")
+        assert sys.argv == original
+
+    def test_configure_invalid_json(self):
+        instance = WitWorld()
+        try:
+            instance.configure("not json")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "JSONDecodeError" in str(e)
+
+
+class TestWitWorldImportPolicy:
+    """Tests for the import policy installed by WitWorld.configure"""
+
+    def teardown_method(self):
+        WitWorld().configure("{}")
+
+    def test_allowed_import_succeeds(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"allowed_imports": ["math"]}))
+        assert instance.exec("import math\nmath.floor(2.5)") == "2"
+
+    def test_import_outside_allowlist_fails(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"allowed_imports": ["math"]}))
+        try:
+            instance.exec("import os")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "ImportError" in str(e)
+            assert "os" in str(e)
+
+    def test_blocked_submodule_import_fails(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"blocked_imports": ["os"]}))
+        try:
+            instance.exec("from os import path")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "ImportError" in str(e)
+
+    def test_allowed_module_can_use_its_own_imports(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"allowed_imports": ["json"]}))
+        assert instance.exec("import json\njson.dumps([1])") == '"[1]"'
+
+    def test_no_policy_allows_everything(self):
+        instance = WitWorld()
+        instance.configure("{}")
+        assert instance.exec("import os\n1") == "1"
+
+
+class TestWitWorldConfigurePath:
+    """Tests for sys.path entries added by WitWorld.configure"""
+
+    def test_configure_prepends_path(self):
+        instance = WitWorld()
+        original = list(sys.path)
+        try:
+            instance.configure(json.dumps({"path": ["/site-packages", "/site-packages/a.whl"]}))
+            assert sys.path[:2] == ["/site-packages", "/site-packages/a.whl"]
+            instance.configure(json.dumps({"path": ["/site-packages"]}))
+            assert sys.path.count("/site-packages") == 1
+        finally:
+            sys.path[:] = original
+
+
+class FakeFigure:
+    def savefig(self, path, format=None):
+        with open(path, "wb") as f:
+            f.write(b"png-" + str(id(self)).encode())
+
+
+class FakePyplot(types.ModuleType):
+    """The parts of matplotlib.pyplot figure capture relies on."""
+
+    def __init__(self):
+        super().__init__("matplotlib.pyplot")
+        self.figures = {}
+
+    def figure(self, number=None):
+        if number is None:
+            number = len(self.figures) + 1
+            self.figures[number] = FakeFigure()
+        return self.figures[number]
+
+    def get_fignums(self):
+        return sorted(self.figures)
+
+    def close(self, which):
+        self.figures.clear()
+
+    def show(self):
+        pass
+
+
+class TestFigureCapture:
+    """Tests for saving matplotlib figures to the host's figure directory"""
+
+    def setup_method(self):
+        self.modules = dict(sys.modules)
+        self.figure_dir = tempfile.mkdtemp()
+        matplotlib = types.ModuleType("matplotlib")
+        matplotlib.use = lambda backend: None
+        figure_module = types.ModuleType("matplotlib.figure")
+        figure_module.Figure = FakeFigure
+        self.original_savefig = FakeFigure.savefig
+        sys.modules["matplotlib"] = matplotlib
+        sys.modules["matplotlib.figure"] = figure_module
+        sys.modules["matplotlib.pyplot"] = matplotlib.pyplot = FakePyplot()
+        WitWorld().configure(json.dumps({"figure_dir": self.figure_dir}))
+
+    def teardown_method(self):
+        import guest
+
+        FakeFigure.savefig = self.original_savefig
+        guest.figure_dir = guest.original_savefig = None
+        guest.figures_captured = 0
+        sys.modules.clear()
+        sys.modules.update(self.modules)
+        shutil.rmtree(self.figure_dir)
+
+    def captured(self):
+        return sorted(os.listdir(self.figure_dir))
+
+    def test_show_captures_open_figures(self):
+        code = "import matplotlib.pyplot as plt\nplt.figure()\nplt.show()\nfig = plt.figure()"
+        WitWorld().exec(code)
+        assert self.captured() == ["figure-0001.png", "figure-0002.png"]
+
+    def test_savefig_is_captured_once(self):
+        code = "import matplotlib.pyplot as plt\nfig = plt.figure()\nfig.savefig(path)"
+        path = os.path.join(self.figure_dir, "user.png")
+        WitWorld().exec_with_inputs(code, json.dumps({"path": path}))
+        assert self.captured() == ["figure-0001.png", "user.png"]
+
+    def test_no_figures_without_plotting(self):
+        WitWorld().exec("1 + 1")
+        assert self.captured() == []
+
+
+class TestHostCall:
+    """Tests for calling host functions through pybox.host"""
+
+    def setup_method(self):
+        def host_call(name, args):
+            if name == "double":
+                return json.dumps(json.loads(args)["n"] * 2)
+            raise MockErr(f"unknown host function '{name}'")
+
+        MockWitWorld.host_call = staticmethod(host_call)
+
+    def test_call_returns_decoded_result(self):
+        instance = WitWorld()
+        assert instance.exec("import pybox.host\npybox.host.call('double', {'n': 21})") == "42"
+
+    def test_call_error_raises_host_call_error(self):
+        instance = WitWorld()
+        try:
+            instance.exec("import pybox.host\npybox.host.call('missing')")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "HostCallError" in str(e)
+            assert "unknown host function" in str(e)
+
+    def test_pybox_import_allowed_under_allowlist(self):
+        instance = WitWorld()
+        try:
+            instance.configure(json.dumps({"allowed_imports": ["math"]}))
+            assert instance.exec("from pybox import host\nhost.call('double', {'n': 1})") == "2"
+        finally:
+            instance.configure("{}")
+
+
+class TestInput:
+    """Tests for input() answered by the host's on_input callback"""
+
+    def setup_method(self):
+        self.original_input = builtins.input
+        self.prompts = []
+
+        def read_input(prompt):
+            self.prompts.append(prompt)
+            return "Ada" if prompt == "Name? " else None
+
+        MockWitWorld.read_input = staticmethod(read_input)
+
+    def teardown_method(self):
+        builtins.input = self.original_input
+
+    def test_input_asks_host(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"input": True}))
+        assert instance.exec("'Hello ' + input('Name? ')") == '"Hello Ada"'
+        assert self.prompts == ["Name? "]
+
+    def test_end_of_input_raises_eof_error(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"input": True}))
+        try:
+            instance.exec("input('Age? ')")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "EOFError" in str(e)
+
+    def test_input_untouched_without_callback(self):
+        WitWorld().configure("{}")
+        assert builtins.input is self.original_input
+
+
+class TestExtensionCall:
+    """Tests for calling host extensions through pybox.extensions"""
+
+    def setup_method(self):
+        def extension_call(extension, method, payload):
+            if extension == "echo":
+                return payload
+            raise MockErr(f"unknown extension '{extension}'")
+
+        MockWitWorld.extension_call = staticmethod(extension_call)
+
+    def test_call_returns_payload(self):
+        instance = WitWorld()
+        code = "import pybox.extensions\npybox.extensions.call('echo', 'echo', 'hi')"
+        assert instance.exec(code) == '"hi"'
+
+    def test_unknown_extension_raises_extension_error(self):
+        instance = WitWorld()
+        try:
+            instance.exec("import pybox.extensions\npybox.extensions.call('nope', 'x')")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "ExtensionError" in str(e)
+
+
+class TestWitWorldExecBytes:
+    """Tests for WitWorld.exec_bytes method"""
+
+    def test_returns_raw_bytes(self):
+        assert WitWorld().exec_bytes("b'\\x00\\xff' + b'png'") == b"\x00\xffpng"
+
+    def test_bytearray_and_memoryview_are_accepted(self):
+        instance = WitWorld()
+        assert instance.exec_bytes("bytearray(b'ab')") == b"ab"
+        assert instance.exec_bytes("memoryview(b'cd')") == b"cd"
+
+    def test_non_bytes_value_is_rejected(self):
+        try:
+            WitWorld().exec_bytes("'text'")
+            assert False, "Expected Err"
+        except Err as e:
+            assert str(e) == "TypeError: last expression must be bytes, got str"
+
+
+class TestWitWorldExecCaptured:
+    """Tests for WitWorld.exec_captured method"""
+
+    def test_value_and_streams_are_separate(self):
+        instance = WitWorld()
+        code = "import sys\nprint('out')\nprint('err', file=sys.stderr)\n40 + 2"
+        result = instance.exec_captured(code)
+        assert result.value == "42"
+        assert result.stdout == "out\n"
+        assert result.stderr == "err\n"
+
+    def test_statement_only_code_has_null_value(self):
+        result = WitWorld().exec_captured("print('hi')")
+        assert result.value == "null"
+        assert result.stdout == "hi\n"
+        assert result.stderr == ""
+
+    def test_error_raises(self):
+        try:
+            WitWorld().exec_captured("print('before')\n1 / 0")
+            assert False, "Expected Err"
+        except Err as e:
+            assert "ZeroDivisionError" in str(e)
+
+
+class TestWitWorldExecCell:
+    """Tests for WitWorld.exec_cell method"""
+
+    def setup_method(self):
+        WitWorld().exec("None")
+
+    def test_cells_share_state(self):
+        instance = WitWorld()
+        first = json.loads(instance.exec_cell("x = 20"))
+        second = json.loads(instance.exec_cell("x + 22"))
+        assert first == {"value": "null", "stdout": "", "stderr": "", "error": None}
+        assert second["value"] == "42"
+
+    def test_cell_captures_stdout(self):
+        instance = WitWorld()
+        result = json.loads(instance.exec_cell("print('hello')\n1"))
+        assert result == {"value": "1", "stdout": "hello\n", "stderr": "", "error": None}
+
+    def test_cell_captures_stderr(self):
+        instance = WitWorld()
+        result = json.loads(instance.exec_cell("import sys\nsys.stderr.write('oops\\n')"))
+        assert result["stdout"] == ""
+        assert result["stderr"] == "oops\n"
+
+    def test_cell_error_keeps_stdout(self):
+        instance = WitWorld()
+        result = json.loads(instance.exec_cell("print('before')\n1 / 0"))
+        assert result["value"] is None
+        assert result["stdout"] == "before\n"
+        assert "ZeroDivisionError" in result["error"]
+
+    def test_state_survives_failed_cell(self):
+        instance = WitWorld()
+        instance.exec_cell("y = 1")
+        instance.exec_cell("raise ValueError('boom')")
+        assert json.loads(instance.exec_cell("y"))["value"] == "1"
+
+    def test_eval_cell_sees_cell_variables(self):
+        instance = WitWorld()
+        instance.exec_cell("z = 2")
+        result = json.loads(instance.eval_cell("print('hi') or z * 21"))
+        assert result == {"value": "42", "stdout": "hi\n", "stderr": "", "error": None}
+
+    def test_eval_cell_rejects_statements(self):
+        result = json.loads(WitWorld().eval_cell("w = 1"))
+        assert result["value"] is None
+        assert "SyntaxError" in result["error"]
+
+
+class FakeFrame:
+    def _repr_html_(self):
+        return "<table></table>"
+
+
+class TestDisplay:
+    """Tests for rich outputs sent through pybox.display"""
+
+    def setup_method(self):
+        self.displayed = []
+        MockWitWorld.display = staticmethod(
+            lambda mime, data: self.displayed.append((mime, data))
+        )
+
+    def test_explicit_mime_types(self):
+        instance = WitWorld()
+        instance.exec("import pybox\npybox.display('<b>hi</b>', mime='text/html')")
+        instance.exec("import pybox\npybox.display(b'\\x89PNG', mime='image/png')")
+        instance.exec("import pybox\npybox.display({'a': 1}, mime='application/json')")
+        assert self.displayed == [
+            ("text/html", b"<b>hi</b>"),
+            ("image/png", b"\x89PNG"),
+            ("application/json", b'{"a": 1}'),
+        ]
+
+    def test_mime_inferred_from_object(self):
+        code = "import pybox\npybox.display(frame)\npybox.display([1, 2])\npybox.display(3)"
+        run_statements(code, {"frame": FakeFrame()})
+        assert self.displayed == [
+            ("text/html", b"<table></table>"),
+            ("application/json", b"[1, 2]"),
+            ("text/plain", b"3"),
+        ]
+
+    def test_unencodable_object_raises(self):
+        try:
+            WitWorld().exec("import pybox\npybox.display(object(), mime='image/png')")
+            assert False, "Expected Err"
+        except Err as e:
+            assert str(e) == "TypeError: can't display object as image/png"
+
+
+class TestProgress:
+    """Tests for progress reports sent through pybox.progress"""
+
+    def setup_method(self):
+        self.reports = []
+        MockWitWorld.report_progress = staticmethod(
+            lambda percent, message: self.reports.append((percent, message))
+        )
+
+    def test_reports_reach_host(self):
+        WitWorld().exec("import pybox\nfor i in range(3):\n    pybox.progress(i * 50, f'step {i}')")
+        assert self.reports == [(0.0, "step 0"), (50.0, "step 1"), (100.0, "step 2")]
+
+    def test_percent_is_clamped(self):
+        WitWorld().exec("import pybox\npybox.progress(-5)\npybox.progress(250, 'over')")
+        assert self.reports == [(0.0, ""), (100.0, "over")]
+
+    def test_nan_is_rejected(self):
+        try:
+            WitWorld().exec("import pybox\npybox.progress(float('nan'))")
+            assert False, "Expected Err"
+        except Err as e:
+            assert "ValueError" in str(e)
+        assert self.reports == []
+
+
+class TestInterrupt:
+    """Tests for the KeyboardInterrupt raised when the host's timeout passes"""
+
+    def setup_method(self):
+        self.polls = 0
+
+        def interrupt_requested():
+            self.polls += 1
+            return True
+
+        MockWitWorld.interrupt_requested = staticmethod(interrupt_requested)
+
+    def teardown_method(self):
+        WitWorld().configure(json.dumps({}))
+
+    def test_handler_returns_partial_result(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"interrupt": True}))
+        code = """
+def work():
+    done = 0
+    try:
+        while True:
+            done += 1
+    except KeyboardInterrupt:
+        return done > 0
+work()"""
+        assert instance.exec(code) == "true"
+        assert self.polls == 1
+
+    def test_unhandled_interrupt_is_a_timeout_after_finally_runs(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"interrupt": True}))
+        code = """
+cleaned = []
+def work():
+    try:
+        while True:
+            pass
+    finally:
+        cleaned.append(True)
+work()"""
+        try:
+            instance.exec(code)
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert "TimeoutError" in str(e)
+        assert json.loads(instance.get_global("cleaned")) == [True]
+
+    def test_not_polled_unless_configured(self):
+        WitWorld().exec("sum(i for i in range(10000))")
+        assert self.polls == 0
+
+
+class TestLineBudget:
+    """Tests for the ExecutionBudgetExceeded raised past the host's line budget"""
+
+    def setup_method(self):
+        self.reports = []
+        MockWitWorld.line_budget_exceeded = staticmethod(self.reports.append)
+
+    def teardown_method(self):
+        WitWorld().configure(json.dumps({}))
+
+    def test_within_budget(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"line_budget": 100}))
+        assert instance.exec("total = 0\nfor i in range(10):\n    total += i\ntotal") == "45"
+
+    def test_over_budget_reports_count(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"line_budget": 50}))
+        try:
+            instance.exec("while True:\n    pass")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert e.value.exception_type == "ExecutionBudgetExceeded"
+            assert e.value.message == "executed 51 lines, over the budget of 50"
+        assert self.reports == [51]
+
+    def test_caught_budget_is_still_fatal(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"line_budget": 50}))
+        code = """
+import pybox
+caught = []
+def work():
+    try:
+        while True:
+            pass
+    except pybox.ExecutionBudgetExceeded:
+        caught.append(True)
+    return 'finished'
+work()"""
+        try:
+            instance.exec(code)
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert e.value.exception_type == "ExecutionBudgetExceeded"
+        assert json.loads(instance.get_global("caught")) == [True]
+
+    def test_budget_resets_between_executions(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"line_budget": 20}))
+        for _ in range(3):
+            assert instance.exec("x = 0\nfor i in range(5):\n    x += i\nx") == "10"
+        assert self.reports == []
+
+    def test_fake_budget_error_is_an_ordinary_exception(self):
+        instance = WitWorld()
+        instance.configure(json.dumps({"line_budget": 50}))
+        try:
+            instance.exec("import pybox\nraise pybox.ExecutionBudgetExceeded('executed 99 lines')")
+            assert False, "Should have raised an exception"
+        except Err as e:
+            assert e.value.exception_type == "ExecutionBudgetExceeded"
+        # Only the trace function tells the host
+        assert self.reports == []
+
+
+class TestVersion:
+    """Tests for WitWorld.version"""
+
+    def test_reports_interpreter(self):
+        report = json.loads(WitWorld().version())
+        assert report["python"] == "%d.%d.%d" % sys.version_info[:3]
+        assert report["implementation"] == sys.implementation.name
+        assert all(isinstance(v, str) for v in report["packages"].values())
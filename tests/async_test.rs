@@ -0,0 +1,114 @@
+#![cfg(feature = "async")]
+
+use pybox::error::PyboxError;
+use pybox::sandbox::{ExecOptions, PySandbox};
+use std::path::Path;
+use std::time::Duration;
+
+/// Helper to check if sandbox.wasm exists
+fn has_sandbox_wasm() -> bool {
+    Path::new("sandbox.wasm").exists()
+}
+
+#[tokio::test]
+async fn test_exec_async_simple_expression() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let result = sandbox.exec_async("1 + 1").await.unwrap();
+    assert_eq!(result, "2");
+}
+
+#[tokio::test]
+async fn test_exec_async_timeout() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(Some(1)).expect("Failed to create sandbox");
+    let result = sandbox.exec_async("while True: pass").await;
+    assert_eq!(result.unwrap_err().to_string(), "Execution timed out");
+}
+
+#[tokio::test]
+async fn test_exec_async_is_cancel_safe() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    // Dropping the future on timeout must not wedge the sandbox
+    let run = sandbox.exec_async("while True: pass");
+    let aborted = tokio::time::timeout(std::time::Duration::from_millis(200), run).await;
+    assert!(aborted.is_err());
+
+    assert_eq!(sandbox.exec_async("2 + 2").await.unwrap(), "4");
+}
+
+#[tokio::test]
+async fn test_exec_async_with_options_can_be_cancelled() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let handle = sandbox.cancel_handle();
+    let options = ExecOptions {
+        cancel: Some(handle.clone()),
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.cancel();
+    });
+    let err = sandbox
+        .exec_async_with_options("while True: pass", &options)
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::Cancelled));
+}
+
+#[tokio::test]
+async fn test_exec_async_with_options_captures_output_and_overrides_timeout() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let options = ExecOptions {
+        capture_output: true,
+        ..Default::default()
+    };
+    let output = sandbox
+        .exec_async_with_options("print('hi')\n1 + 1", &options)
+        .await
+        .unwrap();
+    assert_eq!(output.value, "2");
+    assert_eq!(output.stdout, "hi\n");
+
+    let options = ExecOptions {
+        timeout: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+    let err = sandbox
+        .exec_async_with_options("while True: pass", &options)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Execution timed out");
+}
+
+#[test]
+fn test_exec_async_future_is_send() {
+    fn assert_send<T: Send>(_: T) {}
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    // Needed to run on multi-threaded runtimes such as axum handlers
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    assert_send(sandbox.exec_async("1"));
+    assert_send(sandbox.exec_async_with_options("1", &ExecOptions::default()));
+}
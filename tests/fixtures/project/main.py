@@ -0,0 +1,6 @@
+from helpers import greet
+
+with open("data.txt") as f:
+    name = f.read().strip()
+
+greet(name)
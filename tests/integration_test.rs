@@ -1,5 +1,20 @@
-use pybox::sandbox::PySandbox;
+use pybox::audit::{code_sha256, JsonlAuditLog};
+use pybox::deterministic::{ClockPolicy, RandomPolicy};
+use pybox::error::{CodeLimit, DigestMismatch, LimitViolation, PyException, PyboxError, PythonError, ResourceLimit};
+use pybox::extension::SandboxExtension;
+use pybox::metrics::PrometheusMetrics;
+use pybox::output::{OutputSink, Stream};
+use pybox::policy::SandboxPolicy;
+use pybox::pool::SandboxPool;
+use pybox::quota::Quota;
+use pybox::sandbox::{
+    DisplayData, EngineOptions, ExecOptions, MountMode, PoolingOptions, PySandbox, SandboxFactory,
+    StoreLimitsConfig,
+};
+use pybox::session::Session;
+use pybox::variant::{ComponentRegistry, ComponentVariant};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 /// Helper to check if sandbox.wasm exists
 fn has_sandbox_wasm() -> bool {
@@ -29,6 +44,28 @@ fn test_exec_returns_error_for_invalid_syntax() {
     assert!(result.is_err());
 }
 
+#[test]
+fn test_exec_error_has_exception_type_and_line() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let err = sandbox.exec("x = 1\nx / 0").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PythonError>(),
+        Some(&PythonError {
+            exception: PyException::ZeroDivision,
+            message: "division by zero".to_string(),
+            lineno: Some(2),
+        })
+    );
+
+    let err = sandbox.exec("class Custom(Exception): pass\nraise Custom('no')").unwrap_err();
+    let error = err.downcast_ref::<PythonError>().unwrap();
+    assert_eq!(error.exception, PyException::Other("Custom".to_string()));
+}
+
 #[test]
 fn test_exec_handles_empty_string() {
      if !has_sandbox_wasm() {
@@ -126,3 +163,1590 @@ a + b
     // Second result should fail because it references undefined vars
     assert!(result2.is_err());
 }
+
+#[test]
+fn test_precompiled_component_roundtrip() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let path = dir.path().join("sandbox.cwasm");
+
+    let sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    sandbox.precompile_to(&path).expect("Failed to precompile");
+
+    let mut sandbox = PySandbox::from_precompiled(&path, None).expect("Failed to load precompiled");
+    assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+}
+
+#[test]
+fn test_precompiled_component_from_other_engine_is_rejected() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let path = dir.path().join("sandbox.cwasm");
+
+    // The test sandbox uses Winch which produces incompatible artifacts
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    sandbox.precompile_to(&path).expect("Failed to precompile");
+
+    let result = PySandbox::from_precompiled(&path, None);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_fuel_limit_stops_long_running_code() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .fuel_limit(10_000_000)
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("while True: pass").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PyboxError>(),
+        Some(&PyboxError::FuelExhausted { limit: 10_000_000 })
+    );
+}
+
+#[test]
+fn test_fuel_limit_allows_small_programs() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .fuel_limit(u64::MAX)
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("sum(range(10))").unwrap(), "45");
+}
+
+#[test]
+fn test_engine_options_without_cache() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .engine_options(EngineOptions {
+            parallel_compilation: false,
+            cache: false,
+            ..EngineOptions::fast()
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+}
+
+#[test]
+fn test_pooling_allocator_reuses_slots() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .engine_options(EngineOptions {
+            pooling: Some(PoolingOptions {
+                max_instances: 1,
+                ..PoolingOptions::default()
+            }),
+            ..EngineOptions::fast()
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    // Each exec gets a fresh instance, so this only passes if slots are freed
+    for _ in 0..3 {
+        assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+    }
+}
+
+#[test]
+fn test_factory_sandboxes_have_their_own_timeouts() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let builder = PySandbox::builder().engine_options(EngineOptions::fast());
+    let factory = SandboxFactory::new(builder).expect("Failed to compile component");
+    let mut quick = factory.builder().timeout_seconds(1).build().unwrap();
+    let mut patient = factory.builder().timeout_seconds(30).build().unwrap();
+
+    let result = quick.exec("while True: pass");
+    assert_eq!(result.unwrap_err().to_string(), "Execution timed out");
+    assert_eq!(
+        patient.exec("import time\ntime.sleep(1.5)\n1 + 1").unwrap(),
+        "2"
+    );
+}
+
+#[test]
+fn test_factory_rejects_compile_settings() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let builder = PySandbox::builder().engine_options(EngineOptions::fast());
+    let factory = SandboxFactory::new(builder).expect("Failed to compile component");
+    assert!(factory.builder().fuel_limit(1_000_000).build().is_err());
+    assert!(factory.builder().max_wasm_stack(1 << 20).build().is_err());
+    assert!(factory.builder().max_memory_bytes(64 << 20).build().is_ok());
+}
+
+#[test]
+fn test_max_memory_bytes_reports_limit() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .max_memory_bytes(256 * 1024 * 1024)
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("b'x' * (512 * 1024 * 1024)").unwrap_err();
+    match err.downcast_ref::<PyboxError>() {
+        Some(&PyboxError::MemoryLimitExceeded { requested, limit }) => {
+            assert_eq!(limit, 256 * 1024 * 1024);
+            assert!(requested > limit);
+        }
+        other => panic!("expected MemoryLimitExceeded, got {:?}", other),
+    }
+    // Later executions get a fresh store within the limit
+    assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+
+    // Code that recovers from the MemoryError fails with its own exception
+    let code = "def grow():\n    try:\n        b'x' * (512 * 1024 * 1024)\n    except MemoryError:\n        pass\n    raise ValueError('after')\ngrow()";
+    let err = sandbox.exec(code).unwrap_err();
+    let python = err.downcast_ref::<PythonError>().expect("expected a PythonError");
+    assert_eq!(python.exception, PyException::Value);
+}
+
+#[test]
+fn test_limits_report_violation() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .limits(StoreLimitsConfig {
+            memory: Some(256 * 1024 * 1024),
+            ..StoreLimitsConfig::default()
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("b'x' * (512 * 1024 * 1024)").unwrap_err();
+    let violation = err.downcast_ref::<LimitViolation>().unwrap();
+    assert_eq!(violation.limit, ResourceLimit::Memory);
+    assert_eq!(violation.allowed, 256 * 1024 * 1024);
+    assert!(violation.excess().unwrap() > 0);
+}
+
+#[test]
+fn test_count_limits_report_violation() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .max_instances(1)
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("1 + 1").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PyboxError>(),
+        Some(&PyboxError::LimitExceeded(ResourceLimit::Instances))
+    );
+    let violation = err.downcast_ref::<LimitViolation>().unwrap();
+    assert_eq!(violation.allowed, 1);
+    assert!(violation.excess().unwrap() > 0);
+}
+
+#[test]
+fn test_metrics_count_executions_by_outcome() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let metrics = Arc::new(PrometheusMetrics::default());
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .metrics(metrics.clone())
+        .build()
+        .expect("Failed to create sandbox");
+    sandbox.exec("1 + 1").unwrap();
+    sandbox.exec("undefined_name").unwrap_err();
+
+    let text = metrics.render();
+    assert!(text.contains("pybox_executions_started_total 2\n"));
+    assert!(text.contains("pybox_executions_total{outcome=\"succeeded\"} 1\n"));
+    assert!(text.contains("pybox_executions_total{outcome=\"failed\"} 1\n"));
+}
+
+#[test]
+fn test_audit_log_records_each_execution() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .audit(JsonlAuditLog::open(&path).unwrap())
+        .build()
+        .expect("Failed to create sandbox");
+    sandbox.exec("1 + 1").unwrap();
+    sandbox.exec("undefined_name").unwrap_err();
+
+    JsonlAuditLog::verify(&path).expect("Audit log chain is broken");
+    let log = std::fs::read_to_string(&path).unwrap();
+    let records: Vec<serde_json::Value> =
+        log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["code_sha256"], code_sha256("1 + 1"));
+    assert_eq!(records[0]["outcome"], "succeeded");
+    assert_eq!(records[1]["outcome"], "failed");
+}
+
+#[test]
+fn test_capture_output_separates_value_and_streams() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let options = ExecOptions {
+        capture_output: true,
+        ..Default::default()
+    };
+    let code = "import sys\nprint('out')\nprint('err', file=sys.stderr)\n40 + 2";
+    let output = sandbox.exec_with_options(code, &options).unwrap();
+    assert_eq!(output.value, "42");
+    assert_eq!(output.stdout, "out\n");
+    assert_eq!(output.stderr, "err\n");
+}
+
+#[test]
+fn test_on_output_receives_output_while_running() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink: OutputSink = {
+        let received = received.clone();
+        Arc::new(move |stream, bytes: &[u8]| received.lock().unwrap().push((stream, bytes.to_vec())))
+    };
+    let options = ExecOptions {
+        on_output: Some(sink),
+        ..Default::default()
+    };
+    let code = "import sys\nprint('out', flush=True)\nprint('err', file=sys.stderr, flush=True)\n1";
+    let output = sandbox.exec_with_options(code, &options).unwrap();
+    assert_eq!(output.value, "1");
+
+    let received = received.lock().unwrap();
+    let text = |wanted| -> String {
+        received
+            .iter()
+            .filter(|(stream, _)| *stream == wanted)
+            .map(|(_, bytes)| String::from_utf8_lossy(bytes).into_owned())
+            .collect()
+    };
+    assert_eq!(text(Stream::Stdout), "out\n");
+    assert_eq!(text(Stream::Stderr), "err\n");
+}
+
+#[test]
+fn test_exec_bytes_returns_raw_bytes() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let bytes = sandbox.exec_bytes("bytes(range(256))").unwrap();
+    assert_eq!(bytes, (0..=255).collect::<Vec<u8>>());
+    assert!(sandbox.exec_bytes("'not bytes'").is_err());
+}
+
+#[test]
+fn test_capture_figures_without_matplotlib_captures_nothing() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .capture_figures(true)
+        .build()
+        .expect("Failed to create sandbox");
+    let output = sandbox.exec_with_options("1 + 1", &ExecOptions::default()).unwrap();
+    assert_eq!(output.value, "2");
+    assert!(output.figures.is_empty());
+}
+
+#[test]
+fn test_display_collects_mime_outputs_in_order() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let code = "import pybox\npybox.display('<b>hi</b>', mime='text/html')\npybox.display({'a': 1})\n2";
+    let output = sandbox.exec_with_options(code, &ExecOptions::default()).unwrap();
+    assert_eq!(output.value, "2");
+    assert_eq!(
+        output.displays,
+        vec![
+            DisplayData {
+                mime_type: "text/html".to_string(),
+                data: b"<b>hi</b>".to_vec(),
+            },
+            DisplayData {
+                mime_type: "application/json".to_string(),
+                data: br#"{"a": 1}"#.to_vec(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_cancel_handle_aborts_running_exec() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let handle = sandbox.cancel_handle();
+    let canceller = {
+        let handle = handle.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            handle.cancel();
+        })
+    };
+
+    let err = sandbox.exec_with_handle("while True: pass", &handle).unwrap_err();
+    canceller.join().unwrap();
+    assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::Cancelled));
+
+    // The sandbox keeps working after a cancelled run
+    assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+}
+
+#[test]
+fn test_exec_with_timeout_overrides_sandbox_timeout() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(Some(60)).expect("Failed to create sandbox");
+    let start = std::time::Instant::now();
+    let result = sandbox.exec_with_timeout("while True: pass", std::time::Duration::from_secs(1));
+    assert_eq!(result.unwrap_err().downcast_ref::<PyboxError>(), Some(&PyboxError::Timeout));
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+
+    // The override doesn't stick to later calls
+    assert_eq!(sandbox.timeout_seconds, 60);
+    assert_eq!(sandbox.exec("3 * 3").unwrap(), "9");
+}
+
+#[test]
+fn test_exec_many_keeps_order_and_per_item_timeouts() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(Some(1)).expect("Failed to create sandbox");
+    let codes = ["1 + 1", "while True: pass", "print('hi')", "{}['a']", "2 * 3"];
+    let results = sandbox.exec_many(&codes, 2);
+    assert_eq!(results.len(), codes.len());
+    assert_eq!(results[0].as_ref().unwrap().value, "2");
+    let err = results[1].as_ref().unwrap_err();
+    assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::Timeout));
+    assert_eq!(results[2].as_ref().unwrap().stdout, "hi\n");
+    assert!(results[3].as_ref().unwrap_err().downcast_ref::<PythonError>().is_some());
+    assert_eq!(results[4].as_ref().unwrap().value, "6");
+}
+
+#[test]
+fn test_back_to_back_fast_execs_are_not_interrupted() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    // Runs keep going past the point where the first run's timeout would
+    // have passed, a leftover deadline would interrupt a later run
+    let mut sandbox = PySandbox::new_for_test(Some(1)).expect("Failed to create sandbox");
+    let options = ExecOptions {
+        timeout: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let start = std::time::Instant::now();
+    while start.elapsed() < std::time::Duration::from_millis(1500) {
+        let output = sandbox.exec_with_options("1 + 1", &options).unwrap();
+        assert_eq!(output.value, "2");
+        assert!(!output.stats.epoch_interrupted);
+    }
+}
+
+#[test]
+fn test_timeout_grace_interrupts_before_killing() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .timeout_seconds(1)
+        .timeout_grace(std::time::Duration::from_secs(5))
+        .build()
+        .expect("Failed to create sandbox");
+
+    // A handler returns partial results
+    let code = "def work():\n    done = 0\n    try:\n        while True:\n            done += 1\n    except KeyboardInterrupt:\n        return done > 0\nwork()";
+    let output = sandbox.exec_with_options(code, &ExecOptions::default()).unwrap();
+    assert_eq!(output.value, "true");
+    assert!(output.stats.interrupted);
+    assert!(output.stats.wall_time < std::time::Duration::from_secs(5));
+
+    // Unhandled it is a timeout, and code ignoring it is killed
+    for code in [
+        "while True: pass",
+        "def work():\n    while True:\n        try:\n            while True: pass\n        except KeyboardInterrupt:\n            pass\nwork()",
+    ] {
+        let err = sandbox.exec(code).unwrap_err();
+        assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::Timeout));
+    }
+}
+
+#[test]
+fn test_exec_with_options_reports_stats() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .fuel_limit(u64::MAX)
+        .build()
+        .expect("Failed to create sandbox");
+    let output = sandbox
+        .exec_with_options("sum(range(1000))", &ExecOptions::default())
+        .unwrap();
+
+    assert_eq!(output.value, "499500");
+    assert!(output.stats.wall_time > std::time::Duration::ZERO);
+    assert!(output.stats.fuel_consumed.unwrap() > 0);
+    assert_eq!(
+        output.stats.fuel_consumed.unwrap() + output.stats.fuel_remaining.unwrap(),
+        u64::MAX
+    );
+    assert!(output.stats.peak_memory_bytes > 0);
+}
+
+#[test]
+fn test_sandbox_pool_runs_concurrently() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let pool = SandboxPool::new(sandbox, 2);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let pool = &pool;
+                scope.spawn(move || pool.exec(&format!("{} * 2", i)).unwrap())
+            })
+            .collect();
+        let results: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, vec!["0", "2", "4", "6"]);
+    });
+
+    assert_eq!(pool.idle(), pool.size());
+}
+
+#[test]
+fn test_sandbox_pool_recovers_after_failure() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(Some(1)).expect("Failed to create sandbox");
+    let pool = SandboxPool::new(sandbox, 1);
+
+    assert!(pool.exec("while True: pass").is_err());
+    assert_eq!(pool.exec("1 + 1").unwrap(), "2");
+    assert_eq!(pool.idle(), 1);
+}
+
+#[test]
+fn test_exec_project_imports_and_reads_files() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let result = sandbox.exec_project("tests/fixtures/project", "main.py").unwrap();
+    assert_eq!(result, "\"hello pybox\"");
+}
+
+#[test]
+fn test_exec_file_runs_script() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let result = sandbox.exec_file("tests/fixtures/project/main.py").unwrap();
+    assert_eq!(result, "\"hello pybox\"");
+}
+
+#[test]
+fn test_exec_file_missing_script_is_an_error() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    assert!(sandbox.exec_file("tests/fixtures/project/missing.py").is_err());
+}
+
+#[test]
+fn test_exec_with_inputs_binds_variables() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let inputs = serde_json::json!({
+        "numbers": [1, 2, 3],
+        "label": "total'); import os; ('",
+    });
+    let result = sandbox
+        .exec_with_inputs("[label, sum(numbers)]", inputs.as_object().unwrap())
+        .unwrap();
+    assert_eq!(result, r#"["total'); import os; ('", 6]"#);
+}
+
+#[test]
+fn test_get_globals_reads_variables_after_exec() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .keep_globals(true)
+        .build()
+        .expect("Failed to create sandbox");
+    sandbox
+        .exec("result = sum([1, 2, 3])\nsummary = {'count': 3}")
+        .unwrap();
+    let globals = sandbox.get_globals(&["result", "summary"]).unwrap();
+    assert_eq!(globals["result"], serde_json::json!(6));
+    assert_eq!(globals["summary"], serde_json::json!({"count": 3}));
+
+    assert!(sandbox.get_globals(&["missing"]).is_err());
+}
+
+#[test]
+fn test_get_globals_before_exec_is_an_error() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    assert!(sandbox.get_globals(&["x"]).is_err());
+}
+
+#[test]
+fn test_exec_releases_its_instance_without_keep_globals() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    sandbox.exec("x = 1").unwrap();
+    let err = sandbox.get_globals(&["x"]).unwrap_err();
+    assert!(err.to_string().contains("keep_globals"));
+
+    // `run` still reads from the statements it just ran
+    assert_eq!(sandbox.run("y = 2", "y * 21").unwrap(), "42");
+    assert!(sandbox.get_globals(&["y"]).is_err());
+}
+
+#[test]
+fn test_call_function_with_json_arguments() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .keep_globals(true)
+        .build()
+        .expect("Failed to create sandbox");
+    sandbox
+        .exec("import math\ndef hypot(a, b, scale=1):\n    return math.sqrt(a * a + b * b) * scale")
+        .unwrap();
+
+    let kwargs = serde_json::json!({"scale": 2});
+    let result = sandbox
+        .call_function(
+            "hypot",
+            &[serde_json::json!(3), serde_json::json!(4)],
+            kwargs.as_object().unwrap(),
+        )
+        .unwrap();
+    assert_eq!(result, serde_json::json!(10.0));
+
+    let err = sandbox
+        .call_function("missing", &[], &serde_json::Map::new())
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("NameError"));
+}
+
+#[test]
+fn test_eval_expression() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    assert_eq!(sandbox.eval("[i * i for i in range(4)]").unwrap(), "[0, 1, 4, 9]");
+    // Statements are not expressions
+    assert!(sandbox.eval("x = 1").is_err());
+}
+
+#[test]
+fn test_run_statements_then_expression() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let statements = r#"
+def fibonacci(n):
+    seq = [0, 1]
+    while len(seq) < n:
+        seq.append(seq[-1] + seq[-2])
+    return seq[:n]
+"#;
+    let result = sandbox.run(statements, "fibonacci(6)").unwrap();
+    assert_eq!(result, "[0, 1, 1, 2, 3, 5]");
+}
+
+#[test]
+fn test_mounts_respect_access_mode() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let data = tempfile::tempdir().expect("Failed to create temp dir");
+    let scratch = tempfile::tempdir().expect("Failed to create temp dir");
+    std::fs::write(data.path().join("input.txt"), "42").unwrap();
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .mount(data.path(), "/data", MountMode::ReadOnly)
+        .mount(scratch.path(), "/out", MountMode::ReadWrite)
+        .build()
+        .expect("Failed to create sandbox");
+
+    let code = r#"
+with open("/data/input.txt") as f:
+    value = int(f.read())
+with open("/out/result.txt", "w") as f:
+    f.write(str(value * 2))
+value
+"#;
+    assert_eq!(sandbox.exec(code).unwrap(), "42");
+    assert_eq!(
+        std::fs::read_to_string(scratch.path().join("result.txt")).unwrap(),
+        "84"
+    );
+
+    // Read-only mounts can't be written to
+    assert!(sandbox.exec("open('/data/new.txt', 'w')").is_err());
+}
+
+#[test]
+fn test_work_dir_artifacts_are_returned() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .work_dir(true)
+        .build()
+        .expect("Failed to create sandbox");
+
+    let code = r#"
+import os
+os.makedirs("/work/reports")
+with open("/work/data.csv", "w") as f:
+    f.write("a,b\n1,2\n")
+with open("/work/reports/summary.txt", "w") as f:
+    f.write("ok")
+"#;
+    let output = sandbox.exec_with_options(code, &ExecOptions::default()).unwrap();
+    assert_eq!(
+        output.artifacts,
+        vec![
+            (std::path::PathBuf::from("data.csv"), b"a,b\n1,2\n".to_vec()),
+            (std::path::PathBuf::from("reports/summary.txt"), b"ok".to_vec()),
+        ]
+    );
+
+    // Each execution starts with an empty work directory
+    let output = sandbox
+        .exec_with_options("import os\nos.listdir('/work')", &ExecOptions::default())
+        .unwrap();
+    assert_eq!(output.value, "[]");
+    assert!(output.artifacts.is_empty());
+}
+
+#[test]
+fn test_env_vars_are_visible_to_guest() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .env("GREETING", "hello")
+        .envs([("A", "1"), ("B", "2")])
+        .build()
+        .expect("Failed to create sandbox");
+
+    let result = sandbox
+        .exec("import os\n[os.environ['GREETING'], os.environ['A'], os.environ['B']]")
+        .unwrap();
+    assert_eq!(result, r#"["hello", "1", "2"]"#);
+}
+
+#[test]
+fn test_host_env_is_not_inherited_by_default() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let result = sandbox.exec("import os\nlen(os.environ)").unwrap();
+    assert_eq!(result, "0");
+}
+
+#[test]
+fn test_args_are_visible_as_sys_argv() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .args(["script.py", "--flag", "x"])
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox.exec("import sys\nsys.argv").unwrap();
+    assert_eq!(result, r#"["script.py", "--flag", "x"]"#);
+}
+
+#[test]
+fn test_max_output_bytes_truncates_output() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .max_output_bytes(1024)
+        .build()
+        .expect("Failed to create sandbox");
+    let code = "for _ in range(100):\n    print('x' * 1000)\n1";
+    let output = sandbox.exec_with_options(code, &ExecOptions::default()).unwrap();
+    assert_eq!(output.value, "1");
+    assert!(output.stats.output_truncated);
+}
+
+#[test]
+fn test_max_write_bytes_fails_writes_past_the_limit() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .fast_compilation(true)
+        .work_dir(true)
+        .max_write_bytes(4096)
+        .build()
+        .expect("Failed to create sandbox");
+
+    let code = r#"
+import errno
+with open("/work/small.txt", "w") as f:
+    f.write("x" * 1000)
+try:
+    with open("/work/big.bin", "wb") as f:
+        for _ in range(100):
+            f.write(b"y" * 1000)
+    caught = None
+except OSError as e:
+    caught = e.errno
+caught == errno.ENOSPC
+"#;
+    let output = sandbox.exec_with_options(code, &ExecOptions::default()).unwrap();
+    assert_eq!(output.value, "true");
+    let written: usize = output.artifacts.iter().map(|(_, bytes)| bytes.len()).sum();
+    assert_eq!(written, 4096);
+
+    // Each execution gets the full quota again
+    let output = sandbox
+        .exec_with_options("open('/work/again.txt', 'w').write('z' * 4000)", &ExecOptions::default())
+        .unwrap();
+    assert_eq!(output.value, "4000");
+}
+
+#[test]
+fn test_allowed_imports_blocks_other_modules() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .allowed_imports(["math"])
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("import math\nmath.floor(2.5)").unwrap(), "2");
+    let err = sandbox.exec("import os").unwrap_err();
+    assert!(err.to_string().contains("ImportError"));
+}
+
+#[test]
+fn test_blocked_imports() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .blocked_imports(["os"])
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("from os import listdir").unwrap_err();
+    assert!(err.to_string().contains("ImportError"));
+}
+
+#[test]
+fn test_add_package_makes_it_importable() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .add_package("tests/fixtures/packages/greeting")
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox.exec("import greeting\ngreeting.greet('pybox')").unwrap();
+    assert_eq!(result, r#""hello pybox""#);
+}
+
+#[test]
+fn test_add_module_makes_it_importable() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .add_module("helpers", "def double(x):\n    return 2 * x\n")
+        .add_module("tools.text", "def shout(s):\n    return s.upper()\n")
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox
+        .exec("import helpers\nfrom tools.text import shout\n[helpers.double(21), shout('hi')]")
+        .unwrap();
+    assert_eq!(result, r#"[42, "HI"]"#);
+}
+
+#[test]
+fn test_new_preinitialized_has_modules_loaded() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_preinitialized(".", &["json"]).expect("Failed to create sandbox");
+    let result = sandbox.exec("import sys\n'json' in sys.modules").unwrap();
+    assert_eq!(result, "true");
+}
+
+#[test]
+fn test_deterministic_runs_are_identical() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let code = "import random, time, os\n[random.random(), time.time(), os.urandom(4).hex()]";
+    let run = |seed| {
+        let mut sandbox = PySandbox::builder()
+            .deterministic(seed)
+            .build()
+            .expect("Failed to create sandbox");
+        sandbox.exec(code).unwrap()
+    };
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[test]
+fn test_pure_compute_policy_hides_host() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .env("API_KEY", "secret")
+        .policy(SandboxPolicy::pure_compute())
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox
+        .exec("import os, time\n['API_KEY' in os.environ, time.gmtime().tm_year]")
+        .unwrap();
+    assert_eq!(result, "[false, 2000]");
+}
+
+#[cfg(feature = "validation")]
+#[test]
+fn test_validator_rejects_before_running() {
+    use pybox::validate::{CodeValidator, ValidationError};
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .validator(CodeValidator::strict().ban_imports(["os"]))
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec("import os\nos.listdir('/')").unwrap_err();
+    let validation = err.downcast_ref::<ValidationError>().unwrap();
+    assert_eq!(validation.violations[0].line, 1);
+    assert_eq!(sandbox.exec("import json\njson.dumps([1])").unwrap(), "\"[1]\"");
+}
+
+#[test]
+fn test_code_size_limits_reject_before_running() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .max_code_bytes(64)
+        .max_lines(2)
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("x = 1\nx + 1").unwrap(), "2");
+
+    let err = sandbox.exec(&"x = 1\n".repeat(3)).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<PyboxError>(),
+        Some(PyboxError::CodeTooLarge { limit: CodeLimit::Lines, actual: 3, .. })
+    ));
+    let err = sandbox.exec(&format!("'{}'", "x".repeat(100))).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<PyboxError>(),
+        Some(PyboxError::CodeTooLarge { limit: CodeLimit::Bytes, allowed: 64, .. })
+    ));
+}
+
+#[test]
+fn test_quota_is_shared_by_pool_workers() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::builder()
+        .quota(Quota {
+            max_executions: Some(3),
+            max_total_cpu: None,
+            window: std::time::Duration::from_secs(3600),
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    let pool = SandboxPool::new(sandbox, 2);
+    for _ in 0..3 {
+        assert_eq!(pool.exec("1 + 1").unwrap(), "2");
+    }
+    let err = pool.exec("1 + 1").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<PyboxError>(),
+        Some(PyboxError::QuotaExceeded { .. })
+    ));
+}
+
+#[test]
+fn test_quota_charges_guest_cpu() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .quota(Quota {
+            max_executions: None,
+            max_total_cpu: Some(std::time::Duration::from_millis(1)),
+            window: std::time::Duration::from_secs(3600),
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    let output = sandbox
+        .exec_with_options("sum(range(100_000))", &ExecOptions::default())
+        .unwrap();
+    assert!(output.stats.cpu_time > std::time::Duration::ZERO);
+    assert!(sandbox.exec("1 + 1").is_err());
+}
+
+#[test]
+fn test_verified_load_rejects_other_components() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sandbox.wasm");
+    std::fs::write(&path, b"\0asm\x0d\0\x01\0").unwrap();
+    let digest = "0".repeat(64);
+    let err = PySandbox::from_path_verified(&path, &digest, None)
+        .err()
+        .expect("A swapped component should be rejected");
+    let mismatch = err.downcast_ref::<DigestMismatch>().unwrap();
+    assert_eq!(mismatch.expected, digest);
+    assert!(format!("{:#}", err).contains("Refusing to load"));
+}
+
+#[test]
+fn test_exec_json_returns_value() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let value = sandbox.exec_json("{'name': 'ada', 'tags': [1, 2.5, None]}").unwrap();
+    assert_eq!(value, serde_json::json!({"name": "ada", "tags": [1, 2.5, null]}));
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn test_msgpack_inputs_carry_bytes() {
+    use pybox::sandbox::Transport;
+    use serde_bytes::ByteBuf;
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .transport(Transport::MessagePack)
+        .keep_globals(true)
+        .build()
+        .expect("Failed to create sandbox");
+    let inputs = std::collections::HashMap::from([("blob", ByteBuf::from(vec![0u8, 255]))]);
+    let doubled: ByteBuf = sandbox.exec_with_inputs_msgpack("out = blob * 2\nout", &inputs).unwrap();
+    assert_eq!(doubled.into_vec(), [0, 255, 0, 255]);
+    let out: ByteBuf = sandbox.get_global_msgpack("out").unwrap();
+    assert_eq!(out.len(), 4);
+
+    let mut json_inputs = serde_json::Map::new();
+    json_inputs.insert("n".to_string(), serde_json::json!(21));
+    assert_eq!(sandbox.exec_with_inputs("m = n * 2\nm", &json_inputs).unwrap(), "42");
+    let globals = sandbox.get_globals(&["m"]).unwrap();
+    assert_eq!(globals["m"], serde_json::json!(42));
+}
+
+#[test]
+fn test_host_fn_is_callable_from_guest() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .host_fn("lookup_user", |args| {
+            let id = args["id"].as_i64().ok_or_else(|| anyhow::anyhow!("missing id"))?;
+            Ok(serde_json::json!({"id": id, "name": "ada"}))
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox
+        .exec("import pybox.host\npybox.host.call('lookup_user', {'id': 7})['name']")
+        .unwrap();
+    assert_eq!(result, r#""ada""#);
+
+    let err = sandbox.exec("import pybox.host\npybox.host.call('lookup_user', {})").unwrap_err();
+    assert!(err.to_string().contains("HostCallError: missing id"));
+}
+
+struct Reverse;
+
+impl SandboxExtension for Reverse {
+    fn name(&self) -> &str {
+        "reverse"
+    }
+
+    fn call(&self, _method: &str, payload: &str) -> anyhow::Result<String> {
+        Ok(payload.chars().rev().collect())
+    }
+}
+
+#[test]
+fn test_extension_is_callable_from_guest() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .extension(Reverse)
+        .build()
+        .expect("Failed to create sandbox");
+    let result = sandbox
+        .exec("import pybox.extensions\npybox.extensions.call('reverse', 'run', 'abc')")
+        .unwrap();
+    assert_eq!(result, r#""cba""#);
+}
+
+#[test]
+fn test_session_cells_share_state() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let mut session = Session::new(sandbox);
+    let results = session.exec_cells(&[
+        "def square(n):\n    return n * n",
+        "print('computing')\nsquare(6)",
+        "undefined_name",
+        "square(3)",
+    ]);
+
+    assert_eq!(results[0].value.as_deref(), Some("null"));
+    assert_eq!(results[1].value.as_deref(), Some("36"));
+    assert_eq!(results[1].stdout, "computing\n");
+    assert!(results[2].error.as_deref().unwrap().contains("NameError"));
+    assert_eq!(results[3].value.as_deref(), Some("9"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_serves_executions_over_socket() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("pybox.sock");
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let path = socket.clone();
+    let max_timeout = std::time::Duration::from_secs(1);
+    std::thread::spawn(move || pybox::daemon::run(sandbox, path, 2, max_timeout));
+
+    let mut client = loop {
+        match pybox::daemon::Client::connect(&socket) {
+            Ok(client) => break client,
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(20)),
+        }
+    };
+    let report = client.exec("print('hi')\n6 * 7", None).unwrap();
+    assert_eq!(report["result"], 42);
+    assert_eq!(report["stdout"], "hi\n");
+    let report = client.exec("1 / 0", None).unwrap();
+    assert_eq!(report["ok"], false);
+    assert!(report["error"].as_str().unwrap().contains("ZeroDivisionError"));
+
+    // Asking for an hour still stops at the daemon's max timeout
+    let hour = Some(std::time::Duration::from_secs(3600));
+    let report = client.exec("while True: pass", hour).unwrap();
+    assert_eq!(report["error"], "Execution timed out");
+}
+
+#[test]
+fn test_session_eval_cell_sees_cell_variables() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let mut session = Session::new(sandbox);
+    session.exec_cell("x = 20");
+
+    let result = session.eval_cell("x + 22");
+    assert_eq!(result.value.as_deref(), Some("42"));
+    let result = session.eval_cell("y = 1");
+    assert!(result.error.as_deref().unwrap().contains("SyntaxError"));
+    assert_eq!(session.eval_cell("x").value.as_deref(), Some("20"));
+}
+
+#[test]
+fn test_scientific_stack_numpy_math() {
+    if !Path::new("sandbox-scientific.wasm").exists() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .with_scientific_stack()
+        .build()
+        .expect("Failed to create sandbox");
+    let code = "import numpy as np\na = np.arange(6).reshape(2, 3)\n(a @ a.T).tolist()";
+    assert_eq!(sandbox.exec(code).unwrap(), "[[5, 14], [14, 50]]");
+
+    let code = "import pandas as pd\nint(pd.DataFrame({'x': [1, 2, 3]})['x'].sum())";
+    assert_eq!(sandbox.exec(code).unwrap(), "6");
+}
+
+#[test]
+fn test_scientific_stack_requires_component() {
+    if Path::new("sandbox-scientific.wasm").exists() {
+        return;
+    }
+
+    let err = PySandbox::builder()
+        .with_scientific_stack()
+        .build()
+        .err()
+        .expect("Missing component should be reported");
+    assert!(err.to_string().contains("build_component.py --scientific"));
+}
+
+#[cfg(feature = "arrow")]
+#[test]
+fn test_exec_with_table_round_trips_arrow() {
+    use arrow_array::{Array, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+    use std::sync::Arc;
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    let batch = RecordBatch::try_from_iter([
+        ("id", Arc::new(Int64Array::from(vec![1, 2, 3])) as Arc<dyn Array>),
+        ("name", Arc::new(StringArray::from(vec![Some("a"), None, Some("c")])) as Arc<dyn Array>),
+    ])
+    .unwrap();
+    let code = "import pybox\n\
+                assert isinstance(table, pybox.Table)\n\
+                {'id': table['id'], 'half': [i / 2 for i in table['id']], 'named': [n is not None for n in table['name']]}";
+    let result = sandbox.exec_with_table(code, &batch).unwrap();
+    assert_eq!(result.num_rows(), 3);
+    let half = result.column_by_name("half").unwrap();
+    assert_eq!(half.as_any().downcast_ref::<Float64Array>().unwrap().value(1), 1.0);
+    let named = result.column_by_name("named").unwrap();
+    assert!(!named.as_any().downcast_ref::<BooleanArray>().unwrap().value(1));
+
+    let err = sandbox.exec_with_table("42", &batch).unwrap_err();
+    assert!(format!("{:#}", err).contains("TypeError"));
+}
+
+#[test]
+fn test_toolbox_describes_and_invokes_tools() {
+    use pybox::toolcall::Toolbox;
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let source = "def area(width: float, height: float = 1.0) -> float:\n    \"\"\"Area of a rectangle.\"\"\"\n    return width * height\n";
+    let sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    let mut tools = Toolbox::new(sandbox, source);
+    let specs = tools.describe_tools().unwrap();
+    assert_eq!(specs.len(), 1);
+    assert_eq!(specs[0].description, "Area of a rectangle.");
+    assert_eq!(specs[0].parameters["required"], serde_json::json!(["width"]));
+
+    assert_eq!(tools.invoke_tool("area", r#"{"width": 2, "height": 3}"#).unwrap(), serde_json::json!(6));
+    let err = tools.invoke_tool("area", r#"{"depth": 2}"#).unwrap_err();
+    let error = err.downcast_ref::<PythonError>().unwrap();
+    assert_eq!(error.exception, PyException::Value);
+    assert!(tools.invoke_tool("area", "not json").is_err());
+}
+
+#[test]
+fn test_session_manager_evicts_idle_sessions() {
+    use pybox::session::{SessionLimit, SessionManager};
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    let sessions = SessionManager::new(sandbox, 2, Some(std::time::Duration::from_millis(200)));
+    let a = sessions.get_or_create("a").unwrap();
+    a.lock().unwrap().exec_cell("x = 1");
+    drop(a);
+    sessions.get_or_create("b").unwrap();
+    assert_eq!(
+        sessions.get_or_create("c").err(),
+        Some(SessionLimit { max_sessions: 2 })
+    );
+
+    // The same session comes back with its state
+    let a = sessions.get("a").unwrap();
+    assert_eq!(a.lock().unwrap().eval_cell("x").value.as_deref(), Some("1"));
+
+    // Held sessions are in use, the others expire
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert_eq!(sessions.evict_expired(), 1);
+    assert!(sessions.get("b").is_none());
+    drop(a);
+    assert!(sessions.get_or_create("c").is_ok());
+    assert!(sessions.remove("a"));
+    assert_eq!(sessions.len(), 1);
+}
+
+#[test]
+fn test_session_snapshot_restores_in_another_sandbox() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut session = Session::new(PySandbox::new(None).expect("Failed to create sandbox"));
+    session.exec_cells(&["import math", "total = [1, 2]", "def double(x):\n    return x * 2"]);
+    let snapshot = session.snapshot().unwrap();
+
+    let mut restored = Session::new(PySandbox::new(None).expect("Failed to create sandbox"));
+    restored.restore(&snapshot).unwrap();
+    let cell = restored.exec_cell("total + [math.floor(2.5)]");
+    assert_eq!(cell.value.as_deref(), Some("[1, 2, 2]"));
+    // Functions defined in cells aren't captured
+    assert!(restored.exec_cell("double(1)").error.unwrap().contains("NameError"));
+
+    assert!(restored.restore(b"not a snapshot").is_err());
+}
+
+#[test]
+fn test_input_is_answered_by_host() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let prompts = Arc::new(Mutex::new(Vec::new()));
+    let seen = prompts.clone();
+    let mut sandbox = PySandbox::builder()
+        .on_input(move |prompt| {
+            seen.lock().unwrap().push(prompt.to_string());
+            (prompt == "Name? ").then(|| "Ada\n".to_string())
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("'Hello ' + input('Name? ')").unwrap(), "\"Hello Ada\"");
+    let err = sandbox.exec("input('Age? ')").unwrap_err();
+    let error = err.downcast_ref::<PythonError>().unwrap();
+    assert_eq!(error.exception, PyException::Other("EOFError".to_string()));
+    assert_eq!(*prompts.lock().unwrap(), ["Name? ", "Age? "]);
+
+    // Without a callback stdin is empty
+    let mut sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    assert!(sandbox.exec("input()").is_err());
+    assert_eq!(sandbox.exec("import sys\nsys.stdin.read()").unwrap(), "\"\"");
+}
+
+#[test]
+fn test_progress_reaches_host() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let seen = reports.clone();
+    let mut sandbox = PySandbox::builder()
+        .on_progress(move |percent, message| {
+            seen.lock().unwrap().push((percent, message.to_string()));
+        })
+        .build()
+        .expect("Failed to create sandbox");
+    sandbox
+        .exec("import pybox\nfor i in range(3):\n    pybox.progress(i * 50, f'step {i}')")
+        .unwrap();
+    assert_eq!(
+        *reports.lock().unwrap(),
+        [
+            (0.0, "step 0".to_string()),
+            (50.0, "step 1".to_string()),
+            (100.0, "step 2".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_component_variant_reports_version() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let registry = ComponentRegistry::new().register("default", ComponentVariant::new("sandbox.wasm"));
+    let mut sandbox = PySandbox::builder()
+        .component_registry(registry)
+        .component_variant("default")
+        .build()
+        .expect("Failed to create sandbox");
+    let version = sandbox.version().unwrap();
+    assert_eq!(version.variant.as_deref(), Some("default"));
+    assert!(version.python.starts_with("3."));
+    assert_eq!(version.implementation, "cpython");
+
+    let version = PySandbox::new(None).unwrap().version().unwrap();
+    assert_eq!(version.variant, None);
+}
+
+#[test]
+fn test_reload_component_switches_clones() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::new(None).expect("Failed to create sandbox");
+    let mut session = Session::new(sandbox.clone());
+    assert_eq!(session.exec_cell("x = 1\nx").value.as_deref(), Some("1"));
+
+    sandbox.reload_component("sandbox.wasm").unwrap();
+    // The session keeps its interpreter, new executions use the new component
+    assert_eq!(session.exec_cell("x + 1").value.as_deref(), Some("2"));
+    assert_eq!(session.sandbox().exec("1 + 1").unwrap(), "2");
+
+    // A component that fails to load leaves the previous one in use
+    assert!(sandbox.reload_component("missing.wasm").is_err());
+    assert_eq!(sandbox.exec("2 + 2").unwrap(), "4");
+}
+
+#[test]
+fn test_reload_component_checks_the_pinned_digest() {
+    use sha2::{Digest, Sha256};
+
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let digest = format!("{:x}", Sha256::digest(std::fs::read("sandbox.wasm").unwrap()));
+    let mut sandbox = PySandbox::builder()
+        .expected_sha256(&digest)
+        .build()
+        .expect("Failed to create sandbox");
+    let dir = tempfile::tempdir().unwrap();
+    let swapped = dir.path().join("sandbox.wasm");
+    std::fs::write(&swapped, b"\0asm\x0d\0\x01\0").unwrap();
+
+    let err = sandbox.reload_component(&swapped).unwrap_err();
+    assert!(err.downcast_ref::<DigestMismatch>().is_some());
+    assert!(sandbox.reload_component_pinned(&swapped, "0".repeat(64)).is_err());
+    sandbox.reload_component("sandbox.wasm").unwrap();
+    assert_eq!(sandbox.exec("1 + 1").unwrap(), "2");
+}
+
+#[test]
+fn test_clock_policy_controls_wall_clock() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    let mut sandbox = PySandbox::builder()
+        .clock_policy(ClockPolicy::FrozenAt(at))
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("import time\n[time.time(), time.time()]").unwrap(), "[1700000000.0, 1700000000.0]");
+
+    let mut sandbox = PySandbox::builder()
+        .clock_policy(ClockPolicy::MonotonicOnly)
+        .build()
+        .expect("Failed to create sandbox");
+    let code = "import time\nstart = time.monotonic()\ntime.sleep(0.01)\n[time.time(), time.monotonic() > start]";
+    assert_eq!(sandbox.exec(code).unwrap(), "[0.0, true]");
+}
+
+#[test]
+fn test_random_policy_seeds_or_denies_entropy() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let code = "import os\nos.urandom(8).hex()";
+    let draw = |policy| {
+        let mut sandbox = PySandbox::builder()
+            .random(policy)
+            .build()
+            .expect("Failed to create sandbox");
+        sandbox.exec(code).unwrap()
+    };
+    assert_eq!(draw(RandomPolicy::Seeded(7)), draw(RandomPolicy::Seeded(7)));
+    assert_ne!(draw(RandomPolicy::Seeded(7)), draw(RandomPolicy::Seeded(8)));
+
+    let mut sandbox = PySandbox::builder()
+        .random(RandomPolicy::Deny)
+        .build()
+        .expect("Failed to create sandbox");
+    let err = sandbox.exec(code).unwrap_err();
+    assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::RandomDenied));
+}
+
+#[test]
+fn test_line_budget_stops_long_running_code() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let mut sandbox = PySandbox::builder()
+        .line_budget(1000)
+        .build()
+        .expect("Failed to create sandbox");
+    assert_eq!(sandbox.exec("sum(range(10))").unwrap(), "45");
+
+    let err = sandbox.exec("while True:\n    pass").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<PyboxError>(),
+        Some(&PyboxError::ExecutionBudgetExceeded {
+            budget: 1000,
+            executed: 1001
+        })
+    );
+}
+
+#[test]
+fn test_session_recovers_after_timeout() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let stuck = ExecOptions {
+        timeout: Some(std::time::Duration::from_millis(200)),
+        ..Default::default()
+    };
+
+    // By hand, from the last snapshot
+    let mut session = Session::new(PySandbox::new_for_test(None).expect("Failed to create sandbox"));
+    session.exec_cell("x = 41");
+    session.snapshot().unwrap();
+    session.exec_cell("y = 1");
+    assert!(session.exec_cell_with_options("while True:\n    pass", &stuck).error.is_some());
+    session.recover().unwrap();
+    assert_eq!(session.eval_cell("x + 1").value.as_deref(), Some("42"));
+    assert!(session.eval_cell("y").error.as_deref().unwrap().contains("NameError"));
+
+    // Automatically, from the cell before the one that failed
+    let sandbox = PySandbox::builder()
+        .auto_recover(true)
+        .build()
+        .expect("Failed to create sandbox");
+    let mut session = Session::new(sandbox);
+    session.exec_cell("x = 41");
+    session.exec_cell("def add(a, b):\n    return a + b");
+    // An exception leaves the interpreter, and the function the snapshot
+    // can't hold, in place
+    assert!(session.exec_cell("undefined_name").error.is_some());
+    assert_eq!(session.eval_cell("add(x, 1)").value.as_deref(), Some("42"));
+    session.exec_cell("y = 1");
+    assert!(session.exec_cell_with_options("while True:\n    pass", &stuck).error.is_some());
+    assert_eq!(session.eval_cell("x + y").value.as_deref(), Some("42"));
+}
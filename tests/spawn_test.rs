@@ -0,0 +1,55 @@
+#![cfg(feature = "tokio-rt")]
+
+use pybox::error::PyboxError;
+use pybox::sandbox::{ExecOptions, PySandbox};
+use std::path::Path;
+use std::time::Duration;
+
+/// Helper to check if sandbox.wasm exists
+fn has_sandbox_wasm() -> bool {
+    Path::new("sandbox.wasm").exists()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spawn_exec_runs_concurrently() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let runs: Vec<_> = (0..4).map(|i| sandbox.spawn_exec(format!("{} * 2", i))).collect();
+    for (i, run) in runs.into_iter().enumerate() {
+        assert_eq!(run.await.unwrap().value, (i * 2).to_string());
+    }
+}
+
+#[tokio::test]
+async fn test_spawn_exec_timeout() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(Some(1)).expect("Failed to create sandbox");
+    let err = sandbox.spawn_exec("while True: pass").await.unwrap_err();
+    assert_eq!(err.downcast_ref::<PyboxError>(), Some(&PyboxError::Timeout));
+}
+
+#[tokio::test]
+async fn test_dropping_spawned_exec_cancels_it() {
+    if !has_sandbox_wasm() {
+        return;
+    }
+
+    let sandbox = PySandbox::new_for_test(None).expect("Failed to create sandbox");
+    let cancel = sandbox.cancel_handle();
+    let options = ExecOptions {
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    };
+    let run = sandbox.spawn_exec_with_options("while True: pass", options);
+    let aborted = tokio::time::timeout(Duration::from_millis(200), run).await;
+    assert!(aborted.is_err());
+    assert!(cancel.is_cancelled());
+
+    assert_eq!(sandbox.spawn_exec("2 + 2").await.unwrap().value, "4");
+}
@@ -0,0 +1,219 @@
+//! Development tasks, run with `cargo xtask <task>`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use pybox::sandbox::PySandbox;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Parser)]
+#[command(name = "xtask")]
+struct Cli {
+    #[command(subcommand)]
+    command: Task,
+}
+
+#[derive(Debug, Subcommand)]
+enum Task {
+    /// Build sandbox.wasm from guest.py and check it against sandbox.wit.
+    BuildComponent(BuildArgs),
+    /// Regenerate ffi/include/pybox.h from the `extern "C"` functions of
+    /// the ffi crate.
+    FfiHeader(HeaderArgs),
+}
+
+#[derive(Debug, Args)]
+struct BuildArgs {
+    /// Where to write the component, relative to the repository root.
+    #[arg(short, long, default_value = "sandbox.wasm")]
+    output: PathBuf,
+    /// Modules to import before the interpreter is snapshotted.
+    #[arg(long, num_args = 1.., value_name = "MODULE")]
+    preload: Vec<String>,
+    /// Bundle numpy and pandas from the wasi wheels in this directory.
+    #[arg(long, value_name = "WHEELS_DIR")]
+    scientific: Option<PathBuf>,
+    /// Also copy the component to $XDG_DATA_HOME/pybox, where an
+    /// installed pybox finds it from any directory.
+    #[arg(long)]
+    install: bool,
+}
+
+#[derive(Debug, Args)]
+struct HeaderArgs {
+    /// Fail if the checked in header is out of date instead of writing it.
+    #[arg(long)]
+    check: bool,
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Task::BuildComponent(args) => build_component(args),
+        Task::FfiHeader(args) => ffi_header(args),
+    }
+}
+
+fn build_component(args: BuildArgs) -> Result<()> {
+    let root = repo_root();
+    let output = root.join(&args.output);
+    componentize(&root, &args, &output)?;
+    verify(&output)?;
+
+    let digest = Sha256::digest(fs::read(&output)?);
+    println!("{}  {}", hex(&digest), output.display());
+
+    if args.install {
+        let dir = data_dir()?.join("pybox");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let installed = dir.join("sandbox.wasm");
+        fs::copy(&output, &installed)
+            .with_context(|| format!("Failed to copy to {}", installed.display()))?;
+        println!("Installed {}", installed.display());
+    }
+    Ok(())
+}
+
+fn ffi_header(args: HeaderArgs) -> Result<()> {
+    let root = repo_root();
+    let path = root.join("ffi/include/pybox.h");
+    let header = generate_header(&root.join("ffi"))?;
+    if args.check {
+        let current = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        if current != header {
+            bail!("{} is out of date, run `cargo xtask ffi-header`", path.display());
+        }
+        return Ok(());
+    }
+    fs::write(&path, header).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The C header for the ffi crate in `crate_dir`, configured by its
+/// cbindgen.toml.
+fn generate_header(crate_dir: &Path) -> Result<Vec<u8>> {
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .map_err(|e| anyhow!("Failed to read cbindgen.toml: {}", e))?;
+    let mut header = Vec::new();
+    cbindgen::Builder::new()
+        .with_crate(crate_dir)
+        .with_config(config)
+        .generate()
+        .context("Failed to generate the C header")?
+        .write(&mut header);
+    Ok(header)
+}
+
+/// Run build_component.py with the componentize-py version locked in
+/// uv.lock, so the same sources build the same component.
+fn componentize(root: &Path, args: &BuildArgs, output: &Path) -> Result<()> {
+    let mut command = Command::new("uv");
+    command
+        .current_dir(root)
+        .args(["run", "--frozen", "build_component.py", "-o"])
+        .arg(output);
+    if !args.preload.is_empty() {
+        command.arg("--preload").args(&args.preload);
+    }
+    if let Some(wheels) = &args.scientific {
+        command.arg("--scientific").arg(wheels);
+    }
+    let status = command
+        .status()
+        .context("Failed to run uv, install it from https://docs.astral.sh/uv/")?;
+    if !status.success() {
+        bail!("build_component.py failed with {}", status);
+    }
+    Ok(())
+}
+
+/// Load the component the way the host does, which checks its imports
+/// and exports against the bindings generated from sandbox.wit, then run
+/// a snippet through it.
+fn verify(component: &Path) -> Result<()> {
+    let mut sandbox = PySandbox::builder()
+        .component_file(component)
+        .build()
+        .context("The component doesn't match sandbox.wit")?;
+    let value = sandbox
+        .exec("1 + 1")
+        .context("The component failed to run a snippet")?;
+    if value != "2" {
+        return Err(anyhow!("The component evaluated 1 + 1 to {}", value));
+    }
+    Ok(())
+}
+
+fn repo_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask is in the repository")
+        .to_path_buf()
+}
+
+fn data_dir() -> Result<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .context("Neither XDG_DATA_HOME nor HOME is set")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_build_component() {
+        let cli = Cli::try_parse_from([
+            "xtask",
+            "build-component",
+            "--preload",
+            "json",
+            "re",
+            "--install",
+        ])
+        .unwrap();
+        let Task::BuildComponent(args) = cli.command else {
+            panic!("expected build-component");
+        };
+        assert_eq!(args.output, Path::new("sandbox.wasm"));
+        assert_eq!(args.preload, ["json", "re"]);
+        assert!(args.install);
+    }
+
+    #[test]
+    fn test_ffi_header_is_up_to_date() {
+        // Stands in for running `cargo xtask ffi-header --check` in CI
+        let cli = Cli::try_parse_from(["xtask", "ffi-header", "--check"]).unwrap();
+        let Task::FfiHeader(args) = cli.command else {
+            panic!("expected ffi-header");
+        };
+        ffi_header(args).unwrap();
+    }
+
+    #[test]
+    fn test_verify_rejects_other_components() {
+        // An empty component has none of the exports of sandbox.wit
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.wasm");
+        fs::write(&path, b"\0asm\x0d\0\x01\0").unwrap();
+        assert!(verify(&path).is_err());
+    }
+}